@@ -0,0 +1,87 @@
+#!/bin/bash
+# Checks that the audio path can't panic, in the style of the panic-never crate
+#
+# Builds the firmware with the no-panic feature and disassembles it
+# Starting from the buffer fill (fill_next, which runs the player and its effects) and the DMA interrupts,
+# every function in this crate that can be reached is checked
+# The output DMA interrupt is DMA1_STREAM5 with the f4-discovery feature, and the second output's with dual-i2s
+# The check fails if any of them calls into the core panic machinery
+# Functions from other crates (the HAL, cortex-m) are not followed
+#
+# The sd card driver in the HAL is inlined into the SdioBlockDevice BlockDevice impl, its FIFO copy has a length check that can't be removed
+# That function is listed in ALLOWED, panics found in it are reported but don't fail the check
+#
+# Needs llvm-objdump (from llvm or cargo-binutils)
+# Usage: scripts/check_no_panic.sh
+
+set -euo pipefail
+cd "$(dirname "$0")/.."
+
+ROOTS="dap::fill_next dap::wav::WavFile::fill_samples DMA1_STREAM4 DMA1_STREAM5"
+ALLOWED='BlockDevice.*for.*SdioBlockDevice.*::read_to_block$'
+ELF=target/thumbv7em-none-eabihf/release/dap
+
+cargo build --release --features no-panic
+llvm-objdump -d --demangle --no-show-raw-insn "$ELF" | awk -v roots="$ROOTS" -v allowed="$ALLOWED" '
+    # Function labels, skipping the $t/$d mapping symbols
+    /^[0-9a-f]+ <[^$]/ {
+        fn = $0
+        sub(/^[0-9a-f]+ </, "", fn)
+        sub(/>:$/, "", fn)
+        short = fn
+        sub(/::h[0-9a-f]+$/, "", short)
+        name[fn] = short
+    }
+
+    # Branches with a link, and tail calls
+    /\t(bl|b|b\.w)\t/ && /</ {
+        callee = $0
+        sub(/.*</, "", callee)
+        sub(/>.*/, "", callee)
+        sub(/\+0x[0-9a-f]+$/, "", callee)
+        if (callee != fn) {
+            calls[fn] = calls[fn] "\n" callee
+        }
+    }
+
+    END {
+        n = split(roots, root_list, " ")
+        for (fn in name) {
+            for (i = 1; i <= n; i++) {
+                if (name[fn] == root_list[i]) {
+                    queue[++tail] = fn
+                    seen[fn] = 1
+                }
+            }
+        }
+        if (tail == 0) {
+            print "None of the root functions were found: " roots
+            exit 1
+        }
+
+        failed = 0
+        for (head = 1; head <= tail; head++) {
+            fn = queue[head]
+            m = split(calls[fn], callees, "\n")
+            for (i = 1; i <= m; i++) {
+                callee = callees[i]
+                if (callee == "") continue
+
+                if (callee ~ /^core::(panicking|cell::panic|option::(unwrap|expect)_failed|result::unwrap_failed|slice::index::|str::slice_error|slice::copy_from_slice_impl)/) {
+                    if (name[fn] ~ allowed) {
+                        print "allowed: " name[fn] " -> " callee
+                    } else {
+                        print "can panic: " name[fn] " -> " callee
+                        failed = 1
+                    }
+                } else if (!(callee in seen) && (callee ~ /dap/ || callee ~ /^OUTLINED_FUNCTION/)) {
+                    seen[callee] = 1
+                    queue[++tail] = callee
+                }
+            }
+        }
+
+        if (failed) exit 1
+        print "Checked " tail " functions, the audio path can not panic"
+    }
+'
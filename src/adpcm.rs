@@ -0,0 +1,170 @@
+// Microsoft ADPCM decoder (wav format code 0x0002)
+// Useful resource: https://wiki.multimedia.cx/index.php/Microsoft_ADPCM
+//
+// The data is split into blocks of block_align bytes, each starting with a header for every channel:
+// predictor index (u8), delta (i16), sample1 (i16), sample2 (i16)
+// The header samples are the first two samples of the block (sample2 comes first)
+// The rest of the block is 4 bit nibbles, high nibble first, with the channels interleaved
+
+use heapless::Vec;
+
+pub const MAX_CHANNELS: usize = 2;
+pub const MAX_BLOCK_ALIGN: usize = 2048;
+pub const MAX_COEFFICIENTS: usize = 32;
+
+const HEADER_BYTES_PER_CHANNEL: usize = 7;
+const MIN_DELTA: i32 = 16;
+
+const ADAPTATION_TABLE: [i32; 16] = [
+    230, 230, 230, 230, 307, 409, 512, 614,
+    768, 614, 512, 409, 307, 230, 230, 230,
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelState {
+    coefficient1: i32,
+    coefficient2: i32,
+    delta: i32,
+    sample1: i32, // The most recent sample
+    sample2: i32, // The sample before that
+}
+
+pub struct MsAdpcm {
+    coefficients: Vec<(i16, i16), MAX_COEFFICIENTS>, // Predictor coefficient pairs from the fmt chunk
+    block_align: usize,
+    samples_per_block: usize, // Frames per block, including the two header frames
+    n_channels: usize,
+    channels: [ChannelState; MAX_CHANNELS],
+
+    block: [u8; MAX_BLOCK_ALIGN],
+    frame: usize,   // The frame of the current block being decoded
+    channel: usize, // The channel of the next sample
+}
+
+impl core::fmt::Debug for MsAdpcm {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MsAdpcm")
+            .field("block_align", &self.block_align)
+            .field("samples_per_block", &self.samples_per_block)
+            .field("frame", &self.frame)
+            .finish()
+    }
+}
+
+impl MsAdpcm {
+
+    // Returns None if the parameters from the fmt chunk can't be decoded by this implementation
+    pub fn new(block_align: u16, n_channels: u16, samples_per_block: u16, coefficients: Vec<(i16, i16), MAX_COEFFICIENTS>)
+    -> Option<Self> {
+        let block_align = block_align as usize;
+        let n_channels = n_channels as usize;
+        let samples_per_block = samples_per_block as usize;
+
+        if n_channels == 0 || n_channels > MAX_CHANNELS || block_align > MAX_BLOCK_ALIGN || coefficients.is_empty() {
+            return None;
+        }
+
+        // Check that all the samples actually fit in the block
+        let header_bytes = HEADER_BYTES_PER_CHANNEL * n_channels;
+        if samples_per_block < 2 || header_bytes + ((samples_per_block - 2) * n_channels).div_ceil(2) > block_align {
+            return None;
+        }
+
+        Some(MsAdpcm {
+            coefficients,
+            block_align,
+            samples_per_block,
+            n_channels,
+            channels: [ChannelState::default(); MAX_CHANNELS],
+            block: [0; MAX_BLOCK_ALIGN],
+            frame: samples_per_block, // No block has been read yet
+            channel: 0,
+        })
+    }
+
+    // Forget the current block, e.g. when going back to the start of the file
+    pub fn reset(&mut self) {
+        self.frame = self.samples_per_block;
+        self.channel = 0;
+    }
+
+    // Frames per block, including the two header frames
+    pub fn samples_per_block(&self) -> usize {
+        self.samples_per_block
+    }
+
+    // True when all the samples from the current block have been decoded
+    pub fn needs_block(&self) -> bool {
+        self.frame == self.samples_per_block
+    }
+
+    // The buffer that the next block should be read into, followed by a call to start_block
+    pub fn block_mut(&mut self) -> &mut [u8] {
+        let block_align = self.block_align.min(MAX_BLOCK_ALIGN); // Checked in new, this just keeps the compiler from adding a panic
+        &mut self.block[..block_align]
+    }
+
+    // Reads the headers of the block that was just read into block_mut
+    pub fn start_block(&mut self) -> Result<(), ()> {
+        let n_channels = self.n_channels;
+        let read_i16 = |block: &[u8], offset: usize| -> Result<i32, ()> {
+            let bytes = block.get(offset..offset + 2).and_then(|bytes| bytes.try_into().ok()).ok_or(())?;
+            Ok(i16::from_le_bytes(bytes) as i32)
+        };
+
+        for (ch, state) in self.channels.iter_mut().take(n_channels).enumerate() {
+            let predictor = *self.block.get(ch).ok_or(())? as usize;
+            let (coefficient1, coefficient2) = *self.coefficients.get(predictor).ok_or(())?;
+
+            state.coefficient1 = coefficient1 as i32;
+            state.coefficient2 = coefficient2 as i32;
+            state.delta = read_i16(&self.block, n_channels + ch * 2)?;
+            state.sample1 = read_i16(&self.block, n_channels * 3 + ch * 2)?;
+            state.sample2 = read_i16(&self.block, n_channels * 5 + ch * 2)?;
+        }
+
+        self.frame = 0;
+        self.channel = 0;
+        Ok(())
+    }
+
+    // Decodes the next sample, channels are interleaved
+    // Only call this when needs_block is false
+    pub fn next_sample(&mut self) -> Result<i16, ()> {
+        let ch = self.channel;
+        let state = self.channels.get_mut(ch).ok_or(())?;
+
+        let sample = match self.frame {
+            0 => state.sample2,
+            1 => state.sample1,
+            _ => {
+                let nibble_indx = (self.frame - 2) * self.n_channels + ch;
+                let byte = *self.block.get(HEADER_BYTES_PER_CHANNEL * self.n_channels + nibble_indx / 2).ok_or(())?;
+                let nibble = if nibble_indx & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+
+                decode_nibble(state, nibble)
+            }
+        };
+
+        self.channel += 1;
+        if self.channel == self.n_channels {
+            self.channel = 0;
+            self.frame += 1;
+        }
+
+        Ok(sample as i16)
+    }
+}
+
+fn decode_nibble(state: &mut ChannelState, nibble: u8) -> i32 {
+    let signed_nibble = if nibble & 0x08 != 0 { nibble as i32 - 16 } else { nibble as i32 };
+
+    let prediction = (state.sample1 * state.coefficient1 + state.sample2 * state.coefficient2) / 256;
+    let sample = (prediction + signed_nibble * state.delta).clamp(i16::MIN as i32, i16::MAX as i32);
+
+    state.sample2 = state.sample1;
+    state.sample1 = sample;
+    state.delta = (ADAPTATION_TABLE[nibble as usize] * state.delta / 256).max(MIN_DELTA);
+
+    sample
+}
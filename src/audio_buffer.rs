@@ -1,29 +1,227 @@
+// Ring of audio buffers shared between the main loop, which fills them, and the DMA ISR, which plays them
+//
+// The slots are used in order, and three counters say where each slot is
+// filled is how many slots the main loop has filled, only the main loop changes it
+// queued is how many slots the ISR has handed to the DMA, and released how many the DMA has finished, only the ISR changes these
+// Slot n % N is Filled between queued and filled, Playing between released and queued, and Empty otherwise
+// Since every counter only has one writer the main loop and ISR don't need critical sections to share the ring
+//
+// The DMA runs in double buffer mode so it holds two buffers at once, the one it is playing and the one queued after it
+// Each time the DMA finishes a buffer the slot it was playing is released and the next Filled slot is queued
+// With more slots the main loop can fill further ahead, so a slow sd card read doesn't starve the DMA
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum AudioBufState {
     Filling,
     Filled,
-    Playing,
+    Playing, // Handed to the DMA, either playing or queued to play next
     Empty,
 }
 
-#[derive(Debug)]
-pub struct DbufInfo {
-    pub buf_states: [AudioBufState; 2],
+pub struct AudioRing<const N: usize, const LEN: usize> {
+    bufs: UnsafeCell<[[u16; LEN]; N]>,
+
+    // Owned by the main loop
+    filled: AtomicUsize,
+    claimed: AtomicBool, // The slot at filled is being filled
+
+    // Owned by the ISR
+    queued: AtomicUsize,
+    released: AtomicUsize,
+    playing_slot: AtomicBool, // The DMA is playing a slot, otherwise the silence buffer
+    queued_slot: AtomicBool, // The DMA plays a slot next, otherwise the silence buffer
 }
 
-impl DbufInfo {
+// A slot is only written by the main loop while it is Empty, and the ISR only reads slots once they are Filled
+unsafe impl<const N: usize, const LEN: usize> Sync for AudioRing<N, LEN> {}
+
+impl<const N: usize, const LEN: usize> AudioRing<N, LEN> {
+    pub const fn new() -> Self {
+        AudioRing {
+            bufs: UnsafeCell::new([[0; LEN]; N]),
+            filled: AtomicUsize::new(0),
+            claimed: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            released: AtomicUsize::new(0),
+            playing_slot: AtomicBool::new(false),
+            queued_slot: AtomicBool::new(false),
+        }
+    }
+
+    // Claims the next slot if it is Empty, it is Filling until commit_fill is called
+    // Unsafe since only the main loop may call this, and the buffer must not be used after commit_fill
+    #[allow(clippy::mut_from_ref, clippy::missing_safety_doc)] // The ring hands out each slot once, like a split borrow
+    pub unsafe fn claim_fill(&'static self) -> Option<&'static mut [u16; LEN]> {
+        let filled = self.filled.load(Ordering::Relaxed);
+        if filled.wrapping_sub(self.released.load(Ordering::Acquire)) >= N {
+            return None;
+        }
+        self.claimed.store(true, Ordering::Relaxed);
+
+        // The DMA has released this slot and the ISR won't queue it until it is committed
+        // Only this slot is borrowed, the DMA may be reading the others
+        let slot = self.slot(filled % N)?;
+        Some(unsafe { &mut *slot })
+    }
+
+    fn slot(&self, indx: usize) -> Option<*mut [u16; LEN]> {
+        (indx < N).then(|| (self.bufs.get() as *mut [u16; LEN]).wrapping_add(indx))
+    }
+
+    // Marks the claimed slot Filled, so the DMA plays it after the slots before it
+    // Returns the number of the slot, for has_played
+    pub fn commit_fill(&self) -> usize {
+        self.claimed.store(false, Ordering::Relaxed);
+        self.filled.fetch_add(1, Ordering::Release)
+    }
+
+    // True once the DMA has finished playing the slot numbered slot_number
+    pub fn has_played(&self, slot_number: usize) -> bool {
+        // The counters wrap, so released is past slot_number if the difference is small and not zero
+        let played_past = self.released.load(Ordering::Acquire).wrapping_sub(slot_number);
+        played_past != 0 && played_past < usize::MAX / 2
+    }
+
+    // Slots that are Filled or Playing, how far ahead of the output the main loop is
+    pub fn buffered(&self) -> usize {
+        self.filled.load(Ordering::Relaxed).wrapping_sub(self.released.load(Ordering::Acquire)).min(N)
+    }
+
+    // True while every slot is Filled or Playing, so claim_fill would return None
+    pub fn is_full(&self) -> bool {
+        self.filled.load(Ordering::Relaxed).wrapping_sub(self.released.load(Ordering::Acquire)) >= N
+    }
+
+    // True while there are Filled slots that haven't been handed to the DMA
+    pub fn has_filled(&self) -> bool {
+        self.filled.load(Ordering::Relaxed) != self.queued.load(Ordering::Acquire)
+    }
+
+    // Throws away the slots which are waiting to be played, e.g. when the track is changed
+    // The slots that have been handed to the DMA can't be taken back, so they finish first
+    pub fn abandon_filled(&self) {
+        // The ISR could queue a slot while filled is moved back, so this is the one place that needs a critical section
+        cortex_m::interrupt::free(|_cs| {
+            self.filled.store(self.queued.load(Ordering::Relaxed), Ordering::Relaxed);
+        });
+    }
+
+    // Takes back the slots that were handed to the DMA once it has been stopped, so it can be started again after an error
+    // The slot queued to play next hadn't started so it is played again, the rest of the slot that was playing is lost
+    // The ISR mustn't run while this is called, it owns these counters
+    pub fn restart(&self) {
+        let mut queued = self.queued.load(Ordering::Relaxed);
+        if self.queued_slot.load(Ordering::Relaxed) {
+            queued = queued.wrapping_sub(1);
+        }
+        self.queued.store(queued, Ordering::Relaxed);
+        self.released.store(queued, Ordering::Release);
+        self.playing_slot.store(false, Ordering::Relaxed);
+        self.queued_slot.store(false, Ordering::Relaxed);
+    }
+
+    // Called by the ISR each time the DMA finishes a buffer, and twice before the DMA starts for its first two buffers
+    // Releases the slot that was playing and returns the next Filled slot to queue, None if there isn't one
+    pub fn next_play(&'static self) -> Option<&'static [u16; LEN]> {
+        if self.playing_slot.load(Ordering::Relaxed) {
+            self.released.fetch_add(1, Ordering::Release);
+        }
+        self.playing_slot.store(self.queued_slot.load(Ordering::Relaxed), Ordering::Relaxed);
 
-    // Finds the index of the first buffer with the state provided in the paramter
-    pub fn find_buffer(&self, match_state: AudioBufState) -> Option<usize> {
-        for (i, buf_state) in self.buf_states.iter().enumerate() {
-            if *buf_state == match_state {
-                return Some(i)
+        let queued = self.queued.load(Ordering::Relaxed);
+        let has_filled = queued != self.filled.load(Ordering::Acquire);
+        self.queued_slot.store(has_filled, Ordering::Relaxed);
+        if !has_filled {
+            return None;
+        }
+        self.queued.store(queued.wrapping_add(1), Ordering::Release);
+
+        // A slot that has been handed to the DMA isn't written until it is released
+        let slot = self.slot(queued % N)?;
+        Some(unsafe { &*slot })
+    }
+
+    // The last frame of the slot that was filled last, which is the last audio that will be played
+    pub fn last_filled_frame(&self) -> [u16; 2] {
+        let indx = self.filled.load(Ordering::Relaxed).wrapping_sub(1) % N;
+
+        // Only the main loop writes to slots, so this can't change while it is read
+        let buf = self.slot(indx).map(|slot| unsafe { &*slot });
+        let sample = |sample_indx: usize| buf.and_then(|buf| buf.get(sample_indx)).copied().unwrap_or(0);
+        [sample(LEN.wrapping_sub(2)), sample(LEN.wrapping_sub(1))]
+    }
+
+    // State of each slot, for debugging
+    pub fn buf_states(&self) -> [AudioBufState; N] {
+        let released = self.released.load(Ordering::Acquire);
+        let queued = self.queued.load(Ordering::Acquire);
+        let filled = self.filled.load(Ordering::Relaxed);
+
+        let mut buf_states = [AudioBufState::Empty; N];
+        let mut slot_number = released;
+        while slot_number != filled && slot_number.wrapping_sub(released) < N {
+            let in_dma = slot_number.wrapping_sub(released) < queued.wrapping_sub(released);
+            if let Some(buf_state) = buf_states.get_mut(slot_number % N) {
+                *buf_state = if in_dma { AudioBufState::Playing } else { AudioBufState::Filled };
             }
+            slot_number = slot_number.wrapping_add(1);
+        }
+
+        if self.claimed.load(Ordering::Relaxed) {
+            if let Some(buf_state) = buf_states.get_mut(filled % N) {
+                *buf_state = AudioBufState::Filling;
+            }
+        }
+
+        buf_states
+    }
+}
+
+impl<const N: usize, const LEN: usize> Default for AudioRing<N, LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Silence inserted between tracks, some installations need a pause between announcements
+// The gap is played as a number of whole silent buffers, so it is rounded up to the buffer length
+#[derive(Debug)]
+pub struct TrackGap {
+    buffers_left: u32,
+}
+
+impl TrackGap {
+    pub const MAX_GAP_MS: u32 = 5000;
+
+    pub fn new() -> Self {
+        TrackGap { buffers_left: 0 }
+    }
+
+    // Schedules gap_ms of silence, which is limited to MAX_GAP_MS
+    // buffer_frames is the number of stereo frames in one buffer
+    pub fn schedule(&mut self, gap_ms: u32, sample_rate: u32, buffer_frames: u32) {
+        let gap_frames = gap_ms.min(Self::MAX_GAP_MS) as u64 * sample_rate as u64 / 1000;
+        self.buffers_left = gap_frames.div_ceil(buffer_frames as u64) as u32;
+    }
+
+    // Fills buf with silence if the gap hasn't finished
+    // Returns false once the gap is over and buf should be filled with audio instead
+    pub fn fill(&mut self, buf: &mut [u16]) -> bool {
+        if self.buffers_left == 0 {
+            return false;
         }
-        return None;
+
+        buf.fill(0);
+        self.buffers_left -= 1;
+        true
     }
 }
 
-// Hello me try decoupling further by usings a playing and a fillind index
-// Only have filling updated by cpu
-// Only have playing updated by ISR
\ No newline at end of file
+impl Default for TrackGap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -11,6 +11,38 @@ pub trait BlockDevice<const L: usize> {
             Err(()) => return Err(())
         }
     }
+
+    // Read consecutive blocks starting at blockaddr, one block for each element of blocks
+    // Devices which can stream several blocks with one command should override this, by default the blocks are read one at a time
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; L]]) -> Result<(), ()> {
+        for (i, block) in blocks.iter_mut().enumerate() {
+            self.read_to_block(blockaddr + i as u32, block)?;
+        }
+        Ok(())
+    }
+
+    // Starts reading blocks consecutive blocks at blockaddr in the background, for devices that can (e.g. with DMA)
+    // They are collected with finish_read, using the device for anything else first waits for the read and throws it away
+    // By default nothing is started and finish_read reads the blocks
+    fn start_read(&mut self, _blockaddr: u32, _blocks: usize) -> Result<(), ()> {
+        Ok(())
+    }
+
+    // Reads blocks like read_blocks, using the read from start_read if it was for the same blocks
+    fn finish_read(&mut self, blockaddr: u32, blocks: &mut [[u8; L]]) -> Result<(), ()> {
+        self.read_blocks(blockaddr, blocks)
+    }
+
+    // False once the device has gone, e.g. the card has been taken out
+    // This is only checked after a read has failed, devices that can't be removed are always present
+    fn is_present(&mut self) -> bool {
+        true
+    }
+
+    // Starts the device again after it has been put back, the filesystem on it then has to be remounted
+    fn reinit(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
 }
 
 
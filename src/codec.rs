@@ -0,0 +1,63 @@
+// Control of the DAC or codec on the I2S output, for the ones that are set up over I2C or SPI
+//
+// main sets the codec up with init while the I2S is off, calls set_sample_rate once the I2S clocks are running,
+// and power_down once the output has faded to silence
+// A DAC with no control interface (e.g. PCM5102, UDA1334) uses NoCodec, which does nothing
+//
+// The volume is in dB, 0 dB is the codec's normal level and anything below it is quieter
+// Codecs that can turn the volume down say how far with volume_range, the player then uses it instead of scaling the samples
+
+use core::fmt::Debug;
+
+use crate::volume::HardwareVolume;
+
+pub trait Codec {
+    type Error: Debug;
+
+    // Sets the codec up for 16 bit I2S input from the STM32, it stays powered down until set_sample_rate is called
+    fn init(&mut self) -> Result<(), Self::Error>;
+
+    // Called once the I2S is running at sample_rate, codecs that need the clocks to be running are powered up here
+    fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), Self::Error>;
+
+    // Sets the volume of both channels, limited to what the codec can do
+    fn set_volume(&mut self, db: f32) -> Result<(), Self::Error>;
+
+    // How far below 0 dB set_volume can go, and in what steps, None if the volume can't be changed
+    fn volume_range(&self) -> Option<HardwareVolume> {
+        None
+    }
+
+    fn mute(&mut self, muted: bool) -> Result<(), Self::Error>;
+
+    // Powers the codec down, the output should be silent first so it doesn't pop
+    fn power_down(&mut self) -> Result<(), Self::Error>;
+}
+
+// For DACs that only have an I2S input
+#[derive(Debug)]
+pub struct NoCodec;
+
+impl Codec for NoCodec {
+    type Error = ();
+
+    fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_sample_rate(&mut self, _sample_rate: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_volume(&mut self, _db: f32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn mute(&mut self, _muted: bool) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn power_down(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+// Control of the CS43L22 DAC with headphone amplifier over I2C, as on the STM32F4 Discovery boards
+//
+// The audio comes in over I2S with the CS43L22 as the slave, it works out the sample rate from the master clock (256 x fs)
+// Only the headphone output is used, the speaker amplifier is left powered down
+//
+// Power up follows the datasheet: the registers are set up while it's powered down (init),
+// then it's powered up once the I2S clocks are running (set_sample_rate)
+//
+// Useful resources:
+// https://www.cirrus.com/products/cs43l22/ (datasheet, section 4.9 and 4.11 for the power up sequence)
+
+use embedded_hal::i2c::I2c;
+
+use crate::codec::Codec;
+use crate::volume::HardwareVolume;
+
+// 7 bit address with AD0 low, as on the Discovery boards
+pub const DISCOVERY_ADDRESS: u8 = 0x4A;
+
+// Registers
+const ID: u8 = 0x01;
+const POWER_CTL_1: u8 = 0x02;
+const POWER_CTL_2: u8 = 0x04;
+const CLOCKING_CTL: u8 = 0x05;
+const INTERFACE_CTL_1: u8 = 0x06;
+const PLAYBACK_CTL_2: u8 = 0x0F;
+const MASTER_VOLUME_A: u8 = 0x20;
+const MASTER_VOLUME_B: u8 = 0x21;
+
+const CHIP_ID: u8 = 0b1110_0000; // Upper 5 bits of the ID register, the rest is the revision
+const CHIP_ID_MASK: u8 = 0b1111_1000;
+
+const POWERED_DOWN: u8 = 0x01;
+const POWERED_UP: u8 = 0x9E;
+const HEADPHONES_ON_SPEAKERS_OFF: u8 = 0xAF;
+const AUTO_DETECT_SPEED: u8 = 0x80;
+const SLAVE_I2S: u8 = 0x04; // Slave, I2S up to 24 bit data
+const HEADPHONES_MUTED: u8 = 0xC0;
+
+// The master volume is in 0.5 dB steps from -102 dB to +12 dB
+const MIN_VOLUME_HALF_DB: i32 = -204;
+const MAX_VOLUME_HALF_DB: i32 = 24;
+
+// Register writes from section 4.11 of the datasheet, which have to be made after each power up
+const REQUIRED_INIT: [(u8, u8); 2] = [(0x00, 0x99), (0x47, 0x80)];
+const REQUIRED_INIT_BIT_REGISTER: u8 = 0x32;
+const REQUIRED_INIT_BIT: u8 = 0x80;
+const REQUIRED_INIT_END: (u8, u8) = (0x00, 0x00);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cs43l22Error {
+    Bus, // The I2C transfer failed, e.g. nothing answered at the address or the codec is held in reset
+    WrongId(u8), // Something other than a CS43L22 answered
+}
+
+pub struct Cs43l22<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Cs43l22<I2C> {
+    // The codec's reset pin has to be high
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Cs43l22 {
+            i2c,
+            address,
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), Cs43l22Error> {
+        self.i2c.write(self.address, &[register, value]).map_err(|_| Cs43l22Error::Bus)
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, Cs43l22Error> {
+        let mut value = [0];
+        self.i2c.write_read(self.address, &[register], &mut value).map_err(|_| Cs43l22Error::Bus)?;
+        let [value] = value;
+        Ok(value)
+    }
+}
+
+impl<I2C: I2c> Codec for Cs43l22<I2C> {
+    type Error = Cs43l22Error;
+
+    // Sets the codec up for I2S in on the headphone output
+    fn init(&mut self) -> Result<(), Cs43l22Error> {
+        let id = self.read(ID)?;
+        if id & CHIP_ID_MASK != CHIP_ID {
+            return Err(Cs43l22Error::WrongId(id));
+        }
+
+        self.write(POWER_CTL_1, POWERED_DOWN)?;
+        for (register, value) in REQUIRED_INIT {
+            self.write(register, value)?;
+        }
+        let value = self.read(REQUIRED_INIT_BIT_REGISTER)?;
+        self.write(REQUIRED_INIT_BIT_REGISTER, value | REQUIRED_INIT_BIT)?;
+        self.write(REQUIRED_INIT_BIT_REGISTER, value & !REQUIRED_INIT_BIT)?;
+        self.write(REQUIRED_INIT_END.0, REQUIRED_INIT_END.1)?;
+
+        self.write(POWER_CTL_2, HEADPHONES_ON_SPEAKERS_OFF)?;
+        self.write(CLOCKING_CTL, AUTO_DETECT_SPEED)?;
+        self.write(INTERFACE_CTL_1, SLAVE_I2S)?;
+        self.set_volume(0.0)
+    }
+
+    // The sample rate is detected from the master clock, the codec doesn't start without it
+    fn set_sample_rate(&mut self, _sample_rate: u32) -> Result<(), Cs43l22Error> {
+        self.write(POWER_CTL_1, POWERED_UP)
+    }
+
+    // db is limited to -102 dB to +12 dB
+    fn set_volume(&mut self, db: f32) -> Result<(), Cs43l22Error> {
+        // The register is the volume in 0.5 dB steps, as an 8 bit two's complement number that wraps below -64 dB
+        let half_db = ((db * 2.0) as i32).clamp(MIN_VOLUME_HALF_DB, MAX_VOLUME_HALF_DB);
+        let value = half_db as u8;
+        self.write(MASTER_VOLUME_A, value)?;
+        self.write(MASTER_VOLUME_B, value)
+    }
+
+    fn volume_range(&self) -> Option<HardwareVolume> {
+        Some(HardwareVolume { min_db: MIN_VOLUME_HALF_DB as f32 / 2.0, step_db: 0.5 })
+    }
+
+    fn mute(&mut self, muted: bool) -> Result<(), Cs43l22Error> {
+        self.write(PLAYBACK_CTL_2, if muted { HEADPHONES_MUTED } else { 0 })
+    }
+
+    fn power_down(&mut self) -> Result<(), Cs43l22Error> {
+        self.write(POWER_CTL_1, POWERED_DOWN)
+    }
+}
@@ -0,0 +1,186 @@
+// Parser for .cue sheets, which split one big wav file (e.g. a whole album) into tracks
+// Only the parts needed to navigate the tracks are read: FILE, TRACK, TITLE, and INDEX 01
+//
+// FILE "album.wav" WAVE
+//   TRACK 01 AUDIO
+//     TITLE "First song"
+//     INDEX 01 00:00:00
+//   TRACK 02 AUDIO
+//     TITLE "Second song"
+//     INDEX 01 04:12:33
+
+use heapless::{String, Vec};
+
+use crate::block_device::BlockDevice;
+use crate::exfat::{ExFat, FsEntry, FsError, MAX_FILE_NAME_LENGTH};
+
+const SECTOR_SIZE: usize = crate::BLOCK_SIZE;
+
+const MAX_TRACKS: usize = 99; // The most tracks a cue sheet can have
+const MAX_TITLE_LENGTH: usize = 64;
+const MAX_LINE_LENGTH: usize = 256;
+
+// Cue sheet times are in CD frames, there are 75 per second
+pub const FRAMES_PER_SECOND: u32 = 75;
+
+#[derive(Debug)]
+pub struct CueTrack {
+    pub number: u8,
+    pub title: String<MAX_TITLE_LENGTH>,
+    pub start_frame: u32, // Start of the track in CD frames
+}
+
+impl CueTrack {
+    pub fn start_ms(&self) -> u32 {
+        (self.start_frame as u64 * 1000 / FRAMES_PER_SECOND as u64) as u32
+    }
+
+    // Index of the first sample frame of the track in a file with the given sample rate
+    pub fn start_sample(&self, sample_rate: u32) -> u64 {
+        self.start_frame as u64 * sample_rate as u64 / FRAMES_PER_SECOND as u64
+    }
+}
+
+#[derive(Debug)]
+pub struct CueSheet {
+    pub file_name: String<MAX_FILE_NAME_LENGTH>, // The wav file the tracks are in
+    pub title: String<MAX_TITLE_LENGTH>,         // Album title
+    pub tracks: Vec<CueTrack, MAX_TRACKS>,
+}
+
+impl CueSheet {
+    pub fn new() -> Self {
+        CueSheet {
+            file_name: String::new(),
+            title: String::new(),
+            tracks: Vec::new(),
+        }
+    }
+
+    // Parses one line of the cue sheet
+    // Unknown commands and lines that don't parse are ignored
+    pub fn parse_line(&mut self, line: &str) {
+        let line = line.trim();
+        let (command, arguments) = line.split_once(' ').unwrap_or((line, ""));
+        let arguments = arguments.trim();
+
+        match command {
+            // Only the first FILE is used
+            "FILE" if self.file_name.is_empty() => copy_truncated(&mut self.file_name, unquote(arguments)),
+
+            "TRACK" => {
+                let number = arguments.split(' ').next().and_then(|number| number.parse::<u8>().ok());
+                if let Some(number) = number {
+                    let _ = self.tracks.push(CueTrack {
+                        number,
+                        title: String::new(),
+                        start_frame: 0,
+                    });
+                }
+            },
+
+            // A TITLE before the first track is the album title
+            "TITLE" => {
+                let title = match self.tracks.last_mut() {
+                    Some(track) => &mut track.title,
+                    None => &mut self.title,
+                };
+                copy_truncated(title, unquote(arguments));
+            },
+
+            // INDEX 01 is where the track starts, INDEX 00 is the pregap before it
+            "INDEX" => {
+                let (index, time) = arguments.split_once(' ').unwrap_or(("", ""));
+                if index == "01" {
+                    if let (Some(track), Some(start_frame)) = (self.tracks.last_mut(), parse_time(time.trim())) {
+                        track.start_frame = start_frame;
+                    }
+                }
+            },
+
+            _ => (),
+        }
+    }
+}
+
+impl Default for CueSheet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Reads and parses a cue sheet file
+pub fn read_cue_sheet<T: BlockDevice<SECTOR_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<CueSheet, FsError> {
+    let mut cue_sheet = CueSheet::new();
+    let mut line: Vec<u8, MAX_LINE_LENGTH> = Vec::new();
+
+    let sectors_per_cluster = 1 << exfat.sectors_per_cluster_shift;
+    let mut bytes_left = file.valid_data_length;
+    let mut cluster = file.first_cluster;
+
+    while bytes_left > 0 {
+        let cluster_sector = exfat.calc_cluster_sector(cluster);
+
+        for sector_no in 0..sectors_per_cluster {
+            if bytes_left == 0 {
+                break;
+            }
+
+            let sector = exfat.read_sector(cluster_sector + sector_no)?;
+            let valid_bytes = bytes_left.min(SECTOR_SIZE as u64) as usize;
+            bytes_left -= valid_bytes as u64;
+
+            // Lines can continue from one sector into the next
+            for byte in sector.iter().take(valid_bytes) {
+                if *byte == b'\n' || *byte == b'\r' {
+                    parse_line_bytes(&mut cue_sheet, &line);
+                    line.clear();
+                } else {
+                    let _ = line.push(*byte); // Anything past MAX_LINE_LENGTH is dropped
+                }
+            }
+        }
+
+        if bytes_left > 0 {
+            cluster = exfat.next_cluster(cluster, file.contiguous)?;
+        }
+    }
+
+    parse_line_bytes(&mut cue_sheet, &line);
+    Ok(cue_sheet)
+}
+
+fn parse_line_bytes(cue_sheet: &mut CueSheet, line: &[u8]) {
+    if let Ok(line) = core::str::from_utf8(line) {
+        cue_sheet.parse_line(line);
+    }
+}
+
+// Parses a time written as mm:ss:ff into CD frames
+fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+    let frames = parts.next()??;
+
+    Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+}
+
+// Removes the quotes from an argument like "Song title", and anything after the closing quote
+fn unquote(argument: &str) -> &str {
+    match argument.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or(quoted),
+        None => argument.split(' ').next().unwrap_or(argument),
+    }
+}
+
+// Copies as many whole characters as fit
+fn copy_truncated<const N: usize>(destination: &mut String<N>, source: &str) {
+    destination.clear();
+    for character in source.chars() {
+        if destination.push(character).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Removes the DC offset that some cheap recordings have, it wastes headroom and pushes small speaker cones off centre
+//
+// A one pole high pass filter, y[n] = x[n] - x[n - 1] + r * y[n - 1]
+// The closer r is to 1 the lower the cutoff, CUTOFF_HZ is far enough below the music that nothing audible is lost
+
+const CUTOFF_HZ: u32 = 10;
+
+// The pole is Q30, and the output is kept with STATE_FRACTION_BITS bits below the 16 bit samples
+// Without the extra bits the rounding error of the feedback becomes a DC offset of its own
+const POLE_BITS: u32 = 30;
+const STATE_FRACTION_BITS: u32 = 8;
+
+const PI: f32 = core::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelState {
+    x1: i32, // Last input, with STATE_FRACTION_BITS extra bits
+    y1: i32, // Last output, with STATE_FRACTION_BITS extra bits
+}
+
+#[derive(Debug)]
+pub struct DcBlocker {
+    enabled: bool,
+    pole: i32, // r in Q30
+    channels: [ChannelState; 2],
+}
+
+impl DcBlocker {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut dc_blocker = DcBlocker {
+            enabled: false,
+            pole: 0,
+            channels: Default::default(),
+        };
+        dc_blocker.set_sample_rate(sample_rate);
+        dc_blocker
+    }
+
+    // r = 1 - 2 pi fc / fs is close enough for a cutoff this far below the sample rate
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let pole = 1.0 - 2.0 * PI * CUTOFF_HZ as f32 / sample_rate.max(CUTOFF_HZ * 8) as f32;
+        self.pole = (pole * (1 << POLE_BITS) as f32) as i32;
+        self.channels = Default::default();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            self.channels = Default::default();
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Filters interleaved 16 bit stereo samples
+    pub fn apply(&mut self, buf: &mut [u16]) {
+        if !self.enabled {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(2) {
+            for (sample, state) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                let x = (*sample as i16 as i32) << STATE_FRACTION_BITS;
+                let feedback = ((self.pole as i64 * state.y1 as i64) >> POLE_BITS) as i32;
+                let y = x - state.x1 + feedback;
+
+                state.x1 = x;
+                state.y1 = y;
+                *sample = (y >> STATE_FRACTION_BITS).clamp(i16::MIN as i32, i16::MAX as i32) as i16 as u16;
+            }
+        }
+    }
+}
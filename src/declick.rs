@@ -0,0 +1,72 @@
+// Smooths over jumps in the output, e.g. skipping to another track or seeking, which would otherwise click
+//
+// The audio already handed to the DMA can't be faded out, so the step is taken out of the audio after it instead
+// At a discontinuity the difference between the last frame that will be played and the first new frame is added to the new audio,
+// and that offset is ramped down to nothing over a few ms, so the output moves smoothly from where it was to the new audio
+// This runs last, so every way of jumping (play, next, previous, seek) is smoothed without each one having to ramp
+
+use crate::limiter;
+
+#[derive(Debug)]
+pub struct Declicker {
+    frames: u32, // Length of the ramp
+    last_frame: [i16; 2], // The last frame played before the jump
+    pending: bool, // The next buffer follows a jump
+    offset: [i32; 2], // Added to the frames after the jump, ramped down to 0
+    remaining: u32, // Frames of the ramp still to apply
+}
+
+impl Declicker {
+    pub fn new() -> Self {
+        Declicker {
+            frames: 0,
+            last_frame: [0; 2],
+            pending: false,
+            offset: [0; 2],
+            remaining: 0,
+        }
+    }
+
+    pub fn set_frames(&mut self, frames: u32) {
+        self.frames = frames;
+    }
+
+    // The next buffer doesn't follow on from last_frame, e.g. the buffers after it were thrown away
+    // last_frame is the last frame that will be played before the next buffer
+    pub fn jump_from(&mut self, last_frame: [u16; 2]) {
+        self.last_frame = last_frame.map(|sample| sample as i16);
+        self.pending = self.frames > 0;
+    }
+
+    // Call with every buffer that is filled, after everything else has been added to it
+    pub fn apply(&mut self, buf: &mut [u16]) {
+        if self.pending {
+            self.pending = false;
+            if let Some(first_frame) = buf.get(..2) {
+                for ((offset, last_sample), sample) in self.offset.iter_mut().zip(self.last_frame).zip(first_frame) {
+                    *offset = last_sample as i32 - *sample as i16 as i32;
+                }
+                self.remaining = self.frames;
+            }
+        }
+
+        if self.remaining > 0 {
+            for frame in buf.chunks_exact_mut(2) {
+                if self.remaining == 0 {
+                    break;
+                }
+                for (sample, offset) in frame.iter_mut().zip(self.offset) {
+                    let ramped = (offset as i64 * self.remaining as i64 / self.frames.max(1) as i64) as i32;
+                    *sample = limiter::soft_clip(*sample as i16 as i32 + ramped) as u16;
+                }
+                self.remaining -= 1;
+            }
+        }
+    }
+}
+
+impl Default for Declicker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
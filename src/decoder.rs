@@ -0,0 +1,251 @@
+// Interface between the player and the audio file formats
+// The main loop only needs to get 16 bit stereo samples at the output sample rate,
+// so a new format can be played by implementing this trait for its file type, and adding it to TrackDecoder
+// The player only uses a track through the trait, TrackDecoder::prepare is where a format can add extras of its own
+
+use heapless::Vec;
+
+use crate::block_device::BlockDevice;
+use crate::exfat::{ExFat, FileType, FsEntry};
+use crate::raw_pcm::{self, RawPcmFile};
+use crate::wav::{self, ProbeReport, Tags, WavError, WavFile};
+use crate::rprintln;
+
+use crate::BLOCK_SIZE;
+
+pub trait Decoder: Sized {
+    type Error: core::fmt::Debug;
+
+    // Reads the headers of a file and gets ready to decode from the start of the audio
+    fn open<T: BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<Self, Self::Error>;
+
+    // Checks whether the file can be played on an output running at output_sample_rate
+    fn probe(&self, output_sample_rate: u32) -> ProbeReport;
+
+    // Converts the decoded samples to output_sample_rate, returns true if they will be resampled
+    fn set_output_sample_rate(&mut self, output_sample_rate: u32) -> bool;
+
+    // Fills buf with interleaved 16 bit stereo frames
+    // Returns how many samples were filled, fewer than the length of buf means the end of the file was reached
+    // and the rest of buf is silence
+    fn fill<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16]) -> Result<usize, ()>;
+
+    // Format of the file, before any resampling or mixing to stereo
+    fn sample_rate(&self) -> u32;
+    fn n_channels(&self) -> u16;
+
+    // Moves playback to a time in milliseconds from the start of the file
+    fn seek_to_ms<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, ms: u32) -> Result<(), ()>;
+
+    fn position_ms(&self) -> u32;
+    fn duration_ms(&self) -> u32;
+
+    // Plays faster or slower by resampling, the pitch changes with the speed, returns the speed that was set
+    fn set_speed(&mut self, speed_percent: u16) -> u16;
+
+    // Loops forever between two positions in milliseconds, Err if the loop is empty or ends past the end of the file
+    fn set_ab_loop(&mut self, start_ms: u32, end_ms: u32) -> Result<(), ()>;
+    fn clear_ab_loop(&mut self);
+
+    // A position in the file that stays the same whatever the output rate or speed, for resume records
+    // data_offset gives the position of a frame, and seek_to_data_offset moves playback back to it
+    fn data_offset(&self, frame: u32) -> u64;
+    fn seek_to_data_offset<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, offset: u64) -> Result<(), ()>;
+
+    // Title, artist, album, and ReplayGain, fields the file doesn't have are empty
+    // The player fills in the ReplayGain from a sidecar file if the file has none
+    fn tags(&self) -> &Tags;
+    fn tags_mut(&mut self) -> &mut Tags;
+}
+
+// The decoders the player can play a track with, picked from the file name when it's opened
+// Every format so far is decoded by WavFile underneath, which also does the looping, speed changes, and silence skipping
+#[derive(Debug)]
+pub enum TrackDecoder {
+    Wav(WavFile), // Also AIFF
+    RawPcm(RawPcmFile), // Headerless .pcm and .raw files
+}
+
+impl TrackDecoder {
+    // Does the extras set in main.rs for a track that has just been opened, before it's played
+    // Split files are joined, silence is skipped, and sample loops are played, for the formats WavFile decodes
+    pub fn prepare<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, directory_cluster: u32, track: &FsEntry) {
+        let wav_file = match self {
+            TrackDecoder::Wav(wav_file) => wav_file,
+            TrackDecoder::RawPcm(raw_pcm_file) => raw_pcm_file.samples_mut(),
+        };
+        append_split_parts(exfat, directory_cluster, track, wav_file);
+
+        // The end is found first, finding it moves the position
+        if crate::SKIP_TRAILING_SILENCE {
+            match wav_file.skip_trailing_silence(exfat, crate::SILENCE_THRESHOLD, crate::TRAILING_SILENCE_MS, crate::MAX_TRAILING_SILENCE_MS) {
+                Ok(0) => (),
+                Ok(frames) => rprintln!("Cut off {} frames of silence at the end", frames),
+                Err(_) => rprintln!("Couldn't skip the trailing silence"),
+            }
+        }
+        if crate::SKIP_LEADING_SILENCE {
+            match wav_file.skip_leading_silence(exfat, crate::SILENCE_THRESHOLD, crate::LEADING_SILENCE_MS) {
+                Ok(0) => (),
+                Ok(frames) => rprintln!("Skipped {} frames of silence", frames),
+                Err(_) => rprintln!("Couldn't skip the leading silence"),
+            }
+        }
+
+        if let Some(bext) = &wav_file.bext {
+            rprintln!("Recorded by {} on {} {}, timecode {} ms", bext.originator, bext.origination_date, bext.origination_time, bext.time_reference_ms(wav_file.sample_rate));
+        }
+        if crate::PLAY_SAMPLE_LOOPS && !wav_file.set_looping(true) {
+            rprintln!("This file has no loop that can be played: {:?}", wav_file.sample_loop);
+        }
+        if wav_file.missing_fact_chunk() {
+            rprintln!("Warning: {:?} file has no fact chunk, its length is unknown", wav_file.format);
+        }
+    }
+}
+
+impl Decoder for TrackDecoder {
+    type Error = WavError;
+
+    fn open<T: BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<Self, WavError> {
+        if raw_pcm::is_raw_pcm(file) {
+            return RawPcmFile::open(exfat, file).map(TrackDecoder::RawPcm);
+        }
+        <WavFile as Decoder>::open(exfat, file).map(TrackDecoder::Wav)
+    }
+
+    fn probe(&self, output_sample_rate: u32) -> ProbeReport {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::probe(wav_file, output_sample_rate),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::probe(raw_pcm_file, output_sample_rate),
+        }
+    }
+
+    fn set_output_sample_rate(&mut self, output_sample_rate: u32) -> bool {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::set_output_sample_rate(wav_file, output_sample_rate),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::set_output_sample_rate(raw_pcm_file, output_sample_rate),
+        }
+    }
+
+    fn fill<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16]) -> Result<usize, ()> {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::fill(wav_file, exfat, buf),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::fill(raw_pcm_file, exfat, buf),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::sample_rate(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::sample_rate(raw_pcm_file),
+        }
+    }
+
+    fn n_channels(&self) -> u16 {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::n_channels(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::n_channels(raw_pcm_file),
+        }
+    }
+
+    fn seek_to_ms<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, ms: u32) -> Result<(), ()> {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::seek_to_ms(wav_file, exfat, ms),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::seek_to_ms(raw_pcm_file, exfat, ms),
+        }
+    }
+
+    fn position_ms(&self) -> u32 {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::position_ms(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::position_ms(raw_pcm_file),
+        }
+    }
+
+    fn duration_ms(&self) -> u32 {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::duration_ms(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::duration_ms(raw_pcm_file),
+        }
+    }
+
+    fn set_speed(&mut self, speed_percent: u16) -> u16 {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::set_speed(wav_file, speed_percent),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::set_speed(raw_pcm_file, speed_percent),
+        }
+    }
+
+    fn set_ab_loop(&mut self, start_ms: u32, end_ms: u32) -> Result<(), ()> {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::set_ab_loop(wav_file, start_ms, end_ms),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::set_ab_loop(raw_pcm_file, start_ms, end_ms),
+        }
+    }
+
+    fn clear_ab_loop(&mut self) {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::clear_ab_loop(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::clear_ab_loop(raw_pcm_file),
+        }
+    }
+
+    fn data_offset(&self, frame: u32) -> u64 {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::data_offset(wav_file, frame),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::data_offset(raw_pcm_file, frame),
+        }
+    }
+
+    fn seek_to_data_offset<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, offset: u64) -> Result<(), ()> {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::seek_to_data_offset(wav_file, exfat, offset),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::seek_to_data_offset(raw_pcm_file, exfat, offset),
+        }
+    }
+
+    fn tags(&self) -> &Tags {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::tags(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::tags(raw_pcm_file),
+        }
+    }
+
+    fn tags_mut(&mut self) -> &mut Tags {
+        match self {
+            TrackDecoder::Wav(wav_file) => Decoder::tags_mut(wav_file),
+            TrackDecoder::RawPcm(raw_pcm_file) => Decoder::tags_mut(raw_pcm_file),
+        }
+    }
+}
+
+// Joins the other parts of a split file (track.wav.002, track.wav.003, ...) onto its first part
+fn append_split_parts<T: BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, directory_cluster: u32, track: &FsEntry, wav_file: &mut WavFile) {
+    if wav::next_part_name(track.name.as_str()).is_none() {
+        return;
+    }
+    let Some((stem, _)) = track.name.rsplit_once('.') else {
+        return;
+    };
+
+    // Only the files that could be parts are kept, a whole directory listing would use too much memory here
+    let mut parts: Vec<FsEntry, { wav::MAX_FILE_PARTS }> = Vec::new();
+    let result = exfat.for_each_entry(directory_cluster, |fs_entry| {
+        let is_part = matches!(fs_entry.file_type, FileType::File) && fs_entry.name.as_str() != track.name.as_str()
+            && fs_entry.name.starts_with(stem) && wav::next_part_name(fs_entry.name.as_str()).is_some();
+        if is_part {
+            let _ = parts.push(fs_entry);
+        }
+    });
+    if let Err(err) = result {
+        rprintln!("Couldn't look for the parts of a split file: {:?}", err);
+        return;
+    }
+
+    match wav_file.append_parts_from_directory(&*exfat, &parts, track) {
+        Ok(0) => (),
+        Ok(parts_added) => rprintln!("Playing {} files as one stream", parts_added + 1),
+        Err(_) => rprintln!("Couldn't join the parts of a split file"),
+    }
+}
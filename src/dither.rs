@@ -0,0 +1,87 @@
+// Dither for converting 24 bit, 32 bit, and float samples to the 16 bit output
+//
+// Rounding to 16 bits makes the rounding error follow the signal, which sounds like distortion on quiet material
+// Adding triangular (TPDF) noise of +-1 LSB before rounding makes the error a constant, signal independent hiss instead
+// Noise shaping feeds the rounding error of each sample back into the next one,
+// which moves the hiss up towards high frequencies where it is harder to hear
+//
+// Samples are handed to reduce with FRACTION_BITS bits below the 16 bit LSB
+
+use crate::downmix::MAX_CHANNELS;
+use crate::helpers::Xorshift32;
+
+pub const FRACTION_BITS: u32 = 8;
+
+const FRACTION_MASK: u32 = (1 << FRACTION_BITS) - 1;
+const HALF_LSB: i32 = 1 << (FRACTION_BITS - 1);
+const MAX_ERROR: i32 = 2 << FRACTION_BITS;
+
+// The sequence only has to sound like noise, so every file starts from the same seed
+const SEED: u32 = 0x2545_F491;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DitherMode {
+    Off, // Round to the nearest value
+    Tpdf,
+    NoiseShaped, // TPDF dither with first order noise shaping
+}
+
+#[derive(Debug)]
+pub struct Dither {
+    mode: DitherMode,
+    rng: Xorshift32,
+    errors: [i32; MAX_CHANNELS], // Rounding error of the last sample of each channel, for noise shaping
+    channel: usize, // Channel of the next sample in the frame
+}
+
+impl Dither {
+    pub fn new(mode: DitherMode) -> Self {
+        Dither {
+            mode,
+            rng: Xorshift32::new(SEED),
+            errors: [0; MAX_CHANNELS],
+            channel: 0,
+        }
+    }
+
+    // Call before the first sample of each frame, so the error of each channel is fed back into the same channel
+    pub fn start_frame(&mut self) {
+        self.channel = 0;
+    }
+
+    // Forgets the rounding errors, e.g. after a seek
+    pub fn reset(&mut self) {
+        self.errors = [0; MAX_CHANNELS];
+        self.channel = 0;
+    }
+
+    // Converts a sample with FRACTION_BITS extra bits to 16 bits
+    pub fn reduce(&mut self, sample: i32) -> i16 {
+        let channel = self.channel;
+        self.channel += 1;
+
+        let (shaped, dither) = match self.mode {
+            DitherMode::Off => (sample, 0),
+            DitherMode::Tpdf => (sample, self.tpdf()),
+            DitherMode::NoiseShaped => {
+                let error = self.errors.get(channel).copied().unwrap_or(0);
+                (sample.saturating_sub(error), self.tpdf())
+            },
+        };
+
+        let rounded = (shaped.saturating_add(dither).saturating_add(HALF_LSB) >> FRACTION_BITS)
+            .clamp(i16::MIN as i32, i16::MAX as i32);
+
+        // The error includes the dither, so the feedback also shapes the dither noise
+        if let (DitherMode::NoiseShaped, Some(error)) = (self.mode, self.errors.get_mut(channel)) {
+            *error = ((rounded << FRACTION_BITS) - shaped).clamp(-MAX_ERROR, MAX_ERROR); // Large after clipping
+        }
+        rounded as i16
+    }
+
+    // Triangular noise from -1 to 1 LSB, the sum of two uniform random numbers
+    fn tpdf(&mut self) -> i32 {
+        let random = self.rng.next_u32();
+        (random & FRACTION_MASK) as i32 + ((random >> 16) & FRACTION_MASK) as i32 - FRACTION_MASK as i32
+    }
+}
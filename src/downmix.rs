@@ -0,0 +1,106 @@
+// Downmixes multichannel (quad, 5.1, 7.1, ...) samples to stereo
+//
+// WAVE_FORMAT_EXTENSIBLE files have a channel mask saying which speaker each channel is for
+// The channels are stored in the order of the mask bits, lowest bit first
+// Each speaker is mixed into left and right with the usual downmix levels (centre and surrounds at -3 dB)
+// The LFE channel is dropped, it is normally left out of stereo downmixes
+
+use heapless::Vec;
+
+pub const MAX_CHANNELS: usize = 8;
+
+// Fixed point coefficients, FULL_SCALE is a gain of 1.0
+const FULL_SCALE: i32 = 1 << 14;
+const MINUS_3DB: i32 = 11585; // 0.707
+const MINUS_6DB: i32 = 8192; // 0.5
+
+// (left, right) gain for the speaker of each channel mask bit
+const SPEAKER_GAINS: [(i32, i32); 18] = [
+    (FULL_SCALE, 0),        // Front left
+    (0, FULL_SCALE),        // Front right
+    (MINUS_3DB, MINUS_3DB), // Front centre
+    (0, 0),                 // Low frequency
+    (MINUS_3DB, 0),         // Back left
+    (0, MINUS_3DB),         // Back right
+    (FULL_SCALE, 0),        // Front left of centre
+    (0, FULL_SCALE),        // Front right of centre
+    (MINUS_6DB, MINUS_6DB), // Back centre
+    (MINUS_3DB, 0),         // Side left
+    (0, MINUS_3DB),         // Side right
+    (MINUS_6DB, MINUS_6DB), // Top centre
+    (MINUS_3DB, 0),         // Top front left
+    (MINUS_6DB, MINUS_6DB), // Top front centre
+    (0, MINUS_3DB),         // Top front right
+    (MINUS_3DB, 0),         // Top back left
+    (MINUS_6DB, MINUS_6DB), // Top back centre
+    (0, MINUS_3DB),         // Top back right
+];
+
+// Channel masks used when a file doesn't have one
+const QUAD_MASK: u32 = 0x33; // FL, FR, BL, BR
+const SURROUND_5_1_MASK: u32 = 0x3F; // FL, FR, FC, LFE, BL, BR
+const SURROUND_7_1_MASK: u32 = 0x63F; // FL, FR, FC, LFE, BL, BR, SL, SR
+
+#[derive(Debug)]
+pub struct Downmix {
+    gains: Vec<(i32, i32), MAX_CHANNELS>, // (left, right) gain for each channel in the file
+}
+
+impl Downmix {
+
+    // Returns None if there is no sensible mix for the channels
+    // A channel_mask of 0 means the file didn't say which speakers the channels are for
+    pub fn new(n_channels: u16, channel_mask: u32) -> Option<Self> {
+        let n_channels = n_channels as usize;
+        if n_channels > MAX_CHANNELS {
+            return None;
+        }
+
+        let channel_mask = match (channel_mask, n_channels) {
+            (0, 4) => QUAD_MASK,
+            (0, 6) => SURROUND_5_1_MASK,
+            (0, 8) => SURROUND_7_1_MASK,
+            (0, _) => return None,
+            (channel_mask, _) => channel_mask,
+        };
+
+        // Channels past the ones in the mask aren't for any speaker, so they are left out
+        let mut gains: Vec<(i32, i32), MAX_CHANNELS> = Vec::new();
+        for (bit, speaker_gains) in SPEAKER_GAINS.iter().enumerate() {
+            if channel_mask & (1 << bit) != 0 && gains.push(*speaker_gains).is_err() {
+                break;
+            }
+        }
+        while gains.len() < n_channels {
+            let _ = gains.push((0, 0));
+        }
+
+        // Scale the gains down so a full scale signal on every channel can't clip
+        let left_total: i32 = gains.iter().map(|gain| gain.0).sum();
+        let right_total: i32 = gains.iter().map(|gain| gain.1).sum();
+        let total = left_total.max(right_total);
+        if total == 0 {
+            return None;
+        }
+
+        for gain in gains.iter_mut() {
+            gain.0 = gain.0 * FULL_SCALE / total;
+            gain.1 = gain.1 * FULL_SCALE / total;
+        }
+
+        Some(Downmix { gains })
+    }
+
+    // Mixes one frame, with a sample for every channel, into a left and right sample
+    pub fn mix(&self, frame: &[i16]) -> (i16, i16) {
+        let mut left = 0;
+        let mut right = 0;
+
+        for (sample, gain) in frame.iter().zip(self.gains.iter()) {
+            left += *sample as i32 * gain.0;
+            right += *sample as i32 * gain.1;
+        }
+
+        ((left / FULL_SCALE) as i16, (right / FULL_SCALE) as i16)
+    }
+}
@@ -0,0 +1,95 @@
+// Transparent decryption layer for storage devices with AES-XTS encrypted contents
+// Wraps any block device, so the exFAT and WAV layers don't need to know the card is encrypted
+//
+// Each block is encrypted with AES-128-XTS using its block address as the tweak
+// This matches what most disk encryption tools do for 512 byte sectors
+// The key is 32 bytes, the first half is the data key and the second half is the tweak key
+
+use aes::Aes128;
+use aes::cipher::KeyInit;
+use xts_mode::{get_tweak_default, Xts128};
+
+use crate::block_device::BlockDevice;
+
+pub const KEY_LENGTH: usize = 32;
+
+pub struct EncryptedBlockDevice<T> {
+    pub block_device: T,
+    xts: Xts128<Aes128>,
+}
+
+impl<T> EncryptedBlockDevice<T> {
+    pub fn new(block_device: T, key: &[u8; KEY_LENGTH]) -> Self {
+        let (data_key, tweak_key) = key.split_at(KEY_LENGTH / 2);
+
+        // Both halves are always 16 bytes long, so creating the ciphers can't fail
+        let xts = Xts128::new(
+            Aes128::new_from_slice(data_key).unwrap(),
+            Aes128::new_from_slice(tweak_key).unwrap(),
+        );
+
+        EncryptedBlockDevice {
+            block_device,
+            xts,
+        }
+    }
+
+    // Consumes the wrapper and returns the underlying block device
+    pub fn release(self) -> T {
+        self.block_device
+    }
+}
+
+impl<T: BlockDevice<L>, const L: usize> BlockDevice<L> for EncryptedBlockDevice<T> {
+    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; L]) -> Result<(), ()> {
+        self.block_device.read_to_block(blockaddr, block)?;
+        self.xts.decrypt_sector(block, get_tweak_default(blockaddr as u128));
+        Ok(())
+    }
+
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; L]]) -> Result<(), ()> {
+        self.block_device.read_blocks(blockaddr, blocks)?;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            self.xts.decrypt_sector(block, get_tweak_default((blockaddr + i as u32) as u128));
+        }
+        Ok(())
+    }
+
+    fn start_read(&mut self, blockaddr: u32, blocks: usize) -> Result<(), ()> {
+        self.block_device.start_read(blockaddr, blocks)
+    }
+
+    fn finish_read(&mut self, blockaddr: u32, blocks: &mut [[u8; L]]) -> Result<(), ()> {
+        self.block_device.finish_read(blockaddr, blocks)?;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            self.xts.decrypt_sector(block, get_tweak_default((blockaddr + i as u32) as u128));
+        }
+        Ok(())
+    }
+
+    fn is_present(&mut self) -> bool {
+        self.block_device.is_present()
+    }
+
+    fn reinit(&mut self) -> Result<(), ()> {
+        self.block_device.reinit()
+    }
+}
+
+// Parses a key written as 64 hex characters
+// Returns None if the string isn't a valid key
+pub fn parse_key(hex: &str) -> Option<[u8; KEY_LENGTH]> {
+    let hex = hex.as_bytes();
+    if hex.len() != KEY_LENGTH * 2 {
+        return None;
+    }
+
+    let mut key = [0u8; KEY_LENGTH];
+    for (i, byte) in key.iter_mut().enumerate() {
+        let high = (hex[i * 2] as char).to_digit(16)?;
+        let low = (hex[i * 2 + 1] as char).to_digit(16)?;
+        *byte = (high << 4 | low) as u8;
+    }
+
+    Some(key)
+}
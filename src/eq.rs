@@ -0,0 +1,249 @@
+// Equalizer made of cascaded biquad filters, so small speakers can be corrected without an external DSP
+// Useful resource: https://www.w3.org/TR/audio-eq-cookbook/
+//
+// Each band is a low shelf, peaking, or high shelf filter set by its frequency, gain, and Q
+// The coefficients are worked out in floating point when a band changes, the samples are filtered in fixed point
+// There's no libm, so the few maths functions the coefficients need are approximated below
+
+use heapless::Vec;
+
+use crate::limiter;
+
+pub const MAX_BANDS: usize = 4;
+
+// Coefficients are Q28, so they can go up to 8 which covers shelves with a lot of gain
+const COEFFICIENT_BITS: u32 = 28;
+const COEFFICIENT_SCALE: f32 = (1 << COEFFICIENT_BITS) as f32;
+
+// The filter state keeps this many bits below the 16 bit samples, otherwise rounding noise builds up in low frequency bands
+const STATE_FRACTION_BITS: u32 = 8;
+
+// Gains are limited so the coefficients stay in range
+const MAX_GAIN_DB: f32 = 15.0;
+
+const PI: f32 = core::f32::consts::PI;
+const LN_10: f32 = core::f32::consts::LN_10;
+const LOG2_E: f32 = core::f32::consts::LOG2_E;
+const LN_2: f32 = core::f32::consts::LN_2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterType {
+    LowShelf,
+    Peaking,
+    HighShelf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub filter_type: FilterType,
+    pub frequency: u32, // Centre frequency, or the middle of the slope for shelves, in Hz
+    pub gain_db: f32,
+    pub q: f32, // Bandwidth, 0.707 is a gentle shelf or a wide peak
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Coefficients {
+    b0: i32,
+    b1: i32,
+    b2: i32,
+    a1: i32,
+    a2: i32,
+}
+
+// Direct form 1 state of one channel, the last two inputs and outputs with STATE_FRACTION_BITS extra bits
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelState {
+    x1: i32,
+    x2: i32,
+    y1: i32,
+    y2: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    band: Band,
+    coefficients: Coefficients,
+    channels: [ChannelState; 2],
+}
+
+#[derive(Debug)]
+pub struct Equalizer {
+    sample_rate: u32,
+    biquads: Vec<Biquad, MAX_BANDS>,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: u32) -> Self {
+        Equalizer {
+            sample_rate,
+            biquads: Vec::new(),
+        }
+    }
+
+    // Recalculates every band for a new output sample rate
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        for biquad in self.biquads.iter_mut() {
+            biquad.coefficients = coefficients(&biquad.band, sample_rate);
+            biquad.channels = Default::default();
+        }
+    }
+
+    // Adds a band after the others, fails if there are already MAX_BANDS bands
+    pub fn add_band(&mut self, band: Band) -> Result<(), ()> {
+        let biquad = Biquad {
+            band,
+            coefficients: coefficients(&band, self.sample_rate),
+            channels: Default::default(),
+        };
+        self.biquads.push(biquad).map_err(|_| ())
+    }
+
+    // Changes a band while playing, the filter state is kept so there's no click
+    pub fn set_band(&mut self, indx: usize, band: Band) -> Result<(), ()> {
+        let biquad = self.biquads.get_mut(indx).ok_or(())?;
+        biquad.band = band;
+        biquad.coefficients = coefficients(&band, self.sample_rate);
+        Ok(())
+    }
+
+    pub fn band(&self, indx: usize) -> Option<Band> {
+        self.biquads.get(indx).map(|biquad| biquad.band)
+    }
+
+    pub fn clear(&mut self) {
+        self.biquads.clear();
+    }
+
+    // Filters interleaved 16 bit stereo samples through every band in turn
+    pub fn apply(&mut self, buf: &mut [u16]) {
+        // Bands with no gain don't change the sound
+        for biquad in self.biquads.iter_mut().filter(|biquad| biquad.band.gain_db != 0.0) {
+            for frame in buf.chunks_exact_mut(2) {
+                for (sample, state) in frame.iter_mut().zip(biquad.channels.iter_mut()) {
+                    *sample = biquad.coefficients.filter(state, *sample as i16) as u16;
+                }
+            }
+        }
+    }
+}
+
+impl Coefficients {
+    fn filter(&self, state: &mut ChannelState, x: i16) -> i16 {
+        let x = (x as i32) << STATE_FRACTION_BITS;
+        let acc = self.b0 as i64 * x as i64
+            + self.b1 as i64 * state.x1 as i64
+            + self.b2 as i64 * state.x2 as i64
+            - self.a1 as i64 * state.y1 as i64
+            - self.a2 as i64 * state.y2 as i64;
+
+        // A boosted band can go past full scale, the state keeps some of that so the limiter can round it off
+        let max_state = (i16::MAX as i64) << (STATE_FRACTION_BITS + 1);
+        let y = (acc >> COEFFICIENT_BITS).clamp(-max_state, max_state) as i32;
+
+        state.x2 = state.x1;
+        state.x1 = x;
+        state.y2 = state.y1;
+        state.y1 = y;
+        limiter::soft_clip(y >> STATE_FRACTION_BITS)
+    }
+}
+
+// Audio EQ cookbook coefficients, normalised by a0 and converted to fixed point
+fn coefficients(band: &Band, sample_rate: u32) -> Coefficients {
+    let nyquist = sample_rate / 2;
+    let frequency = band.frequency.clamp(1, nyquist.saturating_sub(1).max(1)) as f32;
+    let gain_db = band.gain_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+    let q = if band.q > 0.01 { band.q } else { 0.01 };
+
+    let a = pow10(gain_db / 40.0);
+    let w0 = 2.0 * PI * frequency / sample_rate.max(1) as f32;
+    let (sin_w0, cos_w0) = (sin(w0), sin(w0 + PI / 2.0));
+    let alpha = sin_w0 / (2.0 * q);
+    let sqrt_a_alpha = 2.0 * sqrt(a) * alpha;
+
+    let (b0, b1, b2, a0, a1, a2) = match band.filter_type {
+        FilterType::Peaking => (
+            1.0 + alpha * a,
+            -2.0 * cos_w0,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos_w0,
+            1.0 - alpha / a,
+        ),
+        FilterType::LowShelf => (
+            a * ((a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+            a * ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha),
+            (a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+            (a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha,
+        ),
+        FilterType::HighShelf => (
+            a * ((a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+            a * ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha),
+            (a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+            (a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha,
+        ),
+    };
+
+    let to_fixed = |coefficient: f32| (coefficient / a0 * COEFFICIENT_SCALE) as i32;
+    Coefficients {
+        b0: to_fixed(b0),
+        b1: to_fixed(b1),
+        b2: to_fixed(b2),
+        a1: to_fixed(a1),
+        a2: to_fixed(a2),
+    }
+}
+
+// Sine of x from 0 to 2 pi (a little either side is fine)
+fn sin(x: f32) -> f32 {
+    // Fold into -pi/2 to pi/2, where the series is accurate
+    let mut x = x;
+    while x > PI {
+        x -= 2.0 * PI;
+    }
+    while x < -PI {
+        x += 2.0 * PI;
+    }
+    if x > PI / 2.0 {
+        x = PI - x;
+    } else if x < -PI / 2.0 {
+        x = -PI - x;
+    }
+
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0 * (1.0 - x2 / 110.0)))))
+}
+
+// Square root by a few Newton steps from a guess made from the exponent bits
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    let mut root = f32::from_bits((x.to_bits() >> 1) + 0x1FBD_1DF5);
+    for _ in 0..3 {
+        root = 0.5 * (root + x / root);
+    }
+    root
+}
+
+// 10 to the power of x, for x from about -30 to 30
+pub fn pow10(x: f32) -> f32 {
+    let x = x * LN_10 * LOG2_E; // Power of 2
+
+    // Split into a whole power of 2, which goes in the exponent bits, and a fraction from 0 to 1
+    let mut whole = x as i32;
+    if (whole as f32) > x {
+        whole -= 1;
+    }
+    let fraction = x - whole as f32;
+
+    let fraction_power = 1.0 + fraction * (LN_2 + fraction * (0.240_226_5 + fraction * (0.055_504_1
+        + fraction * (0.009_618_1 + fraction * 0.001_333_4))));
+    f32::from_bits(((whole.clamp(-126, 127) + 127) as u32) << 23) * fraction_power
+}
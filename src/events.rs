@@ -0,0 +1,41 @@
+// Lets the rest of the firmware (a display, LEDs, logging) react to what the player does without changing the player
+//
+// Hooks are plain functions registered with the player, each one is called with every event
+// They are called from Player::poll in the main loop, never while a buffer is being filled, so they can take their time
+// A hook that needs state has to keep it in a static, there's no allocator to box closures with
+
+use heapless::Vec;
+
+use crate::player::PlayerEvent;
+
+pub const MAX_EVENT_HOOKS: usize = 4;
+
+pub type EventHook = fn(&PlayerEvent);
+
+#[derive(Debug)]
+pub struct EventHooks {
+    hooks: Vec<EventHook, MAX_EVENT_HOOKS>,
+}
+
+impl EventHooks {
+    pub fn new() -> Self {
+        EventHooks { hooks: Vec::new() }
+    }
+
+    // Hooks are called in the order they were registered, fails once MAX_EVENT_HOOKS have been registered
+    pub fn register(&mut self, hook: EventHook) -> Result<(), ()> {
+        self.hooks.push(hook).map_err(|_| ())
+    }
+
+    pub fn dispatch(&self, event: &PlayerEvent) {
+        for hook in self.hooks.iter() {
+            hook(event);
+        }
+    }
+}
+
+impl Default for EventHooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -34,6 +34,9 @@ pub struct ExFat<T: BlockDevice<SECTOR_SIZE>> {
     pub number_of_fats: u8,                    // Number of FATs (typically 1)
     pub drive_select: u8,                      // Usually 0x80 for fixed drives
     pub percent_in_use: u8,                    // Approximate % of clusters in use
+
+    pub name_policy: NamePolicy,               // How to handle file names which don't fit in an FsEntry
+    pub check_second_fat: bool,                // Compare each FAT entry with the second FAT as chains are followed, and report any mismatch
 }
 
 
@@ -50,6 +53,41 @@ const FILE_DIRECTORY_ENTRY: u8 = 0x85;
 const STREAM_EXTENSION_ENTRY: u8 = 0xC0;
 const FILE_NAME_ENTRY: u8 = 0xC1;
 
+// Bits of the entry type byte (see the generic directory entry template in the spec)
+const ENTRY_IN_USE_BIT: u8 = 7;
+const ENTRY_CATEGORY_BIT: u8 = 6;   // 0 for primary entries, 1 for secondary entries
+const ENTRY_IMPORTANCE_BIT: u8 = 5; // 0 for critical entries, 1 for benign entries
+
+// How an entry type should be treated when it appears in a file's entry set
+#[derive(Debug, PartialEq)]
+enum EntryClass {
+    Unused,            // Deleted entry or end of directory marker
+    Primary,           // Starts a new entry set
+    CriticalSecondary, // Must be understood, otherwise the whole entry set is unusable
+    BenignSecondary,   // Can be skipped if it isn't understood (e.g. vendor extensions)
+}
+
+fn classify_entry(entry_type: u8) -> EntryClass {
+    let entry_type = entry_type as u64;
+
+    if !binary_helpers::bit_on(entry_type, ENTRY_IN_USE_BIT) {
+        EntryClass::Unused
+    } else if !binary_helpers::bit_on(entry_type, ENTRY_CATEGORY_BIT) {
+        EntryClass::Primary
+    } else if binary_helpers::bit_on(entry_type, ENTRY_IMPORTANCE_BIT) {
+        EntryClass::BenignSecondary
+    } else {
+        EntryClass::CriticalSecondary
+    }
+}
+
+// Bit of volume_flags that selects the active FAT when there are two FATs (TexFAT)
+const ACTIVE_FAT_BIT: u8 = 0;
+const FAT_ENTRY_BYTES: usize = 4;
+
+// Bit of the stream extension flags which is set when a file doesn't use the FAT
+const NO_FAT_CHAIN_BIT: u8 = 1;
+
 #[derive(Debug, Clone, Copy)]
 pub enum FileType {
     Directory,
@@ -60,10 +98,20 @@ pub enum FileType {
 // Choosing a longer value here will use more memory when reading a directory
 const DIR_LENGTH_LIMIT: usize = 205; 
 
-const MAX_FILE_NAME_LENGTH: usize = 255; // exFAT limitation
+pub const MAX_FILE_NAME_LENGTH: usize = 255; // exFAT limitation
+
+// The name is stored as utf-8, so names with non ascii characters can be longer than MAX_FILE_NAME_LENGTH bytes
+// NamePolicy decides what happens to names that don't fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NamePolicy {
+    Error,    // Fail the whole directory listing with FsError::ErrorDecodingName
+    Truncate, // Keep what fits and end the name with TRUNCATION_MARKER, invalid characters are replaced
+}
+
+const TRUNCATION_MARKER: char = '…';
 
 // A filesystem entry is a struct that contains information about either a file or a folder
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FsEntry {
     pub name: String<MAX_FILE_NAME_LENGTH>,
     pub file_type: FileType, 
@@ -71,6 +119,13 @@ pub struct FsEntry {
     pub first_cluster: u32,     // The first cluster in the files cluster chain 
     pub valid_data_length: u64, // Actual length of the file in bytes
     pub data_length: u64,       // Total size of the file in bytes
+
+    pub contiguous: bool,       // The clusters are consecutive, so the FAT doesn't need to be read
+
+    pub modified: u32,          // LastModifiedTimestamp, changes when the file is written or a directory's entries change
+    pub modified_10ms: u8,      // LastModified10msIncrement, the timestamp only counts in 2 second steps
+
+    pub name_truncated: bool,   // The name was too long to store and ends with TRUNCATION_MARKER
 }
 
 
@@ -82,8 +137,21 @@ impl FsEntry {
             first_cluster: 0, 
             valid_data_length: 0, 
             data_length: 0, 
+            contiguous: false,
+            modified: 0,
+            modified_10ms: 0,
+            name_truncated: false,
         }
     }
+
+    // Replaces the end of the name with the truncation marker
+    fn mark_name_truncated(&mut self) {
+        while self.name.len() + TRUNCATION_MARKER.len_utf8() > self.name.capacity() {
+            self.name.pop();
+        }
+
+        let _ = self.name.push(TRUNCATION_MARKER);
+    }
 }
 
 
@@ -102,9 +170,12 @@ pub enum FsError {
     NoBootSector, 
 
     InvalidBootSignature, // The boot singature is incorrect or missing
+    UnsupportedSectorSize(u8), // The sectors aren't SECTOR_SIZE bytes, holds the bytes per sector shift from the boot sector
     ReadFail, // The device failed a read during init
 
     ErrorDecodingName, // Error decoding the file / folder name
+    InvalidCluster, // A cluster index outside of the cluster heap
+    NotFound, // A file or folder that was looked for isn't in the directory
 }
 
 // Finds the boot sector of the block device by searching for the exfat filesystem name
@@ -137,44 +208,68 @@ fn get_boot_sector<T: BlockDevice<SECTOR_SIZE>>(block_device: &mut T) -> Result<
 }
 
 impl<T: BlockDevice<SECTOR_SIZE>> ExFat<T> {
-    pub fn new(mut block_device: T) -> Result<Self, FsError> {
-        let boot_sector = get_boot_sector(&mut block_device)?;
-
-        // Retrieve all the useful information encoded in the boot sector
-        let partition_offset = u64::from_le_bytes(boot_sector.get_bytes_section::<8>(0x040));
-        let volume_length = u64::from_le_bytes(boot_sector.get_bytes_section::<8>(0x048));
-        let fat_offset = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x050));
-        let fat_length = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x054));
-        let cluster_heap_offset = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x058));
-        let cluster_count = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x05C));
-        let first_cluster_of_root_directory = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x060));
-        let volume_serial_number = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x064));
-        let volume_flags = u16::from_le_bytes(boot_sector.get_bytes_section::<2>(0x06a));
+    pub fn new(block_device: T) -> Result<Self, FsError> {
+        let mut exfat = ExFat{
+            block_device,
+            partition_offset: 0,
+            volume_length: 0,
+            fat_offset: 0,
+            fat_length: 0,
+            cluster_heap_offset: 0,
+            cluster_count: 0,
+            first_cluster_of_root_directory: 0,
+            volume_serial_number: 0,
+            volume_flags: 0,
+            bytes_per_sector_shift: 0,
+            sectors_per_cluster_shift: 0,
+            number_of_fats: 0,
+            drive_select: 0,
+            percent_in_use: 0,
+            name_policy: NamePolicy::Error,
+            check_second_fat: false,
+        };
+
+        exfat.read_boot_sector()?;
+        Ok(exfat)
+    }
+
+    // Re-reads the boot sector and refreshes the volume parameters
+    // Use this after the block device has been re-initialized (e.g. after an SD error or a card swap)
+    // Any FsEntry read before the remount should be considered stale, since the card may have changed
+    pub fn remount(&mut self) -> Result<(), FsError> {
+        self.read_boot_sector()
+    }
+
+    // Consumes the filesystem and returns the underlying block device
+    pub fn release(self) -> T {
+        self.block_device
+    }
+
+    // Retrieve all the useful information encoded in the boot sector
+    fn read_boot_sector(&mut self) -> Result<(), FsError> {
+        let boot_sector = get_boot_sector(&mut self.block_device)?;
+
         let bytes_per_sector_shift = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06c));
-        let sectors_per_cluster_shift = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06d));
-        let number_of_fats = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06e));
-        let drive_select = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06f));
-        let percent_in_use = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x070));
+        if 1usize.checked_shl(bytes_per_sector_shift as u32) != Some(SECTOR_SIZE) {
+            return Err(FsError::UnsupportedSectorSize(bytes_per_sector_shift));
+        }
 
-        assert_eq!(1 << bytes_per_sector_shift, SECTOR_SIZE);
-        
-        Ok(ExFat{
-            block_device,
-            partition_offset,
-            volume_length,
-            fat_offset,
-            fat_length,
-            cluster_heap_offset,
-            cluster_count,
-            first_cluster_of_root_directory,
-            volume_serial_number,
-            volume_flags,
-            bytes_per_sector_shift,
-            sectors_per_cluster_shift,
-            number_of_fats,
-            drive_select,
-            percent_in_use,
-        })
+        self.partition_offset = u64::from_le_bytes(boot_sector.get_bytes_section::<8>(0x040));
+        self.volume_length = u64::from_le_bytes(boot_sector.get_bytes_section::<8>(0x048));
+        self.fat_offset = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x050));
+        self.fat_length = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x054));
+        self.cluster_heap_offset = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x058));
+        self.cluster_count = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x05C));
+        self.first_cluster_of_root_directory = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x060));
+        self.volume_serial_number = u32::from_le_bytes(boot_sector.get_bytes_section::<4>(0x064));
+        self.volume_flags = u16::from_le_bytes(boot_sector.get_bytes_section::<2>(0x06a));
+        self.bytes_per_sector_shift = bytes_per_sector_shift;
+        self.sectors_per_cluster_shift = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06d));
+        self.number_of_fats = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06e));
+        self.drive_select = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x06f));
+        self.percent_in_use = u8::from_le_bytes(boot_sector.get_bytes_section::<1>(0x070));
+
+        Ok(())
     }
 
     // Read a sector from the block device, except now the error type is a FsError
@@ -195,16 +290,102 @@ impl<T: BlockDevice<SECTOR_SIZE>> ExFat<T> {
     }
 
 
+    // Returns the sector offset of the FAT which is currently in use
+    // Volumes with two FATs use the ActiveFat bit in the volume flags to choose between them
+    pub fn active_fat_offset(&self) -> u32 {
+        if self.number_of_fats == 2 && binary_helpers::bit_on(self.volume_flags as u64, ACTIVE_FAT_BIT) {
+            self.fat_offset + self.fat_length
+        } else {
+            self.fat_offset
+        }
+    }
+
+    // Reads the FAT entry of a cluster from the active FAT
+    // The entry is the next cluster in the chain, or 0xFFFFFFFF at the end of the chain
+    pub fn read_fat_entry(&mut self, cluster: u32) -> Result<u32, FsError> {
+        self.read_fat_entry_from(self.active_fat_offset(), cluster)
+    }
+
+    // Compares the FAT entry of a cluster between both FATs
+    // Returns true if they match, or if the volume only has one FAT
+    pub fn check_fat_entry(&mut self, cluster: u32) -> Result<bool, FsError> {
+        if self.number_of_fats != 2 {
+            return Ok(true);
+        }
+
+        let first = self.read_fat_entry_from(self.fat_offset, cluster)?;
+        let second = self.read_fat_entry_from(self.fat_offset + self.fat_length, cluster)?;
+        Ok(first == second)
+    }
+
+    // Returns the cluster that follows cluster in a cluster chain
+    // Contiguous files (FsEntry::contiguous) don't have a chain in the FAT
+    // With check_second_fat a mismatch is reported, and the chain carries on through the active FAT
+    pub fn next_cluster(&mut self, cluster: u32, contiguous: bool) -> Result<u32, FsError> {
+        if contiguous {
+            return Ok(cluster + 1);
+        }
+
+        if self.check_second_fat && !self.check_fat_entry(cluster)? {
+            rprintln!("The FATs don't match at cluster {}, using the active FAT", cluster);
+        }
+        self.read_fat_entry(cluster)
+    }
+
+    // True if the clusters of a file are one after another, so it can be read without following its chain
+    // A file that fits in one cluster is, even if it has a FAT chain
+    pub fn is_contiguous(&self, file: &FsEntry) -> bool {
+        file.contiguous || file.data_length <= self.cluster_size() as u64
+    }
+
+    // Number of bytes in one cluster
+    pub fn cluster_size(&self) -> u32 {
+        (SECTOR_SIZE as u32) << self.sectors_per_cluster_shift
+    }
+
+    fn read_fat_entry_from(&mut self, fat_offset: u32, cluster: u32) -> Result<u32, FsError> {
+        // Valid clusters are numbered from 2
+        if cluster < 2 || cluster >= self.cluster_count + 2 {
+            return Err(FsError::InvalidCluster);
+        }
+
+        let byte_offset = cluster as usize * FAT_ENTRY_BYTES;
+        let sector_addr = self.partition_offset as u32 + fat_offset + (byte_offset / SECTOR_SIZE) as u32;
+        let sector = self.read_sector(sector_addr)?;
+
+        Ok(u32::from_le_bytes(sector.get_bytes_section::<4>(byte_offset % SECTOR_SIZE)))
+    }
+
     // Lists the directory that starts at first_cluster
     // The root directory starts at cluster 4
     pub fn list_directory(&mut self, first_cluster: u32) -> Result<Vec<FsEntry, DIR_LENGTH_LIMIT>, FsError> {
         let mut output_directory = Vec::new();
 
+        self.for_each_entry(first_cluster, |fs_entry| {
+            let _ = output_directory.push(fs_entry);
+        })?;
+
+        Ok(output_directory)
+    }
+
+    // Calls on_entry for each entry of the directory that starts at first_cluster
+    // Unlike list_directory this only keeps one FsEntry in memory at a time, which is useful for walking many directories
+    pub fn for_each_entry<F: FnMut(FsEntry)>(&mut self, first_cluster: u32, on_entry: F) -> Result<(), FsError> {
+        self.for_each_entry_from(first_cluster, 0, u32::MAX, on_entry)?;
+        Ok(())
+    }
+
+    // Like for_each_entry, but only for the entries that start in max_sectors sectors from start_sector of the directory
+    // Returns the sector to carry on from, or None once the end of the directory has been reached
+    // This lets a long directory be listed a bit at a time, e.g. between buffer fills
+    pub fn for_each_entry_from<F: FnMut(FsEntry)>(&mut self, first_cluster: u32, start_sector: u32, max_sectors: u32, mut on_entry: F)
+    -> Result<Option<u32>, FsError> {
+
         let mut found_all_entries = false;
-        let mut sector_offset = 0;
+        let mut sector_offset = start_sector;
         let sector_addr = self.calc_cluster_sector(first_cluster);
 
-        while !found_all_entries {
+        while !found_all_entries && sector_offset - start_sector < max_sectors {
             let sector_addr = sector_offset + sector_addr;
             sector_offset += 1;
 
@@ -235,6 +416,8 @@ impl<T: BlockDevice<SECTOR_SIZE>> ExFat<T> {
 
                     let mut fs_entry = FsEntry::new();
                     fs_entry.file_type = file_type;
+                    fs_entry.modified = u32::from_le_bytes(entry_bytes.get_bytes_section::<4>(12));
+                    fs_entry.modified_10ms = entry_bytes[21];
 
                     // Add the entries from the next sector to the current directory entries iterator
                     // Do this to account for cases where a FsEntry has entries which lie on the boundary between two sectors
@@ -243,20 +426,38 @@ impl<T: BlockDevice<SECTOR_SIZE>> ExFat<T> {
 
                     let dir_entries_iter = dir_entries.iter().chain(next_sector_entries.iter());
 
+                    // Set to false if the entry set contains something this implementation can't safely ignore
+                    let mut entry_set_valid = true;
+
                     // Finally, get an iterator over the next directory entries which are associated with the current one
-                    let following_entries_iter = dir_entries_iter.skip(entry_no + 1).take(following_entries_no + 1);
+                    let following_entries_iter = dir_entries_iter.skip(entry_no + 1).take(following_entries_no);
                     for entry_bytes in following_entries_iter {
                         let entry_type = entry_bytes[0];
+                        let entry_class = classify_entry(entry_type);
+
+                        // Every entry described by the secondary count has to be a secondary entry
+                        // If it isn't the entry set is corrupt, or the secondary count is wrong
+                        if entry_class == EntryClass::Unused || entry_class == EntryClass::Primary {
+                            entry_set_valid = false;
+                            break;
+                        }
 
                         // Add useful stream extension information to the fs_entry
                         if entry_type == STREAM_EXTENSION_ENTRY {
                             fs_entry.valid_data_length = u64::from_le_bytes(entry_bytes.get_bytes_section::<8>(8));
                             fs_entry.data_length = u64::from_le_bytes(entry_bytes.get_bytes_section::<8>(24));
                             fs_entry.first_cluster = u32::from_le_bytes(entry_bytes.get_bytes_section::<4>(20));
+
+                            let flags = entry_bytes[1];
+                            fs_entry.contiguous = binary_helpers::bit_on(flags as u64, NO_FAT_CHAIN_BIT);
                         }
 
                         // Decode file name entries
+                        // Once a name has been truncated the remaining name entries are skipped
                         else if entry_type == FILE_NAME_ENTRY {
+                            if fs_entry.name_truncated {
+                                continue;
+                            }
 
                             let utf_16_bytes = entry_bytes.slice_by::<{DIRECORY_ENTRY_BYTES / 2}, 2>();
                             let utf_iterator = utf_16_bytes.iter().skip(1) // Skip first byte which is the entry type
@@ -266,30 +467,49 @@ impl<T: BlockDevice<SECTOR_SIZE>> ExFat<T> {
 
                             // Then iterate over each character and push them to the current file name
                             for char_result in decode_utf16(utf_iterator) {
-                                match char_result {
-                                    Ok(character) => {
-                                        let result = fs_entry.name.push(character);
-                                        match result {
-                                            Ok(()) => (),
-                                            Err(_err) => return Err(FsError::ErrorDecodingName)
+                                let character = match char_result {
+                                    Ok(character) => character,
+                                    Err(_err) => match self.name_policy {
+                                        NamePolicy::Error => return Err(FsError::ErrorDecodingName),
+                                        NamePolicy::Truncate => char::REPLACEMENT_CHARACTER,
+                                    }
+                                };
+
+                                if fs_entry.name.push(character).is_err() {
+                                    match self.name_policy {
+                                        NamePolicy::Error => return Err(FsError::ErrorDecodingName),
+                                        NamePolicy::Truncate => {
+                                            fs_entry.name_truncated = true;
+                                            break;
                                         }
-                                    },
-                                    Err(_err) => return Err(FsError::ErrorDecodingName)
+                                    }
                                 }
                             }
                         } // File name decoing end
 
+                        // Unknown critical secondary entries change how the file must be interpreted
+                        // So the entry set can't be used, but the rest of the directory can still be listed
+                        // Unknown benign secondary entries (vendor extensions etc.) are skipped
+                        else if entry_class == EntryClass::CriticalSecondary {
+                            entry_set_valid = false;
+                        }
                     } 
 
                     // All the following directory entries have now been read so the fs_entry has all it's information
                     // The fs_entry can then be pushed to the output
-                    let _ = output_directory.push(fs_entry);
+                    if entry_set_valid {
+                        if fs_entry.name_truncated {
+                            fs_entry.mark_name_truncated();
+                        }
+
+                        on_entry(fs_entry);
+                    }
                 } // FILE_DIRECTOR_ENTRY section end
 
             } // loop 2 end
         } // loop 1 end
         
-        Ok(output_directory)
+        Ok((!found_all_entries).then_some(sector_offset))
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,102 @@
+// Sample accurate gain ramps, so starting, pausing, and stopping playback doesn't click
+//
+// The gain moves in a straight line from where it is to the target over a number of frames
+// Both channels of a frame get the same gain
+
+use crate::volume::UNITY_GAIN;
+
+#[derive(Debug)]
+pub struct Fade {
+    from: i32, // Q15 gain at the start of the ramp
+    to: i32, // Q15 gain at the end of the ramp
+    frames: u32, // Length of the ramp
+    elapsed: u32, // Frames of the ramp that have been applied
+}
+
+impl Fade {
+    pub fn new() -> Self {
+        Fade {
+            from: UNITY_GAIN,
+            to: UNITY_GAIN,
+            frames: 0,
+            elapsed: 0,
+        }
+    }
+
+    // Ramps up from silence to full gain
+    pub fn fade_in(&mut self, frames: u32) {
+        self.from = 0;
+        self.to = UNITY_GAIN;
+        self.frames = frames;
+        self.elapsed = 0;
+    }
+
+    // Ramps down from the current gain to silence
+    pub fn fade_out(&mut self, frames: u32) {
+        self.from = self.gain();
+        self.to = 0;
+        self.frames = frames;
+        self.elapsed = 0;
+    }
+
+    // Ramps from the current gain to a Q15 gain, e.g. to turn the music down part way
+    pub fn ramp_to(&mut self, to: i32, frames: u32) {
+        self.from = self.gain();
+        self.to = to;
+        self.frames = frames;
+        self.elapsed = 0;
+    }
+
+    // Jumps to the end of the ramp
+    pub fn finish(&mut self) {
+        self.elapsed = self.frames;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.frames
+    }
+
+    // Q15 gain the ramp ends at
+    pub fn target(&self) -> i32 {
+        self.to
+    }
+
+    // True once a fade out has finished
+    pub fn is_silent(&self) -> bool {
+        self.is_finished() && self.to == 0
+    }
+
+    // Q15 gain of the next frame
+    fn gain(&self) -> i32 {
+        if self.is_finished() {
+            return self.to;
+        }
+
+        self.from + ((self.to - self.from) as i64 * self.elapsed as i64 / self.frames as i64) as i32
+    }
+
+    // Applies the ramp to interleaved 16 bit stereo samples
+    // After a fade out has finished everything is silenced
+    pub fn apply(&mut self, buf: &mut [u16]) {
+        if self.is_finished() && self.to == UNITY_GAIN {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(2) {
+            let gain = self.gain();
+            for sample in frame {
+                *sample = ((*sample as i16 as i32 * gain) >> 15) as i16 as u16;
+            }
+
+            if !self.is_finished() {
+                self.elapsed += 1;
+            }
+        }
+    }
+}
+
+impl Default for Fade {
+    fn default() -> Self {
+        Self::new()
+    }
+}
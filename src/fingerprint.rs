@@ -0,0 +1,198 @@
+// Fingerprints files with a CRC-32 of their contents so duplicate tracks can be found
+// This reads every byte of the files being compared, so it is meant to be run while nothing is playing
+
+use heapless::{String, Vec};
+
+use crate::block_device::BlockDevice;
+use crate::exfat::{ExFat, FileType, FsEntry, FsError};
+use crate::rprintln;
+
+const SECTOR_SIZE: usize = crate::BLOCK_SIZE;
+
+// Limits on how much of the card is searched
+// Each file record takes about 70 bytes of memory
+const MAX_FILES: usize = 128;
+const MAX_DIRECTORIES: usize = 16;
+const REPORT_NAME_LENGTH: usize = 40; // Names are shortened to this many bytes in the report
+
+// CRC-32 as used by zip and png (reflected polynomial 0xEDB88320)
+const CRC_POLYNOMIAL: u32 = 0xEDB88320;
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+// Streaming CRC-32, bytes can be added in as many pieces as needed
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { crc: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let index = ((self.crc ^ *byte as u32) & 0xFF) as usize;
+            self.crc = (self.crc >> 8) ^ CRC_TABLE[index];
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Calculates the CRC-32 of the valid data in a file by following its cluster chain
+pub fn fingerprint_file<T: BlockDevice<SECTOR_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<u32, FsError> {
+    fingerprint_clusters(exfat, file.first_cluster, file.valid_data_length, file.contiguous)
+}
+
+fn fingerprint_clusters<T: BlockDevice<SECTOR_SIZE>>(exfat: &mut ExFat<T>, first_cluster: u32, length: u64, contiguous: bool)
+-> Result<u32, FsError> {
+    let mut crc = Crc32::new();
+    let sectors_per_cluster = 1 << exfat.sectors_per_cluster_shift;
+
+    let mut bytes_left = length;
+    let mut cluster = first_cluster;
+
+    while bytes_left > 0 {
+        let cluster_sector = exfat.calc_cluster_sector(cluster);
+
+        for sector_no in 0..sectors_per_cluster {
+            if bytes_left == 0 {
+                break;
+            }
+
+            let sector = exfat.read_sector(cluster_sector + sector_no)?;
+            let valid_bytes = bytes_left.min(SECTOR_SIZE as u64) as usize;
+
+            crc.update(&sector[..valid_bytes]);
+            bytes_left -= valid_bytes as u64;
+        }
+
+        if bytes_left > 0 {
+            cluster = exfat.next_cluster(cluster, contiguous)?;
+        }
+    }
+
+    Ok(crc.finish())
+}
+
+// What is remembered about each file while searching for duplicates
+// A whole FsEntry is too big to keep for every file
+struct FileRecord {
+    name: String<REPORT_NAME_LENGTH>,
+    directory_cluster: u32, // The first cluster of the directory containing the file
+    first_cluster: u32,
+    length: u64,
+    contiguous: bool,
+    crc: Option<u32>,
+}
+
+// Searches the directory at root_cluster and its subdirectories for files with identical contents
+// Prints each set of duplicates over RTT and returns how many duplicate files were found
+pub fn report_duplicates<T: BlockDevice<SECTOR_SIZE>>(exfat: &mut ExFat<T>, root_cluster: u32) -> Result<usize, FsError> {
+    let mut files: Vec<FileRecord, MAX_FILES> = Vec::new();
+    let mut directories: Vec<u32, MAX_DIRECTORIES> = Vec::new();
+    let _ = directories.push(root_cluster);
+
+    // Walk the directory tree, collecting the files
+    let mut skipped_entries = 0;
+    while let Some(directory_cluster) = directories.pop() {
+        exfat.for_each_entry(directory_cluster, |fs_entry| {
+            match fs_entry.file_type {
+                FileType::Directory => {
+                    if directories.push(fs_entry.first_cluster).is_err() {
+                        skipped_entries += 1;
+                    }
+                },
+                FileType::File => {
+                    // Keep as many whole characters of the name as fit
+                    let mut name = String::new();
+                    for character in fs_entry.name.chars() {
+                        if name.push(character).is_err() {
+                            break;
+                        }
+                    }
+
+                    let record = FileRecord {
+                        name,
+                        directory_cluster,
+                        first_cluster: fs_entry.first_cluster,
+                        length: fs_entry.valid_data_length,
+                        contiguous: fs_entry.contiguous,
+                        crc: None,
+                    };
+
+                    if files.push(record).is_err() {
+                        skipped_entries += 1;
+                    }
+                }
+            }
+        })?;
+    }
+
+    if skipped_entries > 0 {
+        rprintln!("Too many entries, {} files or directories were not checked", skipped_entries);
+    }
+
+    // Only files which are the same size as another file can be duplicates, so only those are hashed
+    // Empty files are ignored
+    for i in 0..files.len() {
+        let file = &files[i];
+        let has_same_size = file.length > 0 && files.iter().enumerate().any(|(j, other)| j != i && other.length == file.length);
+
+        if has_same_size {
+            let crc = fingerprint_clusters(exfat, file.first_cluster, file.length, file.contiguous)?;
+            files[i].crc = Some(crc);
+        }
+    }
+
+    // Report each file that matches an earlier file
+    let mut duplicates = 0;
+    for (i, file) in files.iter().enumerate() {
+        let original = files.iter().take(i).find(|other| {
+            other.crc.is_some() && other.crc == file.crc && other.length == file.length
+        });
+
+        if let Some(original) = original {
+            duplicates += 1;
+            rprintln!(
+                "Duplicate: {} (dir {}) == {} (dir {}), crc {:08X}",
+                file.name, file.directory_cluster, original.name, original.directory_cluster, file.crc.unwrap_or(0)
+            );
+        }
+    }
+
+    rprintln!("Checked {} files, found {} duplicates", files.len(), duplicates);
+    Ok(duplicates)
+}
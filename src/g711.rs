@@ -0,0 +1,62 @@
+// G.711 companding, used by telephony recordings
+// Each 8 bit sample expands to a 16 bit linear sample through a lookup table
+
+// The tables are built at compile time, the same as the decoders from the ITU reference implementation
+pub const ALAW_TABLE: [i16; 256] = build_table(Law::A);
+pub const MULAW_TABLE: [i16; 256] = build_table(Law::Mu);
+
+const fn alaw_to_linear(alaw: u8) -> i16 {
+    let alaw = alaw ^ 0x55; // Even bits are inverted in A-law
+
+    let mantissa = ((alaw & 0x0F) as i16) << 4;
+    let exponent = (alaw >> 4) & 0x07;
+
+    let magnitude = match exponent {
+        0 => mantissa + 8,
+        _ => (mantissa + 0x108) << (exponent - 1),
+    };
+
+    // The sign bit is set for positive samples
+    if alaw & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+const MULAW_BIAS: i16 = 0x84;
+
+const fn mulaw_to_linear(mulaw: u8) -> i16 {
+    let mulaw = !mulaw; // All bits are inverted in µ-law
+
+    let mantissa = ((mulaw & 0x0F) as i16) << 3;
+    let exponent = (mulaw >> 4) & 0x07;
+    let magnitude = (mantissa + MULAW_BIAS) << exponent;
+
+    // Unlike A-law the sign bit is set for negative samples
+    if mulaw & 0x80 != 0 {
+        MULAW_BIAS - magnitude
+    } else {
+        magnitude - MULAW_BIAS
+    }
+}
+
+enum Law {
+    A,
+    Mu,
+}
+
+const fn build_table(law: Law) -> [i16; 256] {
+    let mut table = [0i16; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        table[i] = match law {
+            Law::A => alaw_to_linear(i as u8),
+            Law::Mu => mulaw_to_linear(i as u8),
+        };
+        i += 1;
+    }
+
+    table
+}
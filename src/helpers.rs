@@ -19,4 +19,31 @@ where
         return min + sum - max;
     }
     return sum;
-}
\ No newline at end of file
+}
+
+// Xorshift pseudo random number generator, good enough for shuffling tracks
+#[derive(Debug)]
+pub struct Xorshift32 {
+    state: u32,
+}
+
+impl Xorshift32 {
+    // The state can't be zero, so a zero seed is replaced
+    pub fn new(seed: u32) -> Self {
+        Xorshift32 {
+            state: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    // Random number from 0 to below max, max has to be more than 0
+    pub fn below(&mut self, max: u32) -> u32 {
+        self.next_u32() % max
+    }
+}
@@ -0,0 +1,176 @@
+// Minimal ID3v2 parser for the id3 chunk that some taggers put in wav files
+// Useful resource: https://id3.org/id3v2.3.0
+//
+// The tag is a 10 byte header followed by frames, each frame is a header and its data
+// Only the title, artist, and album text frames, and the ReplayGain TXXX frames, are read, everything else is skipped
+// Versions 2.2 (3 character frame ids), 2.3, and 2.4 are supported
+
+use heapless::String;
+
+use crate::block_device::BlockDevice;
+use crate::bytes::BytesTrait;
+use crate::exfat::ExFat;
+use crate::wav::{read_file_bytes, Tags, WavError, MAX_TAG_LENGTH};
+
+use crate::BLOCK_SIZE;
+
+const TAG_HEADER_LENGTH: u64 = 10;
+const EXTENDED_HEADER_FLAG: u8 = 0x40;
+const MAX_TEXT_BYTES: usize = 2 * MAX_TAG_LENGTH; // Enough for MAX_TAG_LENGTH characters of UTF-16
+
+// Text encodings, the first byte of a text frame
+const LATIN_1: u8 = 0;
+const UTF_16: u8 = 1; // Starts with a byte order mark
+const UTF_16_BE: u8 = 2;
+const UTF_8: u8 = 3;
+
+// Reads the tag that starts at tag_start (the byte address of the id3 chunk data) into tags
+// Fields that aren't in the tag are left alone
+pub fn read_id3_tags<T: BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, tag_start: u64, chunk_length: u32, tags: &mut Tags)
+-> Result<(), WavError> {
+    let header = read_file_bytes::<{TAG_HEADER_LENGTH as usize}, T>(exfat, start_block_address, tag_start)?;
+    let version = header[3];
+    if header.get_bytes_section::<3>(0) != *b"ID3" || !(2..=4).contains(&version) {
+        return Ok(()); // Not a tag this can read, but the file can still play
+    }
+
+    let flags = header[5];
+    let tag_length = syncsafe(header.get_bytes_section::<4>(6)) as u64;
+    let tag_end = tag_start + (TAG_HEADER_LENGTH + tag_length).min(chunk_length as u64);
+    let mut pos = tag_start + TAG_HEADER_LENGTH;
+
+    // The length of the extended header doesn't include itself in version 2.3
+    if flags & EXTENDED_HEADER_FLAG != 0 && version >= 3 {
+        let length = read_file_bytes::<4, T>(exfat, start_block_address, pos)?;
+        pos += match version {
+            3 => u32::from_be_bytes(length) as u64 + 4,
+            _ => syncsafe(length) as u64,
+        };
+    }
+
+    let frame_header_length = if version == 2 { 6 } else { 10 };
+    while pos + frame_header_length <= tag_end {
+        let frame_header = read_file_bytes::<10, T>(exfat, start_block_address, pos)?;
+
+        // Version 2.2 has a 3 byte id and length, 2.3 has a 4 byte id and length, and 2.4 has a syncsafe length
+        let (id, frame_length) = match version {
+            2 => {
+                let mut id = frame_header.get_bytes_section::<4>(0);
+                id[3] = b' ';
+                (id, u32::from_be_bytes([0, frame_header[3], frame_header[4], frame_header[5]]))
+            },
+            3 => (frame_header.get_bytes_section::<4>(0), u32::from_be_bytes(frame_header.get_bytes_section::<4>(4))),
+            _ => (frame_header.get_bytes_section::<4>(0), syncsafe(frame_header.get_bytes_section::<4>(4))),
+        };
+
+        // Padding after the last frame is all zeros
+        if id[0] == 0 {
+            break;
+        }
+
+        let frame_data = pos + frame_header_length;
+        let field = match &id {
+            b"TIT2" | b"TT2 " => Some(&mut tags.title),
+            b"TPE1" | b"TP1 " => Some(&mut tags.artist),
+            b"TALB" | b"TAL " => Some(&mut tags.album),
+            _ => None,
+        };
+        if let (Some(field), true) = (field, frame_length > 0) {
+            let text = read_file_bytes::<MAX_TEXT_BYTES, T>(exfat, start_block_address, frame_data)?;
+            let text_length = (frame_length as usize).min(MAX_TEXT_BYTES);
+            decode_text(&text[..text_length], field);
+        } else if (&id == b"TXXX" || &id == b"TXX ") && frame_length > 0 {
+            let text = read_file_bytes::<MAX_TEXT_BYTES, T>(exfat, start_block_address, frame_data)?;
+            let text_length = (frame_length as usize).min(MAX_TEXT_BYTES);
+            read_user_text(&text[..text_length], tags);
+        }
+
+        pos = frame_data + frame_length as u64;
+    }
+
+    Ok(())
+}
+
+// Syncsafe integers use the low 7 bits of each byte, so the tag never contains a false mpeg sync
+fn syncsafe(bytes: [u8; 4]) -> u32 {
+    bytes.iter().fold(0, |value, byte| (value << 7) | (*byte & 0x7F) as u32)
+}
+
+// User defined text frames are an encoding byte, a description, and the text
+// The ReplayGain fields are named by the description, e.g. REPLAYGAIN_TRACK_GAIN
+fn read_user_text(frame: &[u8], tags: &mut Tags) {
+    let Some((encoding, bytes)) = frame.split_first() else {
+        return;
+    };
+
+    // The description ends with a null, which is two bytes in UTF-16
+    let value_start = match *encoding {
+        UTF_16 | UTF_16_BE => bytes.chunks_exact(2).position(|unit| unit == [0, 0]).map(|unit_indx| 2 * unit_indx + 2),
+        _ => bytes.iter().position(|byte| *byte == 0).map(|byte_indx| byte_indx + 1),
+    };
+    let Some(value) = value_start.and_then(|value_start| bytes.get(value_start..)) else {
+        return;
+    };
+
+    let mut description: String<MAX_TAG_LENGTH> = String::new();
+    let mut text: String<MAX_TAG_LENGTH> = String::new();
+    decode_string(*encoding, bytes, &mut description);
+    decode_string(*encoding, value, &mut text);
+    tags.replay_gain.set_field(description.as_str(), text.as_str());
+}
+
+// Decodes the data of a text frame, which is an encoding byte followed by the text
+// Only the first string is kept if there are several, and characters that can't be decoded are replaced
+fn decode_text<const N: usize>(frame: &[u8], text: &mut String<N>) {
+    text.clear();
+    if let Some((encoding, bytes)) = frame.split_first() {
+        decode_string(*encoding, bytes, text);
+    }
+}
+
+// Decodes one null terminated string in the encoding, onto the end of text
+fn decode_string<const N: usize>(encoding: u8, bytes: &[u8], text: &mut String<N>) {
+    match encoding {
+        LATIN_1 => {
+            // The first 256 unicode characters are the same as Latin-1
+            for byte in bytes.iter().take_while(|byte| **byte != 0) {
+                if text.push(*byte as char).is_err() {
+                    break;
+                }
+            }
+        },
+
+        UTF_8 => {
+            let length = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+            let valid_text = match core::str::from_utf8(&bytes[..length]) {
+                Ok(valid_text) => valid_text,
+                Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or(""),
+            };
+            for character in valid_text.chars() {
+                if text.push(character).is_err() {
+                    break;
+                }
+            }
+        },
+
+        UTF_16 | UTF_16_BE => {
+            let (big_endian, bytes) = match (encoding, bytes) {
+                (UTF_16, [0xFF, 0xFE, rest @ ..]) => (false, rest),
+                (UTF_16, [0xFE, 0xFF, rest @ ..]) => (true, rest),
+                _ => (true, bytes),
+            };
+
+            let units = bytes.chunks_exact(2)
+                .map(|unit| if big_endian { u16::from_be_bytes([unit[0], unit[1]]) } else { u16::from_le_bytes([unit[0], unit[1]]) })
+                .take_while(|unit| *unit != 0);
+            for character in char::decode_utf16(units) {
+                if text.push(character.unwrap_or('?')).is_err() {
+                    break;
+                }
+            }
+        },
+
+        _ => (),
+    }
+}
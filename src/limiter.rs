@@ -0,0 +1,29 @@
+// Soft knee limiter, so samples pushed past full scale by a gain boost, the equalizer, or mixing are rounded off instead of clipped
+//
+// The stages that can push a sample past full scale work it out in 32 bits and pass it through soft_clip instead of clamping it
+// The mixer is the last of them, so the samples are limited as they land in the DMA buffer
+//
+// Below KNEE samples aren't changed, above it they are bent smoothly towards full scale, which they never quite reach
+// y = knee + span * over / (over + span), where over is how far the sample is above the knee and span is the room above the knee
+// The curve has a slope of 1 at the knee, so there is no corner to hear
+
+// -1 dBFS, so there's little room for the curve but quiet and medium level material isn't touched
+const KNEE: i32 = 29204;
+const SPAN: i32 = i16::MAX as i32 - KNEE;
+
+// Converts a 32 bit sample to 16 bits, hard clipping it if SOFT_LIMITER is off
+pub fn soft_clip(sample: i32) -> i16 {
+    if !crate::SOFT_LIMITER {
+        return sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    }
+
+    let magnitude = sample.unsigned_abs().min(i32::MAX as u32) as i32;
+    if magnitude <= KNEE {
+        return sample as i16;
+    }
+
+    let over = (magnitude - KNEE) as i64;
+    let bent = (SPAN as i64 * over).checked_div(over + SPAN as i64).unwrap_or(SPAN as i64);
+    let limited = KNEE + bent as i32;
+    if sample < 0 { (-limited) as i16 } else { limited as i16 }
+}
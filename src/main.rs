@@ -3,22 +3,20 @@
 
 use cortex_m_rt::entry;
 
-use rtt_target::{rprint, rprintln, rtt_init_print, ChannelMode};
+use rtt_target::{rprint, rprintln, rtt_init, set_print_channel, ChannelMode};
 
 use stm32f4xx_hal::{
     pac,
     prelude::*,
 
-    gpio::NoPin,
     i2s::{I2s, stm32_i2s_v12x},
-
-    sdio::{ClockFreq, SdCard, Sdio},
 };
 
 use stm32_i2s_v12x::{transfer::*, driver::{I2sDriver, I2sDriverConfig, DataFormat}};
 use stm32f4xx_hal::dma::{StreamsTuple, Transfer, config::DmaConfig, StreamX, MemoryToPeripheral};
 
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use cortex_m::interrupt::Mutex;
 use stm32f4xx_hal::pac::interrupt;
 
@@ -50,54 +48,316 @@ use stm32f4xx_hal::pac::interrupt;
 // These parameters typically correspond, otherwise the card will need to be reformatted
 pub const BLOCK_SIZE: usize = 512;
 
+// How often to try starting the card again after it has been taken out
+const CARD_POLL_INTERVAL_MS: u64 = 500;
+
+// Raise the sd card clock after init, to the fastest clock it can be read at reliably (up to 24 MHz)
+// Otherwise the card stays at the 4 MHz it's initialized at, which is only just fast enough for high bitrate files
+pub const RAISE_SD_CLOCK: bool = true;
+
+// A failed sd card read is tried up to READ_ATTEMPTS times in all, waiting READ_RETRY_BACKOFF_US before each retry
+// After READ_REINIT_AFTER reads in a row have failed every attempt the card is initialized again, 0 to never do this
+#[cfg(not(any(feature = "demo", feature = "spi-sd", feature = "usb-msc", feature = "spi-flash")))]
+const READ_ATTEMPTS: u32 = 3;
+#[cfg(not(any(feature = "demo", feature = "spi-sd", feature = "usb-msc", feature = "spi-flash")))]
+const READ_RETRY_BACKOFF_US: u32 = 100;
+#[cfg(not(any(feature = "demo", feature = "spi-sd", feature = "usb-msc", feature = "spi-flash")))]
+const READ_REINIT_AFTER: u32 = 2;
+
+// With the emmc feature, use all 8 data lines of the eMMC (D4 to D7 on PB8, PB9, PC6, PC7)
+// Otherwise it's wired up with 4 data lines like an sd card
+#[cfg(all(feature = "emmc", not(any(feature = "demo", feature = "spi-sd", feature = "usb-msc", feature = "spi-flash"))))]
+const EMMC_8_BIT_BUS: bool = false;
+
 const BUF_BLOCKS: usize = 1;
-const BUF_SIZE: usize = BLOCK_SIZE * BUF_BLOCKS / 2;
+pub const BUF_SIZE: usize = BLOCK_SIZE * BUF_BLOCKS / 2;
 
-type I2sDma = Transfer<StreamX<pac::DMA1, 4>, 0, I2sDriver<I2s<pac::SPI2>, Master, Transmit, Philips>, MemoryToPeripheral, &'static [u16; BUF_SIZE]>;
+// Number of buffers in the audio ring, each one is about 2.9 ms at 44.1 KHz
+// The main loop can fill all but the two held by the DMA ahead of time, which covers slow sd card reads
+pub const BUF_SLOTS: usize = 8;
+
+// With the i2s-slave feature the clocks come from an external source, e.g. a codec with its own crystal
+#[cfg(not(feature = "i2s-slave"))]
+pub type I2sMode = Master;
+#[cfg(feature = "i2s-slave")]
+pub type I2sMode = Slave;
+
+// With the f4-discovery feature the output is the CS43L22 on the board, which is wired to I2S3 instead of I2S2
+#[cfg(not(feature = "f4-discovery"))]
+type OutputSpi = pac::SPI2;
+#[cfg(not(feature = "f4-discovery"))]
+const OUTPUT_DMA_STREAM: u8 = 4;
+#[cfg(feature = "f4-discovery")]
+type OutputSpi = pac::SPI3;
+#[cfg(feature = "f4-discovery")]
+const OUTPUT_DMA_STREAM: u8 = 5;
+
+type I2sTx = I2sDriver<I2s<OutputSpi>, I2sMode, Transmit, Philips>;
+type I2sDma = Transfer<StreamX<pac::DMA1, OUTPUT_DMA_STREAM>, 0, I2sTx, MemoryToPeripheral, &'static [u16; BUF_SIZE]>;
 static G_TRANSFER: Mutex<RefCell<Option<I2sDma>>> = Mutex::new(RefCell::new(None));
 
+// Incremented by the ISR every time the DMA finishes a buffer
+static G_TRANSFERS_COMPLETE: AtomicU32 = AtomicU32::new(0);
+
+// Incremented by the ISR when it has to play the silence buffer because no buffer was filled in time
+static G_UNDERRUNS: realtime::UnderrunCounter = realtime::UnderrunCounter::new();
 
+// Set by the ISR when the DMA has stopped, or a buffer couldn't be queued, the main loop then restarts the output
+pub static G_DMA_FAILED: AtomicBool = AtomicBool::new(false);
+
+// DMA errors since power on, the DMA carries on by itself after a FIFO error so those are only counted
+pub static G_DMA_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+// Sd card read errors since power on, counted by the sd card block device
+static G_READ_ERRORS: sdio_block_device::ReadErrorCounter = sdio_block_device::ReadErrorCounter::new();
+
+// With the demo feature, the exFAT image that is played instead of the sd card
+// It is included at build time from the path in the WAVPLAYER_DEMO_IMAGE environment variable
+#[cfg(feature = "demo")]
+static DEMO_IMAGE: &[u8] = include_bytes!(env!("WAVPLAYER_DEMO_IMAGE"));
+
+
+// The output sample rate when the I2S can't run at the sample rate of the file
+// With the i2s-slave feature this is the rate the external clocks run at, and every file is resampled to it
 const SAMPLE_RATE: u32 = 44_100;
 
+// The I2S is only run at the file's sample rate if it's within this range,
+// and the clock dividers can get within I2S_RATE_TOLERANCE_PPM of it
+#[cfg(not(feature = "i2s-slave"))]
+const MIN_I2S_SAMPLE_RATE: u32 = 8_000;
+#[cfg(not(feature = "i2s-slave"))]
+const MAX_I2S_SAMPLE_RATE: u32 = 96_000;
+#[cfg(not(feature = "i2s-slave"))]
+const I2S_RATE_TOLERANCE_PPM: u32 = 1000;
+
+// Output a 256 x sample rate master clock on PA3, for DACs and codecs that need one (e.g. CS43L22, WM8731)
+// The clock dividers are coarser with the master clock on, so fewer sample rates can be run without resampling
+// An I2S slave has no master clock output, the external source has to provide it
+// It's always on with the f4-discovery feature, on PC7 for the CS43L22, and with the wm8731 feature
+const I2S_MCLK_OUTPUT: bool = cfg!(any(feature = "f4-discovery", feature = "wm8731"));
+
+// The f4-discovery feature uses the SDIO and SPI chip select pins for the codec, so the files come from a USB drive or the demo image
+#[cfg(all(feature = "f4-discovery", any(feature = "spi-sd", feature = "spi-flash", not(any(feature = "usb-msc", feature = "demo")))))]
+compile_error!("the f4-discovery feature needs the usb-msc or demo feature, without spi-sd or spi-flash");
+#[cfg(all(feature = "f4-discovery", any(feature = "dual-i2s", feature = "i2s-slave")))]
+compile_error!("the f4-discovery feature plays through the CS43L22 as the I2S master, without dual-i2s or i2s-slave");
+#[cfg(all(feature = "wm8731", any(feature = "f4-discovery", feature = "i2s-slave")))]
+compile_error!("the wm8731 feature drives the WM8731 from I2S2 as the I2S master, without f4-discovery or i2s-slave");
+
+// With the wm8731 feature, which of the codec's outputs is used, the line output is at a fixed level
+// De-emphasis is for tracks recorded with pre-emphasis, at 32 KHz, 44.1 KHz or 48 KHz
+#[cfg(feature = "wm8731")]
+const WM8731_OUTPUT: wm8731::Wm8731Output = wm8731::Wm8731Output::Headphone;
+#[cfg(feature = "wm8731")]
+const WM8731_DE_EMPHASIS: bool = false;
+
+// Silence at the start of a track is skipped if it is longer than LEADING_SILENCE_MS
+// Samples within SILENCE_THRESHOLD of zero count as silence
+const SKIP_LEADING_SILENCE: bool = false;
+const SILENCE_THRESHOLD: i16 = 64;
+const LEADING_SILENCE_MS: u32 = 250;
+
+// The track ends early if it ends with more than TRAILING_SILENCE_MS of silence
+// Only the last MAX_TRAILING_SILENCE_MS is checked, reading the silence slows down opening the track
+const SKIP_TRAILING_SILENCE: bool = false;
+const TRAILING_SILENCE_MS: u32 = 1000;
+const MAX_TRAILING_SILENCE_MS: u32 = 10_000;
+
+// Loop forever between the loop points in the smpl chunk of a file, e.g. for background music
+const PLAY_SAMPLE_LOOPS: bool = false;
+
+// Silence between tracks, from 0 to 5000 ms
+const INTER_TRACK_GAP_MS: u32 = 0;
+
+// Also play the tracks in the folders inside the root directory, after the tracks in the root directory
+// Each folder is played in turn like an album, followed by the folders inside it
+const PLAY_SUBFOLDERS: bool = false;
+
+// Compare the two FATs of volumes that have them while following cluster chains, e.g. for cue sheets and duplicate search
+// A mismatch is printed, the inactive FAT is out of date if a write was interrupted, e.g. the card was pulled out
+// Tracks have to be contiguous, so this costs nothing while playing
+const CHECK_SECOND_FAT: bool = true;
+
+// Play the tracks in a random order, the order is different every time since the seed comes from the cycle counter
+const SHUFFLE: bool = false;
+
+// Repeat the current track, or go back to the first track after the last one
+const REPEAT: player::Repeat = player::Repeat::Off;
+
+// Volume at power on, from 0 to 100 %
+const VOLUME_PERCENT: u8 = 100;
+
+// Playback speed at power on in %, from 50 to 200, the pitch changes with the speed
+// With the time-stretch feature PRESERVE_PITCH keeps the pitch the same instead
+const SPEED_PERCENT: u16 = 100;
+#[cfg(feature = "time-stretch")]
+const PRESERVE_PITCH: bool = true;
+
+// Overlap the end of each track with the start of the next, 0 to play the tracks one after another
+// Both tracks are decoded during a crossfade, so filling a buffer takes about twice as long
+const CROSSFADE_MS: u32 = 0;
+
+// Fade in at the start of a track and when resuming, and fade out before pausing or stopping
+const FADE_MS: u32 = 20;
+
+// Left and right balance, from -100 (only left) to 100 (only right)
+const BALANCE: i8 = 0;
+
+// Swap the channels, or play one channel (or both mixed) on both outputs, e.g. for speakers wired the wrong way round
+const ROUTING: routing::Routing = routing::Routing::Stereo;
+
+// Dither 24 bit, 32 bit, and float files down to the 16 bit output, noise shaping makes the dither quieter but brighter
+const DITHER: dither::DitherMode = dither::DitherMode::Tpdf;
+
+// How the samples are stored in headerless .pcm and .raw files, which have nothing in them to say
+// Format is Pcm, IeeeFloat, Alaw, or Mulaw, and the channels are interleaved
+const RAW_PCM_FORMAT: raw_pcm::RawPcmFormat = raw_pcm::RawPcmFormat {
+    format: wav::Format::Pcm,
+    sample_rate: 44_100,
+    n_channels: 2,
+    bits_per_sample: 16,
+    big_endian: false,
+};
+
+// Normalise the loudness of each track, or each album, from its ReplayGain tags (see replay_gain.rs)
+// The preamp is added to the gain of every tagged track, the ReplayGain reference level is quite quiet
+const REPLAY_GAIN: replay_gain::ReplayGainMode = replay_gain::ReplayGainMode::Track;
+const REPLAY_GAIN_PREAMP_DB: f32 = 0.0;
+
+// Filter out any DC offset in the tracks, some cheap recordings have one
+const DC_BLOCKER: bool = false;
+
+// Round off samples that a gain boost, the equalizer, or mixing push past full scale, instead of clipping them
+const SOFT_LIMITER: bool = true;
+
+// Equalizer bands for the speakers, up to eq::MAX_BANDS
+// e.g. eq::Band { filter_type: eq::FilterType::LowShelf, frequency: 150, gain_db: 6.0, q: 0.707 } for more bass from small speakers
+const EQ_BANDS: &[eq::Band] = &[];
+
+// Length of the beep shell command
+const BEEP_MS: u32 = 150;
+
+// How much the music is turned down while a sound from the mixer (e.g. the beep) plays over it, 0 to leave it alone
+const DUCK_DB: f32 = -12.0;
+
+// How often the playback position is published, the position to resume from is saved at the same time
+const PROGRESS_INTERVAL_MS: u32 = 1000;
+
+// Carry on from the track and position that was playing before a reset, see resume.rs
+const RESUME: bool = true;
+
+// Print the peak and RMS levels with the playback position
+const PRINT_LEVELS: bool = false;
+
+// With the dual-i2s feature, what the second output on I2S3 plays, and which channel each of its outputs plays
+// The routing is applied on top of the routing of the first output, see second_output.rs
+#[cfg(feature = "dual-i2s")]
+const SECOND_OUTPUT: second_output::Source = second_output::Source::Mirror;
+#[cfg(feature = "dual-i2s")]
+const SECOND_OUTPUT_ROUTING: routing::Routing = routing::Routing::Stereo;
+
+// With the dual-i2s feature, which outputs are heard at startup, the output shell command switches between them
+#[cfg(feature = "dual-i2s")]
+const ACTIVE_OUTPUTS: second_output::ActiveOutputs = second_output::ActiveOutputs::Both;
+
+// Tracks copied into this folder in the root directory while the player is running are queued
+// The root directory has no timestamp of its own, so a folder is watched instead
+const WATCH_FOLDER: &str = "DROPBOX";
+
+// How often the watched folder is checked for changes
+const WATCH_FOLDER_INTERVAL_MS: u64 = 5000;
+
 pub mod block_device;
 pub mod exfat;
 pub mod bytes;
 pub mod binary_helpers;
 pub mod riff;
+pub mod decoder;
 pub mod wav;
+pub mod raw_pcm;
+pub mod id3;
+pub mod g711;
+pub mod adpcm;
+pub mod downmix;
+pub mod volume;
+pub mod dc_blocker;
+pub mod declick;
+pub mod eq;
+pub mod events;
+pub mod limiter;
+pub mod dither;
+pub mod replay_gain;
+pub mod resume;
+pub mod routing;
+pub mod fade;
+pub mod mixer;
+pub mod meter;
+pub mod resampler;
+#[cfg(feature = "time-stretch")]
+pub mod time_stretch;
+pub mod cue_sheet;
+pub mod playlist;
+pub mod player;
 pub mod audio_buffer;
+pub mod shell;
+pub mod realtime;
+#[cfg(feature = "dual-i2s")]
+pub mod second_output;
+pub mod fingerprint;
+pub mod watch_folder;
+pub mod helpers;
+pub mod sdio_ext;
+pub mod sdio_block_device;
+pub mod spi_sd_card;
+pub mod spi_nor_flash;
+#[cfg(feature = "usb-msc")]
+pub mod usb_host;
+#[cfg(feature = "usb-msc")]
+pub mod usb_msc;
+#[cfg(feature = "encryption")]
+pub mod encrypted_block_device;
+pub mod ram_block_device;
+pub mod codec;
+pub mod cs43l22;
+pub mod wm8731;
 use audio_buffer::*;
+use resume::ResumeStore;
+use block_device::BlockDevice;
+use codec::Codec;
+use decoder::Decoder;
 
-const SILENCE_BUFFER: [u16; BUF_SIZE] = [0; BUF_SIZE];
-static mut G_DBUF: [[u16; BUF_SIZE]; 2] = [[0; BUF_SIZE]; 2];
-static G_DBUF_INFO: Mutex<RefCell<Option<DbufInfo>>> = Mutex::new(RefCell::new(Some(DbufInfo { 
-    buf_states: [AudioBufState::Playing, AudioBufState::Empty], 
-}))); 
-
-// Implement block device trait for the sd card
-impl block_device::BlockDevice<512> for Sdio<SdCard> {
-    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
-
-        // rprintln!("Bout to read to block: {:X}", blockaddr);
-        match self.read_block(blockaddr, block) {
-            Ok(()) => return Ok(()),
-            Err(_err) => {
-                return Err(())
-            }
-        }
-    }
-}
-
+pub const SILENCE_BUFFER: [u16; BUF_SIZE] = [0; BUF_SIZE];
+static G_RING: AudioRing<BUF_SLOTS, BUF_SIZE> = AudioRing::new();
 
 #[entry]
 fn main() -> ! {
-    rtt_init_print!(ChannelMode::BlockIfFull, 4096);
-    let cp = cortex_m::Peripherals::take().unwrap(); // Core peripherals
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 4096,
+                mode: ChannelMode::BlockIfFull,
+                name: "Terminal"
+            }
+        }
+        down: {
+            0: {
+                size: 16,
+                name: "Terminal"
+            }
+        }
+    };
+    set_print_channel(channels.up.0);
+    let mut shell = shell::Shell::new(channels.down.0);
+
+    let mut cp = cortex_m::Peripherals::take().unwrap(); // Core peripherals
     let dp = pac::Peripherals::take().unwrap(); // Device peripherals
 
+    let gpioa = dp.GPIOA.split();
     let gpiob = dp.GPIOB.split();
     let gpioc = dp.GPIOC.split();
-    let gpiod = dp.GPIOD.split();
+
+    // This has to be set up before the RCC is constrained
+    let mut resume_store = resume::BackupRegisters::new(dp.RTC, dp.PWR, &dp.RCC);
 
     let rcc = dp.RCC.constrain();
 
@@ -112,174 +372,627 @@ fn main() -> ! {
 
     assert!(clocks.is_pll48clk_valid());
 
-    let mut delay = cp.SYST.delay(&clocks);
+    // The cycle counter is used to check the buffer fill deadline in debug builds
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
 
     // Enable interrupt
     unsafe {
+        #[cfg(not(feature = "f4-discovery"))]
         cortex_m::peripheral::NVIC::unmask(pac::Interrupt::DMA1_STREAM4); // Enable interrupt for i2s dma
+        #[cfg(feature = "f4-discovery")]
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::DMA1_STREAM5);
+        #[cfg(feature = "dual-i2s")]
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::DMA1_STREAM5); // And for the second output
     }
 
     // Setup ip i2s peripheral 
-    let i2s_pins = (gpiob.pb12, gpiob.pb10, NoPin::new(), gpioc.pc3); // WS, CK, SD
-    let i2s = I2s::new(dp.SPI2, i2s_pins, &clocks);
-    let i2s_config = I2sDriverConfig::new_master()
-        .transmit()
-        .standard(Philips)
-        .data_format(DataFormat::Data16Channel16)
-        .request_frequency(SAMPLE_RATE);
+    #[cfg(not(feature = "f4-discovery"))]
+    let i2s = {
+        let mck: stm32f4xx_hal::gpio::alt::i2s2::Mck = if I2S_MCLK_OUTPUT && !cfg!(feature = "i2s-slave") { gpioa.pa3.into() } else { stm32f4xx_hal::gpio::NoPin::new().into() };
+        let i2s_pins = (gpiob.pb12, gpiob.pb10, mck, gpioc.pc3); // WS, CK, MCK, SD
+        I2s::new(dp.SPI2, i2s_pins, &clocks)
+    };
+    #[cfg(feature = "f4-discovery")]
+    let i2s = I2s::new(dp.SPI3, (gpioa.pa4, gpioc.pc10, gpioc.pc7, gpioc.pc12), &clocks); // WS, CK, MCK, SD of the CS43L22
 
-    let mut i2s_driver = I2sDriver::new(
-        i2s,
-        i2s_config
-    );
-    i2s_driver.enable();
-    i2s_driver.set_tx_dma(true);
+    // The codec is set up while the I2S is off, and powered up once its clocks are running
+    // Boards with a codec that has to be set up select it here
+    #[cfg(not(any(feature = "f4-discovery", feature = "wm8731")))]
+    let mut codec = codec::NoCodec;
+    #[cfg(feature = "f4-discovery")]
+    let mut codec = {
+        use stm32f4xx_hal::i2c::I2c;
 
-    rprintln!("Actual sample rate is {}", i2s_driver.sample_rate());
+        let gpiod = dp.GPIOD.split();
+        let mut codec_reset = gpiod.pd4.into_push_pull_output();
+        codec_reset.set_high(); // The pin stays high once it's dropped
 
-    // Set up SDIO interface
-    let d0 = gpioc.pc8.internal_pull_up(true);
-    let d1 = gpioc.pc9.internal_pull_up(true);
-    let d2 = gpioc.pc10.internal_pull_up(true);
-    let d3 = gpioc.pc11.internal_pull_up(true);
-    let clk = gpioc.pc12;
-    let cmd = gpiod.pd2.internal_pull_up(true);
-    let mut sdio: Sdio<SdCard> = Sdio::new(dp.SDIO, (clk, cmd, d0, d1, d2, d3), &clocks);
+        let i2c = I2c::new(dp.I2C1, (gpiob.pb6, gpiob.pb9), 100.kHz(), &clocks); // SCL, SDA
+        cs43l22::Cs43l22::new(i2c, cs43l22::DISCOVERY_ADDRESS)
+    };
+    #[cfg(feature = "wm8731")]
+    let mut codec = {
+        use stm32f4xx_hal::i2c::I2c;
 
-    // Wait for card to be ready
-    loop {
-        match sdio.init(ClockFreq::F4Mhz) {
-            Ok(_) => break,
-            Err(err) => rprintln!("{:?}", err),
+        let i2c = I2c::new(dp.I2C1, (gpiob.pb6, gpiob.pb7), 100.kHz(), &clocks); // SCL, SDA
+        wm8731::Wm8731::new(i2c, wm8731::ADDRESS, WM8731_OUTPUT, WM8731_DE_EMPHASIS)
+    };
+    if let Err(err) = codec.init() {
+        rprintln!("Codec error: {:?}", err);
+    }
+    // PA15 and PB3 start off as JTAG pins, the debugger has to use SWD with the dual-i2s feature
+    #[cfg(feature = "dual-i2s")]
+    let second_i2s_pins = (gpioa.pa15.into_alternate::<6>(), gpiob.pb3.into_alternate::<6>(), stm32f4xx_hal::gpio::NoPin::new(), gpiob.pb5); // WS, CK, MCK, SD
+    #[cfg(feature = "dual-i2s")]
+    let second_i2s = I2s::new(dp.SPI3, second_i2s_pins, &clocks);
+
+    #[cfg(not(any(feature = "demo", feature = "spi-sd", feature = "usb-msc", feature = "spi-flash")))]
+    let sdio = {
+        use stm32f4xx_hal::sdio::Sdio;
+        use sdio_block_device::SdioCard;
+
+        // With the emmc feature a soldered eMMC part is used instead of an sd card
+        #[cfg(not(feature = "emmc"))]
+        type Card = stm32f4xx_hal::sdio::SdCard;
+        #[cfg(feature = "emmc")]
+        type Card = stm32f4xx_hal::sdio::Emmc;
+
+        let gpiod = dp.GPIOD.split();
+        let mut delay = cp.SYST.delay(&clocks);
+
+        // Set up SDIO interface
+        let d0 = gpioc.pc8.internal_pull_up(true);
+        let d1 = gpioc.pc9.internal_pull_up(true);
+        let d2 = gpioc.pc10.internal_pull_up(true);
+        let d3 = gpioc.pc11.internal_pull_up(true);
+        let clk = gpioc.pc12;
+        let cmd = gpiod.pd2.internal_pull_up(true);
+
+        #[cfg(not(feature = "emmc"))]
+        let mut sdio: Sdio<Card> = Sdio::new(dp.SDIO, (clk, cmd, d0, d1, d2, d3), &clocks);
+
+        // The bus width is set by the number of pins, the HAL then switches the eMMC to it during init
+        #[cfg(feature = "emmc")]
+        let mut sdio: Sdio<Card> = if EMMC_8_BIT_BUS {
+            let d4 = gpiob.pb8.internal_pull_up(true);
+            let d5 = gpiob.pb9.internal_pull_up(true);
+            let d6 = gpioc.pc6.internal_pull_up(true);
+            let d7 = gpioc.pc7.internal_pull_up(true);
+            Sdio::new(dp.SDIO, (clk, cmd, d0, d1, d2, d3, d4, d5, d6, d7), &clocks)
+        } else {
+            Sdio::new(dp.SDIO, (clk, cmd, d0, d1, d2, d3), &clocks)
+        };
+
+        // Wait for card to be ready
+        loop {
+            match Card::init(&mut sdio, sdio_block_device::INIT_CLOCK) {
+                Ok(_) => break,
+                Err(err) => rprintln!("{:?}", err),
+            }
+
+            delay.delay_ms(1000);
         }
 
-        delay.delay_ms(1000);
-    }
+        // The size of an eMMC part over 2 GB is only in its extended CSD, which the HAL doesn't read
+        #[cfg(not(feature = "emmc"))]
+        {
+            let nblocks = sdio.card().map(|c| c.block_count()).unwrap_or(0);
+            rprintln!("Card detected: nbr of blocks: {:?}", nblocks);
+        }
+        #[cfg(feature = "emmc")]
+        rprintln!("eMMC detected");
+
+        let retry = sdio_block_device::RetryPolicy {
+            attempts: READ_ATTEMPTS,
+            backoff_cycles: READ_RETRY_BACKOFF_US * clocks.sysclk().to_MHz(),
+            reinit_after: READ_REINIT_AFTER,
+        };
+        let dma_buffer = cortex_m::singleton!(: sdio_block_device::DmaBuffer = sdio_block_device::DmaBuffer::new()).unwrap();
+        let mut sd_card = sdio_block_device::SdioBlockDevice::new(sdio, retry, &G_READ_ERRORS, dp.DMA2, dma_buffer);
+        if RAISE_SD_CLOCK {
+            match sd_card.raise_clock() {
+                Ok(khz) => rprintln!("{} clock: {} KHz", Card::NAME, khz),
+                Err(err) => rprintln!("{} verification read failed: {:?}", Card::NAME, err),
+            }
+        }
+        sd_card
+    };
 
-    let nblocks = sdio.card().map(|c| c.block_count()).unwrap_or(0);
-    rprintln!("Card detected: nbr of blocks: {:?}", nblocks);
+    // With the spi-sd feature the card is read over SPI1, for boards without the SDIO pins
+    #[cfg(all(feature = "spi-sd", not(feature = "demo")))]
+    let sdio = {
+        use stm32f4xx_hal::gpio::Speed;
+        use stm32f4xx_hal::spi::{Mode, Phase, Polarity, Spi};
 
-    let mut exfat = exfat::ExFat::new(sdio).unwrap();
+        let mut delay = cp.SYST.delay(&clocks);
 
-    // List root directory
-    let dir = exfat.list_directory(exfat.first_cluster_of_root_directory).unwrap();
-    for (i, fs_entry) in dir.iter().enumerate() {
-        rprintln!("entry {}: {:?}", i, &fs_entry);
-    }
+        // The card starts at 400 KHz, set_sd_spi_clock raises it after init
+        let sck = gpioa.pa5.into_alternate::<5>().speed(Speed::VeryHigh);
+        let miso = gpioa.pa6.into_alternate::<5>().internal_pull_up(true).speed(Speed::VeryHigh);
+        let mosi = gpioa.pa7.into_alternate::<5>().speed(Speed::VeryHigh);
+        let mode = Mode { polarity: Polarity::IdleLow, phase: Phase::CaptureOnFirstTransition };
+        let spi = Spi::new(dp.SPI1, (sck, miso, mosi), mode, 400.kHz(), &clocks);
+        let cs = gpioa.pa4.into_push_pull_output();
+        let mut sd_card = spi_sd_card::SpiSdCard::new(spi, cs, set_sd_spi_clock);
 
-    // Open wav file
-    let file_indx = 11;
-    let wav_file = wav::WavFile::new(&mut exfat, &dir[file_indx]);
-    rprintln!("{:?}", wav_file);
+        // Wait for card to be ready
+        loop {
+            match sd_card.init() {
+                Ok(()) => break,
+                Err(err) => rprintln!("{:?}", err),
+            }
 
-    let mut wav_file = wav_file.unwrap();
+            delay.delay_ms(1000);
+        }
 
-    let steams = StreamsTuple::new(dp.DMA1);
-    let stream = steams.4;
-
-    let mut transfer = unsafe {
-        I2sDma::init_memory_to_peripheral(
-            stream, 
-            i2s_driver, 
-            &G_DBUF[0],
-            Some(&G_DBUF[1]),
-            DmaConfig::default()
-            .memory_increment(true)
-            .double_buffer(true)
-            .fifo_error_interrupt(true)
-            .transfer_complete_interrupt(true)
-        )
+        rprintln!("Card detected over SPI");
+        sd_card
     };
-    transfer.clear_all_flags();
 
-    cortex_m::interrupt::free(|cs| {
-        G_TRANSFER.borrow(cs).replace(Some(transfer));
-        G_TRANSFER.borrow(cs).borrow_mut().as_mut().unwrap().start(|_tx| {});
+    // With the usb-msc feature the files are read from a USB flash drive on the OTG port (DM PA11, DP PA12)
+    #[cfg(all(feature = "usb-msc", not(any(feature = "demo", feature = "spi-sd"))))]
+    let sdio = {
+        let mut delay = cp.SYST.delay(&clocks);
+
+        let _dm = gpioa.pa11.into_alternate::<10>();
+        let _dp = gpioa.pa12.into_alternate::<10>();
+        // The Discovery board powers VBUS through a switch, which is turned on by pulling PC0 low
+        #[cfg(feature = "f4-discovery")]
+        let _vbus_on = gpioc.pc0.into_push_pull_output_in_state(stm32f4xx_hal::gpio::PinState::Low);
+        let host = usb_host::UsbHost::new(dp.OTG_FS_GLOBAL, dp.OTG_FS_HOST, dp.OTG_FS_PWRCLK, clocks.sysclk().to_kHz());
+        let mut drive = usb_msc::UsbMsc::new(host);
+
+        // Wait for a drive to be plugged in
+        loop {
+            match drive.init() {
+                Ok(()) => break,
+                Err(err) => rprintln!("{:?}", err),
+            }
+
+            delay.delay_ms(1000);
+        }
+
+        rprintln!("USB drive detected: nbr of blocks: {}", drive.block_count());
+        drive
+    };
+
+    // With the spi-flash feature the files are read from an exFAT image on SPI NOR flash on SPI1, so the board plays without a card
+    #[cfg(all(feature = "spi-flash", not(any(feature = "demo", feature = "spi-sd", feature = "usb-msc"))))]
+    let sdio = {
+        use stm32f4xx_hal::gpio::Speed;
+        use stm32f4xx_hal::spi::{Mode, Phase, Polarity, Spi};
+
+        let mut delay = cp.SYST.delay(&clocks);
+
+        let sck = gpioa.pa5.into_alternate::<5>().speed(Speed::VeryHigh);
+        let miso = gpioa.pa6.into_alternate::<5>().speed(Speed::VeryHigh);
+        let mosi = gpioa.pa7.into_alternate::<5>().speed(Speed::VeryHigh);
+        let mode = Mode { polarity: Polarity::IdleLow, phase: Phase::CaptureOnFirstTransition };
+        let spi = Spi::new(dp.SPI1, (sck, miso, mosi), mode, 24.MHz(), &clocks);
+        let mut cs = gpioa.pa4.into_push_pull_output();
+        cs.set_high();
+        let mut flash = spi_nor_flash::SpiNorFlash::new(spi, cs);
+
+        loop {
+            match flash.init() {
+                Ok(id) => {
+                    rprintln!("SPI flash {:02X?}: nbr of blocks: {}", id, flash.block_count());
+                    break;
+                },
+                Err(err) => rprintln!("{:?}", err),
+            }
+
+            delay.delay_ms(1000);
+        }
+        flash
+    };
+
+    // With the demo feature the files are read from an image in flash instead of the sd card
+    // e.g. WAVPLAYER_DEMO_IMAGE=/path/to/demo.img cargo run --features demo
+    #[cfg(feature = "demo")]
+    let sdio = {
+        let flash = ram_block_device::RamBlockDevice::new(DEMO_IMAGE);
+        rprintln!("Demo image: nbr of blocks: {}", flash.block_count());
+        flash
+    };
+
+    // With the encryption feature the card is decrypted with a key provided at build time
+    // e.g. WAVPLAYER_XTS_KEY=<64 hex characters> cargo build --features encryption
+    #[cfg(feature = "encryption")]
+    let sdio = {
+        let key = encrypted_block_device::parse_key(env!("WAVPLAYER_XTS_KEY")).expect("WAVPLAYER_XTS_KEY is not a valid key");
+        encrypted_block_device::EncryptedBlockDevice::new(sdio, &key)
+    };
+
+    let mut exfat = exfat::ExFat::new(sdio).unwrap();
+    exfat.name_policy = exfat::NamePolicy::Truncate;
+    exfat.check_second_fat = CHECK_SECOND_FAT;
+
+    // Play every track in the root directory
+    let root_cluster = exfat.first_cluster_of_root_directory;
+    let playlist = {
+        let dir = exfat.list_directory(root_cluster).unwrap();
+        for (i, fs_entry) in dir.iter().enumerate() {
+            rprintln!("entry {}: {:?}", i, &fs_entry);
+        }
+        load_playlist(&mut exfat, root_cluster).unwrap()
+    };
+    rprintln!("{} tracks", playlist.len());
+
+    // The time the card takes to start up varies, so the cycle count here is different every boot
+    let mut rng = helpers::Xorshift32::new(cortex_m::peripheral::DWT::cycle_count());
+    let mut player = player::Player::new(playlist);
+    let _ = player.hooks.register(print_event);
+    if SHUFFLE {
+        player.playlist.shuffle_all(&mut rng);
+    }
+    let card_poll_interval = helpers::ms_to_cycles(CARD_POLL_INTERVAL_MS, clocks.sysclk().to_MHz() as u64) as u32;
+    let mut last_card_poll = 0;
+    player.volume.set_hardware(codec.volume_range());
+    player.volume.set(VOLUME_PERCENT);
+    set_codec_volume(&mut codec, &player.volume);
+    player.volume.set_balance(BALANCE);
+    player.routing = ROUTING;
+    #[cfg(feature = "dual-i2s")]
+    player.outputs.set(ACTIVE_OUTPUTS, 0);
+    player.set_ducking(DUCK_DB);
+    player.dc_blocker.set_enabled(DC_BLOCKER);
+    for band in EQ_BANDS {
+        if player.eq.add_band(*band).is_err() {
+            rprintln!("Only {} equalizer bands can be used", eq::MAX_BANDS);
+        }
+    }
+    player.fade_ms = FADE_MS;
+    player.crossfade_ms = CROSSFADE_MS;
+    #[cfg(feature = "time-stretch")]
+    {
+        player.preserve_pitch = PRESERVE_PITCH;
+    }
+    player.set_speed(SPEED_PERCENT);
+    player.repeat = REPEAT;
+    let resumed = RESUME && resume_store.load().is_some_and(|record| match player.resume_from(&mut exfat, &record) {
+        Ok(()) => {
+            rprintln!("Resumed track {} from byte {}", player.playlist.current_indx() + 1, record.data_offset);
+            true
+        },
+        Err(err) => {
+            rprintln!("Couldn't resume: {:?}", err);
+            false
+        },
     });
+    if !resumed && !player.open_current(&mut exfat) {
+        rprintln!("Nothing to play");
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
 
+    // Report files which get added to the watched folder
+    let watch_interval = helpers::ms_to_cycles(WATCH_FOLDER_INTERVAL_MS, clocks.sysclk().to_MHz() as u64) as u32;
+    let mut watch_folder = watch(&mut exfat, root_cluster, watch_interval, cortex_m::peripheral::DWT::cycle_count());
 
-    let mut wav_bytes = [0u8; BLOCK_SIZE];
-    'main: loop {
-        // Find buffer to fill
-        let mut fill_indx: Option<usize> = None;
-        cortex_m::interrupt::free(|cs| {
-            let dbuf_info_ref = G_DBUF_INFO.borrow(cs).borrow();
-            let dbuf_info = dbuf_info_ref.as_ref().unwrap();
+    let file_rate = player.decoder.as_ref().map_or(SAMPLE_RATE, |decoder| decoder.sample_rate());
+    #[cfg(not(feature = "i2s-slave"))]
+    let mut output_file_rate = file_rate; // The file rate the output was set up for
+    let (mut i2s_driver, output_sample_rate) = new_output(i2s, file_rate);
+    #[cfg(not(feature = "i2s-slave"))]
+    i2s_driver.enable();
+    i2s_driver.set_tx_dma(true);
+    #[cfg(feature = "dual-i2s")]
+    let second_i2s_driver = second_output::new_driver(second_i2s, output_sample_rate);
 
-            fill_indx = dbuf_info.find_buffer(AudioBufState::Empty);
-        });
+    let mut fill_budget = realtime::FillBudget::new((BUF_SIZE / 2) as u32, output_sample_rate, clocks.sysclk().raw());
 
-        if let Some(fill_indx) = fill_indx {
-            let buf = unsafe {&mut G_DBUF[fill_indx]};
+    player.set_output_sample_rate(output_sample_rate);
+    let mut last_progress_ms = 0;
+    let mut last_underruns = 0;
 
-            // Update this buf state to Filling
-            cortex_m::interrupt::free(|cs| {
-                G_DBUF_INFO.borrow(cs).borrow_mut().as_mut().unwrap().buf_states[fill_indx] = AudioBufState::Filling;
-            });
+    // Fill every buffer before the DMA starts, so the track starts straight away instead of after silence or an underrun
+    // Nothing is playing yet, so this can take longer than the fill budget and isn't checked against it
+    while fill_next(&mut player, &mut exfat, &mut fill_budget) {}
+
+    let steams = StreamsTuple::new(dp.DMA1);
+    #[cfg(feature = "dual-i2s")]
+    second_output::start(steams.5, second_i2s_driver);
+    #[cfg(not(feature = "f4-discovery"))]
+    start_transfer(steams.4, i2s_driver);
+    #[cfg(feature = "f4-discovery")]
+    start_transfer(steams.5, i2s_driver);
+
+    if let Err(err) = codec.set_sample_rate(output_sample_rate) {
+        rprintln!("Codec error: {:?}", err);
+    }
+
+
+    loop {
+        if G_DMA_FAILED.swap(false, Ordering::Relaxed) {
+            rprintln!("DMA error, restarting the output ({} errors)", G_DMA_ERRORS.load(Ordering::Relaxed));
+            restart_transfer();
+        }
 
-            // This for loop fills the i2s buffer with multiple blocks of PCM data
-            let mut buf_indx = 0;
-            for _ in 0..BUF_BLOCKS { 
+        // Open the next track once the last one has ended, this is too slow to do while filling a buffer
+        if player.poll(&mut exfat) == Some(player::PlayerEvent::PlaylistFinished) {
+            resume_store.clear();
+        }
 
-                // Get raw PCM bytes from wav file
-                match wav_file.get_next_pcm_block(&mut exfat, &mut wav_bytes) {
-                    Err(_) => {
-                        rprintln!("Error, {}", wav_file.bytes_read);
-                        continue 'main;
+        // While the card is out, keep trying to start it again, then carry on with the playlist from the card
+        let now = cortex_m::peripheral::DWT::cycle_count();
+        if player.is_card_removed() && now.wrapping_sub(last_card_poll) >= card_poll_interval {
+            last_card_poll = now;
+            let remounted = exfat.block_device.reinit().is_ok() && match exfat.remount() {
+                Ok(()) => true,
+                Err(err) => {
+                    rprintln!("Couldn't mount the card: {:?}", err);
+                    false
+                },
+            };
+            if remounted {
+                let root_cluster = exfat.first_cluster_of_root_directory;
+                match load_playlist(&mut exfat, root_cluster) {
+                    Ok(mut playlist) => {
+                        rprintln!("Card inserted, {} tracks", playlist.len());
+                        if SHUFFLE {
+                            playlist.shuffle_all(&mut rng);
+                        }
+                        if !player.card_inserted(&mut exfat, playlist) {
+                            rprintln!("Nothing to play");
+                        }
+                        watch_folder = watch(&mut exfat, root_cluster, watch_interval, now);
                     },
-                    Ok(_) => (),
-                };
+                    Err(err) => rprintln!("Couldn't read the playlist: {:?}", err),
+                }
+            }
+        }
+
+        let underruns = G_UNDERRUNS.stats().count;
+        if underruns != last_underruns {
+            last_underruns = underruns;
+            player.report_underrun(underruns);
+        }
+
+        // New tracks are queued so they play next
+        let result = match (watch_folder.as_mut(), player.is_card_removed()) {
+            (Some(watch_folder), false) => {
+                let watch_cluster = watch_folder.directory_cluster;
+                watch_folder.poll(&mut exfat, cortex_m::peripheral::DWT::cycle_count(), |fs_entry| {
+                    rprintln!("New file: {}", fs_entry.name);
+                    if playlist::is_track(&fs_entry) && player.enqueue(fs_entry, watch_cluster).is_err() {
+                        rprintln!("The queue is full");
+                    }
+                })
+            },
+            _ => Ok(()),
+        };
+        match result {
+            Ok(()) => (),
+            Err(exfat::FsError::NotFound) => {
+                rprintln!("{} has gone, it isn't watched any more", WATCH_FOLDER);
+                watch_folder = None;
+            },
+            Err(err) => rprintln!("Error watching folder: {:?}", err),
+        }
 
-                // Fill buf
-                for (i, num) in wav_bytes.iter().enumerate().step_by(2) {
-                    let sample = u16::from_le_bytes([*num, wav_bytes[i + 1]]);
-                    
-                    buf[buf_indx] = sample;
-                    // buf[buf_indx] = SINE_375_U16_STEREO[buf_indx % SINE_375_U16_STEREO.len()]; // Fill with const buf instead
-                    buf_indx += 1;
+        if player.is_stopped() {
+            if let Some(record) = player.resume_record(output_latency_ms(player.output_sample_rate)).filter(|_| RESUME) {
+                resume_store.save(&record);
+            }
+            let _stopped = stop_clean(); // Keep the drivers so the I2S pins stay configured
+            if let Err(err) = codec.power_down() {
+                rprintln!("Codec error: {:?}", err);
+            }
+            rprintln!("Stopped");
+            loop {
+                cortex_m::asm::wfi();
+            }
+        }
+
+        // Handle commands from the host
+        match shell.poll() {
+            Some(shell::Command::Dump) => dump_state(&player),
+
+            // This reads whole files, so playback will underrun until it's done
+            Some(shell::Command::FindDuplicates) => {
+                let root_cluster = exfat.first_cluster_of_root_directory;
+                if let Err(err) = fingerprint::report_duplicates(&mut exfat, root_cluster) {
+                    rprintln!("Duplicate search failed: {:?}", err);
+                }
+            },
+            Some(shell::Command::Play(track_indx)) => {
+                match player.play_indx(&mut exfat, track_indx) {
+                    Ok(()) => (),
+                    Err(wav::WavError::NoTrack) => rprintln!("There are only {} tracks", player.playlist.len()),
+                    Err(err) => rprintln!("Couldn't play track {}: {:?}", track_indx + 1, err),
+                }
+            },
+            Some(shell::Command::Queue(track_indx)) => {
+                match player.enqueue_indx(track_indx) {
+                    Ok(()) => rprintln!("{} queued", player.queue_len()),
+                    Err(()) if track_indx >= player.playlist.len() => rprintln!("There are only {} tracks", player.playlist.len()),
+                    Err(()) => rprintln!("Only {} tracks can be queued", player::MAX_QUEUED_TRACKS),
+                }
+            },
+            Some(shell::Command::ClearQueue) => player.clear_queue(),
+            Some(shell::Command::Next) => {
+                match player.next(&mut exfat) {
+                    Ok(true) => (),
+                    Ok(false) => rprintln!("This is the last track"),
+                    Err(err) => rprintln!("Couldn't play the next track: {:?}", err),
                 }
+            },
+            Some(shell::Command::Previous) => {
+                if let Err(err) = player.previous(&mut exfat) {
+                    rprintln!("Couldn't play the previous track: {:?}", err);
+                }
+            },
+            Some(shell::Command::Volume(percent)) => {
+                player.volume.set(percent);
+                set_codec_volume(&mut codec, &player.volume);
+                rprintln!("Volume {} %", player.volume.percent());
+            },
+            Some(shell::Command::Speed(speed_percent)) => {
+                let speed_percent = player.set_speed(speed_percent);
+                rprintln!("Speed {} %", speed_percent);
+            },
+            Some(shell::Command::LoopStart) => {
+                match player.mark_loop_start(output_latency_ms(player.output_sample_rate)) {
+                    Some(start_ms) => rprintln!("Loop from {} ms", start_ms),
+                    None => rprintln!("Nothing is playing"),
+                }
+            },
+            Some(shell::Command::LoopEnd) => {
+                match player.mark_loop_end(output_latency_ms(player.output_sample_rate)) {
+                    Ok((start_ms, end_ms)) => rprintln!("Looping from {} ms to {} ms", start_ms, end_ms),
+                    Err(()) => rprintln!("Mark the start of the loop before its end"),
+                }
+            },
+            Some(shell::Command::LoopClear) => {
+                player.clear_ab_loop();
+                rprintln!("Loop off");
+            },
+            Some(shell::Command::Shuffle) => {
+                if player.playlist.is_shuffled() {
+                    player.playlist.unshuffle();
+                    rprintln!("Shuffle off");
+                } else {
+                    rng = helpers::Xorshift32::new(rng.next_u32() ^ cortex_m::peripheral::DWT::cycle_count());
+                    player.playlist.shuffle(&mut rng);
+                    rprintln!("Shuffle on");
+                }
+            },
+            Some(shell::Command::Repeat) => {
+                player.repeat = match player.repeat {
+                    player::Repeat::Off => player::Repeat::One,
+                    player::Repeat::One => player::Repeat::All,
+                    player::Repeat::All => player::Repeat::Off,
+                };
+                rprintln!("Repeat {:?}", player.repeat);
+            },
+            Some(shell::Command::Route) => {
+                player.routing = player.routing.next();
+                rprintln!("Routing {:?}", player.routing);
+            },
+            Some(shell::Command::Output) => {
+                #[cfg(feature = "dual-i2s")]
+                {
+                    let active = player.outputs.active().next();
+                    player.outputs.set(active, FADE_MS * player.output_sample_rate / 1000);
+                    rprintln!("Outputs {:?}", active);
+                }
+                #[cfg(not(feature = "dual-i2s"))]
+                rprintln!("There is only one output without the dual-i2s feature");
+            },
+            Some(shell::Command::Balance(balance)) => {
+                player.volume.set_balance(balance);
+                rprintln!("Balance {}", player.volume.balance());
+            },
+            Some(shell::Command::Mute) => {
+                player.set_muted(!player.is_muted());
+                rprintln!("{}", if player.is_muted() { "Muted" } else { "Unmuted" });
+            },
+            Some(shell::Command::Beep) => player.mixer.beep(BEEP_MS),
+            Some(shell::Command::Pause) => {
+                player.pause();
+                rprintln!("Paused");
+            },
+            Some(shell::Command::Resume) => {
+                player.resume();
+                rprintln!("Resumed");
+            },
+            Some(shell::Command::Stop) => player.stop(),
+            Some(shell::Command::Unknown) => rprintln!("Unknown command"),
+            None => (),
+        }
+
+        // A track at another sample rate is played at its own rate, once the audio buffered from the last track has played out
+        // If the I2S can't make the rate the output runs at SAMPLE_RATE and the track is resampled
+        // output_file_rate keeps the next track at that rate from draining the output again
+        #[cfg(not(feature = "i2s-slave"))]
+        if let Some(file_rate) = player.take_output_rate_change().filter(|file_rate| *file_rate != output_file_rate) {
+            output_file_rate = file_rate;
+            let stopped = stop_clean();
+            let (mut i2s_driver, output_sample_rate) = new_output(stopped.i2s_driver.release(), file_rate);
+            i2s_driver.enable();
+            i2s_driver.set_tx_dma(true);
+
+            // The output was faded to silence, so the new track ramps up from zero rather than from the last audio
+            player.set_output_sample_rate(output_sample_rate);
+            player.jump_from([0, 0]);
+            fill_budget = realtime::FillBudget::new((BUF_SIZE / 2) as u32, output_sample_rate, clocks.sysclk().raw());
+            while fill_next(&mut player, &mut exfat, &mut fill_budget) {}
+
+            #[cfg(feature = "dual-i2s")]
+            if let Some((stream, second_i2s_driver)) = stopped.second {
+                second_output::start(stream, second_output::new_driver(second_i2s_driver.release(), output_sample_rate));
+            }
+            start_transfer(stopped.stream, i2s_driver);
+            if let Err(err) = codec.set_sample_rate(output_sample_rate) {
+                rprintln!("Codec error: {:?}", err);
             }
+        }
+
+        // While paused no buffers are filled, and the ISR plays the silence buffer instead
+        G_UNDERRUNS.set_expected(player.is_paused());
+        if !player.is_paused() && fill_next(&mut player, &mut exfat, &mut fill_budget) {
+            fill_budget.finish();
 
-            // Update this buf state to Fillied
-            cortex_m::interrupt::free(|cs| {
-                let mut dbuf_info_ref = G_DBUF_INFO.borrow(cs).borrow_mut();
-                let buf_state = &mut dbuf_info_ref.as_mut().unwrap().buf_states[fill_indx];
-                *buf_state = AudioBufState::Filled;
-            });
+            if let Some(decoder) = player.decoder.as_ref() {
+                let position_ms = decoder.position_ms();
+                if position_ms / PROGRESS_INTERVAL_MS != last_progress_ms / PROGRESS_INTERVAL_MS {
+                    last_progress_ms = position_ms;
+                    publish_progress(position_ms, decoder.duration_ms());
+                    if let Some(record) = player.resume_record(output_latency_ms(player.output_sample_rate)).filter(|_| RESUME) {
+                        resume_store.save(&record);
+                    }
+                    if PRINT_LEVELS {
+                        print_levels(player.levels());
+                    }
+                }
+            }
 
         }
     }
 }
 
+#[cfg(not(feature = "f4-discovery"))]
 #[interrupt]
 fn DMA1_STREAM4() {
-    cortex_m::interrupt::free(|cs| {
-        if let Some(transfer) = G_TRANSFER.borrow(cs).borrow_mut().as_mut() {
-
-            let mut dbuf_info_ref = G_DBUF_INFO.borrow(cs).borrow_mut();
-            let dbuf_info = dbuf_info_ref.as_mut().unwrap();
+    output_dma_interrupt();
+}
 
-            let play_indx = dbuf_info.find_buffer(AudioBufState::Filled);
+#[cfg(feature = "f4-discovery")]
+#[interrupt]
+fn DMA1_STREAM5() {
+    output_dma_interrupt();
+}
 
-            if let Some(play_indx) = play_indx {
-                let next_buf_state = &mut dbuf_info.buf_states[play_indx];
+// Runs in the interrupt of the output's DMA stream
+fn output_dma_interrupt() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(transfer) = G_TRANSFER.borrow(cs).borrow_mut().as_mut() {
+            // A transfer error stops the stream, so the main loop has to start it again
+            let flags = transfer.flags();
+            if flags.is_transfer_error() || flags.is_fifo_error() {
+                G_DMA_ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+            if flags.is_transfer_error() {
+                G_DMA_FAILED.store(true, Ordering::Relaxed);
+            }
 
-                let next_buf = unsafe{&G_DBUF[play_indx]};
-                let result = transfer.next_transfer(next_buf);
-                match result {
-                    Ok(_) => {
+            // Queue the next buffer behind the one that has just started playing
+            if flags.is_transfer_complete() {
+                G_TRANSFERS_COMPLETE.fetch_add(1, Ordering::Relaxed);
 
-                        // Change buf states
-                        *next_buf_state = AudioBufState::Playing;
-                        let old_buf_state = &mut dbuf_info.buf_states[play_indx ^ 1];
-                        *old_buf_state = AudioBufState::Empty;
+                let queued = match G_RING.next_play() {
+                    Some(next_buf) => transfer.next_transfer(next_buf).is_ok(),
+                    None => {
+                        G_UNDERRUNS.record(G_TRANSFERS_COMPLETE.load(Ordering::Relaxed));
+                        transfer.next_transfer(&SILENCE_BUFFER).is_ok()
                     },
-
-                    Err(err) => (),
-                }
-            } else {
-                if transfer.flags().is_transfer_complete() {
-                    let _ = transfer.next_transfer(&SILENCE_BUFFER);
+                };
+                if !queued {
+                    G_DMA_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    G_DMA_FAILED.store(true, Ordering::Relaxed);
                 }
             }
 
@@ -289,6 +1002,314 @@ fn DMA1_STREAM4() {
     });
 }
 
+// Switches SPI1 between 375 KHz for sd card init and 24 MHz once the card is ready, from the 96 MHz APB2 clock
+#[cfg(feature = "spi-sd")]
+fn set_sd_spi_clock(_spi: &mut stm32f4xx_hal::spi::Spi<pac::SPI1>, fast: bool) {
+    let baud_rate = if fast { 0b001 } else { 0b111 }; // Divide by 4 or by 256
+    // Safety: the bus is borrowed mutably, and the sd card driver only changes the clock between transfers
+    unsafe { (*pac::SPI1::ptr()).cr1.modify(|_, w| w.br().bits(baud_rate)) };
+}
+
+// The tracks in the root directory, and in the folders inside it with PLAY_SUBFOLDERS
+fn load_playlist<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &mut exfat::ExFat<T>, root_cluster: u32)
+-> Result<playlist::Playlist, exfat::FsError> {
+    if PLAY_SUBFOLDERS {
+        playlist::Playlist::from_folder(exfat, root_cluster, true)
+    } else {
+        let dir = exfat.list_directory(root_cluster)?;
+        Ok(playlist::Playlist::from_directory(root_cluster, &dir))
+    }
+}
+
+// Starts watching WATCH_FOLDER in the root directory, None if the card doesn't have it
+fn watch<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &mut exfat::ExFat<T>, root_cluster: u32, interval_cycles: u32, now: u32)
+-> Option<watch_folder::WatchFolder> {
+    match watch_folder::WatchFolder::new(exfat, root_cluster, WATCH_FOLDER, interval_cycles, now) {
+        Ok(watch_folder) => Some(watch_folder),
+        Err(exfat::FsError::NotFound) => {
+            rprintln!("There is no {} folder to watch", WATCH_FOLDER);
+            None
+        },
+        Err(err) => {
+            rprintln!("Error watching folder: {:?}", err);
+            None
+        },
+    }
+}
+
+// Claims and fills the next buffer, and the next buffer of the second output with the dual-i2s feature
+// Returns false if there wasn't a buffer to fill
+fn fill_next<T: BlockDevice<BLOCK_SIZE>>(player: &mut player::Player, exfat: &mut exfat::ExFat<T>, fill_budget: &mut realtime::FillBudget) -> bool {
+    #[cfg(feature = "dual-i2s")]
+    if second_output::is_full() {
+        return false;
+    }
+
+    // The main loop is the only producer, and each buffer is committed before the next one is claimed
+    let Some(buf) = (unsafe { G_RING.claim_fill() }) else {
+        return false;
+    };
+    fill_budget.start();
+
+    #[cfg(not(feature = "dual-i2s"))]
+    player.fill(exfat, buf, fill_budget);
+    #[cfg(feature = "dual-i2s")]
+    second_output::fill(player, exfat, buf, fill_budget);
+
+    G_RING.commit_fill();
+    true
+}
+
+// Sets the codec to the part of the volume it does, the rest is done in software by volume
+fn set_codec_volume<C: Codec>(codec: &mut C, volume: &volume::Volume) {
+    if let Err(err) = codec.set_volume(volume.hardware_db()) {
+        rprintln!("Codec error: {:?}", err);
+    }
+}
+
+// How far the buffered audio puts the output behind the decoder
+fn output_latency_ms(output_sample_rate: u32) -> u32 {
+    (G_RING.buffered() as u64 * (BUF_SIZE / 2) as u64 * 1000 / output_sample_rate.max(1) as u64) as u32
+}
+
+// Starts the DMA with the first two buffers in the ring, after that the ISR queues each one as the last finishes
+// The I2S driver should be enabled, with DMA requests turned on
+// An I2S slave should be left disabled, it's enabled here once the DMA has loaded the first sample
+fn start_transfer(stream: StreamX<pac::DMA1, OUTPUT_DMA_STREAM>, i2s_driver: I2sTx) {
+    let first_buf = G_RING.next_play().unwrap_or(&SILENCE_BUFFER);
+    let second_buf = G_RING.next_play().unwrap_or(&SILENCE_BUFFER);
+
+    let mut transfer = I2sDma::init_memory_to_peripheral(
+        stream, 
+        i2s_driver, 
+        first_buf,
+        Some(second_buf),
+        DmaConfig::default()
+        .memory_increment(true)
+        .double_buffer(true)
+        .fifo_error_interrupt(true)
+        .transfer_complete_interrupt(true)
+    );
+    transfer.clear_all_flags();
+
+    cortex_m::interrupt::free(|cs| {
+        G_TRANSFER.borrow(cs).replace(Some(transfer));
+        G_TRANSFER.borrow(cs).borrow_mut().as_mut().unwrap().start(|_i2s_driver| {
+            #[cfg(feature = "i2s-slave")]
+            enable_slave(_i2s_driver);
+        });
+    });
+}
+
+// Stops the DMA and the I2S after a DMA error and starts them again
+// Playback carries on from the buffer that was queued when it stopped, so the track keeps its position
+fn restart_transfer() {
+    let Some(transfer) = cortex_m::interrupt::free(|cs| G_TRANSFER.borrow(cs).borrow_mut().take()) else {
+        return;
+    };
+    let (stream, mut i2s_driver, _, _) = transfer.release();
+
+    i2s_driver.set_tx_dma(false);
+    i2s_driver.disable();
+    G_RING.restart();
+
+    #[cfg(not(feature = "i2s-slave"))]
+    i2s_driver.enable();
+    i2s_driver.set_tx_dma(true);
+    #[cfg(feature = "dual-i2s")]
+    second_output::restart();
+    start_transfer(stream, i2s_driver);
+}
+
+// The slave has to be enabled while WS is high, so it starts at the beginning of a frame
+// This waits for the external clocks, nothing can play without them
+#[cfg(feature = "i2s-slave")]
+fn enable_slave<I: stm32_i2s_v12x::I2sPeripheral>(i2s_driver: &mut I2sDriver<I, Slave, Transmit, Philips>) {
+    use stm32_i2s_v12x::WsPin;
+    while i2s_driver.ws_pin().is_low() {}
+    i2s_driver.enable();
+}
+
+// The configuration of the I2S outputs, 16 bit stereo as close to sample_rate as the clock dividers allow
+// Every output uses the same configuration, so they all run at the same rate
+#[cfg(not(feature = "i2s-slave"))]
+fn i2s_config(sample_rate: u32) -> I2sDriverConfig<I2sMode, Transmit, Philips> {
+    I2sDriverConfig::new_master()
+        .transmit()
+        .standard(Philips)
+        .data_format(DataFormat::Data16Channel16)
+        .master_clock(I2S_MCLK_OUTPUT)
+        .request_frequency(sample_rate)
+}
+
+// As a slave the rate is set by the clock source
+#[cfg(feature = "i2s-slave")]
+fn i2s_config(_sample_rate: u32) -> I2sDriverConfig<I2sMode, Transmit, Philips> {
+    I2sDriverConfig::new_slave()
+        .transmit()
+        .standard(Philips)
+        .data_format(DataFormat::Data16Channel16)
+}
+
+// Creates the I2S driver for a file at file_rate, and returns it with the output sample rate
+// The I2S runs at the sample rate of the file if it can be generated accurately enough
+// Otherwise the output runs at SAMPLE_RATE and the file is resampled
+// The driver is returned disabled
+#[cfg(not(feature = "i2s-slave"))]
+fn new_output(i2s: I2s<OutputSpi>, file_rate: u32) -> (I2sTx, u32) {
+    let mut output_sample_rate = if (MIN_I2S_SAMPLE_RATE..=MAX_I2S_SAMPLE_RATE).contains(&file_rate) {
+        file_rate
+    } else {
+        SAMPLE_RATE
+    };
+
+    let mut i2s_driver = new_i2s_driver(i2s, output_sample_rate);
+    let error_ppm = i2s_driver.sample_rate().abs_diff(output_sample_rate) as u64 * 1_000_000 / output_sample_rate as u64;
+    if output_sample_rate != SAMPLE_RATE && error_ppm > I2S_RATE_TOLERANCE_PPM as u64 {
+        output_sample_rate = SAMPLE_RATE;
+        i2s_driver = new_i2s_driver(i2s_driver.release(), SAMPLE_RATE);
+    }
+    rprintln!("Actual sample rate is {}", i2s_driver.sample_rate());
+    (i2s_driver, output_sample_rate)
+}
+
+// As an I2S slave the external clocks set the sample rate, so the output always runs at SAMPLE_RATE
+// There is no rounding of the sample rate by the clock dividers, it's exactly what the clock source makes
+// The driver is returned disabled
+#[cfg(feature = "i2s-slave")]
+fn new_output(i2s: I2s<OutputSpi>, _file_rate: u32) -> (I2sTx, u32) {
+    rprintln!("I2S slave, the sample rate is set by the clock source");
+    (I2sDriver::new(i2s, i2s_config(SAMPLE_RATE)), SAMPLE_RATE)
+}
+
+// Creates an I2S driver for the output as close to sample_rate as the clock dividers allow
+// The driver is returned disabled
+#[cfg(not(feature = "i2s-slave"))]
+fn new_i2s_driver(i2s: I2s<OutputSpi>, sample_rate: u32) -> I2sTx {
+    I2sDriver::new(i2s, i2s_config(sample_rate))
+}
+
+// The DMA streams and I2S drivers of the outputs once stop_clean has stopped them, so they can be started again
+#[cfg_attr(feature = "i2s-slave", allow(dead_code))] // An I2S slave isn't started again at another rate
+struct StoppedOutput {
+    stream: StreamX<pac::DMA1, OUTPUT_DMA_STREAM>,
+    i2s_driver: I2sTx,
+    #[cfg(feature = "dual-i2s")]
+    second: Option<(StreamX<pac::DMA1, 5>, second_output::SecondI2sTx)>,
+}
+
+// Stops playback without a DC step on the output, for when it feeds measurement equipment or amplifiers
+// Everything already buffered is played out, then the output is faded from the last sample to zero over one buffer
+// Once the DMA has finished the fade and the output is silent the DMA and I2S are stopped
+// The I2S drivers are returned disabled with the data line low and the clocks stopped (unless they come from an external source)
+fn stop_clean() -> StoppedOutput {
+    let fade_slot = commit_fade_out(&G_RING);
+    #[cfg(feature = "dual-i2s")]
+    let second_fade_slot = commit_fade_out(&second_output::G_SECOND_RING);
+
+    // The slot is released once the DMA has played it, and the silence buffer is playing after it
+    while !G_RING.has_played(fade_slot) {}
+    #[cfg(feature = "dual-i2s")]
+    let second = second_output::stop_clean(second_fade_slot);
+
+    let transfer = cortex_m::interrupt::free(|cs| G_TRANSFER.borrow(cs).borrow_mut().take()).unwrap();
+    let (stream, mut i2s_driver, _, _) = transfer.release();
+
+    // Disable sequence from the reference manual, wait for the last (silent) sample to be sent
+    i2s_driver.set_tx_dma(false);
+    while !i2s_driver.status().txe() {}
+    while i2s_driver.status().bsy() {}
+    i2s_driver.disable();
+
+    StoppedOutput {
+        stream,
+        i2s_driver,
+        #[cfg(feature = "dual-i2s")]
+        second,
+    }
+}
+
+// Waits for the buffered audio in ring to be handed to the DMA, then queues a buffer fading from it to zero
+// Returns the number of the fade slot, for has_played
+fn commit_fade_out(ring: &'static AudioRing<BUF_SLOTS, BUF_SIZE>) -> usize {
+    while ring.has_filled() {}
+
+    // The slot filled last holds the last audio that was sent, so the fade starts from its last frame
+    let last_frame = ring.last_filled_frame().map(|sample| sample as i16);
+    let fade_buf = loop {
+        if let Some(fill_slot) = unsafe { ring.claim_fill() } {
+            break fill_slot;
+        }
+    };
+
+    let fade_frames = (BUF_SIZE / 2) as i32;
+    for (frame_indx, frame) in fade_buf.chunks_exact_mut(2).enumerate() {
+        let remaining = fade_frames - 1 - frame_indx as i32;
+        for (sample, last_sample) in frame.iter_mut().zip(last_frame) {
+            *sample = (last_sample as i32 * remaining / fade_frames) as i16 as u16;
+        }
+    }
+    ring.commit_fill()
+}
+
+fn print_levels(levels: meter::Levels) {
+    let [left_peak, right_peak] = levels.peak_dbfs();
+    let [left_rms, right_rms] = levels.rms_dbfs();
+    rprintln!("levels: peak {} {} dBFS, rms {} {} dBFS", left_peak, right_peak, left_rms, right_rms);
+}
+
+// Registered as an event hook, more hooks can be added for a display or LEDs
+fn print_event(event: &player::PlayerEvent) {
+    match event {
+        player::PlayerEvent::TrackStarted { indx } => rprintln!("Start of track {}", indx + 1),
+        player::PlayerEvent::QueuedTrackStarted => rprintln!("Start of queued track"),
+        player::PlayerEvent::TrackFinished { indx } => rprintln!("End of track {}", indx + 1),
+        player::PlayerEvent::QueuedTrackFinished => rprintln!("End of queued track"),
+        player::PlayerEvent::PlaylistFinished => rprintln!("End of playlist"),
+        player::PlayerEvent::Underrun { count } => rprintln!("Underrun {}", count),
+        player::PlayerEvent::ReadError => rprintln!("Read error"),
+        player::PlayerEvent::CardRemoved => rprintln!("Card removed"),
+    }
+}
+
+// Called from the main loop about every PROGRESS_INTERVAL_MS while a track plays
+// This is where a display or remote control interface would be updated
+fn publish_progress(position_ms: u32, duration_ms: u32) {
+    rprintln!("progress: {}.{:03}/{}.{:03} s", position_ms / 1000, position_ms % 1000, duration_ms / 1000, duration_ms % 1000);
+}
+
+// Prints a text snapshot of what the player is currently doing
+// Intended to be copied into bug reports
+fn dump_state(player: &player::Player) {
+    let buf_states = G_RING.buf_states();
+
+    rprintln!("--- dump start ---");
+    rprintln!("track: {}/{}", player.playlist.current_indx() + 1, player.playlist.len());
+    if let Some(file) = player.playlist.current() {
+        rprintln!("file: {:?}", file);
+    }
+    rprintln!("queued: {}", player.queue_len());
+    if let Some(decoder) = player.decoder.as_ref() {
+        rprintln!("decoder: {:?}", decoder);
+        rprintln!("position: {}/{} ms", decoder.position_ms(), decoder.duration_ms());
+    }
+    rprintln!("buf_states: {:?}", buf_states);
+    #[cfg(feature = "dual-i2s")]
+    rprintln!("second output: buf_states: {:?}, underruns: {}", second_output::G_SECOND_RING.buf_states(), second_output::G_SECOND_UNDERRUNS.load(Ordering::Relaxed));
+    rprintln!("dma_errors: {}", G_DMA_ERRORS.load(Ordering::Relaxed));
+    rprintln!("read_errors: {:?}", G_READ_ERRORS.stats());
+
+    let underruns = G_UNDERRUNS.stats();
+    if underruns.count > 0 {
+        let last_ms = underruns.last_transfer as u64 * (BUF_SIZE / 2) as u64 * 1000 / player.output_sample_rate.max(1) as u64;
+        rprintln!("underruns: {}, last at {} ms", underruns.count, last_ms);
+    } else {
+        rprintln!("underruns: 0");
+    }
+    rprintln!("config: sample_rate={} output_sample_rate={} block_size={} buf_blocks={}", SAMPLE_RATE, player.output_sample_rate, BLOCK_SIZE, BUF_BLOCKS);
+    rprintln!("--- dump end ---");
+}
+
 use core::panic::PanicInfo;
 #[inline(never)]
 #[panic_handler]
@@ -0,0 +1,81 @@
+// Level meters, for VU meters on a display, and to spot files that are silent or much too quiet
+//
+// The peak and RMS level of each channel is measured over every buffer as it is filled
+// The buffer is played a few buffers later (see audio_buffer.rs), which is too short a delay to see on a meter
+
+// Levels below this are shown as this, 16 bit audio can't go much quieter
+pub const MIN_DBFS: i16 = -96;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Levels {
+    pub peak: [u16; 2], // Largest sample of the left and right channel, i16::MAX is full scale
+    pub rms: [u16; 2],
+}
+
+impl Levels {
+    // Peak of the left and right channel in dBFS
+    pub fn peak_dbfs(&self) -> [i16; 2] {
+        self.peak.map(dbfs)
+    }
+
+    pub fn rms_dbfs(&self) -> [i16; 2] {
+        self.rms.map(dbfs)
+    }
+}
+
+#[derive(Debug)]
+pub struct LevelMeter {
+    levels: Levels,
+}
+
+impl LevelMeter {
+    pub fn new() -> Self {
+        LevelMeter { levels: Levels::default() }
+    }
+
+    // Measures interleaved 16 bit stereo samples, the levels are replaced with the levels of buf
+    pub fn measure(&mut self, buf: &[u16]) {
+        let mut peak = [0u16; 2];
+        let mut sum_of_squares = [0u64; 2];
+        for frame in buf.chunks_exact(2) {
+            for ((sample, peak), sum_of_squares) in frame.iter().zip(peak.iter_mut()).zip(sum_of_squares.iter_mut()) {
+                let magnitude = (*sample as i16).unsigned_abs();
+                *peak = (*peak).max(magnitude);
+                *sum_of_squares += magnitude as u64 * magnitude as u64;
+            }
+        }
+
+        let frames = (buf.len() / 2).max(1) as u64;
+        self.levels = Levels {
+            peak: peak.map(|peak| peak.min(i16::MAX as u16)),
+            rms: sum_of_squares.map(|sum_of_squares| (sum_of_squares / frames).isqrt() as u16),
+        };
+    }
+
+    pub fn levels(&self) -> Levels {
+        self.levels
+    }
+}
+
+impl Default for LevelMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Converts a level to whole dBFS, 20 log10(level / full scale)
+// There's no libm, so log2 is worked out from the position of the top bit, and the bits below it linearly
+// This is within about half a dB, which is plenty for a meter
+pub fn dbfs(level: u16) -> i16 {
+    if level == 0 {
+        return MIN_DBFS;
+    }
+
+    let top_bit = 15 - level.leading_zeros() as i32;
+    let fraction = ((level as i32) << (16 - top_bit)) & 0xFFFF; // The bits below the top bit, Q16
+    let log2 = (top_bit << 16) + fraction - (15 << 16); // Relative to full scale, Q16
+
+    // 20 log10(2) = 6.0206 dB per bit, Q16
+    let db = (log2 as i64 * 394_566 + (1 << 31)) >> 32;
+    (db as i16).max(MIN_DBFS)
+}
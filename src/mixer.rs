@@ -0,0 +1,118 @@
+// Mixes short sounds (UI beeps, notifications) on top of the music, so they can be played without stopping it
+//
+// A sound is interleaved 16 bit stereo samples, usually in flash, which can be played a number of times in a row
+// Each channel of the sound has its own Q15 gain, and it is added to the music through the limiter so loud sounds don't wrap
+
+use crate::limiter;
+use crate::volume::UNITY_GAIN;
+
+// Frames in one cycle of the beep, 1 KHz at 48 KHz
+const BEEP_CYCLE_FRAMES: usize = 48;
+const BEEP_AMPLITUDE: i32 = 8000;
+
+// One cycle of a triangle wave, played over and over to make a beep
+pub const BEEP: [i16; BEEP_CYCLE_FRAMES * 2] = beep_cycle();
+
+const fn beep_cycle() -> [i16; BEEP_CYCLE_FRAMES * 2] {
+    let mut samples = [0; BEEP_CYCLE_FRAMES * 2];
+    let quarter = BEEP_CYCLE_FRAMES as i32 / 4;
+
+    let mut frame = 0;
+    while frame < BEEP_CYCLE_FRAMES {
+        // Rises for the first quarter, falls for the next two, and rises back to zero in the last
+        let phase = frame as i32;
+        let level = if phase < quarter {
+            phase
+        } else if phase < 3 * quarter {
+            2 * quarter - phase
+        } else {
+            phase - 4 * quarter
+        };
+
+        let sample = (level * BEEP_AMPLITUDE / quarter) as i16;
+        samples[frame * 2] = sample;
+        samples[frame * 2 + 1] = sample;
+        frame += 1;
+    }
+
+    samples
+}
+
+#[derive(Debug)]
+struct Sound {
+    samples: &'static [i16],
+    pos: usize, // Next sample to mix
+    repeats_left: u32, // Times the sound is played again after this one
+    gain: [i32; 2], // Q15 gain of the left and right channel
+}
+
+#[derive(Debug)]
+pub struct Mixer {
+    sound: Option<Sound>,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Mixer { sound: None }
+    }
+
+    // Starts mixing samples into the output, replacing any sound that is already playing
+    // samples are interleaved stereo and are played repeats times, gain is the Q15 gain of the left and right channel
+    pub fn play(&mut self, samples: &'static [i16], repeats: u32, gain: [i32; 2]) {
+        if samples.len() < 2 || repeats == 0 {
+            self.sound = None;
+            return;
+        }
+
+        self.sound = Some(Sound {
+            samples,
+            pos: 0,
+            repeats_left: repeats - 1,
+            gain,
+        });
+    }
+
+    // Plays a beep over the music which lasts about ms milliseconds at 48 KHz
+    pub fn beep(&mut self, ms: u32) {
+        let repeats = (ms as u64 * 48_000 / 1000 / BEEP_CYCLE_FRAMES as u64) as u32;
+        self.play(&BEEP, repeats.max(1), [UNITY_GAIN, UNITY_GAIN]);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.sound.is_some()
+    }
+
+    // Adds the sound to interleaved 16 bit stereo samples
+    pub fn mix(&mut self, buf: &mut [u16]) {
+        let Some(sound) = self.sound.as_mut() else {
+            return;
+        };
+
+        for frame in buf.chunks_exact_mut(2) {
+            let Some(sound_frame) = sound.samples.get(sound.pos..sound.pos + 2) else {
+                break;
+            };
+
+            for ((sample, sound_sample), gain) in frame.iter_mut().zip(sound_frame).zip(sound.gain) {
+                let sound_sample = ((*sound_sample as i32 * gain) >> 15) as i16;
+                *sample = limiter::soft_clip(*sample as i16 as i32 + sound_sample as i32) as u16;
+            }
+
+            sound.pos += 2;
+            if sound.pos + 2 > sound.samples.len() {
+                if sound.repeats_left == 0 {
+                    self.sound = None;
+                    return;
+                }
+                sound.pos = 0;
+                sound.repeats_left -= 1;
+            }
+        }
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
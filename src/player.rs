@@ -0,0 +1,999 @@
+// Plays the tracks of a playlist one after another
+//
+// The main loop hands every Empty buffer to fill, which has to finish within the buffer fill budget (see realtime.rs)
+// Anything slow, like opening the next track, is done by poll which the main loop calls between fills
+
+use heapless::Deque;
+
+use crate::audio_buffer::TrackGap;
+use crate::block_device::BlockDevice;
+use crate::dc_blocker::DcBlocker;
+use crate::declick::Declicker;
+use crate::decoder::{Decoder, TrackDecoder};
+use crate::exfat::{ExFat, FsEntry};
+use crate::eq::{self, Equalizer};
+use crate::events::EventHooks;
+use crate::fade::Fade;
+use crate::limiter;
+use crate::meter::{LevelMeter, Levels};
+use crate::mixer::Mixer;
+use crate::playlist::{Playlist, QueuedTrack};
+use crate::realtime::FillBudget;
+#[cfg(feature = "time-stretch")]
+use crate::time_stretch::TimeStretch;
+use crate::replay_gain::{self, ReplayGainMode};
+use crate::resume::ResumeRecord;
+use crate::routing::Routing;
+#[cfg(feature = "dual-i2s")]
+use crate::second_output::OutputSelect;
+use crate::volume::{self, Volume};
+use crate::wav::{self, WavError};
+use crate::rprintln;
+
+use crate::BLOCK_SIZE;
+
+// How many times to try opening a file when the card fails a read
+const OPEN_ATTEMPTS: usize = 3;
+
+// Previous goes back to the start of the track once it has played for this long, otherwise to the track before
+const RESTART_THRESHOLD_MS: u32 = 3000;
+
+// Length of the ramp that smooths over a jump in the output, see declick.rs
+const DECLICK_MS: u32 = 5;
+
+// How long the music takes to be turned down when the mixer starts a sound, and back up once it has finished
+const DUCK_RAMP_MS: u32 = 50;
+
+// Each queued track holds a whole FsEntry like the playlist does
+pub const MAX_QUEUED_TRACKS: usize = 8;
+
+// Events waiting to be handed out by poll, one is handed out each call
+const MAX_PENDING_EVENTS: usize = 8;
+
+
+#[derive(Debug)]
+pub struct Player {
+    pub playlist: Playlist,
+    pub decoder: Option<TrackDecoder>, // The track being played, None once the end of the playlist has been reached
+    pub output_sample_rate: u32, // 0 until set_output_sample_rate is called, tracks aren't resampled until then
+    pub volume: Volume,
+    pub dc_blocker: DcBlocker, // Off unless enabled, runs before the equalizer
+    pub eq: Equalizer, // Runs at the output sample rate, before the volume
+    pub fade_ms: u32, // Length of the fades at the start of a track, and when pausing, resuming, and stopping
+    pub crossfade_ms: u32, // How much the end of a track overlaps the start of the next, 0 to play tracks one after another
+    speed_percent: u16, // Playback speed of every track, see set_speed
+    loop_start_ms: Option<u32>, // Marked by mark_loop_start, in the current track
+    #[cfg(feature = "time-stretch")]
+    pub preserve_pitch: bool, // Change the speed with the time stretcher instead of resampling, set before set_speed
+    #[cfg(feature = "time-stretch")]
+    time_stretch: TimeStretch, // Only used for the current track, the next track plays at normal speed while crossfading
+    pub repeat: Repeat,
+    pub mixer: Mixer, // Sounds played over the music, these aren't affected by the volume
+    duck_gain: i32, // Q15 gain of the music while the mixer is playing a sound, see set_ducking
+    duck: Fade,
+    pub hooks: EventHooks, // Called with each event as poll hands it out
+    pub routing: Routing, // Which channel each output plays, applied after the mixer
+    #[cfg(feature = "dual-i2s")]
+    pub outputs: OutputSelect, // Which of the two outputs are heard, applied by second_output::fill
+    queue: Deque<QueuedTrack, MAX_QUEUED_TRACKS>, // Played before the playlist carries on, see enqueue
+    playing_queued: bool, // The current track came from the queue, the playlist is still on the track before it
+    read_failed: bool, // The last fill couldn't read the track, poll checks whether the card is still there
+    card_removed: bool, // Nothing is read from the card until card_inserted
+    removed_at: Option<ResumeRecord>, // Where playback had got to when the card was taken out
+
+    track_gap: TrackGap, // Scheduled with INTER_TRACK_GAP_MS when a track ends
+    track_state: TrackState,
+    events: Deque<PlayerEvent, MAX_PENDING_EVENTS>, // Raised by fill and the track changes, handed out by poll
+    fade: Fade,
+    declicker: Declicker,
+    meter: LevelMeter, // Measures what is played, after the mixer
+    mute: Fade, // Faded out while muted, so the track is decoded and carries on in real time without being heard
+    muted: bool,
+    state: State,
+
+    // While crossfading the next track is decoded into mix_buf and added to the end of the current track
+    incoming: Option<TrackDecoder>,
+    incoming_queued: bool, // incoming is the front of the queue, it is taken off the queue once it takes over
+    crossfade_in: Fade,
+    crossfade_out: Fade,
+    crossfade_tried: bool, // The next track has been opened for this track, or couldn't be
+    rate_change_pending: bool, // A track has been opened that the output hasn't been matched to, see take_output_rate_change
+    mix_buf: [u16; crate::BUF_SIZE],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repeat {
+    Off,
+    One, // Loop the current track, it is rewound without opening it again so the loop has no gap
+    All, // Go back to the first track after the last one
+}
+
+// Returned by poll, and passed to the hooks, when something has happened that the rest of the firmware might want to know about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayerEvent {
+    TrackStarted { indx: usize }, // The track at indx in the playlist has been opened, it is heard after the buffered audio
+    QueuedTrackStarted, // A track from the queue has been opened
+    TrackFinished { indx: usize }, // The track at indx in the playlist has been played out
+    QueuedTrackFinished, // A track from the queue has been played out
+    PlaylistFinished, // There are no more tracks to play, the output is silent
+    Underrun { count: u32 }, // The output ran out of filled buffers, count is how many times since playback started
+    ReadError, // The card failed a read, the rest of the track or the track that was being opened is skipped
+    CardRemoved, // A read failed because the card has gone, nothing plays until card_inserted
+}
+
+// A track ends with the buffer that holds its last samples, the rest of that buffer is silence
+// Once that buffer has been handed to the DMA, and any gap between tracks has played, the track is finished
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TrackState {
+    Playing,
+    Ending, // The last partial buffer has been filled
+    Finished, // poll opens the next track
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Playing,
+    Pausing, // Fading out, then Paused
+    Paused,
+    Stopping, // Fading out, then Stopped
+    Stopped,
+}
+
+impl Player {
+    pub fn new(playlist: Playlist) -> Self {
+        Player {
+            playlist,
+            decoder: None,
+            output_sample_rate: 0,
+            volume: Volume::default(),
+            dc_blocker: DcBlocker::new(0),
+            eq: Equalizer::new(0),
+            fade_ms: 0,
+            crossfade_ms: 0,
+            speed_percent: wav::NORMAL_SPEED_PERCENT,
+            loop_start_ms: None,
+            #[cfg(feature = "time-stretch")]
+            preserve_pitch: false,
+            #[cfg(feature = "time-stretch")]
+            time_stretch: TimeStretch::new(),
+            repeat: Repeat::Off,
+            mixer: Mixer::new(),
+            duck_gain: volume::UNITY_GAIN,
+            duck: Fade::new(),
+            hooks: EventHooks::new(),
+            routing: Routing::Stereo,
+            #[cfg(feature = "dual-i2s")]
+            outputs: OutputSelect::new(),
+            queue: Deque::new(),
+            playing_queued: false,
+            read_failed: false,
+            card_removed: false,
+            removed_at: None,
+            track_gap: TrackGap::new(),
+            track_state: TrackState::Playing,
+            events: Deque::new(),
+            fade: Fade::new(),
+            declicker: Declicker::new(),
+            meter: LevelMeter::new(),
+            mute: Fade::new(),
+            muted: false,
+            state: State::Playing,
+            incoming: None,
+            incoming_queued: false,
+            crossfade_in: Fade::new(),
+            crossfade_out: Fade::new(),
+            crossfade_tried: false,
+            rate_change_pending: false,
+            mix_buf: [0; crate::BUF_SIZE],
+        }
+    }
+
+    // Fades out and then stops taking samples from the track, the position in the track is kept
+    // The main loop stops filling buffers once paused, so the DMA plays the silence buffer
+    pub fn pause(&mut self) {
+        if self.state == State::Playing {
+            self.state = State::Pausing;
+            self.fade.fade_out(self.fade_frames());
+        }
+    }
+
+    // Carries on from where the track was paused, fading in from silence
+    pub fn resume(&mut self) {
+        if matches!(self.state, State::Pausing | State::Paused) {
+            self.state = State::Playing;
+            self.fade.fade_in(self.fade_frames());
+        }
+    }
+
+    // True once the fade out before pausing or stopping has finished, no more buffers need to be filled
+    pub fn is_paused(&self) -> bool {
+        matches!(self.state, State::Paused | State::Stopped)
+    }
+
+    // Fades out so the output can be stopped, is_stopped is true once the fade has been filled
+    pub fn stop(&mut self) {
+        if self.state != State::Stopped {
+            self.state = State::Stopping;
+            self.fade.fade_out(self.fade_frames());
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state == State::Stopped
+    }
+
+    // Silences the music while it keeps playing, so unmuting carries on from where the track has got to
+    // Sounds from the mixer can still be heard
+    pub fn set_muted(&mut self, muted: bool) {
+        if muted == self.muted {
+            return;
+        }
+
+        self.muted = muted;
+        if muted {
+            self.mute.fade_out(self.fade_frames());
+        } else {
+            self.mute.fade_in(self.fade_frames());
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    fn fade_frames(&self) -> u32 {
+        (self.fade_ms as u64 * self.output_sample_rate as u64 / 1000) as u32
+    }
+
+    // Moves on from Pausing or Stopping once the fade out has finished
+    fn update_state(&mut self) {
+        if !self.fade.is_silent() {
+            return;
+        }
+
+        self.state = match self.state {
+            State::Pausing => State::Paused,
+            State::Stopping => State::Stopped,
+            state => state,
+        };
+    }
+
+    // Opens the current track of the playlist, moving past any tracks that can't be opened
+    // Returns false if the end of the playlist was reached without opening a track
+    pub fn open_current<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> bool {
+        self.decoder = None;
+        self.playing_queued = false;
+        self.track_state = TrackState::Playing;
+        self.crossfade_tried = false;
+
+        while let Some(track) = self.playlist.current() {
+            let directory_cluster = self.playlist.directory(self.playlist.current_indx());
+            match open_track(exfat, directory_cluster, track, self.output_sample_rate, self.resample_speed()) {
+                Ok(decoder) => {
+                    self.volume.set_track_gain(track_gain(&decoder));
+                    self.decoder = Some(decoder);
+                    self.loop_start_ms = None;
+                    self.reset_time_stretch();
+                    self.fade.fade_in(self.fade_frames());
+                    self.started(false);
+                    return true;
+                },
+                Err(WavError::Unsupported(unsupported)) => {
+                    rprintln!("Can't play {}: {} ({:?}), needs {}", track.name, unsupported.reason(), unsupported, unsupported.needs());
+                },
+                Err(error) => {
+                    rprintln!("Couldn't open {}: {:?}", track.name, error);
+                    if error == WavError::ReadFail {
+                        let record = ResumeRecord {
+                            first_cluster: track.first_cluster,
+                            length: track.valid_data_length as u32,
+                            data_offset: 0,
+                        };
+                        self.raise(PlayerEvent::ReadError);
+
+                        // Don't skip through the whole playlist if the card has gone, it carries on from this track once it's back
+                        if !exfat.block_device.is_present() {
+                            self.remove_card(Some(record));
+                            return false;
+                        }
+                    }
+                },
+            }
+
+            self.playlist.advance();
+        }
+
+        false
+    }
+
+    // Opens the track at the front of the queue, tracks that can't be opened are taken off the queue and skipped
+    // Returns false once the queue is empty, the playlist is left where it is
+    fn open_queued<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> bool {
+        while let Some(queued) = self.queue.pop_front() {
+            match open_track(exfat, queued.directory_cluster, &queued.track, self.output_sample_rate, self.resample_speed()) {
+                Ok(decoder) => {
+                    self.volume.set_track_gain(track_gain(&decoder));
+                    self.decoder = Some(decoder);
+                    self.started(true);
+                    self.loop_start_ms = None;
+                    self.reset_time_stretch();
+                    self.track_state = TrackState::Playing;
+                    self.crossfade_tried = false;
+                    self.fade.fade_in(self.fade_frames());
+                    return true;
+                },
+                Err(error) => {
+                    rprintln!("Couldn't open queued track {}: {:?}", queued.track.name, error);
+                    if error == WavError::ReadFail {
+                        self.raise(PlayerEvent::ReadError);
+                        if !exfat.block_device.is_present() {
+                            self.remove_card(None);
+                            return false;
+                        }
+                    }
+                },
+            }
+        }
+
+        false
+    }
+
+    // Plays track once the current track has finished, before the playlist carries on
+    // Tracks are played in the order they are queued, fails if the queue is full
+    // If the playlist has already finished the track starts straight away (from poll)
+    pub fn enqueue(&mut self, track: FsEntry, directory_cluster: u32) -> Result<(), ()> {
+        self.queue.push_back(QueuedTrack { track, directory_cluster }).map_err(|_| ())
+    }
+
+    // Queues the track at indx in the playlist, the playlist position doesn't change
+    pub fn enqueue_indx(&mut self, indx: usize) -> Result<(), ()> {
+        let track = self.playlist.get(indx).ok_or(())?.clone();
+        self.enqueue(track, self.playlist.directory(indx))
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Forgets the queued tracks, a queued track that is already being crossfaded in still plays
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+
+    // Stops the track being played, throws away the audio buffered from it, and starts playing track from the beginning
+    // A track in the playlist is selected so the playlist carries on after it, any other track plays like a queued track
+    // If track can't be opened the old track carries on playing
+    pub fn play<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, track: &FsEntry) -> Result<(), WavError> {
+        if let Some(indx) = self.find_track(track.first_cluster, track.valid_data_length as u32) {
+            return self.play_indx(exfat, indx);
+        }
+
+        self.play_from(exfat, track, self.playlist.directory_cluster)?;
+        self.started(true);
+        Ok(())
+    }
+
+    // Like play, for a track that is in directory_cluster
+    fn play_from<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, track: &FsEntry, directory_cluster: u32)
+    -> Result<(), WavError> {
+        let decoder = open_track(exfat, directory_cluster, track, self.output_sample_rate, self.resample_speed())?;
+        self.abandon_buffered();
+
+        self.volume.set_track_gain(track_gain(&decoder));
+        self.decoder = Some(decoder);
+        self.loop_start_ms = None;
+        self.reset_time_stretch();
+        self.incoming = None;
+        self.crossfade_tried = false;
+        self.track_gap = TrackGap::new();
+        self.track_state = TrackState::Playing;
+        self.state = State::Playing;
+        self.fade.fade_in(self.fade_frames());
+        Ok(())
+    }
+
+    // Plays the track at indx in the playlist from the beginning
+    // If the track can't be opened the playlist and the old track are left as they were
+    pub fn play_indx<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, indx: usize) -> Result<(), WavError> {
+        let playing_indx = self.playlist.current_indx();
+        let Some(track) = self.playlist.select(indx).cloned() else {
+            return Err(WavError::NoTrack);
+        };
+
+        let result = self.play_from(exfat, &track, self.playlist.directory(indx));
+        match result {
+            Ok(()) => self.started(false),
+            Err(_) => {
+                self.playlist.select(playing_indx);
+            },
+        }
+        result
+    }
+
+    // Skips to the next track in the queue, or in the playlist if nothing is queued
+    // Does nothing on the last track and returns Ok(false), unless the whole playlist is repeated
+    // A queued track that can't be opened is taken off the queue
+    pub fn next<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Result<bool, WavError> {
+        if let Some(queued) = self.queue.pop_front() {
+            self.play_from(exfat, &queued.track, queued.directory_cluster)?;
+            self.started(true);
+            return Ok(true);
+        }
+
+        let Some(next_indx) = self.next_indx() else {
+            return Ok(false);
+        };
+
+        self.play_indx(exfat, next_indx)?;
+        Ok(true)
+    }
+
+    // Goes back to the start of the track, or to the track before if the track has only just started
+    // The first track is restarted, and after the end of the playlist the last track is played again
+    // The track before a queued track is the playlist track it was queued after
+    pub fn previous<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Result<(), WavError> {
+        let indx = self.playlist.current_indx();
+        let previous_indx = match self.playing_queued {
+            true => indx.min(self.playlist.len().saturating_sub(1)),
+            false => indx.saturating_sub(1),
+        };
+
+        let Some(decoder) = self.decoder.as_mut() else {
+            return self.play_indx(exfat, previous_indx);
+        };
+        if (indx == 0 && !self.playing_queued) || decoder.position_ms() >= RESTART_THRESHOLD_MS {
+            decoder.seek_to_ms(exfat, 0).map_err(|_| WavError::ReadFail)?;
+            self.abandon_buffered();
+            self.reset_time_stretch();
+            self.incoming = None;
+            self.crossfade_tried = false;
+            self.track_gap = TrackGap::new();
+            self.track_state = TrackState::Playing;
+            self.fade.fade_in(self.fade_frames());
+            return Ok(());
+        }
+
+        self.play_indx(exfat, previous_indx)
+    }
+
+    // Plays faster or slower, from wav::MIN_SPEED_PERCENT to wav::MAX_SPEED_PERCENT, e.g. 150 to speed up speech
+    // The pitch goes up and down with the speed, unless preserve_pitch is set, returns the speed the current track is playing at
+    pub fn set_speed(&mut self, speed_percent: u16) -> u16 {
+        self.speed_percent = speed_percent.clamp(wav::MIN_SPEED_PERCENT, wav::MAX_SPEED_PERCENT);
+        let resample_speed = self.resample_speed();
+
+        #[cfg(feature = "time-stretch")]
+        self.time_stretch.set_speed(self.speed_percent * wav::NORMAL_SPEED_PERCENT / resample_speed);
+
+        if let Some(incoming) = self.incoming.as_mut() {
+            incoming.set_speed(resample_speed);
+        }
+        match self.decoder.as_mut() {
+            Some(decoder) if resample_speed != wav::NORMAL_SPEED_PERCENT => decoder.set_speed(resample_speed),
+            Some(decoder) => {
+                decoder.set_speed(resample_speed);
+                self.speed_percent
+            },
+            None => self.speed_percent,
+        }
+    }
+
+    // Speed the tracks are resampled to, the time stretcher makes up the rest
+    fn resample_speed(&self) -> u16 {
+        #[cfg(feature = "time-stretch")]
+        if self.preserve_pitch {
+            return wav::NORMAL_SPEED_PERCENT;
+        }
+
+        self.speed_percent
+    }
+
+    // Forgets anything the time stretcher has buffered from the old track, or from before a seek
+    fn reset_time_stretch(&mut self) {
+        #[cfg(feature = "time-stretch")]
+        self.time_stretch.reset();
+    }
+
+    pub fn speed_percent(&self) -> u16 {
+        self.speed_percent
+    }
+
+    // Position in the current track that is being heard, the decoder runs ahead of the output by latency_ms
+    pub fn heard_position_ms(&self, latency_ms: u32) -> Option<u32> {
+        let decoder = self.decoder.as_ref()?;
+        let latency_ms = latency_ms as u64 * self.speed_percent as u64 / wav::NORMAL_SPEED_PERCENT as u64;
+        Some(decoder.position_ms().saturating_sub(latency_ms as u32))
+    }
+
+    // The playlist track and the position in it that is being heard, to save so playback can be resumed after a reset
+    // None for a queued track, the queue isn't saved so it couldn't be found again
+    pub fn resume_record(&self, latency_ms: u32) -> Option<ResumeRecord> {
+        let track = self.playlist.current().filter(|_| !self.playing_queued)?;
+        let decoder = self.decoder.as_ref()?;
+        let frame = self.heard_position_ms(latency_ms)? as u64 * decoder.sample_rate() as u64 / 1000;
+
+        Some(ResumeRecord {
+            first_cluster: track.first_cluster,
+            length: track.valid_data_length as u32,
+            data_offset: decoder.data_offset(frame as u32),
+        })
+    }
+
+    // Plays the track in the record from the saved position, NoTrack if the track isn't in the playlist any more
+    // If the position can't be found, e.g. the file has been changed, the track is played from the beginning
+    pub fn resume_from<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, record: &ResumeRecord) -> Result<(), WavError> {
+        let indx = self.find_track(record.first_cluster, record.length).ok_or(WavError::NoTrack)?;
+        self.play_indx(exfat, indx)?;
+
+        if let Some(decoder) = self.decoder.as_mut() {
+            if decoder.seek_to_data_offset(exfat, record.data_offset).is_err() {
+                rprintln!("Couldn't resume from byte {}, playing from the beginning", record.data_offset);
+                decoder.seek_to_ms(exfat, 0).map_err(|_| WavError::ReadFail)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Marks the start of an A-B loop at what is being heard now, the loop starts once the end is marked
+    pub fn mark_loop_start(&mut self, latency_ms: u32) -> Option<u32> {
+        self.loop_start_ms = self.heard_position_ms(latency_ms);
+        self.loop_start_ms
+    }
+
+    // Marks the end of the A-B loop at what is being heard now, and starts looping
+    // Returns the start and end, or Err if the start hasn't been marked or isn't before the end
+    pub fn mark_loop_end(&mut self, latency_ms: u32) -> Result<(u32, u32), ()> {
+        let start_ms = self.loop_start_ms.ok_or(())?;
+        let end_ms = self.heard_position_ms(latency_ms).ok_or(())?;
+        self.set_ab_loop(start_ms, end_ms)?;
+        Ok((start_ms, end_ms))
+    }
+
+    // Loops the current track between two positions, the loop is cleared when the track changes
+    // The audio already buffered past the end still plays, then the track jumps back without a gap
+    pub fn set_ab_loop(&mut self, start_ms: u32, end_ms: u32) -> Result<(), ()> {
+        self.decoder.as_mut().ok_or(())?.set_ab_loop(start_ms, end_ms)
+    }
+
+    pub fn clear_ab_loop(&mut self) {
+        self.loop_start_ms = None;
+        if let Some(decoder) = self.decoder.as_mut() {
+            decoder.clear_ab_loop();
+        }
+    }
+
+    // Sets the sample rate of the output, the tracks are resampled to it if they are different
+    pub fn set_output_sample_rate(&mut self, output_sample_rate: u32) {
+        self.output_sample_rate = output_sample_rate;
+        self.dc_blocker.set_sample_rate(output_sample_rate);
+        self.declicker.set_frames(DECLICK_MS * output_sample_rate / 1000);
+        self.eq.set_sample_rate(output_sample_rate);
+
+        // The first track is opened before the output sample rate is known, so its fade in starts now
+        if let Some(decoder) = self.decoder.as_mut() {
+            prepare_output(decoder, output_sample_rate);
+            self.fade.fade_in(self.fade_frames());
+        }
+    }
+
+    // The sample rate of a track that has just been opened, if it is different to the output sample rate
+    // The output can then be changed to it before the track is filled, so it doesn't have to be resampled
+    // Tracks that take over from a crossfade aren't returned, and while paused this waits until playback resumes
+    pub fn take_output_rate_change(&mut self) -> Option<u32> {
+        if self.is_paused() || !core::mem::take(&mut self.rate_change_pending) {
+            return None;
+        }
+        let file_rate = self.decoder.as_ref()?.sample_rate();
+        (file_rate != self.output_sample_rate).then_some(file_rate)
+    }
+
+    // Fills buf with the next samples to play, with any sounds from the mixer on top
+    // Nothing is filled once paused, so the mixer can't be heard then
+    pub fn fill<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16], fill_budget: &mut FillBudget) {
+        self.fill_music(exfat, buf, fill_budget);
+
+        // The music is turned down while a sound plays over it, and ramps back up after the buffer the sound ends in
+        let duck_to = if self.mixer.is_playing() { self.duck_gain } else { volume::UNITY_GAIN };
+        if self.duck.target() != duck_to {
+            self.duck.ramp_to(duck_to, DUCK_RAMP_MS * self.output_sample_rate / 1000);
+        }
+        self.duck.apply(buf);
+
+        if self.mixer.is_playing() {
+            self.mixer.mix(buf);
+            fill_budget.checkpoint("mixer");
+        }
+
+        // The declicker ramps from the last frame as it was played, so it has to run after the routing
+        self.routing.apply(buf);
+        self.declicker.apply(buf);
+        fill_budget.checkpoint("declick");
+
+        self.meter.measure(buf);
+        fill_budget.checkpoint("meter");
+    }
+
+    // Fills buf with the music and mixer_buf with the sounds from the mixer on their own, for a second output (see second_output.rs)
+    // The music isn't ducked, the sounds aren't played over it
+    #[cfg(feature = "dual-i2s")]
+    pub fn fill_split<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16], mixer_buf: &mut [u16], fill_budget: &mut FillBudget) {
+        self.fill_music(exfat, buf, fill_budget);
+
+        mixer_buf.fill(0);
+        if self.mixer.is_playing() {
+            self.mixer.mix(mixer_buf);
+            fill_budget.checkpoint("mixer");
+        }
+
+        self.routing.apply(buf);
+        self.declicker.apply(buf);
+        fill_budget.checkpoint("declick");
+
+        self.meter.measure(buf);
+        fill_budget.checkpoint("meter");
+    }
+
+    // Call when the audio that was filled after last_frame won't be played, e.g. when the output has been stopped and started again
+    // The next buffer is ramped from last_frame, so the jump doesn't click
+    pub fn jump_from(&mut self, last_frame: [u16; 2]) {
+        self.declicker.jump_from(last_frame);
+    }
+
+    // Throws away the audio from the old track that is waiting to be played, so a new track starts as soon as possible
+    // The declicker ramps from the last audio that will be played, so the jump doesn't click
+    // The second output's buffers are thrown away as well so the outputs stay in step, including any sounds from the mixer
+    fn abandon_buffered(&mut self) {
+        crate::G_RING.abandon_filled();
+        #[cfg(feature = "dual-i2s")]
+        crate::second_output::G_SECOND_RING.abandon_filled();
+        self.jump_from(crate::G_RING.last_filled_frame());
+    }
+
+    // Position in the playlist of the track with this first cluster and length, the same way resume records identify tracks
+    fn find_track(&self, first_cluster: u32, length: u32) -> Option<usize> {
+        (0..self.playlist.len()).find(|indx| self.playlist.get(*indx)
+            .is_some_and(|track| track.first_cluster == first_cluster && track.valid_data_length as u32 == length))
+    }
+
+    // Turns the music down by db (e.g. -12.0) while the mixer plays a sound, so announcements can be heard over it
+    // 0 dB leaves the music alone
+    pub fn set_ducking(&mut self, db: f32) {
+        self.duck_gain = (eq::pow10(db.min(0.0) / 20.0) * volume::UNITY_GAIN as f32) as i32;
+    }
+
+    // Peak and RMS level of each channel in the last buffer that was filled
+    pub fn levels(&self) -> Levels {
+        self.meter.levels()
+    }
+
+    // Fills buf with the music
+    // This is silence during the gap between tracks, and after the end of the playlist
+    fn fill_music<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16], fill_budget: &mut FillBudget) {
+        // Play out the gap between tracks before any more audio
+        // There's nothing to fade out during silence
+        if self.track_gap.fill(buf) {
+            self.fade.finish();
+            self.update_state();
+            fill_budget.checkpoint("gap");
+            return;
+        }
+
+        // The final partial buffer has been queued, so everything after it is silence
+        if self.track_state == TrackState::Ending {
+            self.finish_track();
+        }
+
+        if self.decoder.is_none() || self.track_state != TrackState::Playing {
+            buf.fill(0);
+            self.fade.finish();
+            self.update_state();
+            return;
+        }
+
+        let mut result = self.fill_track(exfat, buf);
+
+        // Rewind to the start and carry on filling the buffer, so there is no gap in the loop
+        if let (Repeat::One, Ok(samples_filled), Some(decoder)) = (self.repeat, result, self.decoder.as_mut()) {
+            if samples_filled < buf.len() && decoder.seek_to_ms(exfat, 0).is_ok() {
+                result = self.fill_track(exfat, &mut buf[samples_filled..]).map(|rest_filled| samples_filled + rest_filled);
+            }
+        }
+
+        if result.is_err() {
+            // Skip the rest of a track that can't be read
+            buf.fill(0);
+            self.raise(PlayerEvent::ReadError);
+            self.read_failed = true;
+            rprintln!("Error at {} ms", self.decoder.as_ref().map_or(0, |decoder| decoder.position_ms()));
+        }
+        let track_finished = !matches!(result, Ok(samples_filled) if samples_filled == buf.len());
+
+        // Mix the start of the next track into the end of this one
+        if let Some(incoming) = self.incoming.as_mut() {
+            self.crossfade_out.apply(buf);
+
+            let mix_buf = &mut self.mix_buf[..buf.len().min(crate::BUF_SIZE)];
+            match incoming.fill(exfat, mix_buf) {
+                Ok(_) => {
+                    // Volume applies the gain of the current track, so the next track is scaled to its own gain first
+                    let relative_gain = (track_gain(incoming) as i64 * volume::UNITY_GAIN as i64)
+                        .checked_div(self.volume.track_gain() as i64).unwrap_or(volume::UNITY_GAIN as i64);
+                    volume::apply_gain(mix_buf, relative_gain as i32);
+                    self.crossfade_in.apply(mix_buf);
+                    for (sample, mix_sample) in buf.iter_mut().zip(mix_buf.iter()) {
+                        *sample = limiter::soft_clip(*sample as i16 as i32 + *mix_sample as i16 as i32) as u16;
+                    }
+                },
+                Err(()) => rprintln!("Error crossfading at {} ms", incoming.position_ms()),
+            }
+
+            // The next track takes over once this one has faded out
+            if track_finished || self.crossfade_out.is_silent() {
+                self.volume.set_track_gain(track_gain(incoming));
+                self.raise(self.finished_event());
+                self.decoder = self.incoming.take();
+                self.loop_start_ms = None;
+                self.reset_time_stretch();
+                if self.incoming_queued {
+                    self.queue.pop_front();
+                } else {
+                    self.advance();
+                }
+                self.started(self.incoming_queued);
+                self.crossfade_tried = false;
+                self.rate_change_pending = false; // It's already being heard, so it carries on being resampled
+                rprintln!("Crossfaded to the next track");
+            }
+        } else if track_finished {
+            // The rest of the buffer is silence, the track is finished after it and the gap have played
+            if result.is_ok() {
+                self.track_state = TrackState::Ending;
+                self.track_gap.schedule(crate::INTER_TRACK_GAP_MS, self.output_sample_rate, (buf.len() / 2) as u32);
+            } else {
+                self.finish_track();
+            }
+        }
+
+        fill_budget.checkpoint("wav");
+
+        self.dc_blocker.apply(buf);
+        fill_budget.checkpoint("dc blocker");
+
+        self.eq.apply(buf);
+        fill_budget.checkpoint("eq");
+
+        self.volume.apply(buf);
+        self.fade.apply(buf);
+        self.mute.apply(buf);
+        self.update_state();
+        fill_budget.checkpoint("gain");
+    }
+
+    // Fills buf from the current track, through the time stretcher if it is on
+    fn fill_track<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16]) -> Result<usize, ()> {
+        let decoder = self.decoder.as_mut().ok_or(())?;
+
+        #[cfg(feature = "time-stretch")]
+        if self.time_stretch.is_active() {
+            return self.time_stretch.fill(buf, |input| decoder.fill(exfat, input));
+        }
+
+        decoder.fill(exfat, buf)
+    }
+
+    // Switches to silence and raises the event for poll
+    fn finish_track(&mut self) {
+        self.track_state = TrackState::Finished;
+        self.raise(self.finished_event());
+    }
+
+    // Records that a track has been opened, from the queue or from the playlist
+    fn started(&mut self, queued: bool) {
+        self.playing_queued = queued;
+        self.rate_change_pending = true;
+        self.raise(match queued {
+            true => PlayerEvent::QueuedTrackStarted,
+            false => PlayerEvent::TrackStarted { indx: self.playlist.current_indx() },
+        });
+    }
+
+    // Keeps an event for poll, if poll hasn't been called for a while the newest events are dropped
+    fn raise(&mut self, event: PlayerEvent) {
+        let _ = self.events.push_back(event);
+    }
+
+    // Passes on an underrun counted by the DMA interrupt, so the hooks hear about it along with everything else
+    pub fn report_underrun(&mut self, count: u32) {
+        self.raise(PlayerEvent::Underrun { count });
+    }
+
+    fn finished_event(&self) -> PlayerEvent {
+        match self.playing_queued {
+            true => PlayerEvent::QueuedTrackFinished,
+            false => PlayerEvent::TrackFinished { indx: self.playlist.current_indx() },
+        }
+    }
+
+    // Does the work that is too slow for fill, call this from the main loop after a buffer has been filled
+    // Moves on to the next track once a track has finished
+    // Returns the oldest event that hasn't been handed out yet, after passing it to the hooks
+    pub fn poll<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Option<PlayerEvent> {
+        // A track that couldn't be read isn't skipped if the card has been taken out, so it can be resumed
+        if self.read_failed {
+            self.read_failed = false;
+            if !exfat.block_device.is_present() {
+                self.remove_card(self.resume_record(0));
+            }
+        }
+
+        // Queued tracks are played before moving on in the playlist
+        if !self.card_removed && self.track_state == TrackState::Finished && self.decoder.is_some() && !self.open_queued(exfat) {
+            self.advance();
+            if !self.open_current(exfat) && !self.card_removed {
+                self.raise(PlayerEvent::PlaylistFinished);
+            }
+        }
+
+        // Once the playlist has finished, tracks that are queued are played as they come
+        if !self.card_removed && self.decoder.is_none() && self.state == State::Playing && self.open_queued(exfat) {
+            self.track_gap = TrackGap::new();
+        }
+
+        if self.crossfade_ms > 0 && self.repeat != Repeat::One && !self.crossfade_tried
+            && self.track_state == TrackState::Playing && self.state == State::Playing {
+            if let Some(decoder) = self.decoder.as_ref() {
+                // The position is in track time, it goes by faster than real time when the speed is up
+                let remaining_ms = decoder.duration_ms().saturating_sub(decoder.position_ms()) as u64
+                    * wav::NORMAL_SPEED_PERCENT as u64 / self.speed_percent.max(1) as u64;
+                let remaining_ms = remaining_ms as u32;
+                if remaining_ms <= self.crossfade_ms {
+                    self.start_crossfade(exfat, remaining_ms);
+                }
+            }
+        }
+
+        let event = self.events.pop_front()?;
+        self.hooks.dispatch(&event);
+        Some(event)
+    }
+
+    // Forgets the tracks from the card that has gone, record is where to carry on from once it's back
+    fn remove_card(&mut self, record: Option<ResumeRecord>) {
+        self.card_removed = true;
+        self.removed_at = record;
+        self.decoder = None;
+        self.incoming = None;
+        self.queue.clear();
+        self.playing_queued = false;
+        self.track_state = TrackState::Playing;
+        self.raise(PlayerEvent::CardRemoved);
+    }
+
+    // True from a CardRemoved event until card_inserted
+    pub fn is_card_removed(&self) -> bool {
+        self.card_removed
+    }
+
+    // Carries on playing once a card is back, with the playlist read from it
+    // If it's the same card the track that was playing is resumed, otherwise the new playlist starts from the beginning
+    // Returns false if there's nothing on the card that can be played
+    pub fn card_inserted<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, playlist: Playlist) -> bool {
+        self.playlist = playlist;
+        self.card_removed = false;
+        self.crossfade_tried = false;
+
+        if let Some(record) = self.removed_at.take() {
+            if self.resume_from(exfat, &record).is_ok() {
+                return true;
+            }
+        }
+        self.open_current(exfat)
+    }
+
+    // Position in the playlist of the track after the current one
+    // None after the last track, unless the whole playlist is repeated
+    fn next_indx(&self) -> Option<usize> {
+        let next_indx = self.playlist.current_indx() + 1;
+        if next_indx < self.playlist.len() {
+            Some(next_indx)
+        } else if self.repeat == Repeat::All && !self.playlist.is_empty() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    // Moves the playlist on to the next track, wrapping around if the whole playlist is repeated
+    fn advance(&mut self) {
+        match self.next_indx() {
+            Some(next_indx) => {
+                self.playlist.select(next_indx);
+            },
+            None => {
+                self.playlist.advance();
+            },
+        }
+    }
+
+    // Opens the next track so it can be mixed in while the current track fades out over its last remaining_ms
+    // If there is no next track, or it can't be opened, the current track just plays to the end
+    fn start_crossfade<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, remaining_ms: u32) {
+        self.crossfade_tried = true;
+        self.incoming_queued = !self.queue.is_empty();
+        let next = match self.queue.front() {
+            Some(queued) => Some((&queued.track, queued.directory_cluster)),
+            None => self.next_indx().and_then(|next_indx| Some((self.playlist.get(next_indx)?, self.playlist.directory(next_indx)))),
+        };
+        let Some((track, directory_cluster)) = next else {
+            return;
+        };
+
+        match open_track(exfat, directory_cluster, track, self.output_sample_rate, self.resample_speed()) {
+            Ok(decoder) => {
+                let frames = (remaining_ms as u64 * self.output_sample_rate as u64 / 1000) as u32;
+                self.crossfade_in.fade_in(frames);
+                self.crossfade_out = Fade::new();
+                self.crossfade_out.fade_out(frames);
+                self.incoming = Some(decoder);
+            },
+            Err(error) => rprintln!("Couldn't open {} to crossfade: {:?}", track.name, error),
+        }
+    }
+}
+
+// Opens a track and gets it ready to play
+// Reads are retried, the card sometimes fails a read when it has only just been inserted
+fn open_track<T: BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, directory_cluster: u32, track: &FsEntry, output_sample_rate: u32, speed_percent: u16)
+-> Result<TrackDecoder, WavError> {
+    let mut decoder = TrackDecoder::open(exfat, track);
+    for _ in 1..OPEN_ATTEMPTS {
+        if !matches!(decoder, Err(WavError::ReadFail)) {
+            break;
+        }
+        decoder = TrackDecoder::open(exfat, track);
+    }
+    let mut decoder = decoder?;
+    rprintln!("Playing {}: {:?}", track.name, decoder);
+
+    decoder.prepare(exfat, directory_cluster, track);
+
+    if crate::REPLAY_GAIN != ReplayGainMode::Off && decoder.tags().replay_gain.is_empty() {
+        match replay_gain::read_sidecar(exfat, directory_cluster, track) {
+            Ok(replay_gain) => decoder.tags_mut().replay_gain = replay_gain,
+            Err(err) => rprintln!("Couldn't look for a ReplayGain file: {:?}", err),
+        }
+    }
+
+    let tags = decoder.tags();
+    if !tags.title.is_empty() {
+        rprintln!("Playing {} by {} from {}", tags.title, tags.artist, tags.album);
+    }
+    if !tags.replay_gain.is_empty() {
+        rprintln!("ReplayGain {:?}", tags.replay_gain);
+    }
+    if output_sample_rate != 0 {
+        prepare_output(&mut decoder, output_sample_rate);
+    }
+    if decoder.set_speed(speed_percent) != speed_percent {
+        rprintln!("Can't play this file at {} % speed", speed_percent);
+    }
+
+    Ok(decoder)
+}
+
+// Q15 loudness normalisation gain of a track, from its ReplayGain tags
+fn track_gain(decoder: &TrackDecoder) -> i32 {
+    decoder.tags().replay_gain.gain(crate::REPLAY_GAIN, crate::REPLAY_GAIN_PREAMP_DB)
+}
+
+// Sets up resampling to the output sample rate, and warns about anything that won't play correctly
+fn prepare_output(decoder: &mut TrackDecoder, output_sample_rate: u32) {
+    if decoder.set_output_sample_rate(output_sample_rate) {
+        rprintln!("Resampling from {} Hz to {} Hz", decoder.sample_rate(), output_sample_rate);
+    }
+    if let Some(unsupported) = decoder.probe(output_sample_rate).unsupported {
+        rprintln!("This file won't play correctly: {} ({:?}), needs {}", unsupported.reason(), unsupported, unsupported.needs());
+    }
+}
+
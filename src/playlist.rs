@@ -0,0 +1,238 @@
+// Ordered list of the tracks to play, e.g. every wav file in a directory
+// The player works through the list from the first track, see player.rs
+//
+// The tracks are played in the order of order, which holds indexes into tracks
+// Normally this is the order the tracks were added in, shuffling reorders the tracks that haven't been played yet
+// Indexes given to and returned from the playlist are positions in the play order
+//
+// A playlist can also be made from a folder and the folders inside it (from_folder), like the album folders of a portable player
+// Each folder's tracks are played in directory order, followed by each of its subfolders in turn
+
+use heapless::Vec;
+
+use crate::block_device::BlockDevice;
+use crate::exfat::{ExFat, FileType, FsEntry, FsError};
+use crate::helpers::Xorshift32;
+
+use crate::BLOCK_SIZE;
+
+// Each track is a whole FsEntry (about 300 bytes), so this is kept fairly small
+pub const MAX_TRACKS: usize = 32;
+
+// Folders waiting to be searched by from_folder, this limits how deeply folders can be nested
+const MAX_FOLDERS: usize = 16;
+
+// Files that can be opened by the player's TrackDecoder, compared without case
+// .pcm and .raw files are raw samples in crate::RAW_PCM_FORMAT, see raw_pcm.rs
+const TRACK_EXTENSIONS: [&str; 7] = [".wav", ".wave", ".aif", ".aiff", ".aifc", ".pcm", ".raw"];
+
+// macOS leaves a hidden ._ file next to every file it copies, these aren't audio
+const HIDDEN_FILE_PREFIX: &str = "._";
+
+// The first part of a split file (track.wav.001) is played, the other parts are appended to it
+pub const FIRST_PART_SUFFIX: &str = ".001";
+
+// A track to play before the playlist carries on, see Player::enqueue
+// Queued tracks don't have to be in the playlist directory, so each keeps the directory its split parts are in
+#[derive(Debug, Clone)]
+pub struct QueuedTrack {
+    pub track: FsEntry,
+    pub directory_cluster: u32,
+}
+
+#[derive(Debug)]
+pub struct Playlist {
+    pub directory_cluster: u32, // The directory the playlist was made from
+    tracks: Vec<FsEntry, MAX_TRACKS>,
+    directories: Vec<u32, MAX_TRACKS>, // The directory each track is in, split files are joined with other files from here
+    order: Vec<u8, MAX_TRACKS>, // Index in tracks of each track, in the order they are played
+    current: usize, // Position of the track being played, tracks.len() once the end has been reached
+    shuffled: bool,
+}
+
+impl Playlist {
+    pub fn new(directory_cluster: u32) -> Self {
+        Playlist {
+            directory_cluster,
+            tracks: Vec::new(),
+            directories: Vec::new(),
+            order: Vec::new(),
+            current: 0,
+            shuffled: false,
+        }
+    }
+
+    // Adds the files in a directory listing which look like tracks, in directory order
+    // Files past MAX_TRACKS are left out
+    pub fn from_directory(directory_cluster: u32, directory: &[FsEntry]) -> Self {
+        let mut playlist = Playlist::new(directory_cluster);
+
+        for fs_entry in directory.iter().filter(|fs_entry| is_track(fs_entry)) {
+            if playlist.push(fs_entry.clone()).is_err() {
+                break;
+            }
+        }
+
+        playlist
+    }
+
+    // Adds the tracks in a folder, and if subfolders is set the tracks in every folder inside it
+    // Tracks past MAX_TRACKS, and folders nested too deeply to search, are left out
+    pub fn from_folder<T: BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, directory_cluster: u32, subfolders: bool)
+    -> Result<Self, FsError> {
+        let mut playlist = Playlist::new(directory_cluster);
+        let mut folders: Vec<u32, MAX_FOLDERS> = Vec::new();
+        let _ = folders.push(directory_cluster);
+
+        let mut skipped_entries = 0;
+        while let Some(folder_cluster) = folders.pop() {
+            let mut inner_folders: Vec<u32, MAX_FOLDERS> = Vec::new();
+            exfat.for_each_entry(folder_cluster, |fs_entry| {
+                let added = match fs_entry.file_type {
+                    FileType::Directory if subfolders => inner_folders.push(fs_entry.first_cluster).is_ok(),
+                    FileType::Directory => true,
+                    FileType::File if is_track(&fs_entry) => playlist.push_in(fs_entry, folder_cluster).is_ok(),
+                    FileType::File => true,
+                };
+                if !added {
+                    skipped_entries += 1;
+                }
+            })?;
+
+            // The folders are taken off the end, so the first folder has to be put on last
+            for inner_cluster in inner_folders.iter().rev() {
+                if folders.push(*inner_cluster).is_err() {
+                    skipped_entries += 1;
+                }
+            }
+        }
+
+        if skipped_entries > 0 {
+            crate::rprintln!("Too many entries, {} tracks or folders were left out of the playlist", skipped_entries);
+        }
+        Ok(playlist)
+    }
+
+    // Adds a track to the end of the playlist, fails if the playlist is full
+    pub fn push(&mut self, track: FsEntry) -> Result<(), ()> {
+        self.push_in(track, self.directory_cluster)
+    }
+
+    // Adds a track which is in the directory at directory_cluster
+    pub fn push_in(&mut self, track: FsEntry, directory_cluster: u32) -> Result<(), ()> {
+        let indx = self.tracks.len() as u8;
+        if self.tracks.is_full() {
+            return Err(());
+        }
+        self.tracks.push(track).map_err(|_| ())?;
+        self.directories.push(directory_cluster).map_err(|_| ())?;
+        self.order.push(indx).map_err(|_| ())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    // The track being played, None once the end of the playlist has been reached
+    pub fn current(&self) -> Option<&FsEntry> {
+        self.get(self.current)
+    }
+
+    pub fn get(&self, indx: usize) -> Option<&FsEntry> {
+        self.tracks.get(*self.order.get(indx)? as usize)
+    }
+
+    // The directory the track at indx is in, or the directory of the playlist if there is no track at indx
+    pub fn directory(&self, indx: usize) -> u32 {
+        self.order.get(indx)
+            .and_then(|track_indx| self.directories.get(*track_indx as usize))
+            .copied()
+            .unwrap_or(self.directory_cluster)
+    }
+
+    // Makes the track at indx the current track, returns None if there is no track at indx
+    pub fn select(&mut self, indx: usize) -> Option<&FsEntry> {
+        if indx >= self.tracks.len() {
+            return None;
+        }
+
+        self.current = indx;
+        self.current()
+    }
+
+    pub fn current_indx(&self) -> usize {
+        self.current
+    }
+
+    // Moves on to the next track, returns None at the end of the playlist
+    pub fn advance(&mut self) -> Option<&FsEntry> {
+        self.current = (self.current + 1).min(self.tracks.len());
+        self.current()
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffled
+    }
+
+    // Plays the tracks after the current one in a random order, each track is still played once
+    // The tracks that have already been played keep their place, so previous goes back through them
+    pub fn shuffle(&mut self, rng: &mut Xorshift32) {
+        let first = (self.current + 1).min(self.order.len());
+        self.shuffle_from(first, rng);
+    }
+
+    // Shuffles every track and starts again from the first track, for before anything has been played
+    pub fn shuffle_all(&mut self, rng: &mut Xorshift32) {
+        self.current = 0;
+        self.shuffle_from(0, rng);
+    }
+
+    // Fisher-Yates shuffle of the tracks from position first to the end
+    fn shuffle_from(&mut self, first: usize, rng: &mut Xorshift32) {
+        self.shuffled = true;
+
+        let unplayed = &mut self.order[first..];
+        for i in (1..unplayed.len()).rev() {
+            let j = rng.below(i as u32 + 1) as usize;
+            unplayed.swap(i, j);
+        }
+    }
+
+    // Goes back to playing the tracks in the order they were added, carrying on from the current track
+    pub fn unshuffle(&mut self) {
+        self.shuffled = false;
+
+        let current_track = self.order.get(self.current).copied();
+        for (position, indx) in self.order.iter_mut().enumerate() {
+            *indx = position as u8;
+        }
+        if let Some(current_track) = current_track {
+            self.current = current_track as usize;
+        }
+    }
+}
+
+// True for files which should be played as tracks
+pub fn is_track(fs_entry: &FsEntry) -> bool {
+    if let FileType::Directory = fs_entry.file_type {
+        return false;
+    }
+
+    let name = fs_entry.name.as_str();
+    if name.starts_with(HIDDEN_FILE_PREFIX) {
+        return false;
+    }
+
+    let name = name.strip_suffix(FIRST_PART_SUFFIX).unwrap_or(name);
+    TRACK_EXTENSIONS.iter().any(|extension| ends_with_ignore_case(name, extension))
+}
+
+pub fn ends_with_ignore_case(name: &str, suffix: &str) -> bool {
+    name.len() >= suffix.len()
+        && name.is_char_boundary(name.len() - suffix.len())
+        && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+}
@@ -0,0 +1,46 @@
+// Read only block device over a disk image in memory, e.g. one linked into the firmware
+// With the demo feature this is used instead of the sd card, so a bare board can still play a sample track
+// Any canned exFAT image can be read through it, so the filesystem and WAV parsing can be tried without a card
+//
+// The image is a raw exFAT image with 512 byte sectors, e.g. made with mkfs.exfat on a small file
+// A partial block at the end of the image can't be read
+//
+// There is no host test that mounts an image through this, the firmware is one no_std binary for thumbv7em
+// and exfat.rs and wav.rs depend on the HAL and RTT through the rest of the crate, so they can't be built for the host
+// That needs the filesystem and decoders moved into their own crate, until then the demo feature is the way to try an image
+
+use crate::block_device::BlockDevice;
+use crate::BLOCK_SIZE;
+
+pub struct RamBlockDevice {
+    image: &'static [u8],
+}
+
+impl RamBlockDevice {
+    pub fn new(image: &'static [u8]) -> Self {
+        RamBlockDevice { image }
+    }
+
+    pub fn block_count(&self) -> u32 {
+        (self.image.len() / BLOCK_SIZE) as u32
+    }
+}
+
+impl BlockDevice<BLOCK_SIZE> for RamBlockDevice {
+    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+        let start = blockaddr as usize * BLOCK_SIZE;
+        let image_block = self.image.get(start..start + BLOCK_SIZE).ok_or(())?;
+
+        block.copy_from_slice(image_block);
+        Ok(())
+    }
+
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), ()> {
+        let dest = blocks.as_flattened_mut();
+        let start = blockaddr as usize * BLOCK_SIZE;
+        let image_blocks = self.image.get(start..start + dest.len()).ok_or(())?;
+
+        dest.copy_from_slice(image_blocks);
+        Ok(())
+    }
+}
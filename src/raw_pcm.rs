@@ -0,0 +1,123 @@
+// Decoder for headerless .pcm and .raw files, e.g. sound effects exported straight from an audio tool
+// There is nothing in the file saying how the samples are stored, so the format has to come from the caller,
+// the player opens them with crate::RAW_PCM_FORMAT
+// The samples are decoded by WavFile, as if the whole file was the data chunk of a wav file
+
+use crate::block_device::BlockDevice;
+use crate::decoder::Decoder;
+use crate::exfat::{ExFat, FsEntry};
+use crate::playlist;
+use crate::wav::{Format, ProbeReport, Tags, WavError, WavFile};
+
+use crate::BLOCK_SIZE;
+
+// Extensions of the files that are played as raw samples, compared without case
+pub const RAW_PCM_EXTENSIONS: [&str; 2] = [".pcm", ".raw"];
+
+// How the samples in a raw file are stored, channels are interleaved
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RawPcmFormat {
+    pub format: Format, // Pcm, IeeeFloat, Alaw, or Mulaw
+    pub sample_rate: u32,
+    pub n_channels: u16,
+    pub bits_per_sample: u16,
+    pub big_endian: bool, // Byte order of multi byte samples
+}
+
+#[derive(Debug)]
+pub struct RawPcmFile {
+    pub raw_format: RawPcmFormat,
+    samples: WavFile,
+}
+
+impl RawPcmFile {
+    // Returns WavError::Unsupported if WavFile can't decode samples in raw_format
+    pub fn new<T: BlockDevice<BLOCK_SIZE>>(exfat: &ExFat<T>, file: &FsEntry, raw_format: RawPcmFormat) -> Result<Self, WavError> {
+        Ok(RawPcmFile {
+            raw_format,
+            samples: WavFile::from_raw_pcm(exfat, file, &raw_format)?,
+        })
+    }
+
+    // The WavFile that decodes the samples, it also does the looping, speed changes, and silence skipping
+    pub fn samples_mut(&mut self) -> &mut WavFile {
+        &mut self.samples
+    }
+}
+
+// True if the file should be played as raw samples, from its extension
+// The first part of a split file is played as the file it was split from
+pub fn is_raw_pcm(file: &FsEntry) -> bool {
+    let name = file.name.as_str();
+    let name = name.strip_suffix(playlist::FIRST_PART_SUFFIX).unwrap_or(name);
+    RAW_PCM_EXTENSIONS.iter().any(|extension| playlist::ends_with_ignore_case(name, extension))
+}
+
+impl Decoder for RawPcmFile {
+    type Error = WavError;
+
+    fn open<T: BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<Self, WavError> {
+        RawPcmFile::new(exfat, file, crate::RAW_PCM_FORMAT)
+    }
+
+    fn probe(&self, output_sample_rate: u32) -> ProbeReport {
+        self.samples.probe(output_sample_rate)
+    }
+
+    fn set_output_sample_rate(&mut self, output_sample_rate: u32) -> bool {
+        self.samples.set_output_sample_rate(output_sample_rate)
+    }
+
+    fn fill<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16]) -> Result<usize, ()> {
+        self.samples.fill_samples(exfat, buf)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.raw_format.sample_rate
+    }
+
+    fn n_channels(&self) -> u16 {
+        self.raw_format.n_channels
+    }
+
+    fn seek_to_ms<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, ms: u32) -> Result<(), ()> {
+        self.samples.seek_to_ms(exfat, ms)
+    }
+
+    fn position_ms(&self) -> u32 {
+        self.samples.position_ms()
+    }
+
+    fn duration_ms(&self) -> u32 {
+        self.samples.duration_ms()
+    }
+
+    fn set_speed(&mut self, speed_percent: u16) -> u16 {
+        self.samples.set_speed(speed_percent)
+    }
+
+    fn set_ab_loop(&mut self, start_ms: u32, end_ms: u32) -> Result<(), ()> {
+        self.samples.set_ab_loop(start_ms, end_ms)
+    }
+
+    fn clear_ab_loop(&mut self) {
+        self.samples.clear_ab_loop()
+    }
+
+    fn data_offset(&self, frame: u32) -> u64 {
+        self.samples.data_offset(frame)
+    }
+
+    fn seek_to_data_offset<T: BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, offset: u64) -> Result<(), ()> {
+        self.samples.seek_to_data_offset(exfat, offset)
+    }
+
+    // A raw file has nothing but samples, so only the ReplayGain from a sidecar file can be set
+    fn tags(&self) -> &Tags {
+        &self.samples.tags
+    }
+
+    fn tags_mut(&mut self) -> &mut Tags {
+        &mut self.samples.tags
+    }
+}
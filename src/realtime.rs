@@ -0,0 +1,154 @@
+// Debug instrumentation for the soft real-time guarantee of the buffer fill path
+//
+// While one half of the double buffer is playing the CPU has to fill the other half
+// The time between claiming an Empty buffer and marking it Filled must be less than the time
+// it takes to play one buffer, otherwise the DMA runs out of audio and plays silence
+//
+// In debug builds FillBudget measures each fill with the DWT cycle counter and asserts it stayed within budget
+// Each stage of the fill path is timed separately so a failed assert names the module that used the most time
+// In release builds all of this compiles down to nothing
+// With the no-panic feature an overrun is printed instead of asserted
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+use heapless::Vec;
+
+#[cfg(feature = "no-panic")]
+use crate::rprintln;
+
+const MAX_STAGES: usize = 8;
+
+#[derive(Debug)]
+struct Stage {
+    module: &'static str,
+    cycles: u32,
+}
+
+#[derive(Debug)]
+pub struct FillBudget {
+    budget_cycles: u32, // Cycles it takes to play one buffer
+    start: u32,
+    last_checkpoint: u32,
+    stages: Vec<Stage, MAX_STAGES>,
+}
+
+impl FillBudget {
+
+    // Calculates the budget from the length of a buffer in stereo frames
+    // The DWT cycle counter must be enabled for the measurements to mean anything
+    pub fn new(buffer_frames: u32, sample_rate: u32, clock_hz: u32) -> Self {
+        let budget_cycles = (buffer_frames as u64 * clock_hz as u64 / sample_rate as u64) as u32;
+
+        FillBudget {
+            budget_cycles,
+            start: 0,
+            last_checkpoint: 0,
+            stages: Vec::new(),
+        }
+    }
+
+    // Call when an Empty buffer has been claimed
+    pub fn start(&mut self) {
+        if cfg!(debug_assertions) {
+            self.stages.clear();
+            self.start = DWT::cycle_count();
+            self.last_checkpoint = self.start;
+        }
+    }
+
+    // Call after a stage of the fill path has run
+    // The cycles since the last checkpoint are added to the total for this module
+    pub fn checkpoint(&mut self, module: &'static str) {
+        if cfg!(debug_assertions) {
+            let now = DWT::cycle_count();
+            let cycles = now.wrapping_sub(self.last_checkpoint);
+            self.last_checkpoint = now;
+
+            if let Some(stage) = self.stages.iter_mut().find(|stage| stage.module == module) {
+                stage.cycles += cycles;
+            } else {
+                let _ = self.stages.push(Stage { module, cycles });
+            }
+        }
+    }
+
+    // Call when the buffer has been marked Filled
+    // Panics in debug builds if the fill took longer than the buffer takes to play
+    pub fn finish(&self) {
+        if cfg!(debug_assertions) {
+            let total = DWT::cycle_count().wrapping_sub(self.start);
+            if total <= self.budget_cycles {
+                return;
+            }
+
+            let slowest = self.stages.iter().max_by_key(|stage| stage.cycles);
+            let (module, cycles) = slowest.map(|stage| (stage.module, stage.cycles)).unwrap_or(("unknown", 0));
+
+            #[cfg(feature = "no-panic")]
+            rprintln!(
+                "Buffer fill took {} cycles but the budget is {} cycles, slowest module: {} ({} cycles)",
+                total, self.budget_cycles, module, cycles
+            );
+
+            #[cfg(not(feature = "no-panic"))]
+            panic!(
+                "Buffer fill took {} cycles but the budget is {} cycles, slowest module: {} ({} cycles)",
+                total, self.budget_cycles, module, cycles
+            );
+        }
+    }
+}
+
+// Counts the times the DMA ran out of audio and the ISR had to play the silence buffer
+// Useful when tuning BUF_BLOCKS or the sd card clock, the fill budget only catches overruns in debug builds
+// The ISR records underruns, everything else can read them from the main loop
+#[derive(Debug)]
+pub struct UnderrunCounter {
+    count: AtomicU32,
+    last_transfer: AtomicU32, // Number of the DMA transfer that last underran
+    expected: AtomicBool, // Silence is being played on purpose, e.g. while paused
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UnderrunStats {
+    pub count: u32,
+    pub last_transfer: u32, // Multiply by the buffer length for the time since playback started
+}
+
+impl UnderrunCounter {
+    pub const fn new() -> Self {
+        UnderrunCounter {
+            count: AtomicU32::new(0),
+            last_transfer: AtomicU32::new(0),
+            expected: AtomicBool::new(false),
+        }
+    }
+
+    // Called by the ISR when there was no Filled buffer, transfer is the number of transfers completed so far
+    pub fn record(&self, transfer: u32) {
+        if self.expected.load(Ordering::Relaxed) {
+            return;
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.last_transfer.store(transfer, Ordering::Relaxed);
+    }
+
+    // While set the silence buffer isn't counted as an underrun
+    pub fn set_expected(&self, expected: bool) {
+        self.expected.store(expected, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> UnderrunStats {
+        UnderrunStats {
+            count: self.count.load(Ordering::Relaxed),
+            last_transfer: self.last_transfer.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for UnderrunCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,169 @@
+// ReplayGain loudness normalisation, so quiet and loud tracks play at about the same volume
+// Useful resource: https://wiki.hydrogenaud.io/index.php?title=ReplayGain_specification
+//
+// A tagger measures each track (and each album) and stores the gain that brings it to a reference loudness
+// The gain can come from three places, the first one found is used:
+//   an rgad chunk in the wav file, which is the ReplayGain adjustment chunk some wav taggers write
+//   TXXX frames (REPLAYGAIN_TRACK_GAIN etc.) in an id3 chunk
+//   a sidecar text file next to the track, named like the track with .rg on the end (track.wav.rg)
+// The sidecar has one field on each line, in the same form as the tags:
+//   REPLAYGAIN_TRACK_GAIN=-6.54 dB
+//   REPLAYGAIN_TRACK_PEAK=0.988
+//
+// The gain is applied by Volume with the volume and balance
+// If the peak is known the gain is limited so the loudest sample doesn't clip
+
+use heapless::String;
+
+use crate::block_device::BlockDevice;
+use crate::exfat::{ExFat, FileType, FsEntry, FsError, MAX_FILE_NAME_LENGTH};
+use crate::volume::UNITY_GAIN;
+
+const SECTOR_SIZE: usize = crate::BLOCK_SIZE;
+
+const SIDECAR_EXTENSION: &str = ".rg";
+
+// Taggers don't store gains outside of this, anything else is a broken tag
+const MAX_GAIN_DB: f32 = 24.0;
+
+// Fields of the rgad chunk, each gain field is a name, who set it, and the gain in tenths of a dB
+const RGAD_NAME_SHIFT: u16 = 13;
+const RGAD_SIGN_BIT: u16 = 1 << 9;
+const RGAD_VALUE_MASK: u16 = 0x1FF;
+const RGAD_NAME_TRACK: u16 = 1; // Called radio gain in the chunk
+const RGAD_NAME_ALBUM: u16 = 2; // Called audiophile gain in the chunk
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayGainMode {
+    Off,
+    Track, // Every track plays at the same loudness
+    Album, // Albums play at the same loudness, the tracks of an album keep their levels, tracks without an album gain use their track gain
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain_db: Option<f32>,
+    pub track_peak: Option<f32>, // Loudest sample of the track, 1.0 is full scale
+    pub album_gain_db: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    pub fn is_empty(&self) -> bool {
+        self.track_gain_db.is_none() && self.album_gain_db.is_none()
+    }
+
+    // Sets a field from a tag name and its text, names that aren't ReplayGain fields are ignored
+    pub fn set_field(&mut self, name: &str, value: &str) {
+        let name = name.trim();
+        let field = if name.eq_ignore_ascii_case("REPLAYGAIN_TRACK_GAIN") {
+            &mut self.track_gain_db
+        } else if name.eq_ignore_ascii_case("REPLAYGAIN_TRACK_PEAK") {
+            &mut self.track_peak
+        } else if name.eq_ignore_ascii_case("REPLAYGAIN_ALBUM_GAIN") {
+            &mut self.album_gain_db
+        } else if name.eq_ignore_ascii_case("REPLAYGAIN_ALBUM_PEAK") {
+            &mut self.album_peak
+        } else {
+            return;
+        };
+
+        if let Some(value) = parse_number(value) {
+            *field = Some(value);
+        }
+    }
+
+    // Parses a line of a sidecar file, NAME=value
+    pub fn parse_line(&mut self, line: &str) {
+        if let Some((name, value)) = line.split_once('=') {
+            self.set_field(name, value);
+        }
+    }
+
+    // Reads the gains from an rgad chunk, rgad_data is the 8 bytes of chunk data
+    pub fn read_rgad(&mut self, rgad_data: [u8; 8]) {
+        let peak = f32::from_le_bytes([rgad_data[0], rgad_data[1], rgad_data[2], rgad_data[3]]);
+        for field in [u16::from_le_bytes([rgad_data[4], rgad_data[5]]), u16::from_le_bytes([rgad_data[6], rgad_data[7]])] {
+            let gain_db = (field & RGAD_VALUE_MASK) as f32 / 10.0;
+            let gain_db = if field & RGAD_SIGN_BIT != 0 { -gain_db } else { gain_db };
+            match field >> RGAD_NAME_SHIFT {
+                RGAD_NAME_TRACK => self.track_gain_db = Some(gain_db),
+                RGAD_NAME_ALBUM => self.album_gain_db = Some(gain_db),
+                _ => (),
+            }
+        }
+
+        // The chunk has one peak, for the track
+        if peak > 0.0 && !self.is_empty() {
+            self.track_peak = Some(peak);
+        }
+    }
+
+    // Q15 gain for the mode, with preamp_db added on
+    // Tracks without a gain play at unity gain, without the preamp
+    pub fn gain(&self, mode: ReplayGainMode, preamp_db: f32) -> i32 {
+        let track = (self.track_gain_db, self.track_peak);
+        let (gain_db, peak) = match mode {
+            ReplayGainMode::Off => return UNITY_GAIN,
+            ReplayGainMode::Track => track,
+            ReplayGainMode::Album => match self.album_gain_db {
+                Some(album_gain_db) => (Some(album_gain_db), self.album_peak),
+                None => track,
+            },
+        };
+        let Some(gain_db) = gain_db else {
+            return UNITY_GAIN;
+        };
+
+        let gain_db = (gain_db + preamp_db).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+        let mut gain = crate::eq::pow10(gain_db / 20.0);
+        if let Some(peak) = peak.filter(|peak| *peak > 0.0) {
+            gain = gain.min(1.0 / peak);
+        }
+        (gain * UNITY_GAIN as f32) as i32
+    }
+}
+
+// Looks for the sidecar of track in its directory and reads it
+// Returns the empty ReplayGain if there isn't one
+pub fn read_sidecar<T: BlockDevice<SECTOR_SIZE>>(exfat: &mut ExFat<T>, directory_cluster: u32, track: &FsEntry)
+-> Result<ReplayGain, FsError> {
+    let mut replay_gain = ReplayGain::default();
+
+    let mut sidecar_name: String<MAX_FILE_NAME_LENGTH> = String::new();
+    if sidecar_name.push_str(track.name.as_str()).is_err() || sidecar_name.push_str(SIDECAR_EXTENSION).is_err() {
+        return Ok(replay_gain);
+    }
+
+    let mut sidecar = None;
+    exfat.for_each_entry(directory_cluster, |fs_entry| {
+        if matches!(fs_entry.file_type, FileType::File) && fs_entry.name.eq_ignore_ascii_case(sidecar_name.as_str()) {
+            sidecar = Some(fs_entry);
+        }
+    })?;
+    let Some(sidecar) = sidecar else {
+        return Ok(replay_gain);
+    };
+
+    // The four fields fit easily in the first sector
+    let valid_bytes = sidecar.valid_data_length.min(SECTOR_SIZE as u64) as usize;
+    if valid_bytes == 0 {
+        return Ok(replay_gain); // Empty files have no clusters
+    }
+    let sector = exfat.read_sector(exfat.calc_cluster_sector(sidecar.first_cluster))?;
+    let text = sector.get(..valid_bytes).unwrap_or(&[]);
+    for line in text.split(|byte| *byte == b'\n' || *byte == b'\r') {
+        if let Ok(line) = core::str::from_utf8(line) {
+            replay_gain.parse_line(line);
+        }
+    }
+
+    Ok(replay_gain)
+}
+
+// Parses the number at the start of text, e.g. -6.54 from "-6.54 dB"
+fn parse_number(text: &str) -> Option<f32> {
+    let text = text.trim();
+    let length = text.find(|character: char| !(character.is_ascii_digit() || "+-.".contains(character))).unwrap_or(text.len());
+    text[..length].parse().ok()
+}
@@ -0,0 +1,87 @@
+// Sample rate converter for files which don't match the output sample rate (e.g. 22.05 kHz or 48 kHz on a 44.1 kHz output)
+// Uses linear interpolation between input frames in fixed point
+// This isn't as clean as a polyphase filter, but it's cheap enough to run in the buffer fill deadline
+//
+// The same conversion plays a file faster or slower, by pretending its sample rate is higher or lower
+
+// The position between two input frames is a 16 bit fraction
+const PHASE_BITS: u32 = 16;
+const PHASE_ONE: u32 = 1 << PHASE_BITS;
+
+// At most this many input frames are read for every output frame
+// Higher ratios would need a low pass filter to avoid aliasing, and take too long to read
+pub const MAX_RATIO: u32 = 4;
+
+#[derive(Debug)]
+pub struct Resampler {
+    step: u32, // Input frames per output frame, as a fixed point fraction
+    phase: u32, // Position of the next output frame after the previous input frame
+    previous: (i16, i16),
+    next: (i16, i16),
+}
+
+impl Resampler {
+
+    // Returns None if the rates are 0 or too far apart
+    pub fn new(input_rate: u32, output_rate: u32) -> Option<Self> {
+        let mut resampler = Resampler {
+            step: step(input_rate, output_rate)?,
+            phase: 0,
+            previous: (0, 0),
+            next: (0, 0),
+        };
+        resampler.reset();
+        Some(resampler)
+    }
+
+    // Changes the rates while playing, the frames read so far are kept so there's no glitch
+    // Returns false and leaves the rates alone if they are 0 or too far apart
+    pub fn set_rates(&mut self, input_rate: u32, output_rate: u32) -> bool {
+        match step(input_rate, output_rate) {
+            Some(step) => {
+                self.step = step;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Forget the frames read so far, e.g. when going back to the start of the file
+    pub fn reset(&mut self) {
+        self.phase = 2 * PHASE_ONE; // Two frames are needed before the first output frame
+        self.previous = (0, 0);
+        self.next = (0, 0);
+    }
+
+    // True if another input frame has to be pushed before the next output frame
+    pub fn needs_input(&self) -> bool {
+        self.phase >= PHASE_ONE
+    }
+
+    pub fn push(&mut self, frame: (i16, i16)) {
+        self.previous = self.next;
+        self.next = frame;
+        self.phase -= PHASE_ONE;
+    }
+
+    // Only call this when needs_input is false
+    pub fn next_frame(&mut self) -> (i16, i16) {
+        let phase = self.phase as i32;
+        let interpolate = |previous: i16, next: i16| {
+            (previous as i32 + (((next as i32 - previous as i32) * phase) >> PHASE_BITS)) as i16
+        };
+
+        let frame = (interpolate(self.previous.0, self.next.0), interpolate(self.previous.1, self.next.1));
+        self.phase += self.step;
+        frame
+    }
+}
+
+// Input frames per output frame, None if the rates are 0 or too far apart
+fn step(input_rate: u32, output_rate: u32) -> Option<u32> {
+    if input_rate == 0 || output_rate == 0 || input_rate as u64 > output_rate as u64 * MAX_RATIO as u64 {
+        return None;
+    }
+
+    Some(((input_rate as u64) << PHASE_BITS).div_ceil(output_rate as u64) as u32)
+}
@@ -0,0 +1,103 @@
+// Remembers the track and position that was playing, so playback carries on from there after a reset
+//
+// The record is kept in the RTC backup registers, which keep their contents through a reset
+// They are only kept through a power cut if VBAT has a battery, otherwise the record is lost and playback starts from the beginning
+// Writing them is quick, so unlike flash the record can be updated while playing without causing an underrun
+
+use stm32f4xx_hal::pac;
+use stm32f4xx_hal::rcc::Enable;
+
+// Marks registers that hold a record, the backup registers are 0 after the backup domain is reset
+const MAGIC: u32 = 0x5741_5652; // "WAVR"
+
+// Backup registers used for the record, the other registers are left for anything else
+const FIRST_REGISTER: usize = 0;
+const RECORD_WORDS: usize = 6;
+
+// A track in the playlist and a position in it
+// The track is identified by its first cluster and length, like the watch folder does, the names are too long to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResumeRecord {
+    pub first_cluster: u32,
+    pub length: u32, // Low 32 bits of the length of the file
+    pub data_offset: u64, // Byte offset in the audio data, see WavFile::data_offset
+}
+
+impl ResumeRecord {
+    fn to_words(self) -> [u32; RECORD_WORDS] {
+        let mut words = [
+            MAGIC,
+            self.first_cluster,
+            self.length,
+            self.data_offset as u32,
+            (self.data_offset >> 32) as u32,
+            0,
+        ];
+        words[RECORD_WORDS - 1] = checksum(&words);
+        words
+    }
+
+    fn from_words(words: &[u32; RECORD_WORDS]) -> Option<Self> {
+        if words[0] != MAGIC || words[RECORD_WORDS - 1] != checksum(words) {
+            return None;
+        }
+
+        Some(ResumeRecord {
+            first_cluster: words[1],
+            length: words[2],
+            data_offset: words[3] as u64 | (words[4] as u64) << 32,
+        })
+    }
+}
+
+// Somewhere a record can be kept between boots
+pub trait ResumeStore {
+    fn load(&mut self) -> Option<ResumeRecord>;
+    fn save(&mut self, record: &ResumeRecord);
+
+    // Forgets the record, e.g. once the playlist has finished so the next boot starts from the beginning
+    fn clear(&mut self);
+}
+
+pub struct BackupRegisters {
+    rtc: pac::RTC,
+}
+
+impl BackupRegisters {
+    // Turns on the interface to the backup domain and allows writes to it
+    // The RTC itself doesn't need to be running
+    pub fn new(rtc: pac::RTC, pwr: pac::PWR, rcc: &pac::RCC) -> Self {
+        pac::PWR::enable(rcc);
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+        BackupRegisters { rtc }
+    }
+
+    fn write(&mut self, words: &[u32; RECORD_WORDS]) {
+        for (register, word) in self.rtc.bkpr.iter().skip(FIRST_REGISTER).zip(words) {
+            register.write(|w| w.bits(*word));
+        }
+    }
+}
+
+impl ResumeStore for BackupRegisters {
+    fn load(&mut self) -> Option<ResumeRecord> {
+        let mut words = [0; RECORD_WORDS];
+        for (word, register) in words.iter_mut().zip(self.rtc.bkpr.iter().skip(FIRST_REGISTER)) {
+            *word = register.read().bits();
+        }
+        ResumeRecord::from_words(&words)
+    }
+
+    fn save(&mut self, record: &ResumeRecord) {
+        self.write(&record.to_words());
+    }
+
+    fn clear(&mut self) {
+        self.write(&[0; RECORD_WORDS]);
+    }
+}
+
+// So a record is only used if every word was written, the magic word alone could be left from an older record
+fn checksum(words: &[u32; RECORD_WORDS]) -> u32 {
+    words.iter().take(RECORD_WORDS - 1).fold(MAGIC, |checksum, word| checksum.rotate_left(5) ^ word)
+}
@@ -8,11 +8,29 @@ use crate::bytes::*;
 
 use crate::BLOCK_SIZE;
 
+// Identifier and length
+const CHUNK_HEADER_LENGTH: usize = 8;
+
+// Chunks that only hold padding, some encoders put these before the data chunk so it lines up with a sector
+const FILLER_CHUNKS: [&str; 4] = ["JUNK", "junk", "PAD ", "FLLR"];
+const MAX_FILLER_CHUNKS: usize = 16; // How many filler chunks in a row are skipped before giving up
+
+// Chunks in RF64 files which are too big for a 32 bit length have this length, the real length is in the ds64 chunk
+pub const RF64_LENGTH_IN_DS64: u32 = 0xFFFFFFFF;
+
+// RIFF files are little endian, AIFF files use the same chunk layout from IFF but are big endian
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
 // Chunk info necessary for reading chunks sequentially
 #[derive(Debug)]
 pub struct ChunkInfo {
     pub identifier: String<4>, // 4 Character chunk identifier
     pub length: u32, // Length of the chunk in bytes
+    pub byte_order: ByteOrder, // Byte order of the file the chunk is in, set by the first chunk
     
     // Byte addressing starts from the first block of the riff file and can extend beyond the length of one block 
     // So a chunk that starts at byte 16 of the second block in the file would have byte address 512 + 16 = 528
@@ -22,36 +40,79 @@ pub struct ChunkInfo {
 
 impl ChunkInfo {
 
-    // Get the next chunk after the current chunk
-    // This will break for files with many chunks, as it doesn't account for chunks whose headers cross a block boundary
-    // Leaving for now as it will work okay for wav
+    // Chunk identifiers are 4 printable ascii characters
+    // Anything else means the chunk isn't really a chunk, e.g. the header was read from a run of zeros past the real chunks
+    pub fn has_valid_identifier(&self) -> bool {
+        self.identifier.len() == 4 && self.identifier.chars().all(|character| character.is_ascii_graphic() || character == ' ')
+    }
+
+    // Get the next chunk after the current chunk, skipping any filler chunks
     pub fn get_next_chunk<T: BlockDevice<{BLOCK_SIZE}>>(&self, block_device: &mut T, start_block_address: u32) -> Result<ChunkInfo, ()> {
+        let mut next_chunk = self.read_next_chunk(block_device, start_block_address)?;
+
+        for _ in 0..MAX_FILLER_CHUNKS {
+            if !FILLER_CHUNKS.contains(&next_chunk.identifier.as_str()) {
+                return Ok(next_chunk);
+            }
+            next_chunk = next_chunk.read_next_chunk(block_device, start_block_address)?;
+        }
+
+        Err(())
+    }
+
+    // Reads the header of the chunk after the current chunk
+    // The 8 byte chunk header can be split across two blocks, in which case both are read
+    fn read_next_chunk<T: BlockDevice<{BLOCK_SIZE}>>(&self, block_device: &mut T, start_block_address: u32) -> Result<ChunkInfo, ()> {
 
         // Get the correct block to read the next chunk from
         let offset_blocks = self.next_chunk / BLOCK_SIZE as u64;
         let relevant_block_addr = start_block_address + offset_blocks as u32;
 
         let relevant_block = block_device.read_block(relevant_block_addr)?;
-        let next_chunk_in_block = self.next_chunk - offset_blocks * BLOCK_SIZE as u64;
+        let next_chunk_in_block = (self.next_chunk - offset_blocks * BLOCK_SIZE as u64) as usize;
 
+        // Copy the header, continuing into the following block if it doesn't fit in this one
+        let mut header: Bytes<CHUNK_HEADER_LENGTH> = [0; CHUNK_HEADER_LENGTH];
+        let bytes_in_block = (BLOCK_SIZE - next_chunk_in_block).min(CHUNK_HEADER_LENGTH);
+        header[..bytes_in_block].copy_from_slice(&relevant_block[next_chunk_in_block..next_chunk_in_block + bytes_in_block]);
 
+        if bytes_in_block < CHUNK_HEADER_LENGTH {
+            let following_block = block_device.read_block(relevant_block_addr + 1)?;
+            header[bytes_in_block..].copy_from_slice(&following_block[..CHUNK_HEADER_LENGTH - bytes_in_block]);
+        }
 
+        let identifier = decode_identifier(header.get_bytes_section::<4>(0));
+        let identifier_str = identifier.as_str();
 
-        let identifier = relevant_block.decode_ascii::<4>(next_chunk_in_block as usize);
-        let length = u32::from_le_bytes(relevant_block.get_bytes_section::<4>(next_chunk_in_block as usize + 4));
+        let byte_order = match identifier_str {
+            "RIFF" | "RF64" | "BW64" => ByteOrder::Little,
+            "FORM" => ByteOrder::Big,
+            _ => self.byte_order,
+        };
+        let length = match byte_order {
+            ByteOrder::Little => u32::from_le_bytes(header.get_bytes_section::<4>(4)),
+            ByteOrder::Big => u32::from_be_bytes(header.get_bytes_section::<4>(4)),
+        };
 
-        let identifier_str = identifier.as_str();
-        let new_next_chunk = if identifier_str == "RIFF" || identifier_str == "LIST" {
+        // RIFF and LIST chunks contain other chunks, so the next chunk is the first one inside them
+        // A LIST starts with a 4 character list type (e.g. INFO or adtl) before its first chunk
+        // RF64 and BW64 are RIFF for files over 4 GB, their real sizes are in a ds64 chunk
+        // FORM is the AIFF equivalent of RIFF, with a form type of AIFF or AIFC instead of WAVE
+        let new_next_chunk = if identifier_str == "RIFF" || identifier_str == "RF64" || identifier_str == "BW64" || identifier_str == "FORM" {
             self.next_chunk + 8
-        } else if identifier_str == "WAVE" {
+        } else if identifier_str == "LIST" {
+            self.next_chunk + 12
+        } else if identifier_str == "WAVE" || identifier_str == "AIFF" || identifier_str == "AIFC" {
            self.next_chunk + 4 
         } else {
-            self.next_chunk + 8 + length as u64 
+            // Chunks with an odd length are followed by a pad byte
+            self.next_chunk + 8 + length as u64 + (length & 1) as u64
         };
 
         let next_chunk_info = ChunkInfo {
             identifier,
             length,
+            byte_order,
             chunk_start: self.next_chunk,
             next_chunk: new_next_chunk,
         };
@@ -60,11 +121,21 @@ impl ChunkInfo {
     }  
 }
 
+// Non ascii bytes are replaced with nulls, so the identifier is always 4 characters which are each one byte
+fn decode_identifier(bytes: [u8; 4]) -> String<4> {
+    let mut identifier = String::new();
+    for byte in bytes {
+        let _ = identifier.push(if byte.is_ascii() { byte as char } else { '\0' });
+    }
+    identifier
+}
+
 // Get the first chunk in the file
 pub fn get_first_chunk<T: BlockDevice<BLOCK_SIZE>>(start_block_address: u32, block_device: &mut T) -> Result<ChunkInfo, ()> {
     let start_chunk = ChunkInfo {
         identifier: String::new(),
         length: 0,
+        byte_order: ByteOrder::Little,
         chunk_start: 0,
         next_chunk: 0,
     };
@@ -0,0 +1,48 @@
+// Routes the left and right channels to the outputs, for speakers wired the wrong way round or a single mono amplifier
+//
+// This is applied to everything that is played, including sounds from the mixer, just before the samples are handed to the DMA
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Routing {
+    Stereo, // Left to the left output, right to the right output
+    Swapped, // Left and right are swapped
+    LeftOnly, // The left channel is played on both outputs
+    RightOnly, // The right channel is played on both outputs
+    Mono, // Both outputs play the average of the two channels, so nothing panned to one side is lost
+}
+
+impl Routing {
+    // Routes interleaved 16 bit stereo samples
+    pub fn apply(&self, buf: &mut [u16]) {
+        if *self == Routing::Stereo {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(2) {
+            let [left, right] = frame else {
+                continue;
+            };
+            (*left, *right) = match self {
+                Routing::Stereo => (*left, *right),
+                Routing::Swapped => (*right, *left),
+                Routing::LeftOnly => (*left, *left),
+                Routing::RightOnly => (*right, *right),
+                Routing::Mono => {
+                    let mono = ((*left as i16 as i32 + *right as i16 as i32) >> 1) as i16 as u16;
+                    (mono, mono)
+                },
+            };
+        }
+    }
+
+    // The next routing, for a control that steps through them
+    pub fn next(&self) -> Routing {
+        match self {
+            Routing::Stereo => Routing::Swapped,
+            Routing::Swapped => Routing::LeftOnly,
+            Routing::LeftOnly => Routing::RightOnly,
+            Routing::RightOnly => Routing::Mono,
+            Routing::Mono => Routing::Stereo,
+        }
+    }
+}
@@ -0,0 +1,352 @@
+// An sd card or a soldered eMMC part on the SDIO bus as a block device
+//
+// The two only differ in how they are initialized, which the HAL does, after that they are read with the same commands
+//
+// Every card has to be initialized at a slow clock, the HAL then leaves it there
+// After init the clock is raised to the fastest one the card reads reliably at, which is checked by reading a few blocks
+// If CRC errors keep happening while playing (e.g. long or noisy wires) the clock is lowered again, one step at a time
+//
+// The clock stops at 24 MHz, the most a card supports in default speed mode (eMMC goes up to 26 MHz)
+// 48 MHz needs the card to be switched to high speed mode (CMD6), which the HAL can't do
+//
+// The blocks of a WAV file are read with DMA while the blocks before them are decoded (see BlockDevice::start_read),
+// so the CPU isn't stuck copying from the SDIO FIFO while the audio buffer needs filling
+// Other reads (the filesystem, retries) copy from the FIFO, they are small or already late
+//
+// A failed read is tried again a few times (see RetryPolicy) since most errors are one off, e.g. a glitch on the bus
+// If reads keep failing the card can be initialized again, which gets it out of a bad state without a power cycle
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use stm32f4xx_hal::pac;
+use stm32f4xx_hal::rcc::Enable;
+use stm32f4xx_hal::sdio::{ClockFreq, Emmc, Error, SdCard, Sdio, SdioPeripheral};
+
+use crate::block_device::BlockDevice;
+use crate::sdio_ext;
+use crate::BLOCK_SIZE;
+use crate::rprintln;
+
+// Every card can be initialized at this clock
+pub const INIT_CLOCK: ClockFreq = ClockFreq::F4Mhz;
+
+// Clock dividers to try after init, fastest first, the last one is the clock the card was initialized at
+const CLOCK_STEPS: [u8; 4] = [ClockFreq::F24Mhz as u8, ClockFreq::F16Mhz as u8, ClockFreq::F12Mhz as u8, INIT_CLOCK as u8];
+
+// A clock is used if the first VERIFY_BLOCKS blocks read back the same as at the init clock VERIFY_READS times
+const VERIFY_BLOCKS: usize = 4;
+const VERIFY_READS: usize = 4;
+
+// The clock is lowered after this many CRC errors in a row
+const MAX_CRC_ERRORS: u32 = 3;
+
+// Most blocks read in the background at once
+pub const DMA_READ_BLOCKS: usize = 4;
+
+// Where background reads are put, the DMA moves words so it has to be word aligned
+#[repr(C, align(4))]
+pub struct DmaBuffer([[u8; BLOCK_SIZE]; DMA_READ_BLOCKS]);
+
+impl DmaBuffer {
+    pub const fn new() -> Self {
+        DmaBuffer([[0; BLOCK_SIZE]; DMA_READ_BLOCKS])
+    }
+}
+
+impl Default for DmaBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A background read that hasn't been collected yet
+struct PendingRead {
+    blockaddr: u32,
+    blocks: usize,
+    read: sdio_ext::DmaRead,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32, // Attempts at each read, including the first
+    pub backoff_cycles: u32, // Wait before each retry, in cpu cycles
+    pub reinit_after: u32, // Initialize the card again after this many reads in a row failed every attempt, 0 to never
+}
+
+// Counts the read errors since power on, kept in a static so they can be reported from anywhere
+pub struct ReadErrorCounter {
+    crc: AtomicU32,
+    timeouts: AtomicU32,
+    other: AtomicU32,
+    retries: AtomicU32,
+    failed_reads: AtomicU32, // Reads that failed every attempt
+    reinits: AtomicU32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadErrorStats {
+    pub crc: u32,
+    pub timeouts: u32,
+    pub other: u32,
+    pub retries: u32,
+    pub failed_reads: u32,
+    pub reinits: u32,
+}
+
+impl ReadErrorCounter {
+    pub const fn new() -> Self {
+        ReadErrorCounter {
+            crc: AtomicU32::new(0),
+            timeouts: AtomicU32::new(0),
+            other: AtomicU32::new(0),
+            retries: AtomicU32::new(0),
+            failed_reads: AtomicU32::new(0),
+            reinits: AtomicU32::new(0),
+        }
+    }
+
+    fn record(&self, error: Error) {
+        let counter = match error {
+            Error::Crc | Error::DataCrcFail => &self.crc,
+            Error::Timeout | Error::SoftwareTimeout => &self.timeouts,
+            _ => &self.other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ReadErrorStats {
+        ReadErrorStats {
+            crc: self.crc.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            other: self.other.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            failed_reads: self.failed_reads.load(Ordering::Relaxed),
+            reinits: self.reinits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ReadErrorCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The card types that can be used with SdioBlockDevice
+pub trait SdioCard: SdioPeripheral + Sized {
+    const NAME: &'static str; // For log messages
+
+    // Initializes the card with the HAL, the bus width is the one given by the pins passed to Sdio::new
+    fn init(sdio: &mut Sdio<Self>, freq: ClockFreq) -> Result<(), Error>;
+}
+
+impl SdioCard for SdCard {
+    const NAME: &'static str = "sd card";
+
+    fn init(sdio: &mut Sdio<Self>, freq: ClockFreq) -> Result<(), Error> {
+        sdio.init(freq)
+    }
+}
+
+impl SdioCard for Emmc {
+    const NAME: &'static str = "eMMC";
+
+    fn init(sdio: &mut Sdio<Self>, freq: ClockFreq) -> Result<(), Error> {
+        sdio.init(freq)
+    }
+}
+
+pub struct SdioBlockDevice<P: SdioCard> {
+    pub sdio: Sdio<P>,
+    pub retry: RetryPolicy,
+    errors: &'static ReadErrorCounter,
+    dma: pac::DMA2,
+    dma_buffer: &'static mut DmaBuffer,
+    pending: Option<PendingRead>,
+    clock_step: usize, // Index in CLOCK_STEPS of the current clock
+    crc_errors: u32, // CRC errors in a row at the current clock
+    failed_reads: u32, // Reads in a row that failed every attempt
+}
+
+impl<P: SdioCard> SdioBlockDevice<P> {
+    // The card has to have been initialized at INIT_CLOCK
+    pub fn new(sdio: Sdio<P>, retry: RetryPolicy, errors: &'static ReadErrorCounter, dma: pac::DMA2, dma_buffer: &'static mut DmaBuffer) -> Self {
+        // Safety: the DMA is owned by the block device, nothing else uses its clock enable bit
+        unsafe { pac::DMA2::enable_unchecked() };
+
+        SdioBlockDevice {
+            sdio,
+            retry,
+            errors,
+            dma,
+            dma_buffer,
+            pending: None,
+            clock_step: CLOCK_STEPS.len() - 1,
+            crc_errors: 0,
+            failed_reads: 0,
+        }
+    }
+
+    // Moves to the fastest clock that passes the verification reads
+    // Returns the new card clock in KHz, or Err if the card can't be read at the init clock either
+    pub fn raise_clock(&mut self) -> Result<u32, Error> {
+        self.discard_pending();
+        let init_step = CLOCK_STEPS.len() - 1;
+        self.set_clock_step(init_step);
+        let expected = self.verify_checksum()?;
+
+        for step in 0..init_step {
+            self.set_clock_step(step);
+            if (0..VERIFY_READS).all(|_| self.verify_checksum() == Ok(expected)) {
+                return Ok(self.clock_khz());
+            }
+            rprintln!("{} failed verification at {} KHz", P::NAME, self.clock_khz());
+        }
+
+        self.set_clock_step(init_step);
+        Ok(self.clock_khz())
+    }
+
+    pub fn clock_khz(&self) -> u32 {
+        CLOCK_STEPS.get(self.clock_step).map_or(0, |divider| sdio_ext::clock_khz(*divider))
+    }
+
+    fn set_clock_step(&mut self, step: usize) {
+        if let Some(divider) = CLOCK_STEPS.get(step) {
+            sdio_ext::set_clock_divider(&mut self.sdio, *divider);
+            self.clock_step = step;
+            self.crc_errors = 0;
+        }
+    }
+
+    // Reads the first blocks of the card and sums them up, so reads at different clocks can be compared
+    fn verify_checksum(&mut self) -> Result<u32, Error> {
+        let mut blocks = [[0; BLOCK_SIZE]; VERIFY_BLOCKS];
+        sdio_ext::read_blocks(&mut self.sdio, 0, &mut blocks)?;
+
+        Ok(blocks.as_flattened().iter().fold(0_u32, |checksum, byte| checksum.rotate_left(5) ^ *byte as u32))
+    }
+
+    // Initializes the card from scratch, it could be a different card to the one that was there before
+    fn init_card(&mut self) -> Result<(), Error> {
+        self.discard_pending();
+        P::init(&mut self.sdio, INIT_CLOCK)?;
+        self.clock_step = CLOCK_STEPS.len() - 1;
+        self.crc_errors = 0;
+        self.failed_reads = 0;
+
+        if crate::RAISE_SD_CLOCK {
+            self.raise_clock()?;
+        }
+        Ok(())
+    }
+
+    // Runs read until it succeeds or every attempt has failed, then initializes the card again if reads keep failing
+    fn read_with_retries<F: FnMut(&mut Sdio<P>) -> Result<(), Error>>(&mut self, mut read: F) -> Result<(), ()> {
+        self.discard_pending();
+        for attempt in 0..self.retry.attempts.max(1) {
+            if attempt > 0 {
+                self.errors.retries.fetch_add(1, Ordering::Relaxed);
+                cortex_m::asm::delay(self.retry.backoff_cycles);
+            }
+
+            let result = read(&mut self.sdio);
+            if self.check(result).is_ok() {
+                self.failed_reads = 0;
+                return Ok(());
+            }
+        }
+
+        self.errors.failed_reads.fetch_add(1, Ordering::Relaxed);
+        self.failed_reads += 1;
+        if self.retry.reinit_after > 0 && self.failed_reads >= self.retry.reinit_after {
+            self.errors.reinits.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = self.init_card() {
+                rprintln!("{} init failed: {:?}", P::NAME, err);
+            }
+            self.failed_reads = 0;
+        }
+        Err(())
+    }
+
+    // Waits for a background read that is no longer wanted, the card can't do anything else until it's finished
+    fn discard_pending(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            let result = sdio_ext::finish_read_dma(&mut self.sdio, &self.dma, pending.read);
+            let _ = self.check(result);
+        }
+    }
+
+    // Counts errors, and keeps track of CRC errors so the clock is lowered if they keep happening
+    fn check(&mut self, result: Result<(), Error>) -> Result<(), ()> {
+        let Err(error) = result else {
+            self.crc_errors = 0;
+            return Ok(());
+        };
+
+        self.errors.record(error);
+        if matches!(error, Error::Crc | Error::DataCrcFail) {
+            self.crc_errors += 1;
+            if self.crc_errors >= MAX_CRC_ERRORS && self.clock_step + 1 < CLOCK_STEPS.len() {
+                self.set_clock_step(self.clock_step + 1);
+                rprintln!("{} CRC errors, clock lowered to {} KHz", P::NAME, self.clock_khz());
+            }
+        }
+        Err(())
+    }
+}
+
+impl<P: SdioCard> BlockDevice<BLOCK_SIZE> for SdioBlockDevice<P> {
+    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+        self.read_with_retries(|sdio| sdio.read_block(blockaddr, block))
+    }
+
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), ()> {
+        self.read_with_retries(|sdio| sdio_ext::read_blocks(sdio, blockaddr, blocks))
+    }
+
+    fn start_read(&mut self, blockaddr: u32, blocks: usize) -> Result<(), ()> {
+        self.discard_pending();
+        if blocks == 0 || blocks > DMA_READ_BLOCKS {
+            return Err(());
+        }
+
+        let buf = self.dma_buffer.0.as_mut_ptr() as *mut u32;
+        // Safety: the buffer is static and word aligned, and it's only used again once the read has been finished
+        let result = unsafe { sdio_ext::start_read_dma(&mut self.sdio, &self.dma, blockaddr, buf, blocks) };
+        match result {
+            Ok(read) => {
+                self.pending = Some(PendingRead { blockaddr, blocks, read });
+                Ok(())
+            },
+            Err(error) => self.check(Err(error)),
+        }
+    }
+
+    fn finish_read(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), ()> {
+        match self.pending.take() {
+            Some(pending) if pending.blockaddr == blockaddr && pending.blocks == blocks.len() => {
+                let result = sdio_ext::finish_read_dma(&mut self.sdio, &self.dma, pending.read);
+                if self.check(result).is_ok() {
+                    for (block, dma_block) in blocks.iter_mut().zip(self.dma_buffer.0.iter()) {
+                        *block = *dma_block;
+                    }
+                    return Ok(());
+                }
+            },
+            Some(pending) => self.pending = Some(pending),
+            None => (),
+        }
+
+        // Nothing was started for these blocks, or the background read failed and is tried again with retries
+        self.read_blocks(blockaddr, blocks)
+    }
+
+    fn is_present(&mut self) -> bool {
+        self.discard_pending();
+        sdio_ext::card_responds(&mut self.sdio)
+    }
+
+    fn reinit(&mut self) -> Result<(), ()> {
+        self.init_card().map_err(|_| ())
+    }
+}
@@ -0,0 +1,337 @@
+// Parts of the sd card and eMMC driver the HAL doesn't provide, multi block reads, a presence check and changing the clock after init
+//
+// The HAL can only read one block per command
+// Reading a block on its own costs a command, a response and waiting for the card to go back to the transfer state
+// READ_MULTIPLE_BLOCK (CMD18) streams blocks until STOP_TRANSMISSION (CMD12), so a run of blocks only pays for that once
+// The HAL doesn't expose its command functions, so this drives the SDIO registers directly in the same way the HAL does
+// The Sdio is borrowed mutably for the whole read, so nothing else can use the peripheral while the registers are touched
+//
+// start_read_dma and finish_read_dma split a read in two, DMA2 (stream 3, channel 4) moves the data while the CPU does other things
+// The SDIO is the flow controller, it tells the DMA when the last word has arrived
+
+use stm32f4xx_hal::pac;
+use stm32f4xx_hal::sdio::{common_cmd, AddressMode, CardStatus, Cmd, CurrentState, Error, ResponseLen, Sdio, SdioPeripheral, SD};
+
+use crate::BLOCK_SIZE;
+
+const BLOCK_SIZE_POWER: u8 = 9; // 2^9 = BLOCK_SIZE
+const FIFO_HALF_WORDS: usize = 8; // Words available once the receive FIFO is half full
+const CMD_TIMEOUT: u32 = 0xFFFF_FFFF; // Status polls before a command is given up on, the same as the HAL
+
+const DMA_STREAM: usize = 3;
+const DMA_CHANNEL: u8 = 4;
+const DMA_STREAM_FLAGS: u32 = 0b11_1101 << 22; // lisr/lifcr bits of stream 3
+
+// The SDIO kernel clock, from the 48 MHz PLL output
+// The card clock is SDIOCLK_KHZ / (divider + 2)
+const SDIOCLK_KHZ: u32 = 48_000;
+
+// Reads blocks.len() consecutive blocks starting at blockaddr
+pub fn read_blocks<P: SdioPeripheral>(sdio: &mut Sdio<P>, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), Error> {
+    match blocks {
+        [] => return Ok(()),
+        [block] => return sdio.read_block(blockaddr, block), // A single block is quicker without the stop command
+        _ => (),
+    }
+
+    let card = sdio.card()?;
+    let rca = card.get_address();
+
+    // SDSC cards are byte addressed hence the blockaddress is in multiples of 512 bytes
+    let blockaddr = match card.get_address_mode() {
+        AddressMode::Byte => blockaddr * BLOCK_SIZE as u32,
+        AddressMode::Block512 => blockaddr,
+    };
+
+    // Safety: the Sdio owns the peripheral and is borrowed mutably, so it isn't being used anywhere else
+    let regs = unsafe { &*pac::SDIO::ptr() };
+
+    cmd(regs, common_cmd::set_block_length(BLOCK_SIZE as u32))?;
+    start_read(regs, (blocks.len() * BLOCK_SIZE) as u32, false);
+    cmd(regs, common_cmd::read_multiple_blocks(blockaddr))?;
+
+    let result = read_fifo(regs, blocks.as_flattened_mut());
+
+    // The card keeps sending blocks until it's told to stop, this has to be sent even if the read failed
+    let stop = cmd(regs, common_cmd::stop_transmission());
+    result?;
+    stop?;
+
+    wait_for_transfer_state(regs, rca)
+}
+
+// A read started by start_read_dma
+#[derive(Debug)]
+pub struct DmaRead {
+    rca: u16,
+    multiple: bool, // Sent with READ_MULTIPLE_BLOCK, so the card has to be stopped
+}
+
+// Starts reading blocks consecutive blocks starting at blockaddr into buf, returns once the card has the read command
+// The data streams into buf while other things are done, finish_read_dma waits for it
+///
+/// # Safety
+/// buf has to be word aligned with room for the blocks, and can't be used or moved until finish_read_dma has returned
+pub unsafe fn start_read_dma<P: SdioPeripheral>(
+    sdio: &mut Sdio<P>,
+    dma: &pac::dma2::RegisterBlock,
+    blockaddr: u32,
+    buf: *mut u32,
+    blocks: usize,
+) -> Result<DmaRead, Error> {
+    let card = sdio.card()?;
+    let rca = card.get_address();
+
+    // SDSC cards are byte addressed hence the blockaddress is in multiples of 512 bytes
+    let blockaddr = match card.get_address_mode() {
+        AddressMode::Byte => blockaddr * BLOCK_SIZE as u32,
+        AddressMode::Block512 => blockaddr,
+    };
+
+    // Safety: the Sdio owns the peripheral and is borrowed mutably, so it isn't being used anywhere else
+    let regs = unsafe { &*pac::SDIO::ptr() };
+    cmd(regs, common_cmd::set_block_length(BLOCK_SIZE as u32))?;
+
+    // Word transfers in bursts of 4, which is what the SDIO FIFO needs
+    let stream = &dma.st[DMA_STREAM];
+    stream.cr.modify(|_, w| w.en().disabled());
+    while stream.cr.read().en().is_enabled() {}
+    // Safety: the values are addresses and flags of this stream
+    unsafe {
+        dma.lifcr.write(|w| w.bits(DMA_STREAM_FLAGS));
+        stream.par.write(|w| w.pa().bits(regs.fifo.as_ptr() as u32));
+        stream.m0ar.write(|w| w.m0a().bits(buf as u32));
+    }
+    stream.fcr.write(|w| w.dmdis().disabled().fth().full());
+    stream.cr.write(|w| {
+        w.chsel().bits(DMA_CHANNEL)
+            .mburst().incr4()
+            .pburst().incr4()
+            .pl().very_high()
+            .msize().bits32()
+            .psize().bits32()
+            .minc().incremented()
+            .pinc().fixed()
+            .dir().peripheral_to_memory()
+            .pfctrl().peripheral()
+            .en().enabled()
+    });
+
+    start_read(regs, (blocks * BLOCK_SIZE) as u32, true);
+
+    let multiple = blocks > 1;
+    let read = match multiple {
+        true => common_cmd::read_multiple_blocks(blockaddr),
+        false => common_cmd::read_single_block(blockaddr),
+    };
+    if let Err(error) = cmd(regs, read) {
+        stop_dma(regs, dma);
+        return Err(error);
+    }
+
+    Ok(DmaRead { rca, multiple })
+}
+
+// Waits for a read started by start_read_dma to finish, then waits for the card to be ready again
+pub fn finish_read_dma<P: SdioPeripheral>(_sdio: &mut Sdio<P>, dma: &pac::dma2::RegisterBlock, read: DmaRead) -> Result<(), Error> {
+    // Safety: the Sdio owns the peripheral and is borrowed mutably, so it isn't being used anywhere else
+    let regs = unsafe { &*pac::SDIO::ptr() };
+
+    let mut result = Err(Error::SoftwareTimeout);
+    for _ in 0..CMD_TIMEOUT {
+        let sta = regs.sta.read();
+        if sta.dataend().bit_is_set() || sta.dcrcfail().bit_is_set() || sta.dtimeout().bit_is_set() || sta.rxoverr().bit_is_set() {
+            result = status_to_error(sta);
+            break;
+        }
+    }
+
+    // The DMA FIFO is emptied into memory after the SDIO has received the last word
+    if result.is_ok() {
+        let dma_done = (0..CMD_TIMEOUT).any(|_| {
+            let lisr = dma.lisr.read();
+            lisr.tcif3().bit_is_set() || lisr.teif3().bit_is_set()
+        });
+        if !dma_done || dma.lisr.read().teif3().bit_is_set() {
+            result = Err(Error::RxOverFlow);
+        }
+    }
+    stop_dma(regs, dma);
+
+    // The card keeps sending blocks until it's told to stop, this has to be sent even if the read failed
+    let stop = match read.multiple {
+        true => cmd(regs, common_cmd::stop_transmission()),
+        false => Ok(()),
+    };
+    result?;
+    stop?;
+
+    wait_for_transfer_state(regs, read.rca)
+}
+
+fn stop_dma(regs: &pac::sdio::RegisterBlock, dma: &pac::dma2::RegisterBlock) {
+    regs.dctrl.modify(|_, w| w.dmaen().disabled());
+    let stream = &dma.st[DMA_STREAM];
+    stream.cr.modify(|_, w| w.en().disabled());
+    while stream.cr.read().en().is_enabled() {}
+    // Safety: only the flags of this stream are cleared
+    dma.lifcr.write(|w| unsafe { w.bits(DMA_STREAM_FLAGS) });
+}
+
+// Waits for the card to finish a read and go back to the transfer state
+fn wait_for_transfer_state(regs: &pac::sdio::RegisterBlock, rca: u16) -> Result<(), Error> {
+    loop {
+        cmd(regs, common_cmd::card_status(rca, false))?;
+        // The state bits are in the same place for SD cards and eMMC
+        if CardStatus::<SD>::from(regs.resp1.read().bits()).state() == CurrentState::Transfer {
+            return Ok(());
+        }
+    }
+}
+
+// True if the card answers a status command, a card that has been taken out doesn't answer
+pub fn card_responds<P: SdioPeripheral>(sdio: &mut Sdio<P>) -> bool {
+    let Ok(card) = sdio.card() else {
+        return false;
+    };
+    let rca = card.get_address();
+
+    // Safety: the Sdio owns the peripheral and is borrowed mutably, so it isn't being used anywhere else
+    let regs = unsafe { &*pac::SDIO::ptr() };
+    cmd(regs, common_cmd::card_status(rca, false)).is_ok()
+}
+
+// Sets up the data path to receive length bytes from the card, into the FIFO or with the DMA
+fn start_read(regs: &pac::sdio::RegisterBlock, length: u32, dma: bool) {
+    // Command AND Data state machines must be idle
+    loop {
+        let status = regs.sta.read();
+        if status.cmdact().bit_is_clear() && status.rxact().bit_is_clear() && status.txact().bit_is_clear() {
+            break;
+        }
+    }
+
+    regs.dtimer.write(|w| w.datatime().bits(0xFFFF_FFFF));
+    regs.dlen.write(|w| w.datalength().bits(length));
+    regs.dctrl.write(|w| w.dblocksize().bits(BLOCK_SIZE_POWER).dtdir().card_to_controller().dmaen().bit(dma).dten().enabled());
+}
+
+// Copies the received words out of the FIFO until bytes is full or the transfer stops
+fn read_fifo(regs: &pac::sdio::RegisterBlock, bytes: &mut [u8]) -> Result<(), Error> {
+    let mut words = bytes.chunks_exact_mut(4).filter_map(|word| <&mut [u8; 4]>::try_from(word).ok()).peekable();
+
+    let status = loop {
+        let sta = regs.sta.read();
+
+        if words.peek().is_none() {
+            break sta;
+        }
+
+        if sta.rxfifohf().bit() {
+            for word in words.by_ref().take(FIFO_HALF_WORDS) {
+                *word = regs.fifo.read().bits().to_le_bytes();
+            }
+        } else if sta.rxdavl().bit() {
+            // The last few words don't fill half the FIFO
+            if let Some(word) = words.next() {
+                *word = regs.fifo.read().bits().to_le_bytes();
+            }
+        } else if sta.rxact().bit_is_clear() {
+            break sta;
+        }
+    };
+
+    status_to_error(status)?;
+    if words.peek().is_some() {
+        return Err(Error::Timeout); // The transfer stopped before every block was received
+    }
+    Ok(())
+}
+
+// Sends a command and waits for its response, the same as the HAL's command function
+fn cmd<R: common_cmd::Resp>(regs: &pac::sdio::RegisterBlock, cmd: Cmd<R>) -> Result<(), Error> {
+    use pac::sdio::cmd::WAITRESP_A;
+
+    // Command state machines must be idle
+    while regs.sta.read().cmdact().bit_is_set() {}
+
+    clear_all_interrupts(regs);
+
+    regs.arg.write(|w| w.cmdarg().bits(cmd.arg));
+
+    let waitresp = match cmd.response_len() {
+        ResponseLen::Zero => WAITRESP_A::NoResponse,
+        ResponseLen::R48 => WAITRESP_A::ShortResponse,
+        ResponseLen::R136 => WAITRESP_A::LongResponse,
+    };
+    regs.cmd.write(|w| w.waitresp().variant(waitresp).cmdindex().bits(cmd.cmd).waitint().disabled().cpsmen().enabled());
+
+    let mut timeout = CMD_TIMEOUT;
+    let status = loop {
+        let sta = regs.sta.read();
+
+        let done = match cmd.response_len() {
+            ResponseLen::Zero => sta.ctimeout().bit_is_set() || sta.cmdsent().bit_is_set(),
+            _ => sta.ctimeout().bit_is_set() || sta.cmdrend().bit_is_set() || sta.ccrcfail().bit_is_set(),
+        };
+        if sta.cmdact().bit_is_clear() && done {
+            break sta;
+        }
+
+        if timeout == 0 {
+            return Err(Error::SoftwareTimeout);
+        }
+        timeout -= 1;
+    };
+
+    status_to_error(status)
+}
+
+fn status_to_error(sta: pac::sdio::sta::R) -> Result<(), Error> {
+    if sta.ctimeout().bit_is_set() || sta.dtimeout().bit_is_set() {
+        Err(Error::Timeout)
+    } else if sta.ccrcfail().bit_is_set() {
+        Err(Error::Crc)
+    } else if sta.dcrcfail().bit_is_set() {
+        Err(Error::DataCrcFail)
+    } else if sta.rxoverr().bit_is_set() {
+        Err(Error::RxOverFlow)
+    } else {
+        Ok(())
+    }
+}
+
+fn clear_all_interrupts(regs: &pac::sdio::RegisterBlock) {
+    regs.icr.write(|w| {
+        w.ccrcfailc().set_bit()
+            .ctimeoutc().set_bit()
+            .ceataendc().set_bit()
+            .cmdrendc().set_bit()
+            .cmdsentc().set_bit()
+            .dataendc().set_bit()
+            .dbckendc().set_bit()
+            .dcrcfailc().set_bit()
+            .dtimeoutc().set_bit()
+            .sdioitc().set_bit()
+            .stbiterrc().set_bit()
+            .rxoverrc().set_bit()
+            .txunderrc().set_bit()
+    });
+}
+
+// Changes the card clock, keeping the bus width set by init
+// The divider is one of the ClockFreq values, returns the new card clock in KHz
+pub fn set_clock_divider<P: SdioPeripheral>(_sdio: &mut Sdio<P>, divider: u8) -> u32 {
+    // Safety: the Sdio owns the peripheral and is borrowed mutably, so it isn't being used anywhere else
+    let regs = unsafe { &*pac::SDIO::ptr() };
+
+    // The clock can't be changed in the middle of a command
+    while regs.sta.read().cmdact().bit_is_set() {}
+    regs.clkcr.modify(|_, w| w.clkdiv().bits(divider));
+
+    clock_khz(divider)
+}
+
+pub fn clock_khz(divider: u8) -> u32 {
+    SDIOCLK_KHZ / (divider as u32 + 2)
+}
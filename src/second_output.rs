@@ -0,0 +1,234 @@
+// Second stereo output on I2S3 (WS PA15, CK PB3, SD PB5), with the dual-i2s feature
+//
+// It has its own ring of buffers and its own DMA stream (DMA1 stream 5), like the first output on I2S2
+// Both are clocked from the I2S PLL with the same dividers, so they run at the same sample rate and are filled together
+// With I2S_MCLK_OUTPUT the master clock on PA3 can be wired to the codecs of both outputs
+//
+// The second output either plays the same audio as the first, for 4 channel output or a second room,
+// or only the sounds from the mixer, so it can be a paging zone without the music
+// Each output has its own routing, so e.g. the second one can play the two channels mixed for a subwoofer
+// Either output can be switched off at runtime, see OutputSelect
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+
+use stm32f4xx_hal::dma::{MemoryToPeripheral, StreamX, Transfer, config::DmaConfig};
+use stm32f4xx_hal::i2s::{I2s, stm32_i2s_v12x};
+use stm32f4xx_hal::pac::{self, interrupt};
+use stm32f4xx_hal::prelude::*;
+use stm32_i2s_v12x::{driver::I2sDriver, transfer::*};
+
+use crate::audio_buffer::AudioRing;
+use crate::block_device::BlockDevice;
+use crate::exfat::ExFat;
+use crate::fade::Fade;
+use crate::player::Player;
+use crate::realtime::FillBudget;
+use crate::volume::UNITY_GAIN;
+use crate::{BLOCK_SIZE, BUF_SIZE, BUF_SLOTS, SILENCE_BUFFER};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Mirror, // The same audio as the first output, after its routing
+    Mixer, // Only the sounds from the mixer, the music isn't ducked under them since they aren't played over it
+}
+
+pub type SecondI2sTx = I2sDriver<I2s<pac::SPI3>, crate::I2sMode, Transmit, Philips>;
+// Which outputs are heard, switched at runtime with the output shell command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActiveOutputs {
+    Both,
+    First, // The second output plays silence
+    Second, // The first output plays silence
+}
+
+impl ActiveOutputs {
+    pub fn next(self) -> Self {
+        match self {
+            ActiveOutputs::Both => ActiveOutputs::First,
+            ActiveOutputs::First => ActiveOutputs::Second,
+            ActiveOutputs::Second => ActiveOutputs::Both,
+        }
+    }
+}
+
+// Fades each output in or out as it's switched on or off, so switching doesn't click
+// An output that is off still has its DMA running with silence, so the outputs stay in step and it comes back straight away
+// The switch is heard once the audio already buffered has played
+#[derive(Debug)]
+pub struct OutputSelect {
+    active: ActiveOutputs,
+    first: Fade,
+    second: Fade,
+}
+
+impl OutputSelect {
+    pub fn new() -> Self {
+        OutputSelect {
+            active: ActiveOutputs::Both,
+            first: Fade::new(),
+            second: Fade::new(),
+        }
+    }
+
+    pub fn active(&self) -> ActiveOutputs {
+        self.active
+    }
+
+    // Switches the outputs over in fade_frames, 0 to switch straight away
+    pub fn set(&mut self, active: ActiveOutputs, fade_frames: u32) {
+        self.active = active;
+        let gain = |on: bool| if on { UNITY_GAIN } else { 0 };
+        self.first.ramp_to(gain(active != ActiveOutputs::Second), fade_frames);
+        self.second.ramp_to(gain(active != ActiveOutputs::First), fade_frames);
+    }
+}
+
+impl Default for OutputSelect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type SecondI2sDma = Transfer<StreamX<pac::DMA1, 5>, 0, SecondI2sTx, MemoryToPeripheral, &'static [u16; BUF_SIZE]>;
+static G_SECOND_TRANSFER: Mutex<RefCell<Option<SecondI2sDma>>> = Mutex::new(RefCell::new(None));
+
+pub static G_SECOND_RING: AudioRing<BUF_SLOTS, BUF_SIZE> = AudioRing::new();
+
+// Buffers of silence the second output played because no buffer was filled in time
+pub static G_SECOND_UNDERRUNS: AtomicU32 = AtomicU32::new(0);
+
+// Creates the driver for the second output, with the same configuration as the first so it runs at the same rate
+// The driver is returned with DMA requests on, and enabled unless it's an I2S slave (see crate::start_transfer)
+pub fn new_driver(i2s: I2s<pac::SPI3>, sample_rate: u32) -> SecondI2sTx {
+    let mut i2s_driver = I2sDriver::new(i2s, crate::i2s_config(sample_rate));
+    #[cfg(not(feature = "i2s-slave"))]
+    i2s_driver.enable();
+    i2s_driver.set_tx_dma(true);
+    i2s_driver
+}
+
+// Fills the next buffer of both outputs, buf is the buffer claimed from the first output's ring
+// The rings are filled together, so check is_full before claiming buf
+pub fn fill<T: BlockDevice<BLOCK_SIZE>>(player: &mut Player, exfat: &mut ExFat<T>, buf: &mut [u16], fill_budget: &mut FillBudget) {
+    // The main loop is the only producer, and the buffer is committed before the next one is claimed
+    let Some(second_buf) = (unsafe { G_SECOND_RING.claim_fill() }) else {
+        player.fill(exfat, buf, fill_budget);
+        player.outputs.first.apply(buf);
+        return;
+    };
+
+    match crate::SECOND_OUTPUT {
+        Source::Mirror => {
+            player.fill(exfat, buf, fill_budget);
+            for (sample, first_sample) in second_buf.iter_mut().zip(buf.iter()) {
+                *sample = *first_sample;
+            }
+        },
+        Source::Mixer => player.fill_split(exfat, buf, second_buf, fill_budget),
+    }
+    crate::SECOND_OUTPUT_ROUTING.apply(second_buf);
+    player.outputs.first.apply(buf);
+    player.outputs.second.apply(second_buf);
+
+    G_SECOND_RING.commit_fill();
+}
+
+// True while the second output has no buffer to fill, the first output waits for it so they stay in step
+pub fn is_full() -> bool {
+    G_SECOND_RING.is_full()
+}
+
+// Starts the DMA with the first two buffers in the ring, as crate::start_transfer does for the first output
+pub fn start(stream: StreamX<pac::DMA1, 5>, i2s_driver: SecondI2sTx) {
+    let first_buf = G_SECOND_RING.next_play().unwrap_or(&SILENCE_BUFFER);
+    let second_buf = G_SECOND_RING.next_play().unwrap_or(&SILENCE_BUFFER);
+
+    let mut transfer = SecondI2sDma::init_memory_to_peripheral(
+        stream,
+        i2s_driver,
+        first_buf,
+        Some(second_buf),
+        DmaConfig::default()
+        .memory_increment(true)
+        .double_buffer(true)
+        .fifo_error_interrupt(true)
+        .transfer_complete_interrupt(true)
+    );
+    transfer.clear_all_flags();
+
+    cortex_m::interrupt::free(|cs| {
+        G_SECOND_TRANSFER.borrow(cs).replace(Some(transfer));
+        if let Some(transfer) = G_SECOND_TRANSFER.borrow(cs).borrow_mut().as_mut() {
+            transfer.start(|_i2s_driver| {
+                #[cfg(feature = "i2s-slave")]
+                crate::enable_slave(_i2s_driver);
+            });
+        }
+    });
+}
+
+// Stops and starts the second output again, when the first output is restarted after a DMA error
+pub fn restart() {
+    let Some(transfer) = cortex_m::interrupt::free(|cs| G_SECOND_TRANSFER.borrow(cs).borrow_mut().take()) else {
+        return;
+    };
+    let (stream, mut i2s_driver, _, _) = transfer.release();
+
+    i2s_driver.set_tx_dma(false);
+    i2s_driver.disable();
+    G_SECOND_RING.restart();
+
+    #[cfg(not(feature = "i2s-slave"))]
+    i2s_driver.enable();
+    i2s_driver.set_tx_dma(true);
+    start(stream, i2s_driver);
+}
+
+// Waits for the slot numbered fade_slot to be played, then stops the DMA and the I2S like crate::stop_clean
+// Returns the stream and the disabled driver so the output can be started again, the pins stay configured if they're dropped
+pub fn stop_clean(fade_slot: usize) -> Option<(StreamX<pac::DMA1, 5>, SecondI2sTx)> {
+    while !G_SECOND_RING.has_played(fade_slot) {}
+
+    let transfer = cortex_m::interrupt::free(|cs| G_SECOND_TRANSFER.borrow(cs).borrow_mut().take())?;
+    let (stream, mut i2s_driver, _, _) = transfer.release();
+
+    i2s_driver.set_tx_dma(false);
+    while !i2s_driver.status().txe() {}
+    while i2s_driver.status().bsy() {}
+    i2s_driver.disable();
+    Some((stream, i2s_driver))
+}
+
+#[interrupt]
+fn DMA1_STREAM5() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(transfer) = G_SECOND_TRANSFER.borrow(cs).borrow_mut().as_mut() {
+            // Both outputs are restarted together after an error, so they stay in step
+            let flags = transfer.flags();
+            if flags.is_transfer_error() || flags.is_fifo_error() {
+                crate::G_DMA_ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+            if flags.is_transfer_error() {
+                crate::G_DMA_FAILED.store(true, Ordering::Relaxed);
+            }
+
+            if flags.is_transfer_complete() {
+                let queued = match G_SECOND_RING.next_play() {
+                    Some(next_buf) => transfer.next_transfer(next_buf).is_ok(),
+                    None => {
+                        G_SECOND_UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+                        transfer.next_transfer(&SILENCE_BUFFER).is_ok()
+                    },
+                };
+                if !queued {
+                    crate::G_DMA_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    crate::G_DMA_FAILED.store(true, Ordering::Relaxed);
+                }
+            }
+
+            transfer.clear_all_flags();
+        }
+    });
+}
@@ -0,0 +1,130 @@
+// Minimal command shell that reads commands sent from the host over an RTT down channel
+// Commands are plain text lines, e.g. typing "dump" into the probe-rs RTT terminal
+
+use heapless::{Deque, String};
+use rtt_target::DownChannel;
+
+const MAX_LINE_LENGTH: usize = 32;
+
+// Bytes read from the host at a time, every other byte can end a command
+const READ_SIZE: usize = 16;
+const MAX_PENDING: usize = READ_SIZE / 2;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    Dump, // Print a snapshot of the player state
+    FindDuplicates, // Fingerprint the files on the card and report duplicates
+    Play(usize), // Play a track from the playlist, numbered from 1 like in the dump
+    Queue(usize), // Play a track from the playlist after the current one
+    ClearQueue, // Forget the queued tracks
+    Next, // Skip to the next track
+    Previous, // Go back to the start of the track, or to the track before within the first few seconds
+    Volume(u8), // Set the volume from 0 to 100 %
+    Balance(i8), // Set the balance from -100 (left) to 100 (right)
+    Route, // Switch between stereo, swapped channels, left only, right only, and mono
+    Output, // Switch between both outputs, the first output only, and the second output only, with the dual-i2s feature
+    Speed(u16), // Set the playback speed in %, 100 is normal speed
+    LoopStart, // Mark the start of an A-B loop
+    LoopEnd, // Mark the end of the A-B loop and start looping
+    LoopClear, // Stop the A-B loop
+    Shuffle, // Turn shuffle on or off
+    Repeat, // Switch between repeat off, repeat one, and repeat all
+    Mute, // Mute or unmute, the track keeps playing while muted
+    Beep, // Play a beep over the music
+    Pause, // Stop playing, keeping the position in the track
+    Resume, // Carry on from where playback was paused
+    Stop, // Fade out, play out the buffered audio, and stop the output
+    Unknown,
+}
+
+impl Command {
+    fn parse(line: &str) -> Command {
+        match line.trim() {
+            "dump" => Command::Dump,
+            "dupes" => Command::FindDuplicates,
+            "next" => Command::Next,
+            "prev" => Command::Previous,
+            "shuffle" => Command::Shuffle,
+            "repeat" => Command::Repeat,
+            "route" => Command::Route,
+            "output" => Command::Output,
+            "mute" => Command::Mute,
+            "beep" => Command::Beep,
+            "pause" => Command::Pause,
+            "resume" => Command::Resume,
+            "stop" => Command::Stop,
+            "a" => Command::LoopStart,
+            "b" => Command::LoopEnd,
+            "noloop" => Command::LoopClear,
+            "unqueue" => Command::ClearQueue,
+            line => parse_with_number(line),
+        }
+    }
+}
+
+// Commands followed by a number, e.g. "play 3"
+fn parse_with_number(line: &str) -> Command {
+    let Some((name, number)) = line.split_once(' ') else {
+        return Command::Unknown;
+    };
+    let Ok(number) = number.trim().parse::<i32>() else {
+        return Command::Unknown;
+    };
+
+    match name {
+        "play" if number > 0 => Command::Play(number as usize - 1),
+        "queue" if number > 0 => Command::Queue(number as usize - 1),
+        "vol" => Command::Volume(number.clamp(0, u8::MAX as i32) as u8),
+        "bal" => Command::Balance(number.clamp(i8::MIN as i32, i8::MAX as i32) as i8),
+        "speed" => Command::Speed(number.clamp(0, u16::MAX as i32) as u16),
+        _ => Command::Unknown,
+    }
+}
+
+pub struct Shell {
+    channel: DownChannel,
+    line: String<MAX_LINE_LENGTH>,
+    overflowed: bool, // The line was too long, the rest of it is thrown away up to the next newline
+    pending: Deque<Command, MAX_PENDING>, // Commands received in the same read, returned one at a time
+}
+
+impl Shell {
+    pub fn new(channel: DownChannel) -> Self {
+        Shell {
+            channel,
+            line: String::new(),
+            overflowed: false,
+            pending: Deque::new(),
+        }
+    }
+
+    // Reads any pending bytes from the host
+    // Returns a command once a full line has been received, otherwise None
+    // Several commands sent at once are returned one per call, in the order they were sent
+    // This doesn't block, so it can be called every iteration of the main loop
+    pub fn poll(&mut self) -> Option<Command> {
+        // Nothing more is read until the pending commands are returned, so there's always room for the commands in a read
+        if !self.pending.is_empty() {
+            return self.pending.pop_front();
+        }
+
+        let mut buf = [0u8; READ_SIZE];
+        let bytes_read = self.channel.read(&mut buf);
+
+        for byte in buf.iter().take(bytes_read) {
+            if *byte == b'\n' || *byte == b'\r' {
+                if !self.line.is_empty() && !self.overflowed {
+                    let _ = self.pending.push_back(Command::parse(self.line.as_str()));
+                }
+                self.line.clear();
+                self.overflowed = false;
+            } else if self.overflowed || self.line.push(*byte as char).is_err() {
+                // Line is too long to be a valid command, throw it away
+                self.line.clear();
+                self.overflowed = true;
+            }
+        }
+
+        self.pending.pop_front()
+    }
+}
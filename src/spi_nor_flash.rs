@@ -0,0 +1,115 @@
+// Read only block device on external SPI NOR flash, e.g. a W25Q series part
+// The flash holds a raw exFAT image with 512 byte sectors, like the demo image, written with a flash programmer
+// This lets a small set of sounds ship on the board and play without a card
+//
+// Only the commands every SPI NOR part has are used: JEDEC ID, release from power down and fast read
+// The size is taken from the JEDEC ID, parts over 16 MB are read with 4 byte addresses
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use crate::block_device::BlockDevice;
+use crate::BLOCK_SIZE;
+
+// Commands
+const READ_JEDEC_ID: u8 = 0x9F;
+const RELEASE_POWER_DOWN: u8 = 0xAB;
+const FAST_READ: u8 = 0x0B;
+const FAST_READ_4B: u8 = 0x0C;
+
+const MAX_3_BYTE_ADDRESS_SIZE: u32 = 1 << 24;
+const ID_ATTEMPTS: usize = 10; // The part takes a few us to wake up from power down
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpiFlashError {
+    Bus, // The SPI bus or chip select pin failed
+    NoFlash, // The JEDEC ID read back as all 0s or 1s, e.g. nothing is connected
+    UnknownSize(u8), // The size byte of the JEDEC ID isn't a power of 2 that makes sense
+    OutOfRange, // The read goes past the end of the flash
+    NotInitialized,
+}
+
+pub struct SpiNorFlash<SPI, CS> {
+    pub spi: SPI,
+    cs: CS,
+    size: u32, // Bytes, 0 until initialized
+}
+
+impl<SPI: SpiBus<u8>, CS: OutputPin> SpiNorFlash<SPI, CS> {
+    pub fn new(spi: SPI, cs: CS) -> Self {
+        SpiNorFlash {
+            spi,
+            cs,
+            size: 0,
+        }
+    }
+
+    // Wakes the flash up and finds its size, returns the JEDEC manufacturer and device ID
+    pub fn init(&mut self) -> Result<[u8; 3], SpiFlashError> {
+        self.size = 0;
+        self.command(&[RELEASE_POWER_DOWN], &mut [])?;
+
+        let mut id = [0; 3];
+        for _ in 0..ID_ATTEMPTS {
+            self.command(&[READ_JEDEC_ID], &mut id)?;
+            if id != [0x00; 3] && id != [0xFF; 3] {
+                break;
+            }
+        }
+        if id == [0x00; 3] || id == [0xFF; 3] {
+            return Err(SpiFlashError::NoFlash);
+        }
+
+        // The last byte is log2 of the size in bytes for nearly every part
+        let [_, _, size_power] = id;
+        if !(16..32).contains(&size_power) {
+            return Err(SpiFlashError::UnknownSize(size_power));
+        }
+        self.size = 1 << size_power;
+        Ok(id)
+    }
+
+    pub fn block_count(&self) -> u32 {
+        self.size / BLOCK_SIZE as u32
+    }
+
+    // Reads blocks.len() consecutive blocks starting at blockaddr
+    pub fn read(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), SpiFlashError> {
+        if self.size == 0 {
+            return Err(SpiFlashError::NotInitialized);
+        }
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        if blockaddr as u64 + blocks.len() as u64 > self.block_count() as u64 {
+            return Err(SpiFlashError::OutOfRange);
+        }
+
+        // Fast read is followed by a dummy byte, and streams data until chip select goes high
+        let address = blockaddr * BLOCK_SIZE as u32;
+        let [a0, a1, a2, a3] = address.to_be_bytes();
+        if self.size > MAX_3_BYTE_ADDRESS_SIZE {
+            self.command(&[FAST_READ_4B, a0, a1, a2, a3, 0], blocks.as_flattened_mut())
+        } else {
+            self.command(&[FAST_READ, a1, a2, a3, 0], blocks.as_flattened_mut())
+        }
+    }
+
+    // Sends a command, then reads response.len() bytes with chip select held low
+    fn command(&mut self, command: &[u8], response: &mut [u8]) -> Result<(), SpiFlashError> {
+        self.cs.set_low().map_err(|_| SpiFlashError::Bus)?;
+        let result = self.spi.write(command).and_then(|_| self.spi.read(response)).and_then(|_| self.spi.flush());
+        self.cs.set_high().map_err(|_| SpiFlashError::Bus)?;
+        result.map_err(|_| SpiFlashError::Bus)
+    }
+}
+
+impl<SPI: SpiBus<u8>, CS: OutputPin> BlockDevice<BLOCK_SIZE> for SpiNorFlash<SPI, CS> {
+    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+        self.read(blockaddr, core::slice::from_mut(block)).map_err(|_| ())
+    }
+
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), ()> {
+        self.read(blockaddr, blocks).map_err(|_| ())
+    }
+}
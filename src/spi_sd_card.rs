@@ -0,0 +1,329 @@
+// Sd card over SPI, for boards where the SDIO pins aren't broken out
+// Works with any embedded-hal SPI bus and chip select pin, the exFAT and WAV layers don't know the difference
+//
+// Useful SPI mode resources:
+// http://elm-chan.org/docs/mmc/mmc_e.html
+// https://www.sdcard.org/downloads/pls/ (Physical Layer Simplified Specification, chapter 7)
+//
+// The card has to be clocked at 400 KHz or less until it has been initialized, then it can go up to 25 MHz
+// The bus can't be re-clocked through embedded-hal, so set_clock is a function that does it for the bus being used
+// CRCs are off (the default in SPI mode), only CMD0 and CMD8 need a valid CRC
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use crate::block_device::BlockDevice;
+use crate::BLOCK_SIZE;
+
+// Commands
+const GO_IDLE_STATE: u8 = 0; // CMD0
+const SEND_IF_COND: u8 = 8; // CMD8
+const SEND_STATUS: u8 = 13; // CMD13
+const STOP_TRANSMISSION: u8 = 12; // CMD12
+const SET_BLOCKLEN: u8 = 16; // CMD16
+const READ_SINGLE_BLOCK: u8 = 17; // CMD17
+const READ_MULTIPLE_BLOCK: u8 = 18; // CMD18
+const APP_CMD: u8 = 55; // CMD55
+const READ_OCR: u8 = 58; // CMD58
+const SD_SEND_OP_COND: u8 = 41; // ACMD41
+
+// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+const IF_COND_ARG: u32 = 0x1AA; // 2.7-3.6 V and a check pattern, echoed back by version 2 cards
+const HCS: u32 = 1 << 30; // ACMD41 argument, the host supports high capacity cards
+const OCR_CCS: u32 = 1 << 30; // The card is high capacity, so it's block addressed
+
+const DATA_START_TOKEN: u8 = 0xFE;
+
+// Polls are counted in bytes clocked out, at 400 KHz each byte is 20 us
+const RESPONSE_POLLS: usize = 10; // The response comes within 8 bytes of the command
+const TOKEN_POLLS: usize = 50_000; // Reads start within 100 ms
+const INIT_ATTEMPTS: usize = 2_500; // ACMD41 takes up to a second
+const INIT_CLOCKS_BYTES: usize = 10; // At least 74 clocks with the card deselected before CMD0
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpiSdError {
+    Bus, // The SPI bus or chip select pin failed
+    NoResponse, // The card didn't answer a command, e.g. there isn't one
+    Command { cmd: u8, r1: u8 }, // The card answered a command with an error
+    Timeout, // The card didn't start sending data, or didn't get out of idle during init
+    DataError(u8), // The card sent an error token instead of a block
+    UnsupportedCard, // Version 1 and MMC cards that don't accept the block length
+    NotInitialized,
+}
+
+pub struct SpiSdCard<SPI, CS> {
+    pub spi: SPI,
+    cs: CS,
+    set_clock: fn(&mut SPI, bool), // Sets the bus to the fast clock when true, or to 400 KHz or less for init
+    block_addressed: bool, // High capacity cards are addressed in blocks, older cards in bytes
+    initialized: bool,
+}
+
+impl<SPI: SpiBus<u8>, CS: OutputPin> SpiSdCard<SPI, CS> {
+    pub fn new(spi: SPI, cs: CS, set_clock: fn(&mut SPI, bool)) -> Self {
+        SpiSdCard {
+            spi,
+            cs,
+            set_clock,
+            block_addressed: false,
+            initialized: false,
+        }
+    }
+
+    // Puts the card in SPI mode and gets it ready to read, then switches the bus to the fast clock
+    pub fn init(&mut self) -> Result<(), SpiSdError> {
+        self.initialized = false;
+        (self.set_clock)(&mut self.spi, false);
+
+        // The card needs clocks with chip select high to start up
+        self.cs.set_high().map_err(|_| SpiSdError::Bus)?;
+        self.clock_out(INIT_CLOCKS_BYTES)?;
+
+        let result = self.init_selected();
+        self.deselect()?;
+        result?;
+
+        (self.set_clock)(&mut self.spi, true);
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn init_selected(&mut self) -> Result<(), SpiSdError> {
+        self.select()?;
+
+        let r1 = self.command(GO_IDLE_STATE, 0)?;
+        if r1 != R1_IDLE {
+            return Err(SpiSdError::Command { cmd: GO_IDLE_STATE, r1 });
+        }
+
+        // Version 1 cards don't know CMD8
+        let r1 = self.command(SEND_IF_COND, IF_COND_ARG)?;
+        let version_2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if version_2 {
+            let r7 = self.read_u32()?;
+            if r7 & 0xFFF != IF_COND_ARG {
+                return Err(SpiSdError::UnsupportedCard);
+            }
+        }
+
+        // Wait for the card to leave the idle state
+        let op_cond_arg = if version_2 { HCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_ATTEMPTS {
+            self.command(APP_CMD, 0)?;
+            let r1 = self.command(SD_SEND_OP_COND, op_cond_arg)?;
+            if r1 & !R1_IDLE != 0 {
+                return Err(SpiSdError::Command { cmd: SD_SEND_OP_COND, r1 });
+            }
+            if r1 == 0 {
+                ready = true;
+                break;
+            }
+        }
+        if !ready {
+            return Err(SpiSdError::Timeout);
+        }
+
+        self.block_addressed = false;
+        if version_2 {
+            let r1 = self.command(READ_OCR, 0)?;
+            if r1 != 0 {
+                return Err(SpiSdError::Command { cmd: READ_OCR, r1 });
+            }
+            self.block_addressed = self.read_u32()? & OCR_CCS != 0;
+        }
+
+        // Byte addressed cards can have other block lengths
+        if !self.block_addressed {
+            let r1 = self.command(SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+            if r1 != 0 {
+                return Err(SpiSdError::UnsupportedCard);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads blocks.len() consecutive blocks starting at blockaddr
+    pub fn read(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), SpiSdError> {
+        if !self.initialized {
+            return Err(SpiSdError::NotInitialized);
+        }
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        self.select()?;
+        let result = self.read_selected(blockaddr, blocks);
+        self.deselect()?;
+        result
+    }
+
+    fn read_selected(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), SpiSdError> {
+        let address = match self.block_addressed {
+            true => blockaddr,
+            false => blockaddr * BLOCK_SIZE as u32,
+        };
+
+        let cmd = if blocks.len() == 1 { READ_SINGLE_BLOCK } else { READ_MULTIPLE_BLOCK };
+        let r1 = self.command(cmd, address)?;
+        if r1 != 0 {
+            return Err(SpiSdError::Command { cmd, r1 });
+        }
+
+        let mut result = Ok(());
+        for block in blocks.iter_mut() {
+            result = self.read_data(block);
+            if result.is_err() {
+                break;
+            }
+        }
+
+        // The card keeps sending blocks until it's told to stop, this has to be sent even if the read failed
+        if cmd == READ_MULTIPLE_BLOCK {
+            self.command(STOP_TRANSMISSION, 0)?;
+            self.wait_not_busy()?;
+        }
+        result
+    }
+
+    // True if the card answers a status command, a card that has been taken out doesn't answer
+    pub fn responds(&mut self) -> bool {
+        if !self.initialized || self.select().is_err() {
+            return false;
+        }
+
+        let r1 = self.command(SEND_STATUS, 0);
+        let r2 = self.transfer_byte(0xFF);
+        let _ = self.deselect();
+        matches!((r1, r2), (Ok(0), Ok(0)))
+    }
+
+    fn select(&mut self) -> Result<(), SpiSdError> {
+        self.cs.set_low().map_err(|_| SpiSdError::Bus)?;
+        self.transfer_byte(0xFF)?;
+        Ok(())
+    }
+
+    // An extra byte after chip select goes high lets the card release the data line
+    fn deselect(&mut self) -> Result<(), SpiSdError> {
+        self.cs.set_high().map_err(|_| SpiSdError::Bus)?;
+        self.transfer_byte(0xFF)?;
+        Ok(())
+    }
+
+    // Sends a command and returns its R1 response, anything after R1 is left to be read
+    fn command(&mut self, cmd: u8, arg: u32) -> Result<u8, SpiSdError> {
+        if cmd != STOP_TRANSMISSION {
+            self.wait_not_busy()?;
+        }
+
+        let [a0, a1, a2, a3] = arg.to_be_bytes();
+        let mut frame = [0x40 | cmd, a0, a1, a2, a3, 0];
+        frame[5] = crc7(&frame[..5]) << 1 | 1;
+        self.spi.write(&frame).map_err(|_| SpiSdError::Bus)?;
+
+        // CMD12 is followed by a stuff byte
+        if cmd == STOP_TRANSMISSION {
+            self.transfer_byte(0xFF)?;
+        }
+
+        for _ in 0..RESPONSE_POLLS {
+            let r1 = self.transfer_byte(0xFF)?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SpiSdError::NoResponse)
+    }
+
+    // Waits for the start of a data block, then reads it and skips its CRC
+    fn read_data(&mut self, block: &mut [u8; BLOCK_SIZE]) -> Result<(), SpiSdError> {
+        let mut token = 0xFF;
+        for _ in 0..TOKEN_POLLS {
+            token = self.transfer_byte(0xFF)?;
+            if token != 0xFF {
+                break;
+            }
+        }
+        match token {
+            DATA_START_TOKEN => (),
+            0xFF => return Err(SpiSdError::Timeout),
+            error => return Err(SpiSdError::DataError(error)),
+        }
+
+        // The data line has to be high while reading
+        block.fill(0xFF);
+        self.spi.transfer_in_place(block).map_err(|_| SpiSdError::Bus)?;
+
+        let mut crc = [0xFF; 2];
+        self.spi.transfer_in_place(&mut crc).map_err(|_| SpiSdError::Bus)?;
+        Ok(())
+    }
+
+    // The card holds the data line low while it's busy
+    fn wait_not_busy(&mut self) -> Result<(), SpiSdError> {
+        for _ in 0..TOKEN_POLLS {
+            if self.transfer_byte(0xFF)? == 0xFF {
+                return Ok(());
+            }
+        }
+        Err(SpiSdError::Timeout)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SpiSdError> {
+        let mut bytes = [0xFF; 4];
+        self.spi.transfer_in_place(&mut bytes).map_err(|_| SpiSdError::Bus)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn transfer_byte(&mut self, byte: u8) -> Result<u8, SpiSdError> {
+        let mut bytes = [byte];
+        self.spi.transfer_in_place(&mut bytes).map_err(|_| SpiSdError::Bus)?;
+        Ok(bytes[0])
+    }
+
+    fn clock_out(&mut self, bytes: usize) -> Result<(), SpiSdError> {
+        for _ in 0..bytes {
+            self.transfer_byte(0xFF)?;
+        }
+        Ok(())
+    }
+}
+
+impl<SPI: SpiBus<u8>, CS: OutputPin> BlockDevice<BLOCK_SIZE> for SpiSdCard<SPI, CS> {
+    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+        self.read(blockaddr, core::slice::from_mut(block)).map_err(|_| ())
+    }
+
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), ()> {
+        self.read(blockaddr, blocks).map_err(|_| ())
+    }
+
+    fn is_present(&mut self) -> bool {
+        self.responds()
+    }
+
+    fn reinit(&mut self) -> Result<(), ()> {
+        self.init().map_err(|_| ())
+    }
+}
+
+// CRC7 of a command frame, polynomial x^7 + x^3 + 1
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0_u8;
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            let input = (byte >> bit) & 1;
+            let top = (crc >> 6) & 1;
+            crc = (crc << 1) & 0x7F;
+            if input ^ top != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
@@ -0,0 +1,208 @@
+// Time stretching with WSOLA (waveform similarity overlap add), so audiobooks can be sped up without the pitch going up
+// Only built with the time-stretch feature, searching for the best overlap takes a lot of CPU time
+//
+// The output is made of segments of HOP frames, each one cross faded into the last
+// The input position of each segment moves on by HOP * speed frames, so the output is shorter or longer than the input
+// Each segment is taken from within SEARCH frames of that position, wherever it lines up best with the end of the last segment,
+// which keeps the waveform continuous across the cross fade and avoids the phasey sound of a plain overlap add
+//
+// The frames that follow the last segment in the input (tail) are what the output would have carried on with,
+// so the candidates are compared against the tail, and the tail is faded out as the new segment fades in
+
+use crate::wav::{MAX_SPEED_PERCENT, NORMAL_SPEED_PERCENT};
+
+// 512 frames is about 12 ms at 44.1 kHz, the segments are twice that which suits speech
+const HOP: usize = 512;
+const SEARCH: usize = 192;
+
+// The search only looks at every SEARCH_STEP candidate, and compares every CORRELATION_STEP frame
+const SEARCH_STEP: usize = 2;
+const CORRELATION_STEP: usize = 4;
+
+// Enough for the search either side of a position, and the two hops after it
+// Everything before the search is thrown away after each segment, so the position is at most SEARCH into the input
+const INPUT_FRAMES: usize = 2 * SEARCH + 2 * HOP;
+
+pub struct TimeStretch {
+    speed_percent: u16,
+
+    input: [u16; INPUT_FRAMES * 2], // Interleaved stereo frames read from the source
+    input_frames: usize, // Frames in input
+    position: usize, // Nominal input frame of the next segment
+    position_fraction: u32, // Hundredths of a frame, for speeds that don't make a whole number of frames per hop
+    source_finished: bool,
+
+    tail: [u16; HOP * 2], // The frames after the last segment
+    has_tail: bool,
+
+    output: [u16; HOP * 2], // The last segment, after the cross fade
+    output_pos: usize, // Samples of output that have been handed out
+    output_len: usize,
+}
+
+impl core::fmt::Debug for TimeStretch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TimeStretch")
+            .field("speed_percent", &self.speed_percent)
+            .field("input_frames", &self.input_frames)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl TimeStretch {
+    pub fn new() -> Self {
+        TimeStretch {
+            speed_percent: NORMAL_SPEED_PERCENT,
+            input: [0; INPUT_FRAMES * 2],
+            input_frames: 0,
+            position: 0,
+            position_fraction: 0,
+            source_finished: false,
+            tail: [0; HOP * 2],
+            has_tail: false,
+            output: [0; HOP * 2],
+            output_pos: 0,
+            output_len: 0,
+        }
+    }
+
+    // The speed can be changed while playing, it takes effect from the next segment
+    // Above MAX_SPEED_PERCENT the position would jump past the input that is kept
+    pub fn set_speed(&mut self, speed_percent: u16) {
+        self.speed_percent = speed_percent.clamp(1, MAX_SPEED_PERCENT);
+    }
+
+    pub fn speed_percent(&self) -> u16 {
+        self.speed_percent
+    }
+
+    // At normal speed the samples are passed straight through, so fill doesn't need to be called
+    pub fn is_active(&self) -> bool {
+        self.speed_percent != NORMAL_SPEED_PERCENT
+    }
+
+    // Forgets the buffered input, call when the source is changed or seeks
+    pub fn reset(&mut self) {
+        self.input_frames = 0;
+        self.position = 0;
+        self.position_fraction = 0;
+        self.source_finished = false;
+        self.has_tail = false;
+        self.output_pos = 0;
+        self.output_len = 0;
+    }
+
+    // Fills buf with interleaved stereo samples, reading from source as needed
+    // source fills a slice with samples and returns how many, fewer than asked for once it has finished (like WavFile::fill)
+    // Returns the number of samples put in buf, fewer than its length once the source has finished and been played out
+    // The rest of buf is filled with silence, and the stretcher is reset so it can carry on with the source after a seek
+    pub fn fill<F: FnMut(&mut [u16]) -> Result<usize, ()>>(&mut self, buf: &mut [u16], mut source: F) -> Result<usize, ()> {
+        let mut samples_filled = 0;
+
+        while samples_filled < buf.len() {
+            if self.output_pos == self.output_len && !self.next_segment(&mut source)? {
+                break;
+            }
+
+            let output = self.output.get(self.output_pos..self.output_len).unwrap_or(&[]);
+            let rest = buf.get_mut(samples_filled..).unwrap_or(&mut []);
+            let samples = output.len().min(rest.len());
+            rest.iter_mut().zip(output).for_each(|(sample, output_sample)| *sample = *output_sample);
+            self.output_pos += samples;
+            samples_filled += samples;
+        }
+
+        if samples_filled < buf.len() {
+            buf.iter_mut().skip(samples_filled).for_each(|sample| *sample = 0);
+            self.reset();
+        }
+        Ok(samples_filled)
+    }
+
+    // Works out the next segment into output, returns false once the source has been played out
+    fn next_segment<F: FnMut(&mut [u16]) -> Result<usize, ()>>(&mut self, source: &mut F) -> Result<bool, ()> {
+        // Read enough for every candidate
+        let needed = (self.position + SEARCH + 2 * HOP).min(INPUT_FRAMES);
+        while self.input_frames < needed && !self.source_finished {
+            let space = self.input.get_mut(self.input_frames * 2..needed * 2).ok_or(())?;
+            let samples = source(space)?;
+            if samples < space.len() {
+                self.source_finished = true;
+            }
+            self.input_frames += samples / 2;
+        }
+
+        // Near the end there isn't a whole segment left, so the tail is played out on its own
+        if self.input_frames < self.position + 2 * HOP {
+            if !self.has_tail {
+                return Ok(false);
+            }
+            self.output = self.tail;
+            self.has_tail = false;
+            self.output_pos = 0;
+            self.output_len = self.output.len();
+            return Ok(true);
+        }
+
+        let start = if self.has_tail { self.best_match() } else { self.position };
+        let segment = self.input.get(start * 2..(start + HOP) * 2).ok_or(())?;
+        for (frame_indx, (output, frame)) in self.output.chunks_exact_mut(2).zip(segment.chunks_exact(2)).enumerate() {
+            for ((output, sample), tail_sample) in output.iter_mut().zip(frame).zip(self.tail.get(frame_indx * 2..).unwrap_or(&[])) {
+                *output = match self.has_tail {
+                    // Linear cross fade, the two weights add up to HOP
+                    true => ((*tail_sample as i16 as i32 * (HOP - frame_indx) as i32 + *sample as i16 as i32 * frame_indx as i32)
+                        / HOP as i32) as i16 as u16,
+                    false => *sample,
+                };
+            }
+        }
+        self.output_pos = 0;
+        self.output_len = self.output.len();
+
+        let tail = self.input.get((start + HOP) * 2..(start + 2 * HOP) * 2).ok_or(())?;
+        self.tail.copy_from_slice(tail);
+        self.has_tail = true;
+
+        // Move on by HOP * speed input frames
+        let advance = HOP as u32 * self.speed_percent as u32 + self.position_fraction;
+        self.position += (advance / NORMAL_SPEED_PERCENT as u32) as usize;
+        self.position_fraction = advance % NORMAL_SPEED_PERCENT as u32;
+
+        // Only the frames the next search can reach are kept
+        let discard = self.position.saturating_sub(SEARCH).min(self.input_frames);
+        self.input.copy_within(discard * 2..self.input_frames * 2, 0);
+        self.input_frames -= discard;
+        self.position -= discard;
+        Ok(true)
+    }
+
+    // Finds the start of the segment near position which lines up best with the tail
+    fn best_match(&self) -> usize {
+        let lowest = self.position.saturating_sub(SEARCH);
+        let highest = (self.position + SEARCH).min(self.input_frames.saturating_sub(2 * HOP));
+
+        let mono = |samples: &[u16], frame_indx: usize| match samples.get(frame_indx * 2..frame_indx * 2 + 2) {
+            Some(frame) => (frame[0] as i16 as i32 + frame[1] as i16 as i32) >> 1,
+            None => 0,
+        };
+
+        let mut best = (self.position.min(highest), i64::MIN);
+        for candidate in (lowest..=highest).step_by(SEARCH_STEP) {
+            let correlation = (0..HOP).step_by(CORRELATION_STEP)
+                .map(|frame_indx| mono(&self.input, candidate + frame_indx) as i64 * mono(&self.tail, frame_indx) as i64)
+                .sum::<i64>();
+            if correlation > best.1 {
+                best = (candidate, correlation);
+            }
+        }
+
+        best.0
+    }
+}
+
+impl Default for TimeStretch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,548 @@
+// USB OTG full speed host, just enough of it to talk to a USB flash drive
+//
+// The HAL only supports the OTG peripheral as a device, so this drives the host registers directly
+// Everything is polled, there is one device on the port and every transfer goes through channel 0,
+// which is set up again for the endpoint of each packet
+// A packet the device answers with NAK is sent again until TRANSFER_TIMEOUT_MS has passed
+//
+// Reference: RM0383 chapter 22 (OTG_FS) and the USB 2.0 specification chapter 9
+//
+// The device needs 5 V on VBUS, which has to come from the board, the OTG peripheral can't supply it
+// VBUS sensing is off so PA9 is left free
+
+use stm32f4xx_hal::pac;
+use stm32f4xx_hal::rcc::{Enable, Reset};
+
+// FIFO sizes in words, there are 320 in all
+const RX_FIFO_WORDS: u32 = 128;
+const NPTX_FIFO_WORDS: u32 = 96;
+const PTX_FIFO_WORDS: u32 = 96;
+
+const FIFO_OFFSET: usize = 0x1000; // The channel 0 FIFO, from the start of the OTG_FS registers
+const FRAME_INTERVAL: u32 = 48_000; // PHY clocks in a 1 ms full speed frame
+const FRAME_MASK: u16 = 0x3FFF; // The frame number wraps at 14 bits
+
+// hprt bits that are cleared by writing 1 (pcdet, penchng, pocchng), and pena which disables the port when 1 is written
+const HPRT_WRITE_CLEAR: u32 = 0b10_1110;
+const HPRT_PRST: u32 = 1 << 8;
+const HPRT_PPWR: u32 = 1 << 12;
+const PORT_SPEED_FULL: u8 = 1;
+
+const HCINT_ALL: u32 = 0x7FF;
+
+// hcchar endpoint types
+const EPTYP_CONTROL: u32 = 0;
+const EPTYP_BULK: u32 = 2;
+
+// hctsiz PIDs
+const PID_DATA0: u32 = 0;
+const PID_DATA1: u32 = 2;
+const PID_SETUP: u32 = 3;
+
+const PKTSTS_IN_DATA: u8 = 0b0010; // grxstsp packet status of received data
+
+// Times from the USB specification
+const CONNECT_DEBOUNCE_MS: u32 = 100;
+const PORT_RESET_MS: u32 = 15;
+const RESET_RECOVERY_MS: u32 = 20;
+const SET_ADDRESS_RECOVERY_MS: u32 = 2;
+const FORCE_HOST_MODE_MS: u32 = 50; // The core takes 25 ms to switch to host mode
+const PORT_ENABLE_TIMEOUT_MS: u32 = 100;
+
+const TRANSFER_TIMEOUT_MS: u16 = 1000; // A packet is given up on if the device keeps answering NAK for this long
+const MAX_TRANSACTION_ERRORS: u32 = 3; // CRC, bit stuffing and response timeout errors in a row before a packet is given up on
+
+// Standard requests and descriptors
+const GET_DESCRIPTOR: u8 = 6;
+const SET_ADDRESS: u8 = 5;
+const SET_CONFIGURATION: u8 = 9;
+const CLEAR_FEATURE: u8 = 1;
+const ENDPOINT_HALT: u16 = 0;
+const DEVICE_DESCRIPTOR: u8 = 1;
+const CONFIG_DESCRIPTOR: u8 = 2;
+const INTERFACE_DESCRIPTOR: u8 = 4;
+const ENDPOINT_DESCRIPTOR: u8 = 5;
+const CONFIG_DESCRIPTOR_LEN: usize = 9;
+const MAX_CONFIG_LEN: usize = 256; // Longer configuration descriptors are cut short, the interfaces after that can't be used
+const BULK: u8 = 2; // Endpoint attributes transfer type
+
+// Request types
+const DEVICE_TO_HOST: u8 = 0x80;
+const TO_ENDPOINT: u8 = 0x02;
+const ENDPOINT_IN: u8 = 0x80; // Direction bit of an endpoint address
+
+const DEVICE_ADDRESS: u8 = 1; // The only device on the port
+const DEFAULT_MAX_PACKET: u16 = 8; // The control endpoint packet size until the device descriptor has been read
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsbError {
+    NoDevice, // Nothing is connected, or it was unplugged
+    UnsupportedDevice, // Low speed, or the interface that was asked for isn't there
+    Stall, // The device refused the request
+    Timeout, // The device kept answering NAK
+    Transaction, // Errors on the bus, several times in a row
+    Babble, // The device sent more than it was asked for
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint {
+    pub number: u8, // Without the direction bit
+    pub max_packet: u16,
+    toggle: bool, // DATA1 is sent or expected next
+}
+
+impl Endpoint {
+    pub fn new(number: u8, max_packet: u16) -> Self {
+        Endpoint {
+            number,
+            max_packet,
+            toggle: false,
+        }
+    }
+
+    fn pid(&self) -> u32 {
+        if self.toggle { PID_DATA1 } else { PID_DATA0 }
+    }
+}
+
+// An interface found by enumerate, with its bulk endpoints
+#[derive(Debug, Clone, Copy)]
+pub struct BulkInterface {
+    pub number: u8,
+    pub bulk_in: Endpoint,
+    pub bulk_out: Endpoint,
+}
+
+// What happened to one packet
+enum Outcome {
+    Done(usize), // Bytes received
+    Nak,
+    Error,
+}
+
+pub struct UsbHost {
+    global: pac::OTG_FS_GLOBAL,
+    host: pac::OTG_FS_HOST,
+    pwrclk: pac::OTG_FS_PWRCLK,
+    cycles_per_ms: u32,
+    address: u8,
+    ep0_max_packet: u16,
+}
+
+impl UsbHost {
+    // Starts the core in host mode and powers the port
+    // The 48 MHz clock has to be running, and DM/DP (PA11/PA12) set to alternate function 10
+    pub fn new(global: pac::OTG_FS_GLOBAL, host: pac::OTG_FS_HOST, pwrclk: pac::OTG_FS_PWRCLK, cycles_per_ms: u32) -> Self {
+        // Safety: the peripheral is owned here, nothing else uses its clock enable or reset bits
+        unsafe {
+            pac::OTG_FS_GLOBAL::enable_unchecked();
+            pac::OTG_FS_GLOBAL::reset_unchecked();
+        }
+
+        let mut usb = UsbHost {
+            global,
+            host,
+            pwrclk,
+            cycles_per_ms,
+            address: 0,
+            ep0_max_packet: DEFAULT_MAX_PACKET,
+        };
+        usb.init_core();
+        usb
+    }
+
+    fn init_core(&mut self) {
+        // Full speed PHY, then a core reset
+        self.global.gusbcfg.modify(|_, w| w.physel().set_bit());
+        while self.global.grstctl.read().ahbidl().bit_is_clear() {}
+        self.global.grstctl.modify(|_, w| w.csrst().set_bit());
+        while self.global.grstctl.read().csrst().bit_is_set() {}
+
+        self.global.gusbcfg.modify(|_, w| w.fdmod().clear_bit().fhmod().set_bit());
+        self.delay_ms(FORCE_HOST_MODE_MS);
+
+        // Transceiver on, without VBUS sensing
+        self.global.gccfg.modify(|_, w| w.pwrdwn().set_bit().novbussens().set_bit().vbusasen().clear_bit().vbusbsen().clear_bit());
+        self.pwrclk.pcgcctl.reset();
+
+        // Safety: the values fit in their fields
+        unsafe {
+            self.host.hcfg.modify(|_, w| w.fslspcs().bits(1)); // 48 MHz PHY clock
+            self.global.grxfsiz.write(|w| w.rxfd().bits(RX_FIFO_WORDS as u16));
+            self.global.hnptxfsiz().write(|w| w.nptxfsa().bits(RX_FIFO_WORDS as u16).nptxfd().bits(NPTX_FIFO_WORDS as u16));
+            self.global.hptxfsiz.write(|w| w.ptxsa().bits((RX_FIFO_WORDS + NPTX_FIFO_WORDS) as u16).ptxfsiz().bits(PTX_FIFO_WORDS as u16));
+        }
+
+        // Flush all transmit FIFOs and the receive FIFO
+        self.global.grstctl.write(|w| unsafe { w.txfnum().bits(0x10) }.txfflsh().set_bit());
+        while self.global.grstctl.read().txfflsh().bit_is_set() {}
+        self.global.grstctl.write(|w| w.rxfflsh().set_bit());
+        while self.global.grstctl.read().rxfflsh().bit_is_set() {}
+
+        // Interrupts are polled
+        self.global.gintmsk.reset();
+        self.global.gintsts.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+        self.modify_port(HPRT_PPWR, 0);
+    }
+
+    // Resets the device on the port, after this it can be enumerated
+    pub fn connect(&mut self) -> Result<(), UsbError> {
+        self.address = 0;
+        self.ep0_max_packet = DEFAULT_MAX_PACKET;
+
+        if self.host.hprt.read().pcsts().bit_is_clear() {
+            return Err(UsbError::NoDevice);
+        }
+        self.delay_ms(CONNECT_DEBOUNCE_MS);
+
+        self.modify_port(HPRT_PRST, 0);
+        self.delay_ms(PORT_RESET_MS);
+        self.modify_port(0, HPRT_PRST);
+
+        let mut enabled = false;
+        for _ in 0..PORT_ENABLE_TIMEOUT_MS {
+            if self.host.hprt.read().pena().bit_is_set() {
+                enabled = true;
+                break;
+            }
+            self.delay_ms(1);
+        }
+        if !enabled {
+            return Err(UsbError::NoDevice);
+        }
+
+        // Low speed devices would need the PHY clock changed, no mass storage device is low speed
+        if self.host.hprt.read().pspd().bits() != PORT_SPEED_FULL {
+            return Err(UsbError::UnsupportedDevice);
+        }
+        self.host.hfir.write(|w| unsafe { w.frivl().bits(FRAME_INTERVAL as u16) });
+
+        self.delay_ms(RESET_RECOVERY_MS);
+        Ok(())
+    }
+
+    // True while a device is connected and the port is enabled
+    pub fn is_connected(&self) -> bool {
+        let hprt = self.host.hprt.read();
+        hprt.pcsts().bit_is_set() && hprt.pena().bit_is_set()
+    }
+
+    // Gives the device its address, then configures it and returns the first interface of the given class that has a bulk endpoint each way
+    pub fn enumerate(&mut self, class: u8, subclass: u8, protocol: u8) -> Result<BulkInterface, UsbError> {
+        // The packet size of the control endpoint is in the first 8 bytes of the device descriptor
+        let mut device = [0; 8];
+        self.control_in(DEVICE_TO_HOST, GET_DESCRIPTOR, (DEVICE_DESCRIPTOR as u16) << 8, 0, &mut device)?;
+        self.ep0_max_packet = (device[7] as u16).max(DEFAULT_MAX_PACKET);
+
+        self.control_out(0, SET_ADDRESS, DEVICE_ADDRESS as u16, 0)?;
+        self.delay_ms(SET_ADDRESS_RECOVERY_MS);
+        self.address = DEVICE_ADDRESS;
+
+        // The header of the configuration descriptor has its full length
+        let mut config = [0; MAX_CONFIG_LEN];
+        let (header, _) = config.split_at_mut(CONFIG_DESCRIPTOR_LEN);
+        if self.control_in(DEVICE_TO_HOST, GET_DESCRIPTOR, (CONFIG_DESCRIPTOR as u16) << 8, 0, header)? < CONFIG_DESCRIPTOR_LEN {
+            return Err(UsbError::UnsupportedDevice);
+        }
+        let total_len = (u16::from_le_bytes([config[2], config[3]]) as usize).min(MAX_CONFIG_LEN);
+        let configuration = config[5];
+
+        let (descriptors, _) = config.split_at_mut(total_len);
+        let len = self.control_in(DEVICE_TO_HOST, GET_DESCRIPTOR, (CONFIG_DESCRIPTOR as u16) << 8, 0, descriptors)?;
+        let interface = find_bulk_interface(descriptors.get(..len).unwrap_or_default(), class, subclass, protocol).ok_or(UsbError::UnsupportedDevice)?;
+
+        self.control_out(0, SET_CONFIGURATION, configuration as u16, 0)?;
+        Ok(interface)
+    }
+
+    // A control request with a data stage from the device, returns the number of bytes received
+    pub fn control_in(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8]) -> Result<usize, UsbError> {
+        let setup = setup_packet(request_type, request, value, index, buf.len() as u16);
+        let mut ep0 = Endpoint::new(0, self.ep0_max_packet);
+        self.out_packet(&ep0, EPTYP_CONTROL, PID_SETUP, &setup)?;
+
+        ep0.toggle = true;
+        let len = self.data_in(&mut ep0, EPTYP_CONTROL, buf)?;
+
+        ep0.toggle = true;
+        self.out_packet(&ep0, EPTYP_CONTROL, ep0.pid(), &[])?;
+        Ok(len)
+    }
+
+    // A control request without a data stage
+    pub fn control_out(&mut self, request_type: u8, request: u8, value: u16, index: u16) -> Result<(), UsbError> {
+        let setup = setup_packet(request_type, request, value, index, 0);
+        let mut ep0 = Endpoint::new(0, self.ep0_max_packet);
+        self.out_packet(&ep0, EPTYP_CONTROL, PID_SETUP, &setup)?;
+
+        ep0.toggle = true;
+        self.data_in(&mut ep0, EPTYP_CONTROL, &mut [])?;
+        Ok(())
+    }
+
+    // Clears a stalled bulk endpoint, the data toggle starts again at DATA0
+    pub fn clear_halt(&mut self, endpoint: &mut Endpoint, direction_in: bool) -> Result<(), UsbError> {
+        let address = endpoint.number | if direction_in { ENDPOINT_IN } else { 0 };
+        self.control_out(TO_ENDPOINT, CLEAR_FEATURE, ENDPOINT_HALT, address as u16)?;
+        endpoint.toggle = false;
+        Ok(())
+    }
+
+    // Receives up to buf.len() bytes, stopping early at a short packet
+    pub fn bulk_in(&mut self, endpoint: &mut Endpoint, buf: &mut [u8]) -> Result<usize, UsbError> {
+        self.data_in(endpoint, EPTYP_BULK, buf)
+    }
+
+    pub fn bulk_out(&mut self, endpoint: &mut Endpoint, data: &[u8]) -> Result<(), UsbError> {
+        for packet in data.chunks(endpoint.max_packet.max(1) as usize) {
+            self.out_packet(endpoint, EPTYP_BULK, endpoint.pid(), packet)?;
+            endpoint.toggle = !endpoint.toggle;
+        }
+        Ok(())
+    }
+
+    fn data_in(&mut self, endpoint: &mut Endpoint, eptyp: u32, buf: &mut [u8]) -> Result<usize, UsbError> {
+        let max_packet = endpoint.max_packet as usize;
+        let mut received = 0;
+        loop {
+            let count = self.in_packet(endpoint, eptyp, buf.get_mut(received..).unwrap_or_default())?;
+            endpoint.toggle = !endpoint.toggle;
+            received += count;
+            if count < max_packet || received >= buf.len() {
+                return Ok(received);
+            }
+        }
+    }
+
+    fn in_packet(&mut self, endpoint: &Endpoint, eptyp: u32, buf: &mut [u8]) -> Result<usize, UsbError> {
+        self.retry(|usb| {
+            usb.start_channel(endpoint, eptyp, true, endpoint.pid(), endpoint.max_packet as u32);
+            usb.wait_channel(buf)
+        })
+    }
+
+    fn out_packet(&mut self, endpoint: &Endpoint, eptyp: u32, pid: u32, data: &[u8]) -> Result<(), UsbError> {
+        self.retry(|usb| {
+            usb.start_channel(endpoint, eptyp, false, pid, data.len() as u32);
+            usb.write_fifo(data)?;
+            usb.wait_channel(&mut [])
+        })?;
+        Ok(())
+    }
+
+    // Runs packet until it goes through, sending it again after a NAK or a bus error
+    fn retry<F: FnMut(&mut Self) -> Result<Outcome, UsbError>>(&mut self, mut packet: F) -> Result<usize, UsbError> {
+        let start = self.frame();
+        let mut errors = 0;
+        loop {
+            match packet(self)? {
+                Outcome::Done(count) => return Ok(count),
+                Outcome::Nak => (),
+                Outcome::Error => {
+                    errors += 1;
+                    if errors >= MAX_TRANSACTION_ERRORS {
+                        return Err(UsbError::Transaction);
+                    }
+                },
+            }
+            if self.frames_since(start) >= TRANSFER_TIMEOUT_MS {
+                return Err(UsbError::Timeout);
+            }
+        }
+    }
+
+    // Sets channel 0 up for one packet to or from the endpoint and enables it
+    fn start_channel(&mut self, endpoint: &Endpoint, eptyp: u32, direction_in: bool, pid: u32, size: u32) {
+        let hcchar = endpoint.max_packet as u32 // mpsiz
+            | (endpoint.number as u32) << 11 // epnum
+            | (direction_in as u32) << 15 // epdir
+            | eptyp << 18
+            | 1 << 20 // mcnt, one packet per frame is the minimum
+            | (self.address as u32) << 22 // dad
+            | 1 << 31; // chena
+
+        // Safety: the values fit in their fields
+        unsafe {
+            self.host.hcint0.write(|w| w.bits(HCINT_ALL));
+            self.host.hctsiz0.write(|w| w.bits(size | 1 << 19 | pid << 29)); // One packet
+            self.host.hcchar0.write(|w| w.bits(hcchar));
+        }
+    }
+
+    // Waits for the packet on channel 0 to finish, the data of an IN packet is put in buf
+    fn wait_channel(&mut self, buf: &mut [u8]) -> Result<Outcome, UsbError> {
+        let start = self.frame();
+        let mut received = 0;
+        loop {
+            received += self.drain_rx_fifo(buf.get_mut(received..).unwrap_or_default());
+
+            let hcint = self.host.hcint0.read();
+            if hcint.xfrc().bit_is_set() {
+                self.halt_channel();
+                if received > buf.len() {
+                    return Err(UsbError::Babble);
+                }
+                return Ok(Outcome::Done(received));
+            } else if hcint.stall().bit_is_set() {
+                self.halt_channel();
+                return Err(UsbError::Stall);
+            } else if hcint.bberr().bit_is_set() {
+                self.halt_channel();
+                return Err(UsbError::Babble);
+            } else if hcint.nak().bit_is_set() {
+                self.halt_channel();
+                return Ok(Outcome::Nak);
+            } else if hcint.txerr().bit_is_set() || hcint.dterr().bit_is_set() || hcint.frmor().bit_is_set() {
+                self.halt_channel();
+                return Ok(Outcome::Error);
+            }
+
+            if !self.is_connected() {
+                return Err(UsbError::NoDevice);
+            }
+            if self.frames_since(start) >= TRANSFER_TIMEOUT_MS {
+                self.halt_channel();
+                return Err(UsbError::Timeout);
+            }
+        }
+    }
+
+    // Disables channel 0 if it's still enabled, and clears its interrupts
+    fn halt_channel(&mut self) {
+        if self.host.hcchar0.read().chena().bit_is_set() {
+            self.host.hcchar0.modify(|_, w| w.chdis().set_bit().chena().set_bit());
+
+            // An IN channel only halts once its entries in the receive FIFO have been read
+            let start = self.frame();
+            while self.host.hcint0.read().chh().bit_is_clear() && self.frames_since(start) < TRANSFER_TIMEOUT_MS && self.is_connected() {
+                self.drain_rx_fifo(&mut []);
+            }
+        }
+        self.host.hcint0.write(|w| unsafe { w.bits(HCINT_ALL) });
+    }
+
+    // Reads everything in the receive FIFO, data goes in buf and anything that doesn't fit is dropped
+    // Returns the number of data bytes the device sent, which can be more than buf.len()
+    fn drain_rx_fifo(&mut self, buf: &mut [u8]) -> usize {
+        let fifo = (pac::OTG_FS_GLOBAL::ptr() as usize + FIFO_OFFSET) as *const u32;
+        let mut received = 0;
+
+        while self.global.gintsts.read().rxflvl().bit_is_set() {
+            let status = self.global.grxstsp_host().read();
+            let count = status.bcnt().bits() as usize;
+            if status.pktsts().bits() != PKTSTS_IN_DATA {
+                continue;
+            }
+
+            let mut index = received;
+            for _ in 0..count.div_ceil(4) {
+                // Safety: the receive FIFO has count bytes waiting, reading it pops them
+                let word = unsafe { fifo.read_volatile() }.to_le_bytes();
+                for byte in word.iter().take(count + received - index) {
+                    if let Some(dest) = buf.get_mut(index) {
+                        *dest = *byte;
+                    }
+                    index += 1;
+                }
+            }
+            received += count;
+        }
+        received
+    }
+
+    // Puts an OUT packet in the transmit FIFO once there's room for it
+    fn write_fifo(&mut self, data: &[u8]) -> Result<(), UsbError> {
+        let words = data.len().div_ceil(4);
+        let start = self.frame();
+        while (self.global.gnptxsts.read().nptxfsav().bits() as usize) < words {
+            if !self.is_connected() {
+                return Err(UsbError::NoDevice);
+            }
+            if self.frames_since(start) >= TRANSFER_TIMEOUT_MS {
+                return Err(UsbError::Timeout);
+            }
+        }
+
+        let fifo = (pac::OTG_FS_GLOBAL::ptr() as usize + FIFO_OFFSET) as *mut u32;
+        for chunk in data.chunks(4) {
+            let mut word = [0; 4];
+            for (dest, byte) in word.iter_mut().zip(chunk) {
+                *dest = *byte;
+            }
+            // Safety: the transmit FIFO has room for the packet
+            unsafe { fifo.write_volatile(u32::from_le_bytes(word)) };
+        }
+        Ok(())
+    }
+
+    // Sets and clears hprt bits without touching the bits that are cleared by writing 1
+    fn modify_port(&mut self, set: u32, clear: u32) {
+        let hprt = self.host.hprt.read().bits() & !HPRT_WRITE_CLEAR;
+        self.host.hprt.write(|w| unsafe { w.bits((hprt | set) & !clear) });
+    }
+
+    fn frame(&self) -> u16 {
+        self.host.hfnum.read().frnum().bits() & FRAME_MASK
+    }
+
+    // The frame number goes up every ms while the port is enabled
+    fn frames_since(&self, start: u16) -> u16 {
+        self.frame().wrapping_sub(start) & FRAME_MASK
+    }
+
+    pub fn delay_ms(&self, ms: u32) {
+        cortex_m::asm::delay(self.cycles_per_ms * ms);
+    }
+}
+
+fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let [value_0, value_1] = value.to_le_bytes();
+    let [index_0, index_1] = index.to_le_bytes();
+    let [length_0, length_1] = length.to_le_bytes();
+    [request_type, request, value_0, value_1, index_0, index_1, length_0, length_1]
+}
+
+// Looks through a configuration descriptor for an interface of the class with a bulk IN and a bulk OUT endpoint
+fn find_bulk_interface(descriptors: &[u8], class: u8, subclass: u8, protocol: u8) -> Option<BulkInterface> {
+    let mut interface: Option<u8> = None;
+    let mut bulk_in = None;
+    let mut bulk_out = None;
+
+    let mut rest = descriptors;
+    while let [len, kind, ..] = *rest {
+        let len = len as usize;
+        if len < 2 || len > rest.len() {
+            break;
+        }
+        let (descriptor, next) = rest.split_at(len);
+        rest = next;
+
+        match (kind, descriptor) {
+            (INTERFACE_DESCRIPTOR, [_, _, number, alternate, _, class_code, subclass_code, protocol_code, ..]) => {
+                if bulk_in.is_some() && bulk_out.is_some() {
+                    break;
+                }
+                let matches = *alternate == 0 && *class_code == class && *subclass_code == subclass && *protocol_code == protocol;
+                interface = matches.then_some(*number);
+                bulk_in = None;
+                bulk_out = None;
+            },
+            (ENDPOINT_DESCRIPTOR, [_, _, address, attributes, size_0, size_1, ..]) if interface.is_some() && *attributes & 0x03 == BULK => {
+                let endpoint = Endpoint::new(*address & 0x0F, u16::from_le_bytes([*size_0, *size_1]) & 0x7FF);
+                if *address & ENDPOINT_IN != 0 {
+                    bulk_in = Some(endpoint);
+                } else {
+                    bulk_out = Some(endpoint);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Some(BulkInterface {
+        number: interface?,
+        bulk_in: bulk_in?,
+        bulk_out: bulk_out?,
+    })
+}
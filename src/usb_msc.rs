@@ -0,0 +1,217 @@
+// USB flash drive as a block device, on the OTG_FS port
+//
+// Only Bulk-Only Transport drives with the SCSI command set are supported, which is nearly all of them
+// Each command is sent in a command block (CBW), the data follows it, then the drive answers with a status block (CSW)
+//
+// Useful resources:
+// https://www.usb.org/sites/default/files/usbmassbulk_10.pdf (Bulk-Only Transport)
+// SCSI Block Commands (SBC-3), for READ(10) and READ CAPACITY(10)
+//
+// The drive has to have 512 byte sectors, the same as an sd card, which every flash drive does
+
+use crate::block_device::BlockDevice;
+use crate::usb_host::{BulkInterface, UsbError, UsbHost};
+use crate::BLOCK_SIZE;
+
+// Interface class, subclass and protocol
+const MASS_STORAGE: u8 = 0x08;
+const SCSI: u8 = 0x06;
+const BULK_ONLY: u8 = 0x50;
+
+// Class requests
+const MASS_STORAGE_RESET: u8 = 0xFF;
+const CLASS_TO_INTERFACE: u8 = 0x21;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+const CBW_DATA_IN: u8 = 0x80;
+const CBW_CDB_OFFSET: usize = 15;
+
+// CSW status
+const COMMAND_PASSED: u8 = 0;
+const COMMAND_FAILED: u8 = 1;
+
+// SCSI commands
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const SENSE_LEN: usize = 18;
+
+// Drives can take a few seconds to be ready after they are plugged in
+const READY_ATTEMPTS: usize = 50;
+const READY_RETRY_MS: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsbMscError {
+    Usb(UsbError),
+    CommandFailed(u8), // The drive returned failed status for this SCSI command
+    PhaseError, // The drive and the host disagree about the command, the drive is reset
+    BadStatus, // The status block was the wrong length or didn't belong to the command
+    NotReady, // TEST UNIT READY kept failing
+    UnsupportedBlockSize(u32),
+    NotInitialized,
+}
+
+impl From<UsbError> for UsbMscError {
+    fn from(error: UsbError) -> Self {
+        UsbMscError::Usb(error)
+    }
+}
+
+pub struct UsbMsc {
+    pub host: UsbHost,
+    interface: Option<BulkInterface>, // Set once the drive has been initialized
+    tag: u32, // Matches a status block to its command
+    block_count: u32,
+}
+
+impl UsbMsc {
+    pub fn new(host: UsbHost) -> Self {
+        UsbMsc {
+            host,
+            interface: None,
+            tag: 0,
+            block_count: 0,
+        }
+    }
+
+    // Enumerates the drive and waits for it to be ready
+    pub fn init(&mut self) -> Result<(), UsbMscError> {
+        self.interface = None;
+        self.host.connect()?;
+        self.interface = Some(self.host.enumerate(MASS_STORAGE, SCSI, BULK_ONLY)?);
+
+        // The first commands after power on fail with unit attention, which is cleared by reading the sense data
+        let mut ready = false;
+        for _ in 0..READY_ATTEMPTS {
+            match self.command(&[TEST_UNIT_READY, 0, 0, 0, 0, 0], &mut []) {
+                Ok(()) => {
+                    ready = true;
+                    break;
+                },
+                Err(UsbMscError::CommandFailed(_)) => {
+                    let mut sense = [0; SENSE_LEN];
+                    self.command(&[REQUEST_SENSE, 0, 0, 0, SENSE_LEN as u8, 0], &mut sense)?;
+                },
+                Err(err) => return Err(err),
+            }
+            self.host.delay_ms(READY_RETRY_MS);
+        }
+        if !ready {
+            return Err(UsbMscError::NotReady);
+        }
+
+        // Returns the last block address and the block length
+        let mut capacity = [0; 8];
+        self.command(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut capacity)?;
+        let [l0, l1, l2, l3, s0, s1, s2, s3] = capacity;
+        let block_size = u32::from_be_bytes([s0, s1, s2, s3]);
+        if block_size != BLOCK_SIZE as u32 {
+            return Err(UsbMscError::UnsupportedBlockSize(block_size));
+        }
+        self.block_count = u32::from_be_bytes([l0, l1, l2, l3]).wrapping_add(1);
+        Ok(())
+    }
+
+    pub fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    // Reads blocks.len() consecutive blocks starting at blockaddr
+    pub fn read(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), UsbMscError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let [a0, a1, a2, a3] = blockaddr.to_be_bytes();
+        let [n0, n1] = (blocks.len() as u16).to_be_bytes();
+        self.command(&[READ_10, 0, a0, a1, a2, a3, 0, n0, n1, 0], blocks.as_flattened_mut())
+    }
+
+    // Sends a SCSI command that reads data.len() bytes from the drive, or nothing if data is empty
+    // A drive that gets out of step with the host is reset so the next command can go through
+    fn command(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), UsbMscError> {
+        let Some(interface) = self.interface.as_mut() else {
+            return Err(UsbMscError::NotInitialized);
+        };
+        self.tag = self.tag.wrapping_add(1);
+
+        let result = transport(&mut self.host, interface, self.tag, cdb, data);
+        if matches!(result, Err(UsbMscError::PhaseError | UsbMscError::BadStatus)) {
+            reset_recovery(&mut self.host, interface)?;
+        }
+        result
+    }
+}
+
+// One command, its data and its status
+fn transport(host: &mut UsbHost, interface: &mut BulkInterface, tag: u32, cdb: &[u8], data: &mut [u8]) -> Result<(), UsbMscError> {
+    let mut cbw = [0; CBW_LEN];
+    cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+    cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+    cbw[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    cbw[12] = if data.is_empty() { 0 } else { CBW_DATA_IN };
+    cbw[13] = 0; // LUN
+    cbw[14] = cdb.len() as u8;
+    for (dest, byte) in cbw.iter_mut().skip(CBW_CDB_OFFSET).zip(cdb) {
+        *dest = *byte;
+    }
+    host.bulk_out(&mut interface.bulk_out, &cbw)?;
+
+    // A drive that can't send the data stalls, the status block still follows
+    if !data.is_empty() {
+        match host.bulk_in(&mut interface.bulk_in, data) {
+            Ok(_) => (),
+            Err(UsbError::Stall) => host.clear_halt(&mut interface.bulk_in, true)?,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // The status can be stalled once as well
+    let mut csw = [0; CSW_LEN];
+    let len = match host.bulk_in(&mut interface.bulk_in, &mut csw) {
+        Err(UsbError::Stall) => {
+            host.clear_halt(&mut interface.bulk_in, true)?;
+            host.bulk_in(&mut interface.bulk_in, &mut csw)?
+        },
+        result => result?,
+    };
+
+    let [g0, g1, g2, g3, t0, t1, t2, t3, _, _, _, _, status] = csw;
+    if len != CSW_LEN || u32::from_le_bytes([g0, g1, g2, g3]) != CSW_SIGNATURE || u32::from_le_bytes([t0, t1, t2, t3]) != tag {
+        return Err(UsbMscError::BadStatus);
+    }
+    match status {
+        COMMAND_PASSED => Ok(()),
+        COMMAND_FAILED => Err(UsbMscError::CommandFailed(cdb.first().copied().unwrap_or(0))),
+        _ => Err(UsbMscError::PhaseError),
+    }
+}
+
+// Resets the drive's command state, then clears both bulk endpoints
+fn reset_recovery(host: &mut UsbHost, interface: &mut BulkInterface) -> Result<(), UsbError> {
+    host.control_out(CLASS_TO_INTERFACE, MASS_STORAGE_RESET, 0, interface.number as u16)?;
+    host.clear_halt(&mut interface.bulk_in, true)?;
+    host.clear_halt(&mut interface.bulk_out, false)
+}
+
+impl BlockDevice<BLOCK_SIZE> for UsbMsc {
+    fn read_to_block(&mut self, blockaddr: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+        self.read(blockaddr, core::slice::from_mut(block)).map_err(|_| ())
+    }
+
+    fn read_blocks(&mut self, blockaddr: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), ()> {
+        self.read(blockaddr, blocks).map_err(|_| ())
+    }
+
+    fn is_present(&mut self) -> bool {
+        self.interface.is_some() && self.host.is_connected()
+    }
+
+    fn reinit(&mut self) -> Result<(), ()> {
+        self.init().map_err(|_| ())
+    }
+}
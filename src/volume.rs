@@ -0,0 +1,192 @@
+// Volume control, in software for DACs which don't have a volume control of their own
+//
+// The volume is set from 0 to 100 % and follows a logarithmic taper, so each step sounds about as loud as the last
+// 100 % is unity gain and every step down takes off 0.6 dB, so 1 % is about -60 dB, and 0 % is muted
+// The gain is a Q15 fixed point number, each sample is multiplied by it and shifted down
+//
+// The balance trims the left and right channels, e.g. when one speaker is nearer the listener
+// It goes from -100 (only left) to 100 (only right), and the channel away from the balance is turned down linearly
+//
+// The track gain is set for each track from its ReplayGain tags (see replay_gain.rs), it can be above unity gain
+//
+// With a codec that has a volume control, the volume is turned down by the codec as far as it can (see set_hardware)
+// Scaling the samples down throws away bits, so this keeps the full dynamic range at low volumes
+// The software gain only makes up the part below the codec's step size or range, the balance and track gain stay in software
+// The codec changes straight away while the software gain changes with the buffers that are already filled,
+// so for a few ms after a change the volume can be off by up to one of the codec's steps
+
+use crate::{eq, limiter};
+
+pub const MAX_VOLUME: u8 = 100;
+pub const MAX_BALANCE: i8 = 100;
+
+// Q15 gain of 1.0
+pub const UNITY_GAIN: i32 = 1 << 15;
+
+// Ratio between the gain of neighbouring steps in Q15, 10^(-0.6 / 20) = 0.9333
+const STEP_RATIO: i32 = 30581;
+const STEP_TENTH_DB: i32 = 6;
+
+// Q15 gain of every volume step, worked out at compile time since there is no floating point pow without libm
+const GAIN_TABLE: [i32; MAX_VOLUME as usize + 1] = gain_table();
+
+const fn gain_table() -> [i32; MAX_VOLUME as usize + 1] {
+    let mut table = [0; MAX_VOLUME as usize + 1];
+    let mut gain = UNITY_GAIN;
+    let mut step = MAX_VOLUME as usize;
+    while step > 0 {
+        table[step] = gain;
+        gain = (gain * STEP_RATIO) >> 15;
+        step -= 1;
+    }
+
+    table // Step 0 is left at 0 so it is muted
+}
+
+// The volume control of a codec, it can be turned down to min_db in steps of step_db
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HardwareVolume {
+    pub min_db: f32,
+    pub step_db: f32,
+}
+
+#[derive(Debug)]
+pub struct Volume {
+    percent: u8,
+    balance: i8,
+    hardware: Option<HardwareVolume>,
+    hardware_db: f32, // The part of the volume the codec does
+    gain: i32, // Q15, the part of the volume done in software
+    track_gain: i32, // Q15, loudness normalisation for the current track
+    channel_gains: [i32; 2], // Q15 gain of the left and right channel, from the volume, balance, and track gain
+}
+
+impl Volume {
+    pub fn new(percent: u8) -> Self {
+        let mut volume = Volume {
+            percent: MAX_VOLUME,
+            balance: 0,
+            hardware: None,
+            hardware_db: 0.0,
+            gain: UNITY_GAIN,
+            track_gain: UNITY_GAIN,
+            channel_gains: [UNITY_GAIN; 2],
+        };
+        volume.set(percent);
+        volume
+    }
+
+    // Sets the volume from 0 to 100 %, anything higher is limited to 100 %
+    // The codec has to be set to hardware_db afterwards
+    pub fn set(&mut self, percent: u8) {
+        self.percent = percent.min(MAX_VOLUME);
+        self.gain = GAIN_TABLE[self.percent as usize];
+        self.hardware_db = 0.0;
+
+        // Worked out in tenths of a dB, so the steps of the codec and the volume line up exactly
+        if let Some(hardware) = self.hardware {
+            let tenth_db = -STEP_TENTH_DB * (MAX_VOLUME - self.percent) as i32;
+            let min_tenth_db = (hardware.min_db * 10.0 - 0.5) as i32;
+            let step_tenth_db = ((hardware.step_db * 10.0 + 0.5) as i32).max(1);
+
+            // Rounded towards 0 dB, so the software gain is never above unity
+            let hardware_tenth_db = tenth_db.max(min_tenth_db) / step_tenth_db * step_tenth_db;
+            self.hardware_db = hardware_tenth_db as f32 / 10.0;
+            let software_db = (tenth_db - hardware_tenth_db) as f32 / 10.0;
+            if self.percent > 0 {
+                self.gain = (eq::pow10(software_db / 20.0) * UNITY_GAIN as f32 + 0.5) as i32;
+            }
+        }
+        self.update_channel_gains();
+    }
+
+    // Turns the volume down with the codec's volume control as far as it can, None to do it all in software
+    pub fn set_hardware(&mut self, hardware: Option<HardwareVolume>) {
+        self.hardware = hardware;
+        self.set(self.percent);
+    }
+
+    // The volume the codec should be set to, 0 dB without a hardware volume control
+    // At 0 % the codec is set as if it was 1 step lower than 1 %, and the software gain mutes the output
+    pub fn hardware_db(&self) -> f32 {
+        self.hardware_db
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    // Sets the balance from -100 (left) to 100 (right), 0 is centred
+    pub fn set_balance(&mut self, balance: i8) {
+        self.balance = balance.clamp(-MAX_BALANCE, MAX_BALANCE);
+        self.update_channel_gains();
+    }
+
+    pub fn balance(&self) -> i8 {
+        self.balance
+    }
+
+    // Sets the Q15 gain of the current track, on top of the volume
+    pub fn set_track_gain(&mut self, track_gain: i32) {
+        self.track_gain = track_gain.max(0);
+        self.update_channel_gains();
+    }
+
+    pub fn track_gain(&self) -> i32 {
+        self.track_gain
+    }
+
+    fn update_channel_gains(&mut self) {
+        let balance = self.balance as i64;
+        let max_balance = MAX_BALANCE as i64;
+        let gain = (self.gain as i64 * self.track_gain as i64) >> 15;
+        let left = gain * (max_balance - balance.max(0)) / max_balance;
+        let right = gain * (max_balance + balance.min(0)) / max_balance;
+        self.channel_gains = [left as i32, right as i32];
+    }
+
+    // Q15 gain for the part of the current volume done in software
+    pub fn gain(&self) -> i32 {
+        self.gain
+    }
+
+    // Scales interleaved 16 bit stereo samples by the volume, balance, and track gain
+    pub fn apply(&self, buf: &mut [u16]) {
+        if self.channel_gains == [UNITY_GAIN; 2] {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(2) {
+            for (sample, gain) in frame.iter_mut().zip(self.channel_gains) {
+                *sample = scale(*sample, gain);
+            }
+        }
+    }
+}
+
+// Scales interleaved 16 bit samples by a Q15 gain
+pub fn apply_gain(buf: &mut [u16], gain: i32) {
+    if gain == UNITY_GAIN {
+        return;
+    }
+
+    for sample in buf.iter_mut() {
+        *sample = scale(*sample, gain);
+    }
+}
+
+// The track gain can be above unity, so the product needs 64 bits and can go past full scale
+fn scale(sample: u16, gain: i32) -> u16 {
+    let scaled = ((sample as i16 as i64 * gain as i64) >> 15) as i32; // Fits, the gain is at most a few times unity
+    if gain > UNITY_GAIN {
+        limiter::soft_clip(scaled) as u16
+    } else {
+        scaled as i16 as u16
+    }
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self::new(MAX_VOLUME)
+    }
+}
@@ -0,0 +1,165 @@
+// Watches a folder for new files, e.g. files copied onto the card while the player is running
+// The folder's entry in its parent is checked periodically, its timestamp and length change when an entry is added to the folder
+// Only then is the folder re-listed, and any file that wasn't there last time is reported
+// It's listed a few sectors at a time from the main loop, since listing a large directory at once would underrun the output
+
+use heapless::Vec;
+
+use crate::block_device::BlockDevice;
+use crate::exfat::{ExFat, FileType, FsEntry, FsError};
+
+const SECTOR_SIZE: usize = crate::BLOCK_SIZE;
+
+// Maximum number of files that are tracked in the watched directory
+const MAX_WATCHED_FILES: usize = 205;
+
+// Directory sectors read each poll while the directory is being re-listed, each sector holds up to 16 entries
+const SECTORS_PER_POLL: u32 = 1;
+
+pub struct WatchFolder {
+    pub directory_cluster: u32,
+    parent_cluster: u32,
+    entry_sector: u32, // The sector of the parent directory the folder's entry starts in, so it's found without listing the parent
+    signature: Option<Signature>, // None after a listing failed, so it's listed again next interval
+    interval_cycles: u32,
+    last_poll: u32, // Cycle count when the signature was last checked
+
+    // Files are identified by their first cluster, which is unique for files with contents
+    // This is much smaller than keeping the names
+    known_files: Vec<u32, MAX_WATCHED_FILES>,
+
+    // While the directory is being re-listed, the sector to carry on from and the files found so far
+    scan_sector: Option<u32>,
+    present_files: Vec<u32, MAX_WATCHED_FILES>,
+}
+
+// From the folder's entry in its parent, the contents of the folder are written whenever one of its entries changes
+// So this changes when a file is added, removed, or has its first data written
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Signature {
+    modified: u32,
+    modified_10ms: u8,
+    valid_data_length: u64, // Grows when the folder needs another cluster for its entries
+}
+
+impl Signature {
+    fn of(fs_entry: &FsEntry) -> Self {
+        Signature {
+            modified: fs_entry.modified,
+            modified_10ms: fs_entry.modified_10ms,
+            valid_data_length: fs_entry.valid_data_length,
+        }
+    }
+}
+
+impl WatchFolder {
+
+    // Starts watching the folder called name in the directory at parent_cluster, files that are already there aren't reported as new
+    // interval_cycles is how often the folder is checked for changes, in cpu cycles
+    pub fn new<T: BlockDevice<SECTOR_SIZE>>(exfat: &mut ExFat<T>, parent_cluster: u32, name: &str, interval_cycles: u32, now: u32)
+    -> Result<Self, FsError> {
+        // The parent is listed a sector at a time, so the sector the folder's entry is in is known
+        let mut entry_sector = 0;
+        let fs_entry = loop {
+            let mut found = None;
+            let next_sector = exfat.for_each_entry_from(parent_cluster, entry_sector, 1, |fs_entry| {
+                if matches!(fs_entry.file_type, FileType::Directory) && fs_entry.name.eq_ignore_ascii_case(name) {
+                    found = Some(fs_entry);
+                }
+            })?;
+            match (found, next_sector) {
+                (Some(fs_entry), _) => break fs_entry,
+                (None, Some(next_sector)) => entry_sector = next_sector,
+                (None, None) => return Err(FsError::NotFound),
+            }
+        };
+
+        let mut watch_folder = WatchFolder {
+            directory_cluster: fs_entry.first_cluster,
+            parent_cluster,
+            entry_sector,
+            signature: Some(Signature::of(&fs_entry)),
+            interval_cycles,
+            last_poll: now,
+            known_files: Vec::new(),
+            scan_sector: None,
+            present_files: Vec::new(),
+        };
+
+        // Nothing is playing yet, so the whole directory is listed at once
+        watch_folder.scan(exfat, 0, u32::MAX, |_| ())?;
+        Ok(watch_folder)
+    }
+
+    // Checks the signature of the folder once the interval has passed, and starts re-listing it if it has changed
+    // Then the next SECTORS_PER_POLL sectors of it are listed each call, on_new_file is called for every file that has appeared
+    // now is the current cycle count, it's fine for it to wrap around
+    pub fn poll<T: BlockDevice<SECTOR_SIZE>, F: FnMut(FsEntry)>(&mut self, exfat: &mut ExFat<T>, now: u32, on_new_file: F)
+    -> Result<(), FsError> {
+        let start_sector = match self.scan_sector {
+            Some(scan_sector) => scan_sector,
+            None if now.wrapping_sub(self.last_poll) >= self.interval_cycles => {
+                self.last_poll = now;
+                let signature = Some(self.read_signature(exfat)?);
+                if signature == self.signature {
+                    return Ok(());
+                }
+                self.signature = signature;
+                0
+            },
+            None => return Ok(()),
+        };
+
+        let result = self.scan(exfat, start_sector, SECTORS_PER_POLL, on_new_file);
+        if result.is_err() {
+            // Start again from the beginning next interval
+            self.scan_sector = None;
+            self.present_files.clear();
+            self.signature = None;
+        }
+        result
+    }
+
+    // Reads the folder's entry from the sector of the parent it was found in, NotFound if it has been moved or deleted
+    fn read_signature<T: BlockDevice<SECTOR_SIZE>>(&self, exfat: &mut ExFat<T>) -> Result<Signature, FsError> {
+        let mut signature = None;
+        exfat.for_each_entry_from(self.parent_cluster, self.entry_sector, 1, |fs_entry| {
+            if matches!(fs_entry.file_type, FileType::Directory) && fs_entry.first_cluster == self.directory_cluster {
+                signature = Some(Signature::of(&fs_entry));
+            }
+        })?;
+        signature.ok_or(FsError::NotFound)
+    }
+
+    // Lists max_sectors sectors of the directory from start_sector, the known files are updated once it's all been listed
+    fn scan<T: BlockDevice<SECTOR_SIZE>, F: FnMut(FsEntry)>(&mut self, exfat: &mut ExFat<T>, start_sector: u32, max_sectors: u32, mut on_new_file: F)
+    -> Result<(), FsError> {
+        let present_files = &mut self.present_files;
+        let known_files = &self.known_files;
+
+        self.scan_sector = exfat.for_each_entry_from(self.directory_cluster, start_sector, max_sectors, |fs_entry| {
+            if let FileType::Directory = fs_entry.file_type {
+                return;
+            }
+
+            // Empty files don't have a first cluster yet, they're reported once data has been written
+            if fs_entry.first_cluster == 0 {
+                return;
+            }
+
+            if present_files.push(fs_entry.first_cluster).is_err() {
+                return;
+            }
+
+            if !known_files.contains(&fs_entry.first_cluster) {
+                on_new_file(fs_entry);
+            }
+        })?;
+
+        // Files that were deleted are forgotten, so they're reported again if they come back
+        if self.scan_sector.is_none() {
+            self.known_files = core::mem::take(&mut self.present_files);
+        }
+        Ok(())
+    }
+}
@@ -3,19 +3,74 @@ use crate::riff;
 use crate::block_device;
 use crate::exfat;
 use crate::bytes::BytesTrait;
+use crate::g711;
+use crate::adpcm::{self, MsAdpcm};
+use crate::downmix::{self, Downmix};
+use crate::resampler::Resampler;
+use crate::id3;
+use crate::decoder::Decoder;
+use crate::dither::{self, Dither};
+use crate::raw_pcm::RawPcmFormat;
+use crate::replay_gain::ReplayGain;
 use exfat::{FsEntry, ExFat};
 
 use crate::BLOCK_SIZE;
 const BUFFER_BLOCKS: usize = 100; // How many blocks to read when buffering samples
+const PCM_READ_BLOCKS: usize = 4; // Most blocks read with one command while decoding, see PcmBlock
 
 use crate::{rprint, rprintln};
 
-use heapless::Vec;
+use heapless::{String, Vec};
 
+// Playback speeds for set_speed, 100 % is normal speed
+pub const NORMAL_SPEED_PERCENT: u16 = 100;
+pub const MIN_SPEED_PERCENT: u16 = 50;
+pub const MAX_SPEED_PERCENT: u16 = 200;
 
-#[derive(Debug)]
+// Trailing silence is found by decoding windows of this many frames, working back from the end
+const SILENCE_SCAN_FRAMES: u32 = 4096;
+
+// Long recordings are sometimes split into numbered parts (track.wav.001, track.wav.002, ...)
+// The first part has the RIFF header, the others just continue the data
+pub const MAX_FILE_PARTS: usize = 16;
+const PART_NUMBER_DIGITS: usize = 3;
+
+// Files with more than 2 channels, or more than 16 bits, often use the extensible fmt chunk
+// The real format code is the first 2 bytes of the sub format GUID
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+const EXTENSIBLE_EXTENSION_SIZE: u16 = 22;
+
+// AIFF files have a COMM chunk instead of fmt, and an SSND chunk instead of data
+// AIFC files add a compression type after the COMM fields
+const COMM_LENGTH: u32 = 18;
+const AIFC_COMM_LENGTH: u32 = 22;
+const SSND_HEADER_LENGTH: u64 = 8; // Offset and block size before the sound data
+const EXTENDED_EXPONENT_BIAS: i32 = 16383; // The sample rate is an 80 bit extended float
+
+// A file which makes up part of the wav data
+// Its blocks are read one after another without following its cluster chain, so it has to be contiguous (see ExFat::is_contiguous)
+#[derive(Debug, Clone, Copy)]
+struct FilePart {
+    start_block_address: u32,
+    length: u64, // Length of the part in bytes
+    blocks: u32, // Length of the part in blocks, rounded up
+}
+
+impl FilePart {
+    fn new<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &ExFat<T>, file: &FsEntry) -> Self {
+        FilePart {
+            start_block_address: exfat.calc_cluster_sector(file.first_cluster),
+            length: file.valid_data_length,
+            blocks: file.valid_data_length.div_ceil(BLOCK_SIZE as u64) as u32,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Format {
     Pcm,
+    MsAdpcm,
     IeeeFloat,
     Alaw,
     Mulaw,
@@ -26,20 +81,161 @@ impl Format {
     fn decode_format(format_code: u16) -> Format {
         match format_code {
             0x0001 => return Format::Pcm,
+            0x0002 => return Format::MsAdpcm,
             0x0003 => return Format::IeeeFloat,
             0x0006 => return Format::Alaw,
-            0x0007 => return Format::Alaw,
+            0x0007 => return Format::Mulaw,
             _      => return Format::Other,
         }
     }
+
+    // Compressed formats don't have a fixed number of bytes per sample
+    // So the RIFF spec requires them to have a fact chunk with the number of samples
+    pub fn is_compressed(&self) -> bool {
+        !matches!(self, Format::Pcm | Format::IeeeFloat)
+    }
+}
+
+// Why a file couldn't be opened
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WavError {
+    NotRiff, // The file doesn't start with a RIFF, RF64, BW64, or FORM header
+    NotWave, // A RIFF file which isn't a wav file (e.g. avi), or a FORM file which isn't AIFF
+    NoFormatChunk, // Gave up looking for the fmt chunk
+    NoDataChunk, // Gave up looking for the data chunk
+    InvalidFormat, // The fmt chunk has values which don't make sense, e.g. 0 channels
+    Unsupported(Unsupported), // A valid wav file which this player can't decode
+    ReadFail, // The block device failed a read, trying again might work
+    Fragmented, // The file's clusters aren't one after another, it has to be copied onto the card again to be played
+    NoTrack, // There is no such track in the playlist, e.g. the index is past the end or a resumed track has been deleted
+}
+
+// Why a file can't be played
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Unsupported {
+    Format(Format),
+    BitDepth(u16),
+    Channels(u16),
+    SampleRate(u32),
+}
+
+impl Unsupported {
+    // A short explanation for the user
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Unsupported::Format(_) => "the audio format can't be decoded",
+            Unsupported::BitDepth(_) => "only 16, 24, and 32 bit PCM, 32 bit float, 8 bit A-law and µ-law, or 4 bit MS ADPCM samples can be decoded",
+            Unsupported::Channels(_) => "only mono, stereo, and multichannel files with known speaker positions can be played",
+            Unsupported::SampleRate(_) => "the sample rate is too far from the output sample rate to be converted",
+        }
+    }
+
+    // What the player would need in order to play the file
+    pub fn needs(&self) -> &'static str {
+        match self {
+            Unsupported::Format(_) => "a decoder for the format",
+            Unsupported::BitDepth(_) => "a conversion from this bit depth",
+            Unsupported::Channels(_) => "channel mixing to stereo",
+            Unsupported::SampleRate(_) => "a better resampler, or an output at the file's sample rate",
+        }
+    }
+}
+
+// A loop from the smpl chunk, positions are in sample frames and end is the last frame of the loop
+// A play_count of 0 means loop forever
+#[derive(Debug, Clone, Copy)]
+pub struct SampleLoop {
+    pub start: u32,
+    pub end: u32,
+    pub play_count: u32,
+}
+
+// Offsets in the smpl chunk data
+const SMPL_LOOP_COUNT_OFFSET: u64 = 28;
+const SMPL_FIRST_LOOP_OFFSET: u64 = 36;
+
+// A position marked in the cue chunk, named by a labl chunk in a LIST adtl chunk
+#[derive(Debug, Clone)]
+pub struct CuePoint {
+    pub id: u32,
+    pub frame: u32, // Position in sample frames from the start of the data
+    pub label: String<MAX_CUE_LABEL_LENGTH>, // Empty if the cue point has no label
+}
+
+const MAX_CUE_POINTS: usize = 16;
+const MAX_CUE_LABEL_LENGTH: usize = 32;
+const CUE_POINT_LENGTH: u64 = 24;
+
+// Metadata from the bext chunk of a broadcast wav file, as written by field recorders
+#[derive(Debug, Clone)]
+pub struct BroadcastExtension {
+    pub description: String<256>,
+    pub originator: String<32>,
+    pub originator_reference: String<32>,
+    pub origination_date: String<10>, // yyyy-mm-dd
+    pub origination_time: String<8>, // hh:mm:ss
+    pub time_reference: u64, // Timecode of the first sample, in samples since midnight
+}
+
+impl BroadcastExtension {
+    // The timecode of the first sample in milliseconds since midnight
+    pub fn time_reference_ms(&self, sample_rate: u32) -> u64 {
+        if sample_rate == 0 {
+            return 0;
+        }
+
+        self.time_reference * 1000 / sample_rate as u64
+    }
+}
+
+// Title, artist, and album from a LIST INFO chunk or an id3 chunk
+// Fields the file doesn't have are empty
+pub const MAX_TAG_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    pub title: String<MAX_TAG_LENGTH>,
+    pub artist: String<MAX_TAG_LENGTH>,
+    pub album: String<MAX_TAG_LENGTH>,
+    pub replay_gain: ReplayGain, // From an rgad chunk or the id3 chunk, or the sidecar file when the player opens the track
+}
+
+// Offsets in the bext chunk data
+const BEXT_ORIGINATOR_OFFSET: u64 = 256;
+const BEXT_ORIGINATOR_REFERENCE_OFFSET: u64 = 288;
+const BEXT_ORIGINATION_DATE_OFFSET: u64 = 320;
+const BEXT_ORIGINATION_TIME_OFFSET: u64 = 330;
+const BEXT_TIME_REFERENCE_OFFSET: u64 = 338;
+
+// Capability report for a file, see WavFile::probe
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeReport {
+    pub supported: bool,
+    pub unsupported: Option<Unsupported>, // The first reason the file can't be played
+}
+
+// The blocks of PCM data currently being decoded into samples
+// Samples don't always line up with block boundaries (e.g. 24 bit audio), so this is kept between fills
+// Up to PCM_READ_BLOCKS consecutive blocks are read at once, which is much quicker than reading them one by one from the sd card
+// While they are decoded the next blocks are read in the background, see BlockDevice::start_read
+struct PcmBlock {
+    blocks: [[u8; BLOCK_SIZE]; PCM_READ_BLOCKS],
+    pos: usize, // Index of the next byte to decode, counting across the blocks
+    end: usize, // Index after the last byte of wav data that was read
+}
+
+impl core::fmt::Debug for PcmBlock {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PcmBlock").field("pos", &self.pos).field("end", &self.end).finish()
+    }
 }
 
 #[derive(Debug)]
 pub struct WavFile {
-    start_block_address: u32,
-    pub data_length: u32, // Length of the wav data chunk in bytes
-    first_byte: u32, // The byte address of the first byte from the data chunk
-    pub bytes_read: u32, // Number of bytes of wav data that have been read
+    pub data_length: u64, // Length of the wav data chunk in bytes
+    stream_length: u64, // Bytes of the data which are in the file, less than data_length if the file was only partly written
+    first_byte: u64, // The byte address of the first byte from the data chunk
+    pub bytes_read: u64, // Number of bytes of wav data that have been read
 
     pub format: Format,
     pub n_channels: u16,
@@ -47,54 +243,90 @@ pub struct WavFile {
     pub byte_rate: u32, // Bytes per second (SampleRate * NumChannels * BitsPerSample/8)
     pub block_align: u16, // The number of bytes for one sample (including all channels)
     pub bits_per_sample: u16, // Audio bit dipth
-    pub bytes_per_channel: u16
+    pub bytes_per_channel: u16,
+
+    pub big_endian: bool, // Samples in AIFF files are big endian
+    pub fact_sample_count: Option<u64>, // Samples per channel, from the fact chunk (or ds64 chunk) if the file has one
+    pub channel_mask: u32, // Speaker positions of the channels from an extensible fmt chunk, 0 if not specified
+    pub sample_loop: Option<SampleLoop>, // The first loop from the smpl chunk
+    looping: bool, // Play sample_loop forever instead of playing through to the end
+    ab_loop: Option<SampleLoop>, // Set by set_ab_loop, played instead of sample_loop
+    frame_pos: u32, // Index of the next frame that will be decoded
+    pub cue_points: Vec<CuePoint, MAX_CUE_POINTS>, // Named positions from the cue chunk, see seek_to_cue
+    pub bext: Option<BroadcastExtension>, // Broadcast wav metadata
+    pub tags: Tags,
+
+    parts: Vec<FilePart, MAX_FILE_PARTS>,
+    ms_adpcm: Option<MsAdpcm>, // Decoder state for MS ADPCM files
+    downmix: Option<Downmix>, // Mix to stereo for files with more than 2 channels
+    resampler: Option<Resampler>, // Converts to the output sample rate if it's different, see set_output_sample_rate
+    output_sample_rate: u32, // 0 until set_output_sample_rate is called
+    speed_percent: u16, // Playback speed, see set_speed
+    dither: Dither, // For samples with more than 16 bits
+
+    pcm_block: PcmBlock,
 }
 
 impl WavFile {
 
     // Create a new wav file with it's format information
-    pub fn new<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<Self, ()> {
+    // AIFF and AIFC files are read the same way, they have the same chunk layout but are big endian
+    pub fn new<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<Self, WavError> {
+        if !exfat.is_contiguous(file) {
+            return Err(WavError::Fragmented);
+        }
         let start_block_address: u32 = exfat.calc_cluster_sector(file.first_cluster);
 
-        let mut wav_file = WavFile {
-            start_block_address,
-            data_length: 0,
-            first_byte: 0,
-            bytes_read: 0,
-            format: Format::Other,
-            n_channels: 0,
-            sample_rate: 0,
-            byte_rate: 0,
-            block_align: 0,
-            bits_per_sample: 0,
-            bytes_per_channel: 0,
+        let mut wav_file = WavFile::empty(exfat, file);
+
+        // Check this is a wav file before looking at its chunks
+        let mut current_chunk = riff::get_first_chunk(start_block_address, &mut exfat.block_device).map_err(|_| WavError::ReadFail)?;
+        let form_type = read_file_bytes::<4, T>(exfat, start_block_address, current_chunk.next_chunk)?;
+        let is_aifc = match (current_chunk.identifier.as_str(), &form_type) {
+            ("RIFF" | "RF64" | "BW64", b"WAVE") => false,
+            ("FORM", b"AIFF") => false,
+            ("FORM", b"AIFC") => true,
+            ("RIFF" | "RF64" | "BW64" | "FORM", _) => return Err(WavError::NotWave),
+            _ => return Err(WavError::NotRiff),
         };
 
         // Loop through chunks until we find the fmt chunk and data chunk to complete a WavFile struct
-        let mut current_chunk = riff::get_first_chunk(start_block_address, &mut exfat.block_device)?;
         let mut found_format_chunk = false;
         let mut found_data_chunk = false;
+        let mut labels: Vec<(u32, String<MAX_CUE_LABEL_LENGTH>), MAX_CUE_POINTS> = Vec::new();
+        let mut ds64_data_length = None;
 
-
-        for _ in 0..10 {
+        // The scan ends at the end of the file, each chunk is at least a header past the one before it
+        loop {
 
             if current_chunk.identifier == "fmt " {
                 found_format_chunk = true;
 
-                // Assume information from the format chunk is all contained in the first block
-                // It should be for a proper wav file since the format chunk should only be offset 12 bytes from the start
-                let first_block = exfat.block_device.read_block(start_block_address)?;
-                let chunk_start = current_chunk.chunk_start as usize;
+                // The format chunk is usually in the first block, but it can come after large metadata chunks
+                // So it's read at its byte address, which may be in a later block or split across two
+                let fmt = read_file_bytes::<16, T>(exfat, start_block_address, current_chunk.chunk_start + 8)?;
 
-                let format_code = u16::from_le_bytes(first_block.get_bytes_section::<2>(chunk_start + 8));
-                let n_channels = u16::from_le_bytes(first_block.get_bytes_section::<2>(chunk_start + 10));
-                let sample_rate = u32::from_le_bytes(first_block.get_bytes_section::<4>(chunk_start + 12));
-                let byte_rate = u32::from_le_bytes(first_block.get_bytes_section::<4>(chunk_start + 16));
-                let block_align = u16::from_le_bytes(first_block.get_bytes_section::<2>(chunk_start + 20));
-                let bits_per_sample = u16::from_le_bytes(first_block.get_bytes_section::<2>(chunk_start + 22));
+                let mut format_code = u16::from_le_bytes(fmt.get_bytes_section::<2>(0));
+                let n_channels = u16::from_le_bytes(fmt.get_bytes_section::<2>(2));
+                let sample_rate = u32::from_le_bytes(fmt.get_bytes_section::<4>(4));
+                let byte_rate = u32::from_le_bytes(fmt.get_bytes_section::<4>(8));
+                let block_align = u16::from_le_bytes(fmt.get_bytes_section::<2>(12));
+                let bits_per_sample = u16::from_le_bytes(fmt.get_bytes_section::<2>(14));
 
+                if n_channels == 0 || block_align == 0 || sample_rate == 0 {
+                    return Err(WavError::InvalidFormat);
+                }
                 let bytes_per_channel = block_align / n_channels;
 
+                if format_code == WAVE_FORMAT_EXTENSIBLE {
+                    let fmt_data = current_chunk.chunk_start + 8;
+                    let extension_size = u16::from_le_bytes(read_file_bytes::<2, T>(exfat, start_block_address, fmt_data + 16)?);
+                    if extension_size >= EXTENSIBLE_EXTENSION_SIZE {
+                        wav_file.channel_mask = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, fmt_data + 20)?);
+                        format_code = u16::from_le_bytes(read_file_bytes::<2, T>(exfat, start_block_address, fmt_data + 24)?);
+                    }
+                }
+
                 let format = Format::decode_format(format_code);
 
                 wav_file.format = format; wav_file.n_channels = n_channels;
@@ -103,48 +335,855 @@ impl WavFile {
                 wav_file.block_align = block_align;
                 wav_file.bits_per_sample = bits_per_sample;
                 wav_file.bytes_per_channel = bytes_per_channel;
+
+                if n_channels > 2 {
+                    wav_file.downmix = Downmix::new(n_channels, wav_file.channel_mask);
+                }
+
+                // MS ADPCM needs the predictor coefficients from the extended part of the fmt chunk
+                if format == Format::MsAdpcm {
+                    wav_file.ms_adpcm = read_ms_adpcm_format(exfat, start_block_address, current_chunk.chunk_start + 8, block_align, n_channels)?;
+                }
+            } else if current_chunk.identifier == "COMM" {
+                found_format_chunk = true;
+
+                let comm_data = current_chunk.chunk_start + 8;
+                let (n_channels, frame_count, bits_per_sample, sample_rate) = read_aiff_common(exfat, start_block_address, comm_data)?;
+                if n_channels == 0 || bits_per_sample == 0 || sample_rate == 0 {
+                    return Err(WavError::InvalidFormat);
+                }
+
+                // AIFF files are always big endian PCM, AIFC files say how the samples are stored
+                // The COMM bits_per_sample of companded files is the decoded size, the samples themselves are 8 bits
+                let compression_type = if is_aifc && current_chunk.length >= AIFC_COMM_LENGTH {
+                    read_file_bytes::<4, T>(exfat, start_block_address, comm_data + COMM_LENGTH as u64)?
+                } else {
+                    *b"NONE"
+                };
+                let (format, bits_per_sample, big_endian) = match &compression_type {
+                    b"NONE" | b"twos" => (Format::Pcm, bits_per_sample, true),
+                    b"sowt" => (Format::Pcm, bits_per_sample, false),
+                    b"fl32" | b"FL32" => (Format::IeeeFloat, 32, true),
+                    b"alaw" | b"ALAW" => (Format::Alaw, 8, true),
+                    b"ulaw" | b"ULAW" => (Format::Mulaw, 8, true),
+                    _ => (Format::Other, bits_per_sample, true),
+                };
+
+                let bytes_per_channel = bits_per_sample.div_ceil(8);
+                wav_file.format = format;
+                wav_file.n_channels = n_channels;
+                wav_file.sample_rate = sample_rate;
+                wav_file.block_align = bytes_per_channel * n_channels;
+                wav_file.byte_rate = sample_rate * wav_file.block_align as u32;
+                wav_file.bits_per_sample = bits_per_sample;
+                wav_file.bytes_per_channel = bytes_per_channel;
+                wav_file.big_endian = big_endian;
+                wav_file.fact_sample_count = Some(frame_count as u64);
+
+                if n_channels > 2 {
+                    wav_file.downmix = Downmix::new(n_channels, 0);
+                }
+            } else if current_chunk.identifier == "SSND" {
+                // The sound data starts offset bytes after the SSND header, offset is almost always 0
+                found_data_chunk = true;
+                let ssnd_data = current_chunk.chunk_start + 8;
+                let offset = u32::from_be_bytes(read_file_bytes::<4, T>(exfat, start_block_address, ssnd_data)?) as u64;
+                wav_file.first_byte = ssnd_data + SSND_HEADER_LENGTH + offset;
+                wav_file.data_length = (current_chunk.length as u64).saturating_sub(SSND_HEADER_LENGTH + offset);
+            } else if current_chunk.identifier == "fact" {
+                let sample_count = read_file_bytes::<4, T>(exfat, start_block_address, current_chunk.chunk_start + 8)?;
+                let sample_count = u32::from_le_bytes(sample_count);
+                if sample_count != riff::RF64_LENGTH_IN_DS64 {
+                    wav_file.fact_sample_count = Some(sample_count as u64);
+                }
+            } else if current_chunk.identifier == "ds64" {
+                // 64 bit sizes for RF64 files: RIFF size, data size, sample count
+                let ds64_data = current_chunk.chunk_start + 8;
+                ds64_data_length = Some(u64::from_le_bytes(read_file_bytes::<8, T>(exfat, start_block_address, ds64_data + 8)?));
+                let sample_count = u64::from_le_bytes(read_file_bytes::<8, T>(exfat, start_block_address, ds64_data + 16)?);
+                if sample_count != 0 {
+                    wav_file.fact_sample_count = Some(sample_count);
+                }
+            } else if current_chunk.identifier == "smpl" {
+                wav_file.sample_loop = read_sample_loop(exfat, start_block_address, current_chunk.chunk_start + 8)?;
+            } else if current_chunk.identifier == "cue " {
+                read_cue_points(exfat, start_block_address, current_chunk.chunk_start + 8, &mut wav_file.cue_points)?;
+            } else if current_chunk.identifier == "labl" {
+                let (id, label) = read_label(exfat, start_block_address, current_chunk.chunk_start + 8, current_chunk.length)?;
+                let _ = labels.push((id, label));
+            } else if current_chunk.identifier == "INAM" || current_chunk.identifier == "IART" || current_chunk.identifier == "IPRD" {
+                // Text chunks from a LIST INFO chunk, IPRD is the album (product) name
+                let text = read_info_text(exfat, start_block_address, current_chunk.chunk_start + 8, current_chunk.length)?;
+                match current_chunk.identifier.as_str() {
+                    "INAM" => wav_file.tags.title = text,
+                    "IART" => wav_file.tags.artist = text,
+                    _ => wav_file.tags.album = text,
+                }
+            } else if current_chunk.identifier == "id3 " || current_chunk.identifier == "ID3 " {
+                id3::read_id3_tags(exfat, start_block_address, current_chunk.chunk_start + 8, current_chunk.length, &mut wav_file.tags)?;
+            } else if current_chunk.identifier == "rgad" {
+                let rgad_data = read_file_bytes::<8, T>(exfat, start_block_address, current_chunk.chunk_start + 8)?;
+                wav_file.tags.replay_gain.read_rgad(rgad_data);
+            } else if current_chunk.identifier == "bext" {
+                wav_file.bext = Some(read_broadcast_extension(exfat, start_block_address, current_chunk.chunk_start + 8)?);
             } else if current_chunk.identifier == "data" {
                 found_data_chunk = true;
-                wav_file.first_byte = current_chunk.chunk_start as u32 + 8;
-                wav_file.data_length = current_chunk.length;
+                wav_file.first_byte = current_chunk.chunk_start + 8;
+                wav_file.data_length = current_chunk.length as u64;
+
+                // In RF64 files the next chunk can't be found from the 32 bit length, so stop looking
+                if let (riff::RF64_LENGTH_IN_DS64, Some(ds64_data_length)) = (current_chunk.length, ds64_data_length) {
+                    wav_file.data_length = ds64_data_length;
+                    break;
+                }
+
+                // Cue points and their labels usually come after the data, so keep going
+            }
+
+            // Stop at the end of the first part of the file
+            if current_chunk.next_chunk + 8 > file.valid_data_length {
                 break;
             }
 
             // Update current chunk with the next chunk
-            current_chunk = current_chunk.get_next_chunk(&mut exfat.block_device, start_block_address)?;
-        } 
+            // Chunks after the data are optional, so a file with a broken chunk there can still be played
+            current_chunk = match current_chunk.get_next_chunk(&mut exfat.block_device, start_block_address) {
+                Ok(next_chunk) => next_chunk,
+                Err(()) if found_data_chunk => break,
+                Err(()) => return Err(WavError::ReadFail),
+            };
 
-        if found_data_chunk && found_format_chunk {
-            return Ok(wav_file)
+            // Give up on chunks which aren't really chunks, the missing chunks are reported below
+            if !current_chunk.has_valid_identifier() || current_chunk.next_chunk <= current_chunk.chunk_start {
+                break;
+            }
         }
 
-        Err(())
+        // Labels can come before or after the cue chunk, so they are matched up at the end
+        for cue_point in wav_file.cue_points.iter_mut() {
+            if let Some((_, label)) = labels.iter().find(|(id, _)| *id == cue_point.id) {
+                cue_point.label = label.clone();
+            }
+        }
+
+        if !found_format_chunk {
+            return Err(WavError::NoFormatChunk);
+        }
+        if !found_data_chunk {
+            return Err(WavError::NoDataChunk);
+        }
+        wav_file.update_stream_length();
+
+        // Only the sample rate depends on the output, so that is left for the player to probe
+        if let Some(unsupported) = wav_file.probe(wav_file.sample_rate).unsupported {
+            return Err(WavError::Unsupported(unsupported));
+        }
+
+        Ok(wav_file)
+    }
+
+    // Create a wav file for a file with no headers, where the whole file is samples in the given format
+    pub fn from_raw_pcm<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &ExFat<T>, file: &FsEntry, raw_format: &RawPcmFormat)
+    -> Result<Self, WavError> {
+        let RawPcmFormat { format, sample_rate, n_channels, bits_per_sample, big_endian } = *raw_format;
+        if n_channels == 0 || bits_per_sample == 0 || sample_rate == 0 {
+            return Err(WavError::InvalidFormat);
+        }
+        if !exfat.is_contiguous(file) {
+            return Err(WavError::Fragmented);
+        }
+
+        let mut wav_file = WavFile::empty(exfat, file);
+        let bytes_per_channel = bits_per_sample.div_ceil(8);
+
+        wav_file.data_length = file.valid_data_length;
+        wav_file.stream_length = file.valid_data_length;
+        wav_file.format = format;
+        wav_file.n_channels = n_channels;
+        wav_file.sample_rate = sample_rate;
+        wav_file.block_align = bytes_per_channel * n_channels;
+        wav_file.byte_rate = sample_rate * wav_file.block_align as u32;
+        wav_file.bits_per_sample = bits_per_sample;
+        wav_file.bytes_per_channel = bytes_per_channel;
+        wav_file.big_endian = big_endian;
+
+        if n_channels > 2 {
+            wav_file.downmix = Downmix::new(n_channels, 0);
+        }
+
+        if let Some(unsupported) = wav_file.probe(sample_rate).unsupported {
+            return Err(WavError::Unsupported(unsupported));
+        }
+
+        Ok(wav_file)
+    }
+
+    // A file with no format or data yet, the data is read from the start of file
+    fn empty<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &ExFat<T>, file: &FsEntry) -> Self {
+        let mut wav_file = WavFile {
+            data_length: 0,
+            stream_length: 0,
+            first_byte: 0,
+            bytes_read: 0,
+            format: Format::Other,
+            n_channels: 0,
+            sample_rate: 0,
+            byte_rate: 0,
+            block_align: 0,
+            bits_per_sample: 0,
+            bytes_per_channel: 0,
+            big_endian: false,
+            fact_sample_count: None,
+            channel_mask: 0,
+            sample_loop: None,
+            looping: false,
+            ab_loop: None,
+            frame_pos: 0,
+            cue_points: Vec::new(),
+            bext: None,
+            tags: Tags::default(),
+            parts: Vec::new(),
+            ms_adpcm: None,
+            downmix: None,
+            resampler: None,
+            output_sample_rate: 0,
+            speed_percent: NORMAL_SPEED_PERCENT,
+            dither: Dither::new(crate::DITHER),
+            pcm_block: PcmBlock {
+                blocks: [[0; BLOCK_SIZE]; PCM_READ_BLOCKS],
+                pos: 0, // Empty, so the first decode reads a new block
+                end: 0,
+            },
+        };
+
+        let _ = wav_file.parts.push(FilePart::new(exfat, file));
+        wav_file
+    }
+
+    // Checks whether the file can be played on an output running at output_sample_rate
+    pub fn probe(&self, output_sample_rate: u32) -> ProbeReport {
+        // MS ADPCM files can also have parameters outside the limits of the decoder
+        let decodable_format = matches!(self.format, Format::Pcm | Format::IeeeFloat | Format::Alaw | Format::Mulaw | Format::MsAdpcm);
+        let adpcm_unsupported = self.format == Format::MsAdpcm && self.ms_adpcm.is_none();
+
+        let unsupported = if !decodable_format || adpcm_unsupported {
+            Some(Unsupported::Format(self.format))
+        } else if !is_decodable(self.format, self.bits_per_sample) {
+            Some(Unsupported::BitDepth(self.bits_per_sample))
+        } else if !matches!(self.n_channels, 1 | 2) && self.downmix.is_none() {
+            Some(Unsupported::Channels(self.n_channels))
+        } else if self.sample_rate != output_sample_rate && Resampler::new(self.sample_rate, output_sample_rate).is_none() {
+            Some(Unsupported::SampleRate(self.sample_rate))
+        } else {
+            None
+        };
+
+        ProbeReport {
+            supported: unsupported.is_none(),
+            unsupported,
+        }
+    }
+
+    // Number of sample frames (one sample for every channel) in the file
+    // Compressed formats can only be measured with the fact chunk, so this is None if it's missing
+    pub fn frame_count(&self) -> Option<u64> {
+        if let Some(sample_count) = self.fact_sample_count {
+            return Some(sample_count);
+        }
+
+        if self.format.is_compressed() || self.block_align == 0 {
+            return None;
+        }
+
+        Some(self.data_length / self.block_align as u64)
+    }
+
+    // Length of the file in milliseconds
+    // This is from the frame count when it's known, otherwise it's estimated from the length of the data and the byte rate
+    pub fn duration_ms(&self) -> u32 {
+        if let (Some(frame_count), true) = (self.frame_count(), self.sample_rate != 0) {
+            return (frame_count * 1000 / self.sample_rate as u64) as u32;
+        }
+
+        if self.byte_rate == 0 {
+            return 0;
+        }
+
+        (self.data_length * 1000 / self.byte_rate as u64) as u32
+    }
+
+    // How far into the file playback is in milliseconds
+    // This counts the frames decoded rather than bytes_read, as bytes_read runs up to a block ahead of the decoder
+    pub fn position_ms(&self) -> u32 {
+        if self.sample_rate == 0 {
+            return 0;
+        }
+
+        (self.frame_pos as u64 * 1000 / self.sample_rate as u64) as u32
+    }
+
+    // True for compressed files without a fact chunk, their length can't be calculated accurately
+    pub fn missing_fact_chunk(&self) -> bool {
+        self.format.is_compressed() && self.fact_sample_count.is_none()
     }
 
     // Get the next block from the wav file
+    // Returns the number of bytes of wav data in buf, the last block of the data is padded with zeros
+    // Returns 0 once all the data has been read
     pub fn get_next_pcm_block<'a, T: block_device::BlockDevice<BLOCK_SIZE>>
         (&mut self, exfat: &mut ExFat<T>, buf: &mut [u8; BLOCK_SIZE])
-    -> Result<(), ()> {
+    -> Result<usize, ()> {
+        if self.is_finished() {
+            return Ok(0);
+        }
 
-        // Ignore the first couple of samples because they aren't alligned to a block
-        if self.bytes_read == 0 {
-            self.bytes_read += BLOCK_SIZE as u32 - self.first_byte;
-            return self.get_next_pcm_block(exfat, buf);
+        let (blockaddr, _, start, new_bytes_read) = self.next_pcm_read(1)?;
+        exfat.block_device.read_to_block(blockaddr, buf)?;
+
+        // The data chunk doesn't have to start on a block boundary, so the first block is moved to the front of buf
+        let valid_bytes = (new_bytes_read - self.bytes_read) as usize;
+        buf.copy_within(start.., 0);
+        buf.iter_mut().skip(valid_bytes).for_each(|byte| *byte = 0);
+        self.bytes_read = new_bytes_read;
+        Ok(valid_bytes)
+    }
+
+    // Plans the next read of up to max_blocks blocks of PCM data
+    // Returns the address of the first block, how many blocks to read, the index in the first block where the unread data starts,
+    // and what bytes_read will be once they have been read
+    // The blocks are always consecutive on the block device, so a read stops early at the end of a part of the file
+    fn next_pcm_read(&self, max_blocks: usize) -> Result<(u32, usize, usize, u64), ()> {
+        if self.bytes_read >= self.stream_length {
+            return Err(());
         }
 
-        // Similairly ignore the last couple of samples
-        let new_bytes_read = self.bytes_read + BLOCK_SIZE as u32;
-        if new_bytes_read >= self.data_length {
+        // The first block is shared with the chunks before the data, so the data starts part way into it
+        // The last block of the data is usually only partly filled
+        let file_byte = self.first_byte + self.bytes_read;
+        let start = (file_byte % BLOCK_SIZE as u64) as usize;
+        let (blockaddr, blocks_in_part) = self.block_run((file_byte / BLOCK_SIZE as u64) as u32)?;
+
+        let unread_blocks = (start as u64 + self.stream_length - self.bytes_read).div_ceil(BLOCK_SIZE as u64);
+        let blocks = (max_blocks as u64).min(blocks_in_part as u64).min(unread_blocks).max(1) as usize;
+        let new_bytes_read = (self.bytes_read + (blocks * BLOCK_SIZE - start) as u64).min(self.stream_length);
+
+        Ok((blockaddr, blocks, start, new_bytes_read))
+    }
+
+    // True once all the wav data has been read and decoded
+    // Compressed files can have padding after their last sample, so they finish after the number of samples in the fact chunk
+    pub fn is_finished(&self) -> bool {
+        let past_last_frame = self.format.is_compressed() && self.fact_sample_count.is_some_and(|count| self.frame_pos as u64 >= count);
+        past_last_frame || (self.bytes_read >= self.stream_length && self.pcm_block.pos >= self.pcm_block.end)
+    }
+
+    // Only the valid data of a file is streamed, the rest of its last cluster could be anything
+    // A file which is still being written (or was only partly copied) can have a data chunk longer than the file
+    fn update_stream_length(&mut self) {
+        let valid_length: u64 = self.parts.iter().map(|part| part.length).sum();
+        self.stream_length = self.data_length.min(valid_length.saturating_sub(self.first_byte));
+    }
+
+    // Converts the index of a block in the file to a block address on the block device
+    // This follows the file across its parts if it has been split
+    fn block_address(&self, file_block: u32) -> Result<u32, ()> {
+        Ok(self.block_run(file_block)?.0)
+    }
+
+    // Like block_address, but also returns how many blocks from there to the end of the part are consecutive
+    fn block_run(&self, file_block: u32) -> Result<(u32, u32), ()> {
+        let mut part_first_block = 0;
+
+        for part in self.parts.iter() {
+            if file_block < part_first_block + part.blocks {
+                let offset = file_block - part_first_block;
+                return Ok((part.start_block_address + offset, part.blocks - offset));
+            }
+            part_first_block += part.blocks;
+        }
+
+        Err(())
+    }
+
+    // Adds a file containing the continuation of the wav data
+    // Every part except the last has to be a whole number of blocks long, otherwise the data wouldn't line up
+    // Like the first part, each part has to be contiguous
+    pub fn append_part<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &ExFat<T>, file: &FsEntry) -> Result<(), ()> {
+        if !exfat.is_contiguous(file) {
             return Err(());
         }
+        if let Some(last_part) = self.parts.last() {
+            if last_part.length % BLOCK_SIZE as u64 != 0 {
+                return Err(());
+            }
+        }
 
-        // Otherwise get the block address and return the block
-        let blockaddr = self.start_block_address + ((self.first_byte as u32 + self.bytes_read) / BLOCK_SIZE as u32);
-        exfat.block_device.read_to_block(blockaddr, buf)?;
-        self.bytes_read = new_bytes_read;
+        self.parts.push(FilePart::new(exfat, file)).map_err(|_| ())?;
+        self.update_stream_length();
         Ok(())
     }
 
+    // Finds the numbered parts that follow first_part in a directory listing and appends them
+    // Returns how many parts were added, which is 0 if first_part isn't named like track.wav.001
+    pub fn append_parts_from_directory<T: block_device::BlockDevice<BLOCK_SIZE>>
+        (&mut self, exfat: &ExFat<T>, directory: &[FsEntry], first_part: &FsEntry)
+    -> Result<usize, ()> {
+        let mut parts_added = 0;
+        let mut part_name = next_part_name(first_part.name.as_str());
+
+        while let Some(name) = part_name {
+            let Some(part) = directory.iter().find(|fs_entry| fs_entry.name == name) else {
+                break;
+            };
+
+            self.append_part(exfat, part)?;
+            parts_added += 1;
+            part_name = next_part_name(name.as_str());
+        }
+
+        Ok(parts_added)
+    }
+
+    // Get the next byte of PCM data, reading new blocks when the current ones have been used up
+    fn next_pcm_byte<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Result<u8, ()> {
+        if self.pcm_block.pos >= self.pcm_block.end {
+            let (blockaddr, blocks, start, new_bytes_read) = self.next_pcm_read(PCM_READ_BLOCKS)?;
+            exfat.block_device.finish_read(blockaddr, self.pcm_block.blocks.get_mut(..blocks).ok_or(())?)?;
+            self.pcm_block.pos = start;
+            self.pcm_block.end = start + (new_bytes_read - self.bytes_read) as usize;
+            self.bytes_read = new_bytes_read;
+
+            // The next blocks can stream in while these are decoded, if the block device can read in the background
+            if let Ok((next_blockaddr, next_blocks, _, _)) = self.next_pcm_read(PCM_READ_BLOCKS) {
+                let _ = exfat.block_device.start_read(next_blockaddr, next_blocks);
+            }
+        }
+
+        let byte = *self.pcm_block.blocks.as_flattened().get(self.pcm_block.pos).ok_or(())?;
+        self.pcm_block.pos += 1;
+        Ok(byte)
+    }
+
+    // Decode the next sample of one channel as a 16 bit sample
+    fn next_sample<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Result<i16, ()> {
+        match (self.format, self.bits_per_sample) {
+            (Format::Pcm, 16) => {
+                let bytes = [self.next_pcm_byte(exfat)?, self.next_pcm_byte(exfat)?];
+                if self.big_endian {
+                    Ok(i16::from_be_bytes(bytes))
+                } else {
+                    Ok(i16::from_le_bytes(bytes))
+                }
+            },
+
+            // 24 bit samples are 3 little endian bytes
+            // They are dithered down to 16 bits
+            (Format::Pcm, 24) => {
+                let mut bytes = [0, self.next_pcm_byte(exfat)?, self.next_pcm_byte(exfat)?, self.next_pcm_byte(exfat)?];
+                if self.big_endian {
+                    bytes.swap(1, 3);
+                }
+                let sample = i32::from_le_bytes(bytes) >> (16 - dither::FRACTION_BITS); // Sign extend the 24 bit sample
+                Ok(self.dither.reduce(sample))
+            },
+
+            // Float samples are nominally between -1.0 and 1.0, and 32 bit PCM samples are 4 bytes
+            // Both are dithered down to 16 bits
+            (Format::IeeeFloat, 32) | (Format::Pcm, 32) => {
+                let mut bytes = [0u8; 4];
+                for byte in bytes.iter_mut() {
+                    *byte = self.next_pcm_byte(exfat)?;
+                }
+
+                if self.big_endian {
+                    bytes.reverse();
+                }
+                let sample = match self.format {
+                    Format::IeeeFloat => float_to_fixed(f32::from_le_bytes(bytes)),
+                    _ => i32::from_le_bytes(bytes) >> (16 - dither::FRACTION_BITS),
+                };
+                Ok(self.dither.reduce(sample))
+            },
+
+            (Format::MsAdpcm, 4) => {
+                let needs_block = self.ms_adpcm.as_ref().ok_or(())?.needs_block();
+
+                // Read a whole ADPCM block once the previous one has been decoded
+                // The last block is often cut short, the rest of it is zeros and the fact chunk says where its samples end
+                if needs_block {
+                    let mut block = [0u8; adpcm::MAX_BLOCK_ALIGN];
+                    let block_align = self.block_align as usize;
+                    for (indx, byte) in block.iter_mut().take(block_align).enumerate() {
+                        match self.next_pcm_byte(exfat) {
+                            Ok(pcm_byte) => *byte = pcm_byte,
+                            Err(()) if indx > 0 && self.is_finished() => break,
+                            Err(()) => return Err(()),
+                        }
+                    }
+
+                    let ms_adpcm = self.ms_adpcm.as_mut().ok_or(())?;
+                    for (dest, byte) in ms_adpcm.block_mut().iter_mut().zip(block.iter()) {
+                        *dest = *byte;
+                    }
+                    ms_adpcm.start_block()?;
+                }
+
+                self.ms_adpcm.as_mut().ok_or(())?.next_sample()
+            },
+
+            // A-law and µ-law samples are a single companded byte
+            (Format::Alaw, 8) => Ok(g711::ALAW_TABLE[self.next_pcm_byte(exfat)? as usize]),
+            (Format::Mulaw, 8) => Ok(g711::MULAW_TABLE[self.next_pcm_byte(exfat)? as usize]),
+
+            _ => Err(()),
+        }
+    }
+
+    // Decode the next frame as a left and right sample
+    // Mono samples are copied to both channels, and more than 2 channels are downmixed
+    fn next_stereo_frame<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Result<(i16, i16), ()> {
+        // Jump back to the start of the loop after its last frame
+        // This happens between two frames of a fill, so the output carries straight on from the start of the loop
+        if let Some(active_loop) = self.ab_loop.or(self.sample_loop.filter(|_| self.looping)) {
+            if self.frame_pos > active_loop.end {
+                self.seek_to_sample(exfat, active_loop.start)?;
+            }
+        }
+        if self.is_finished() {
+            return Err(());
+        }
+        self.frame_pos += 1;
+        self.dither.start_frame();
+
+        match self.n_channels {
+            1 => {
+                let sample = self.next_sample(exfat)?;
+                Ok((sample, sample))
+            },
+            2 => Ok((self.next_sample(exfat)?, self.next_sample(exfat)?)),
+            n_channels => {
+                let mut frame = [0i16; downmix::MAX_CHANNELS];
+                let frame = frame.get_mut(..n_channels as usize).ok_or(())?;
+                for sample in frame.iter_mut() {
+                    *sample = self.next_sample(exfat)?;
+                }
+
+                Ok(self.downmix.as_ref().ok_or(())?.mix(frame))
+            },
+        }
+    }
+
+    // Fills buf with 16 bit stereo samples decoded from the wav data, channels are interleaved
+    // Returns the number of samples put in buf, if this is less than the length of buf the end of the data was reached
+    // and the rest of buf is filled with silence
+    // With the no-panic feature this is kept as its own function so scripts/check_no_panic.sh can find it
+    #[cfg_attr(feature = "no-panic", inline(never))]
+    pub fn fill_samples<T: block_device::BlockDevice<BLOCK_SIZE>>
+        (&mut self, exfat: &mut ExFat<T>, buf: &mut [u16])
+    -> Result<usize, ()> {
+        let mut samples_filled = 0;
+
+        for frame in buf.chunks_exact_mut(2) {
+            match self.next_output_frame(exfat) {
+                Ok((left, right)) => {
+                    frame[0] = left as u16;
+                    frame[1] = right as u16;
+                    samples_filled += 2;
+                },
+                Err(()) if self.is_finished() => break,
+                Err(()) => return Err(()),
+            }
+        }
+
+        buf.iter_mut().skip(samples_filled).for_each(|sample| *sample = 0);
+        Ok(samples_filled)
+    }
+
+    // Decode the next stereo frame at the output sample rate
+    fn next_output_frame<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>) -> Result<(i16, i16), ()> {
+        if self.resampler.is_none() {
+            return self.next_stereo_frame(exfat);
+        }
+
+        while self.resampler.as_ref().is_some_and(|resampler| resampler.needs_input()) {
+            let input_frame = self.next_stereo_frame(exfat)?;
+            self.resampler.as_mut().ok_or(())?.push(input_frame);
+        }
+        Ok(self.resampler.as_mut().ok_or(())?.next_frame())
+    }
+
+    // Go back to the start of the wav data
+    fn rewind(&mut self) {
+        self.bytes_read = 0;
+        self.frame_pos = 0;
+        self.pcm_block.pos = 0;
+        self.pcm_block.end = 0;
+        if let Some(ms_adpcm) = self.ms_adpcm.as_mut() {
+            ms_adpcm.reset();
+        }
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+        self.dither.reset();
+    }
+
+    // Moves playback to a sample frame (a sample for every channel), so the next sample decoded is the first sample of that frame
+    // The position is recalculated from the frame, so seeking backwards and forwards both work
+    pub fn seek_to_sample<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, sample: u32) -> Result<(), ()> {
+        if self.block_align == 0 || (self.format == Format::MsAdpcm && self.ms_adpcm.is_none()) {
+            return Err(());
+        }
+
+        // MS ADPCM can only be decoded from the start of a block, so decoding starts there and runs up to the sample
+        let (frames_per_block, skip_frames) = match self.ms_adpcm.as_ref() {
+            Some(ms_adpcm) => {
+                let frames_per_block = ms_adpcm.samples_per_block().max(1) as u32;
+                (frames_per_block, sample % frames_per_block)
+            },
+            None => (1, 0),
+        };
+
+        let data_byte = (sample / frames_per_block) as u64 * self.block_align as u64;
+        if data_byte >= self.stream_length {
+            return Err(());
+        }
+
+        // Load the block containing the frame, and set bytes_read as if every block before it had been read
+        let file_byte = self.first_byte + data_byte;
+        let file_block = file_byte / BLOCK_SIZE as u64;
+        let blockaddr = self.block_address(file_block as u32)?;
+        let [first_block, ..] = &mut self.pcm_block.blocks;
+        exfat.block_device.read_to_block(blockaddr, first_block)?;
+
+        self.bytes_read = ((file_block + 1) * BLOCK_SIZE as u64 - self.first_byte).min(self.stream_length);
+        self.pcm_block.pos = (file_byte % BLOCK_SIZE as u64) as usize;
+        self.pcm_block.end = (self.first_byte + self.bytes_read - file_block * BLOCK_SIZE as u64) as usize;
+
+        if let Some(ms_adpcm) = self.ms_adpcm.as_mut() {
+            ms_adpcm.reset();
+        }
+        for _ in 0..skip_frames * self.n_channels as u32 {
+            self.next_sample(exfat)?;
+        }
+
+        self.frame_pos = sample;
+        Ok(())
+    }
+
+    // Moves playback to a time in milliseconds from the start of the file
+    pub fn seek_to_ms<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, ms: u32) -> Result<(), ()> {
+        let sample = ms as u64 * self.sample_rate as u64 / 1000;
+        self.seek_to_sample(exfat, sample.try_into().map_err(|_| ())?)
+    }
+
+    // Byte offset in the data of the block that holds frame, so a position can be saved without knowing the format
+    pub fn data_offset(&self, frame: u32) -> u64 {
+        (frame / self.frames_per_block()) as u64 * self.block_align as u64
+    }
+
+    // Moves playback to the start of the block at a byte offset in the data, from data_offset
+    pub fn seek_to_data_offset<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, offset: u64) -> Result<(), ()> {
+        let block = offset.checked_div(self.block_align as u64).ok_or(())?;
+        let frame = block * self.frames_per_block() as u64;
+        self.seek_to_sample(exfat, frame.try_into().map_err(|_| ())?)
+    }
+
+    // Frames in each block of block_align bytes, MS ADPCM packs many frames into a block
+    fn frames_per_block(&self) -> u32 {
+        self.ms_adpcm.as_ref().map_or(1, |ms_adpcm| ms_adpcm.samples_per_block().max(1) as u32)
+    }
+
+    // Moves playback to one of the cue points in cue_points
+    pub fn seek_to_cue<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, cue_indx: usize) -> Result<(), ()> {
+        let frame = self.cue_points.get(cue_indx).ok_or(())?.frame;
+        self.seek_to_sample(exfat, frame)
+    }
+
+    // Loops between the smpl chunk loop points forever when enabled
+    // Returns false if looping was requested but the file has no loop
+    pub fn set_looping(&mut self, looping: bool) -> bool {
+        let can_loop = self.sample_loop.is_some_and(|sample_loop| sample_loop.start <= sample_loop.end);
+        self.looping = looping && can_loop;
+        self.looping == looping
+    }
+
+    // Loops forever between two positions, e.g. to practise along with a passage, the jump back is sample accurate
+    // The end is exclusive, returns Err if the loop is empty or ends past the end of the file
+    pub fn set_ab_loop(&mut self, start_ms: u32, end_ms: u32) -> Result<(), ()> {
+        let to_frame = |ms: u32| (ms as u64 * self.sample_rate as u64 / 1000) as u32;
+        let (start, end) = (to_frame(start_ms), to_frame(end_ms));
+        if start >= end || self.frame_count().is_some_and(|frame_count| end as u64 > frame_count) {
+            return Err(());
+        }
+
+        self.ab_loop = Some(SampleLoop { start, end: end - 1, play_count: 0 });
+        Ok(())
+    }
+
+    // Plays on past the end of the A-B loop
+    pub fn clear_ab_loop(&mut self) {
+        self.ab_loop = None;
+    }
+
+    pub fn ab_loop(&self) -> Option<SampleLoop> {
+        self.ab_loop
+    }
+
+    // Converts the samples from fill_samples to output_sample_rate if the file has a different sample rate
+    // Returns true if the samples will be resampled
+    pub fn set_output_sample_rate(&mut self, output_sample_rate: u32) -> bool {
+        self.output_sample_rate = output_sample_rate;
+        self.resampler = None;
+        self.update_resampler();
+        self.resampler.is_some()
+    }
+
+    // Plays the file faster or slower, from MIN_SPEED_PERCENT to MAX_SPEED_PERCENT, the pitch changes with the speed
+    // Frames are skipped or repeated by the resampler, interpolating between them
+    // Takes effect from the next frame once the output sample rate has been set, returns the speed that was set
+    pub fn set_speed(&mut self, speed_percent: u16) -> u16 {
+        let old_speed_percent = self.speed_percent;
+        self.speed_percent = speed_percent.clamp(MIN_SPEED_PERCENT, MAX_SPEED_PERCENT);
+        if !self.update_resampler() {
+            self.speed_percent = old_speed_percent; // The resampler can't go this fast from this sample rate
+        }
+        self.speed_percent
+    }
+
+    pub fn speed_percent(&self) -> u16 {
+        self.speed_percent
+    }
+
+    // Sets up the resampler for the sample rate, output sample rate, and speed
+    // It is kept if it is already running, so the speed can be changed without a glitch
+    // Returns false if the resampler can't convert between the rates, then the file plays at the wrong rate
+    fn update_resampler(&mut self) -> bool {
+        if self.output_sample_rate == 0 {
+            return true;
+        }
+
+        let input_rate = (self.sample_rate as u64 * self.speed_percent as u64 / NORMAL_SPEED_PERCENT as u64) as u32;
+        if input_rate == self.output_sample_rate {
+            self.resampler = None;
+            return true;
+        }
+
+        match self.resampler.as_mut() {
+            Some(resampler) => resampler.set_rates(input_rate, self.output_sample_rate),
+            None => {
+                self.resampler = Resampler::new(input_rate, self.output_sample_rate);
+                self.resampler.is_some()
+            },
+        }
+    }
+
+    // Skips silence at the start of the file, so tracks with sloppy exports start instantly
+    // Silence is any frame where every sample is within threshold of zero
+    // Leading silence shorter than min_silence_ms is left alone
+    // Call before any samples have been read, returns the number of frames skipped
+    pub fn skip_leading_silence<T: block_device::BlockDevice<BLOCK_SIZE>>
+        (&mut self, exfat: &mut ExFat<T>, threshold: i16, min_silence_ms: u32)
+    -> Result<u32, ()> {
+        let min_silence_frames = (self.sample_rate as u64 * min_silence_ms as u64 / 1000) as u32;
+        let threshold = threshold.unsigned_abs();
+
+        // Count the silent frames
+        // If the file ends before any sound the silence is left alone
+        self.rewind();
+        let mut silent_frames = 0;
+        'count: loop {
+            for _ in 0..self.n_channels {
+                match self.next_sample(exfat) {
+                    Ok(sample) if sample.unsigned_abs() <= threshold => (),
+                    Ok(_) => break 'count,
+                    Err(_) => {
+                        silent_frames = 0;
+                        break 'count;
+                    },
+                }
+            }
+            silent_frames += 1;
+        }
+
+        // Counting read past the first frame with sound, so go back and skip just the silence
+        self.rewind();
+        if silent_frames < min_silence_frames {
+            return Ok(0);
+        }
+
+        for _ in 0..silent_frames * self.n_channels as u32 {
+            self.next_sample(exfat)?;
+        }
+        self.frame_pos = silent_frames;
+
+        Ok(silent_frames)
+    }
+
+    // Ends the track where the sound ends, so tracks with a long gap at the end move on to the next track sooner
+    // Silence is the same as for skip_leading_silence, trailing silence shorter than min_silence_ms is left alone
+    // Only the last max_scan_ms of the file is decoded, from a longer silence just the last max_scan_ms is cut off
+    // Call before any samples have been read, returns the number of frames cut off
+    pub fn skip_trailing_silence<T: block_device::BlockDevice<BLOCK_SIZE>>
+        (&mut self, exfat: &mut ExFat<T>, threshold: i16, min_silence_ms: u32, max_scan_ms: u32)
+    -> Result<u32, ()> {
+        // Compressed files without a fact chunk can't be measured, so their end can't be found
+        let Some(frame_count) = self.frame_count() else {
+            return Ok(0);
+        };
+        let frame_count = frame_count.min(u32::MAX as u64) as u32;
+        let min_silence_frames = (self.sample_rate as u64 * min_silence_ms as u64 / 1000) as u32;
+        let scan_frames = (self.sample_rate as u64 * max_scan_ms as u64 / 1000) as u32;
+
+        let sound_end = self.find_sound_end(exfat, threshold.unsigned_abs(), frame_count.saturating_sub(scan_frames), frame_count);
+        self.rewind();
+
+        // A file that is silent all the way through is left alone
+        let sound_end = sound_end?;
+        let silent_frames = frame_count - sound_end;
+        if sound_end == 0 || silent_frames < min_silence_frames {
+            return Ok(0);
+        }
+
+        // The data ends after the block holding the last frame with sound
+        self.data_length = self.data_length.min(self.data_offset(sound_end - 1) + self.block_align as u64);
+        self.update_stream_length();
+        if self.format.is_compressed() || self.fact_sample_count.is_some() {
+            self.fact_sample_count = Some(sound_end as u64);
+        }
+
+        Ok(silent_frames)
+    }
+
+    // The frame after the last frame between first_frame and end_frame with a sample above threshold, or first_frame if they're all silent
+    fn find_sound_end<T: block_device::BlockDevice<BLOCK_SIZE>>
+        (&mut self, exfat: &mut ExFat<T>, threshold: u16, first_frame: u32, end_frame: u32)
+    -> Result<u32, ()> {
+        let mut window_end = end_frame;
+        while window_end > first_frame {
+            let window_start = window_end.saturating_sub(SILENCE_SCAN_FRAMES).max(first_frame);
+            self.seek_to_sample(exfat, window_start)?;
+
+            // The last frame can be past the end of a file that was cut short, those frames are silent
+            let mut sound_end = None;
+            'window: for frame in window_start..window_end {
+                for _ in 0..self.n_channels {
+                    match self.next_sample(exfat) {
+                        Ok(sample) if sample.unsigned_abs() <= threshold => (),
+                        Ok(_) => sound_end = Some(frame + 1),
+                        Err(_) => break 'window,
+                    }
+                }
+            }
+
+            if let Some(sound_end) = sound_end {
+                return Ok(sound_end);
+            }
+            window_end = window_start;
+        }
+
+        Ok(first_frame)
+    }
+
     // Fills the sample_vec buffer and returns an iterator over that buffer that converts the bytes into usable PCM samples
     // Not very useful for DMA 
     pub fn get_next_samples<'a, T: block_device::BlockDevice<BLOCK_SIZE>>
@@ -153,23 +1192,27 @@ impl WavFile {
 
         // Limits of the implementation
         if self.bytes_per_channel > 4 {
+            #[cfg(feature = "no-panic")]
+            return Err(());
+
+            #[cfg(not(feature = "no-panic"))]
             panic!();
         }
 
         *sample_vec = Vec::new(); // Clear sample vec before starting so the old samples aren't reused
 
-        let blockaddr = self.start_block_address + ((self.first_byte as u32 + self.bytes_read) / 512);
+        let first_file_block = ((self.first_byte + self.bytes_read) / BLOCK_SIZE as u64) as u32;
 
         // Bytes to skip off the front
         let skip_bytes: u32 = if self.bytes_read == 0 {
-            ((self.first_byte) % BLOCK_SIZE as u32) as u32
+            ((self.first_byte) % BLOCK_SIZE as u64) as u32
         } else {
             0
         };
 
         let mut bytes_read = 0; // The total bytes read during this function
         for i in 0..BUFFER_BLOCKS as u32 {
-            let block = exfat.block_device.read_block(blockaddr + i)?;
+            let block = exfat.block_device.read_block(self.block_address(first_file_block + i)?)?;
             let _ = sample_vec.extend_from_slice(&block);
 
             // Bytes read now is the number of bytes read past the start of the pcm data, or past the start of the block
@@ -186,7 +1229,7 @@ impl WavFile {
             // This if statement catches the end of the pcm data
             // It will break from the for loop once all the pcm data has been added to the vec
             // and will update the bytes_read accordingly 
-            let bytes_left = self.data_length - self.bytes_read;
+            let bytes_left = self.stream_length.saturating_sub(self.bytes_read).min(u32::MAX as u64) as u32;
             if bytes_read_now > bytes_left {
                 bytes_read += bytes_left;
                 break;
@@ -195,7 +1238,7 @@ impl WavFile {
             bytes_read += bytes_read_now
         }
 
-        self.bytes_read += bytes_read;
+        self.bytes_read += bytes_read as u64;
 
         // This sample iter contains only the bytes which are PCM data,and ecludes other RIFF bytes
         let mut sample_iter = sample_vec.iter().skip(skip_bytes as usize).take(bytes_read as usize);
@@ -228,3 +1271,288 @@ impl WavFile {
         Ok(samples)
     }
 }
+
+impl Decoder for WavFile {
+    type Error = WavError;
+
+    fn open<T: block_device::BlockDevice<BLOCK_SIZE>>(exfat: &mut ExFat<T>, file: &FsEntry) -> Result<Self, WavError> {
+        WavFile::new(exfat, file)
+    }
+
+    fn probe(&self, output_sample_rate: u32) -> ProbeReport {
+        WavFile::probe(self, output_sample_rate)
+    }
+
+    fn set_output_sample_rate(&mut self, output_sample_rate: u32) -> bool {
+        WavFile::set_output_sample_rate(self, output_sample_rate)
+    }
+
+    fn fill<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, buf: &mut [u16]) -> Result<usize, ()> {
+        self.fill_samples(exfat, buf)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn n_channels(&self) -> u16 {
+        self.n_channels
+    }
+
+    fn seek_to_ms<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, ms: u32) -> Result<(), ()> {
+        WavFile::seek_to_ms(self, exfat, ms)
+    }
+
+    fn position_ms(&self) -> u32 {
+        WavFile::position_ms(self)
+    }
+
+    fn duration_ms(&self) -> u32 {
+        WavFile::duration_ms(self)
+    }
+
+    fn set_speed(&mut self, speed_percent: u16) -> u16 {
+        WavFile::set_speed(self, speed_percent)
+    }
+
+    fn set_ab_loop(&mut self, start_ms: u32, end_ms: u32) -> Result<(), ()> {
+        WavFile::set_ab_loop(self, start_ms, end_ms)
+    }
+
+    fn clear_ab_loop(&mut self) {
+        WavFile::clear_ab_loop(self)
+    }
+
+    fn data_offset(&self, frame: u32) -> u64 {
+        WavFile::data_offset(self, frame)
+    }
+
+    fn seek_to_data_offset<T: block_device::BlockDevice<BLOCK_SIZE>>(&mut self, exfat: &mut ExFat<T>, offset: u64) -> Result<(), ()> {
+        WavFile::seek_to_data_offset(self, exfat, offset)
+    }
+
+    fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    fn tags_mut(&mut self) -> &mut Tags {
+        &mut self.tags
+    }
+}
+
+// True if next_sample can decode samples with this format and bit depth
+fn is_decodable(format: Format, bits_per_sample: u16) -> bool {
+    matches!(
+        (format, bits_per_sample),
+        (Format::Pcm, 16) | (Format::Pcm, 24) | (Format::Pcm, 32) | (Format::IeeeFloat, 32) | (Format::Alaw, 8) | (Format::Mulaw, 8) | (Format::MsAdpcm, 4)
+    )
+}
+
+// Converts a float sample into a 16 bit sample with dither::FRACTION_BITS bits below it
+// Anything outside of -1.0 to 1.0 is clipped, and NaN becomes silence
+fn float_to_fixed(sample: f32) -> i32 {
+    let scaled = sample.clamp(-1.0, 1.0) * (i16::MAX as i32 * (1 << dither::FRACTION_BITS)) as f32;
+    scaled as i32 // Float to int casts saturate, and NaN is cast to 0
+}
+
+// Reads the extended fmt chunk of an MS ADPCM file, fmt_data is the byte address of the fmt chunk data
+// Returns None if the decoder can't handle the parameters
+fn read_ms_adpcm_format<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, fmt_data: u64, block_align: u16, n_channels: u16)
+-> Result<Option<MsAdpcm>, WavError> {
+    let extension_size = u16::from_le_bytes(read_file_bytes::<2, T>(exfat, start_block_address, fmt_data + 16)?);
+    if extension_size < 4 {
+        return Ok(None);
+    }
+
+    let samples_per_block = u16::from_le_bytes(read_file_bytes::<2, T>(exfat, start_block_address, fmt_data + 18)?);
+    let n_coefficients = u16::from_le_bytes(read_file_bytes::<2, T>(exfat, start_block_address, fmt_data + 20)?) as usize;
+    if n_coefficients > adpcm::MAX_COEFFICIENTS {
+        return Ok(None);
+    }
+
+    let mut coefficients = Vec::new();
+    for i in 0..n_coefficients as u64 {
+        let pair = read_file_bytes::<4, T>(exfat, start_block_address, fmt_data + 22 + i * 4)?;
+        let coefficient1 = i16::from_le_bytes([pair[0], pair[1]]);
+        let coefficient2 = i16::from_le_bytes([pair[2], pair[3]]);
+        let _ = coefficients.push((coefficient1, coefficient2));
+    }
+
+    Ok(MsAdpcm::new(block_align, n_channels, samples_per_block, coefficients))
+}
+
+// Reads the COMM chunk of an AIFF file, comm_data is the byte address of the chunk data
+// Returns the number of channels, number of sample frames, bits per sample, and sample rate
+fn read_aiff_common<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, comm_data: u64)
+-> Result<(u16, u32, u16, u32), WavError> {
+    let comm = read_file_bytes::<{COMM_LENGTH as usize}, T>(exfat, start_block_address, comm_data)?;
+
+    let n_channels = u16::from_be_bytes(comm.get_bytes_section::<2>(0));
+    let frame_count = u32::from_be_bytes(comm.get_bytes_section::<4>(2));
+    let bits_per_sample = u16::from_be_bytes(comm.get_bytes_section::<2>(6));
+    let sample_rate = extended_to_u32(comm.get_bytes_section::<10>(8));
+
+    Ok((n_channels, frame_count, bits_per_sample, sample_rate))
+}
+
+// Converts an 80 bit extended precision float to an integer, rounding down
+// This is a sign bit, a 15 bit exponent, and a 64 bit mantissa with an explicit integer bit
+// Negative numbers and numbers less than 1 are 0, numbers too big for a u32 are u32::MAX
+fn extended_to_u32(bytes: [u8; 10]) -> u32 {
+    let sign_exponent = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let mantissa = u64::from_be_bytes(bytes.get_bytes_section::<8>(2));
+
+    let exponent = (sign_exponent & 0x7FFF) as i32 - EXTENDED_EXPONENT_BIAS;
+    if sign_exponent & 0x8000 != 0 || exponent < 0 {
+        return 0;
+    }
+    if exponent > 31 {
+        return u32::MAX;
+    }
+
+    (mantissa >> (63 - exponent)) as u32
+}
+
+// Reads the first loop from a smpl chunk, smpl_data is the byte address of the chunk data
+fn read_sample_loop<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, smpl_data: u64)
+-> Result<Option<SampleLoop>, WavError> {
+    let loop_count = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, smpl_data + SMPL_LOOP_COUNT_OFFSET)?);
+    if loop_count == 0 {
+        return Ok(None);
+    }
+
+    // Each loop is: cue point id, type, start, end, fraction, play count
+    let first_loop = smpl_data + SMPL_FIRST_LOOP_OFFSET;
+    let start = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, first_loop + 8)?);
+    let end = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, first_loop + 12)?);
+    let play_count = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, first_loop + 20)?);
+
+    Ok(Some(SampleLoop { start, end, play_count }))
+}
+
+// Reads the cue points from a cue chunk, cue_data is the byte address of the chunk data
+// Cue points past MAX_CUE_POINTS are ignored
+fn read_cue_points<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, cue_data: u64, cue_points: &mut Vec<CuePoint, MAX_CUE_POINTS>)
+-> Result<(), WavError> {
+    let count = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, cue_data)?);
+
+    // Each cue point is: id, position, data chunk id, chunk start, block start, sample offset
+    for i in 0..(count as usize).min(MAX_CUE_POINTS) as u64 {
+        let cue_point = cue_data + 4 + i * CUE_POINT_LENGTH;
+        let id = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, cue_point)?);
+        let frame = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, cue_point + 20)?);
+
+        let _ = cue_points.push(CuePoint { id, frame, label: String::new() });
+    }
+
+    Ok(())
+}
+
+// Reads a labl chunk, which is a cue point id followed by a null terminated name
+// Non ascii characters are replaced, and long names are shortened to MAX_CUE_LABEL_LENGTH
+fn read_label<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, labl_data: u64, length: u32)
+-> Result<(u32, String<MAX_CUE_LABEL_LENGTH>), WavError> {
+    let id = u32::from_le_bytes(read_file_bytes::<4, T>(exfat, start_block_address, labl_data)?);
+    let text = read_file_bytes::<MAX_CUE_LABEL_LENGTH, T>(exfat, start_block_address, labl_data + 4)?;
+
+    let text_length = (length as usize).saturating_sub(4).min(MAX_CUE_LABEL_LENGTH);
+    Ok((id, ascii_string(&text[..text_length])))
+}
+
+// Reads a null terminated text chunk from a LIST INFO chunk, text_data is the byte address of the chunk data
+fn read_info_text<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, text_data: u64, length: u32)
+-> Result<String<MAX_TAG_LENGTH>, WavError> {
+    let text = read_file_bytes::<MAX_TAG_LENGTH, T>(exfat, start_block_address, text_data)?;
+    let text_length = (length as usize).min(MAX_TAG_LENGTH);
+    Ok(ascii_string(&text[..text_length]))
+}
+
+// Reads a bext chunk, bext_data is the byte address of the chunk data
+fn read_broadcast_extension<T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, bext_data: u64)
+-> Result<BroadcastExtension, WavError> {
+    let description = read_file_bytes::<256, T>(exfat, start_block_address, bext_data)?;
+    let originator = read_file_bytes::<32, T>(exfat, start_block_address, bext_data + BEXT_ORIGINATOR_OFFSET)?;
+    let originator_reference = read_file_bytes::<32, T>(exfat, start_block_address, bext_data + BEXT_ORIGINATOR_REFERENCE_OFFSET)?;
+    let origination_date = read_file_bytes::<10, T>(exfat, start_block_address, bext_data + BEXT_ORIGINATION_DATE_OFFSET)?;
+    let origination_time = read_file_bytes::<8, T>(exfat, start_block_address, bext_data + BEXT_ORIGINATION_TIME_OFFSET)?;
+    let time_reference = read_file_bytes::<8, T>(exfat, start_block_address, bext_data + BEXT_TIME_REFERENCE_OFFSET)?;
+
+    Ok(BroadcastExtension {
+        description: ascii_string(&description),
+        originator: ascii_string(&originator),
+        originator_reference: ascii_string(&originator_reference),
+        origination_date: ascii_string(&origination_date),
+        origination_time: ascii_string(&origination_time),
+        time_reference: u64::from_le_bytes(time_reference), // Stored as the low then high 32 bits
+    })
+}
+
+// Converts text that is padded or terminated with nulls, non ascii characters are replaced
+// Anything that doesn't fit in N bytes is cut off
+fn ascii_string<const N: usize>(bytes: &[u8]) -> String<N> {
+    let mut string = String::new();
+    for byte in bytes.iter().take_while(|byte| **byte != 0) {
+        let character = if byte.is_ascii() { *byte as char } else { '?' };
+        if string.push(character).is_err() {
+            break;
+        }
+    }
+
+    string
+}
+
+// Split files are named like track.wav.001, track.wav.002, ...
+// Returns the name of the part which follows name, or None if name doesn't end in a part number
+pub fn next_part_name(name: &str) -> Option<String<{exfat::MAX_FILE_NAME_LENGTH}>> {
+    let (stem, number) = name.rsplit_once('.')?;
+    if number.len() != PART_NUMBER_DIGITS || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let next_number = number.parse::<u32>().ok()? + 1;
+    if next_number >= 10_u32.pow(PART_NUMBER_DIGITS as u32) {
+        return None;
+    }
+
+    let mut next_name = String::new();
+    next_name.push_str(stem).ok()?;
+    next_name.push('.').ok()?;
+    for digit in [next_number / 100, next_number / 10 % 10, next_number % 10] {
+        next_name.push(char::from_digit(digit, 10)?).ok()?;
+    }
+
+    Some(next_name)
+}
+
+// Reads N bytes starting at a byte address in the file
+// The bytes can cross a block boundary
+pub fn read_file_bytes<const N: usize, T: block_device::BlockDevice<BLOCK_SIZE>>
+    (exfat: &mut ExFat<T>, start_block_address: u32, byte_addr: u64)
+-> Result<[u8; N], WavError> {
+    let mut output = [0u8; N];
+    let mut output_indx = 0;
+
+    while output_indx < N {
+        let addr = byte_addr + output_indx as u64;
+        let blockaddr = start_block_address + (addr / BLOCK_SIZE as u64) as u32;
+        let block = exfat.block_device.read_block(blockaddr).map_err(|_| WavError::ReadFail)?;
+
+        for byte in block.iter().skip((addr % BLOCK_SIZE as u64) as usize) {
+            if output_indx == N {
+                break;
+            }
+
+            output[output_indx] = *byte;
+            output_indx += 1;
+        }
+    }
+
+    Ok(output)
+}
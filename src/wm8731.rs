@@ -0,0 +1,167 @@
+// Control of the WM8731 and WM8731L codecs over I2C, as on many audio breakout boards
+//
+// The audio comes in over I2S with the WM8731 as the slave, on a master clock of 256 x fs from the STM32
+// The DAC drives the line output and the headphone amplifier, the ADC and inputs are powered down
+// The line output is at a fixed level, so the volume is set with the headphone amplifier
+//
+// The registers can't be read back, each write is 7 bits of address and 9 bits of data
+//
+// Useful resources:
+// https://www.cirrus.com/products/wm8731/ (datasheet, see "Device Setup" for the power up order)
+
+use embedded_hal::i2c::I2c;
+
+use crate::codec::Codec;
+use crate::volume::HardwareVolume;
+
+// 7 bit address with CSB low, 0x1B with it high
+pub const ADDRESS: u8 = 0x1A;
+
+// Registers
+const LEFT_LINE_IN: u8 = 0x00;
+const RIGHT_LINE_IN: u8 = 0x01;
+const LEFT_HEADPHONE_OUT: u8 = 0x02; // The right channel (0x03) is set with it
+const ANALOGUE_PATH: u8 = 0x04;
+const DIGITAL_PATH: u8 = 0x05;
+const POWER_DOWN: u8 = 0x06;
+const INTERFACE_FORMAT: u8 = 0x07;
+const SAMPLING: u8 = 0x08;
+const ACTIVE: u8 = 0x09;
+const RESET: u8 = 0x0F;
+
+const LINE_IN_MUTED: u16 = 0x080;
+const DAC_SELECTED_MIC_MUTED: u16 = 0x012;
+const I2S_16_BIT_SLAVE: u16 = 0x002;
+
+// Power down bits, everything but the DAC and the outputs is left off
+const OUTPUTS_OFF: u16 = 0x010;
+const PLAYBACK_POWER: u16 = 0x067; // Line in, mic, ADC, oscillator and clock out off
+const POWER_OFF: u16 = 0x0FF;
+
+// Digital audio path bits
+const DAC_SOFT_MUTE: u16 = 0x008;
+const DE_EMPHASIS_32K: u16 = 0b01 << 1;
+const DE_EMPHASIS_44K1: u16 = 0b10 << 1;
+const DE_EMPHASIS_48K: u16 = 0b11 << 1;
+const DE_EMPHASIS_TOLERANCE: u32 = 500; // Hz
+
+// Sampling control in normal mode, the codec only cares about the ratio of the master clock to the sample rate
+const SAMPLING_256FS: u16 = 0x000;
+const SAMPLING_128FS: u16 = 0b0111 << 2; // 96 KHz mode, needs the master clock halved
+const CLOCK_IN_DIVIDE_BY_2: u16 = 0x040;
+const MAX_256FS_RATE: u32 = 48_000; // The master clock is too fast for the codec above this without the divider
+
+// Headphone volume bits, from -73 dB to +6 dB in 1 dB steps, below that it's muted
+const HEADPHONE_BOTH_CHANNELS: u16 = 0x100;
+const HEADPHONE_ZERO_CROSS: u16 = 0x080;
+const HEADPHONE_0DB: i32 = 0x79;
+const HEADPHONE_MIN: i32 = 0x30;
+const HEADPHONE_MAX: i32 = 0x7F;
+const HEADPHONE_MUTED: u16 = 0x000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wm8731Output {
+    Headphone, // The headphone amplifier follows the volume, and the line output is at full level
+    Line, // Only the line output, the headphone amplifier is muted
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wm8731Error {
+    Bus, // The I2C transfer failed, e.g. nothing answered at the address
+}
+
+pub struct Wm8731<I2C> {
+    i2c: I2C,
+    address: u8,
+    output: Wm8731Output,
+    de_emphasis: bool, // For tracks recorded with pre-emphasis, e.g. some early CDs
+    digital_path: u16, // Kept since the registers can't be read back
+}
+
+impl<I2C: I2c> Wm8731<I2C> {
+    pub fn new(i2c: I2C, address: u8, output: Wm8731Output, de_emphasis: bool) -> Self {
+        Wm8731 {
+            i2c,
+            address,
+            output,
+            de_emphasis,
+            digital_path: 0,
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<(), Wm8731Error> {
+        let [high, low] = value.to_be_bytes();
+        self.i2c.write(self.address, &[register << 1 | (high & 1), low]).map_err(|_| Wm8731Error::Bus)
+    }
+}
+
+impl<I2C: I2c> Codec for Wm8731<I2C> {
+    type Error = Wm8731Error;
+
+    // Everything but the activation and the outputs is set up here, so the outputs are turned on last and don't pop
+    fn init(&mut self) -> Result<(), Wm8731Error> {
+        self.write(RESET, 0)?;
+        self.write(POWER_DOWN, PLAYBACK_POWER | OUTPUTS_OFF)?;
+        self.write(LEFT_LINE_IN, LINE_IN_MUTED)?;
+        self.write(RIGHT_LINE_IN, LINE_IN_MUTED)?;
+        self.write(ANALOGUE_PATH, DAC_SELECTED_MIC_MUTED)?;
+        self.digital_path = 0;
+        self.write(DIGITAL_PATH, self.digital_path)?;
+        self.write(INTERFACE_FORMAT, I2S_16_BIT_SLAVE)?;
+        self.write(SAMPLING, SAMPLING_256FS)?;
+        self.set_volume(0.0)
+    }
+
+    // The de-emphasis filter is chosen by the sample rate, it's only there for 32 KHz, 44.1 KHz and 48 KHz
+    fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), Wm8731Error> {
+        let sampling = if sample_rate > MAX_256FS_RATE {
+            SAMPLING_128FS | CLOCK_IN_DIVIDE_BY_2
+        } else {
+            SAMPLING_256FS
+        };
+
+        let de_emphasis = [(32_000, DE_EMPHASIS_32K), (44_100, DE_EMPHASIS_44K1), (48_000, DE_EMPHASIS_48K)]
+            .iter()
+            .find(|(rate, _)| sample_rate.abs_diff(*rate) <= DE_EMPHASIS_TOLERANCE)
+            .map_or(0, |(_, bits)| *bits);
+        self.digital_path = (self.digital_path & DAC_SOFT_MUTE) | if self.de_emphasis { de_emphasis } else { 0 };
+
+        // The sampling can only be changed while the interface is inactive
+        self.write(ACTIVE, 0)?;
+        self.write(SAMPLING, sampling)?;
+        self.write(DIGITAL_PATH, self.digital_path)?;
+        self.write(ACTIVE, 1)?;
+        self.write(POWER_DOWN, PLAYBACK_POWER)
+    }
+
+    // db is limited to -73 dB to +6 dB, and only changes the headphone output
+    fn set_volume(&mut self, db: f32) -> Result<(), Wm8731Error> {
+        let volume = match self.output {
+            Wm8731Output::Headphone => (HEADPHONE_0DB + db as i32).clamp(HEADPHONE_MIN, HEADPHONE_MAX) as u16,
+            Wm8731Output::Line => HEADPHONE_MUTED,
+        };
+
+        // Changes wait for a zero crossing so they don't click, both channels are set from the left register
+        self.write(LEFT_HEADPHONE_OUT, HEADPHONE_BOTH_CHANNELS | HEADPHONE_ZERO_CROSS | volume)
+    }
+
+    // Only the headphone output has a volume control
+    fn volume_range(&self) -> Option<HardwareVolume> {
+        (self.output == Wm8731Output::Headphone).then_some(HardwareVolume {
+            min_db: (HEADPHONE_MIN - HEADPHONE_0DB) as f32,
+            step_db: 1.0,
+        })
+    }
+
+    // The DAC's soft mute ramps the volume down, so it doesn't click
+    fn mute(&mut self, muted: bool) -> Result<(), Wm8731Error> {
+        self.digital_path = if muted { self.digital_path | DAC_SOFT_MUTE } else { self.digital_path & !DAC_SOFT_MUTE };
+        self.write(DIGITAL_PATH, self.digital_path)
+    }
+
+    // The outputs are turned off before the rest, so they don't pop
+    fn power_down(&mut self) -> Result<(), Wm8731Error> {
+        self.write(POWER_DOWN, PLAYBACK_POWER | OUTPUTS_OFF)?;
+        self.write(POWER_DOWN, POWER_OFF)
+    }
+}